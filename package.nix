@@ -8,8 +8,36 @@ stdenv.mkDerivation {
       whitelist = map builtins.toString [
         ./src
         ./src/lib.rs
+        ./src/bin
+        ./src/bin/overlay
+        ./src/bin/overlay/main.rs
+        ./src/bin/overlay/attrs.rs
+        ./src/bin/overlay/commit.rs
+        ./src/bin/overlay/export.rs
+        ./src/bin/overlay/gc.rs
+        ./src/bin/overlay/snapshot.rs
+        ./src/bin/overlay/tar.rs
+        ./src/bin/overlay/upper.rs
         ./src/config.rs
+        ./src/control.rs
+        ./src/copy.rs
+        ./src/evict.rs
+        ./src/hardlink.rs
+        ./src/hide.rs
+        ./src/lock.rs
+        ./src/log.rs
+        ./src/metacopy.rs
+        ./src/program.rs
+        ./src/quota.rs
         ./src/redir.rs
+        ./src/reload.rs
+        ./src/remote.rs
+        ./src/rewrite.rs
+        ./src/synthetic.rs
+        ./src/sysno.rs
+        ./src/toml.rs
+        ./src/transform.rs
+        ./src/whiteout.rs
       ];
     in
       builtins.filterSource (path: type: builtins.elem path whitelist) ./.;
@@ -23,12 +51,22 @@ stdenv.mkDerivation {
       --crate-name overlay \
       src/lib.rs \
       --crate-type cdylib \
+      --crate-type rlib \
+      -C opt-level=3 \
+      --out-dir out
+    rustc \
+      --edition=2018 \
+      --crate-name overlay \
+      src/bin/overlay/main.rs \
+      --crate-type bin \
+      --extern overlay=out/liboverlay.rlib \
       -C opt-level=3 \
       --out-dir out
   '';
 
   installPhase = ''
-    mkdir -p $out/lib
+    mkdir -p $out/lib $out/bin
     mv out/liboverlay.so $out/lib
+    mv out/overlay $out/bin
   '';
 }
\ No newline at end of file
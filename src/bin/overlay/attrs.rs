@@ -0,0 +1,46 @@
+//! Copies the owner, mode and times of files between the dirs of an overlay.
+
+use std::ffi::CString;
+use std::fs::Metadata;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+extern "C" {
+    fn lchown(path: *const c_char, owner: u32, group: u32) -> c_int;
+    fn utimensat(dirfd: c_int, path: *const c_char, times: *const [i64; 2], flags: c_int) -> c_int;
+}
+
+const AT_FDCWD: c_int = -100;
+const AT_SYMLINK_NOFOLLOW: c_int = 0x100;
+
+/// Gives `path` the owner and times in `metadata` and the permissions `mode`, without following
+/// symlinks. Symlinks have no permissions of their own.
+pub fn set(path: &Path, metadata: &Metadata, mode: u32) -> std::io::Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    // Only root may give files away, everybody else can at best hand them to the group
+    if unsafe { lchown(cpath.as_ptr(), metadata.uid(), metadata.gid()) } != 0 {
+        unsafe { lchown(cpath.as_ptr(), !0, metadata.gid()) };
+    }
+    if !metadata.file_type().is_symlink() {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    let times = [
+        [metadata.atime(), metadata.atime_nsec()],
+        [metadata.mtime(), metadata.mtime_nsec()],
+    ];
+    if unsafe {
+        utimensat(
+            AT_FDCWD,
+            cpath.as_ptr(),
+            times.as_ptr(),
+            AT_SYMLINK_NOFOLLOW,
+        )
+    } != 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
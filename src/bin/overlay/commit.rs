@@ -0,0 +1,361 @@
+//! `overlay commit` applies the upper dir to the lower dir, so that the lower dir ends up with the
+//! contents of the merged view, and empties the upper dir in the process.
+//!
+//! Files and symlinks of the upper dir replace their lower counterparts, stubs only pass on their
+//! metadata, and whited out lower entries are deleted. Directories recreated in the upper dir are
+//! opaque, the lower entries they do not contain are deleted as well.
+//!
+//! A lower entry that changed after the overlay modified it, i.e. whose status change time is more
+//! recent than that of the upper entry or whiteout, is a conflict. Conflicts are reported and left
+//! in place in both dirs, unless `--force` is given.
+
+use std::ffi::OsString;
+use std::fs::Metadata;
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use overlay::whiteout;
+
+use crate::attrs;
+use crate::upper;
+use crate::Options;
+
+const ENOTEMPTY: c_int = 39;
+
+/// The hard link index of the upper dir, see `hardlink.rs`.
+const INDEX_DIR: &str = ".wh..wh.index";
+
+#[derive(Default)]
+struct Stats {
+    conflicts: u64,
+    errors: u64,
+}
+
+pub fn run(options: &Options) -> i32 {
+    let mut stats = Stats::default();
+    let lower = match options.lower_dir.metadata() {
+        Ok(lower) => lower,
+        Err(e) => {
+            report(&options.lower_dir, &e, &mut stats);
+            return 1;
+        }
+    };
+    if let Err(e) = make_writable(options, &options.lower_dir, &lower) {
+        report(&options.lower_dir, &e, &mut stats);
+        return 1;
+    }
+    apply_dir(
+        options,
+        &options.upper_dir,
+        &options.lower_dir,
+        false,
+        &mut stats,
+    );
+    let restored = do_unless_dry(options, || {
+        std::fs::set_permissions(&options.lower_dir, lower.permissions())
+    });
+    if let Err(e) = restored {
+        report(&options.lower_dir, &e, &mut stats);
+    }
+    if !options.dry_run {
+        prune_index(&options.upper_dir.join(INDEX_DIR));
+    }
+    if stats.conflicts > 0 {
+        eprintln!(
+            "overlay: {} conflicts, rerun with --force to overwrite the lower entries",
+            stats.conflicts
+        );
+    }
+    if stats.conflicts > 0 || stats.errors > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+/// Applies the entries of `dir_in_upper` to `lower_dir`. If the dir is `opaque`, the lower entries
+/// it does not contain are deleted. Returns whether everything was applied.
+fn apply_dir(
+    options: &Options,
+    dir_in_upper: &Path,
+    lower_dir: &Path,
+    opaque: bool,
+    stats: &mut Stats,
+) -> bool {
+    let mut names: Vec<OsString> = match std::fs::read_dir(dir_in_upper) {
+        Ok(entries) => entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.file_name())
+            .collect(),
+        Err(e) => return report(dir_in_upper, &e, stats),
+    };
+    // Sorted for a stable report, which also puts whiteouts first
+    names.sort();
+    let mut applied = true;
+    for name in &names {
+        let path = dir_in_upper.join(name);
+        let lower_path = lower_dir.join(name);
+        applied &= match whiteout::hidden_name(name.as_bytes()) {
+            // Bookkeeping entries go away along with the entries they belong to
+            Some(hidden) if hidden.starts_with(b".wh.") => true,
+            // A whiteout with an upper entry in place only makes that entry opaque
+            Some(hidden) if names.iter().any(|name| name.as_bytes() == hidden) => true,
+            Some(hidden) => {
+                let lower_path = lower_dir.join(Path::new(std::ffi::OsStr::from_bytes(hidden)));
+                apply_whiteout(options, &path, &lower_path, stats)
+            }
+            None => apply_entry(options, dir_in_upper, name, &lower_path, stats),
+        };
+    }
+    if opaque {
+        applied &= delete_others(options, &names, lower_dir, stats);
+    }
+    applied
+}
+
+/// Applies the upper entry `name` in `dir_in_upper` to `lower_path`.
+fn apply_entry(
+    options: &Options,
+    dir_in_upper: &Path,
+    name: &OsString,
+    lower_path: &Path,
+    stats: &mut Stats,
+) -> bool {
+    let path = dir_in_upper.join(name);
+    let upper = match path.symlink_metadata() {
+        Ok(upper) => upper,
+        Err(e) => return report(&path, &e, stats),
+    };
+    let lower = lower_path.symlink_metadata().ok();
+    let marker = whiteout::marker_path(&path).filter(|marker| marker.symlink_metadata().is_ok());
+    let opaque = marker.is_some();
+    if upper.is_dir() {
+        let lower_is_dir = lower.as_ref().map_or(false, |lower| lower.is_dir());
+        if !lower_is_dir {
+            let status = if lower.is_some() { "M" } else { "A" };
+            let created = lower
+                .as_ref()
+                .map_or(Ok(()), |_| remove_lower(options, lower_path))
+                .and_then(|_| do_unless_dry(options, || std::fs::create_dir(lower_path)));
+            if let Err(e) = created {
+                return report(lower_path, &e, stats);
+            }
+            println!("{} {}/", status, relative(options, &path).display());
+        }
+        if let Some(lower) = lower.as_ref().filter(|lower| lower.is_dir()) {
+            if let Err(e) = make_writable(options, lower_path, lower) {
+                return report(lower_path, &e, stats);
+            }
+        }
+        let applied = apply_dir(options, &path, lower_path, opaque, stats)
+            && apply_metadata(options, lower_path, &upper, lower.as_ref(), stats);
+        return applied
+            && finish(options, &path, true, stats)
+            && marker.map_or(true, |marker| finish(options, &marker, false, stats));
+    }
+    if let Some(lower) = &lower {
+        if !options.force && changed_after(lower, &upper) {
+            return conflict(options, &path, stats);
+        }
+    }
+    let (status, applied) = if upper::is_stub(&path, &upper) {
+        (
+            "M",
+            apply_metadata(options, lower_path, &upper, lower.as_ref(), stats),
+        )
+    } else {
+        let replaced = lower.as_ref().map_or(Ok(()), |lower| {
+            // Files are overwritten in place, which keeps their hard links
+            if lower.is_file() && upper.is_file() {
+                make_writable(options, lower_path, lower)
+            } else {
+                remove_lower(options, lower_path)
+            }
+        });
+        let copied = replaced.and_then(|_| {
+            do_unless_dry(options, || {
+                if upper.file_type().is_symlink() {
+                    std::os::unix::fs::symlink(std::fs::read_link(&path)?, lower_path)
+                } else {
+                    std::fs::copy(&path, lower_path).map(|_| ())
+                }
+            })
+        });
+        match copied {
+            Ok(()) => (
+                if lower.is_some() { "M" } else { "A" },
+                apply_metadata(options, lower_path, &upper, lower.as_ref(), stats),
+            ),
+            Err(e) => ("", report(lower_path, &e, stats)),
+        }
+    };
+    if !applied {
+        return false;
+    }
+    println!("{} {}", status, relative(options, &path).display());
+    finish(options, &path, false, stats)
+        && marker.map_or(true, |marker| finish(options, &marker, false, stats))
+        && upper::meta_marker(&path).map_or(true, |marker| finish(options, &marker, false, stats))
+}
+
+/// Deletes the lower entry `lower_path`, which is hidden by the whiteout `marker`.
+fn apply_whiteout(options: &Options, marker: &Path, lower_path: &Path, stats: &mut Stats) -> bool {
+    if let Ok(lower) = lower_path.symlink_metadata() {
+        let whited_out = match marker.symlink_metadata() {
+            Ok(whited_out) => whited_out,
+            Err(e) => return report(marker, &e, stats),
+        };
+        if !options.force && changed_after(&lower, &whited_out) {
+            return conflict(options, marker, stats);
+        }
+        if let Err(e) = remove_lower(options, lower_path) {
+            return report(lower_path, &e, stats);
+        }
+        let relative =
+            relative(options, marker).with_file_name(lower_path.file_name().unwrap_or_default());
+        println!("D {}", relative.display());
+    }
+    finish(options, marker, false, stats)
+}
+
+/// Deletes the entries of the lower dir `lower_dir` that are not among the upper `names`.
+fn delete_others(
+    options: &Options,
+    names: &[OsString],
+    lower_dir: &Path,
+    stats: &mut Stats,
+) -> bool {
+    let entries = match std::fs::read_dir(lower_dir) {
+        Ok(entries) => entries,
+        // A dir created by the dry run
+        Err(_) if options.dry_run => return true,
+        Err(e) => return report(lower_dir, &e, stats),
+    };
+    let mut applied = true;
+    for entry in entries.filter_map(Result::ok) {
+        if names.contains(&entry.file_name()) {
+            continue;
+        }
+        let lower_path = entry.path();
+        if let Err(e) = remove_lower(options, &lower_path) {
+            applied = report(&lower_path, &e, stats);
+            continue;
+        }
+        let relative = lower_path
+            .strip_prefix(&options.lower_dir)
+            .unwrap_or(&lower_path);
+        println!("D {}", relative.display());
+    }
+    applied
+}
+
+/// Checks whether the lower entry `lower` changed after the upper entry `upper` was last changed.
+fn changed_after(lower: &Metadata, upper: &Metadata) -> bool {
+    (lower.ctime(), lower.ctime_nsec()) > (upper.ctime(), upper.ctime_nsec())
+}
+
+/// Gives `lower_path` the owner, mode and times of the upper entry `upper`, where `lower` is the
+/// metadata the lower entry had before.
+fn apply_metadata(
+    options: &Options,
+    lower_path: &Path,
+    upper: &Metadata,
+    lower: Option<&Metadata>,
+    stats: &mut Stats,
+) -> bool {
+    if options.dry_run {
+        return true;
+    }
+    // Copy-ups make files writable and directories accessible for their owner, which is not a
+    // change to pass on
+    let added = if upper.is_dir() { 0o700 } else { 0o200 };
+    let mode = match lower {
+        Some(lower) if lower.mode() | added == upper.mode() | added => lower.mode(),
+        _ => upper.mode(),
+    };
+    match attrs::set(lower_path, upper, mode) {
+        Ok(()) => true,
+        Err(e) => report(lower_path, &e, stats),
+    }
+}
+
+/// Lets the owner write to the lower entry `lower_path`, until its mode is applied or restored.
+fn make_writable(options: &Options, lower_path: &Path, lower: &Metadata) -> std::io::Result<()> {
+    let added = if lower.is_dir() { 0o700 } else { 0o200 };
+    if lower.mode() & added == added {
+        return Ok(());
+    }
+    let mode = std::fs::Permissions::from_mode(lower.mode() & 0o7777 | added);
+    do_unless_dry(options, || std::fs::set_permissions(lower_path, mode))
+}
+
+/// Removes the entry `path` from the upper dir once it has been applied. Directories are kept if
+/// bookkeeping entries remain in them.
+fn finish(options: &Options, path: &Path, dir: bool, stats: &mut Stats) -> bool {
+    let removed = do_unless_dry(options, || {
+        if dir {
+            std::fs::remove_dir(path)
+        } else {
+            std::fs::remove_file(path)
+        }
+    });
+    match removed {
+        Ok(()) => true,
+        Err(ref e) if dir && e.raw_os_error() == Some(ENOTEMPTY) => true,
+        Err(e) => report(path, &e, stats),
+    }
+}
+
+/// Removes the lower entry `lower_path`, along with everything in it.
+fn remove_lower(options: &Options, lower_path: &Path) -> std::io::Result<()> {
+    do_unless_dry(options, || {
+        if lower_path.symlink_metadata()?.is_dir() {
+            std::fs::remove_dir_all(lower_path)
+        } else {
+            std::fs::remove_file(lower_path)
+        }
+    })
+}
+
+/// Removes the entries of the hard link index whose copies have been committed.
+fn prune_index(index_dir: &Path) {
+    let entries = match std::fs::read_dir(index_dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        if entry.metadata().map_or(false, |copy| copy.nlink() == 1) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+    let _ = std::fs::remove_dir(index_dir);
+}
+
+fn do_unless_dry<F: FnOnce() -> std::io::Result<()>>(
+    options: &Options,
+    action: F,
+) -> std::io::Result<()> {
+    if options.dry_run {
+        Ok(())
+    } else {
+        action()
+    }
+}
+
+fn relative<'a>(options: &Options, path: &'a Path) -> &'a Path {
+    path.strip_prefix(&options.upper_dir).unwrap_or(path)
+}
+
+fn conflict(options: &Options, path: &Path, stats: &mut Stats) -> bool {
+    println!("C {}", relative(options, path).display());
+    stats.conflicts += 1;
+    false
+}
+
+fn report(path: &Path, error: &std::io::Error, stats: &mut Stats) -> bool {
+    eprintln!("overlay: {}: {}", path.display(), error);
+    stats.errors += 1;
+    false
+}
@@ -0,0 +1,261 @@
+//! `overlay export` packages the changes in an upper dir, so that they can be shipped elsewhere or
+//! reviewed.
+//!
+//! The changes are written as a tar archive in the format of container image layers: whited out
+//! lower entries become empty `.wh.<name>` files, and directories recreated in the upper dir,
+//! which hide all of their lower contents, contain a `.wh..wh..opq` file. Files whose metadata
+//! alone was changed are archived with the contents of their lower file. With `--manifest`, the
+//! changes are listed instead, one per line, as `A path` for added, `M path` for modified and
+//! `D path` for deleted entries, and `R dir/` for directories that replace their lower one.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{File, Metadata};
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use overlay::whiteout;
+
+use crate::tar;
+use crate::upper;
+use crate::Options;
+
+/// Marks a directory as opaque in container image layers.
+const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+enum Sink {
+    Tar(tar::Builder<Box<dyn Write>>),
+    Manifest(Box<dyn Write>),
+}
+
+struct Export {
+    sink: Sink,
+    /// The archived paths of files with several links, by device and inode number.
+    links: HashMap<(u64, u64), Vec<u8>>,
+    errors: u64,
+}
+
+pub fn run(options: &Options) -> i32 {
+    let output = options
+        .output
+        .as_ref()
+        .map_or(Path::new("-"), PathBuf::as_path);
+    let out: Box<dyn Write> = if output == Path::new("-") {
+        Box::new(std::io::BufWriter::new(std::io::stdout()))
+    } else {
+        match File::create(output) {
+            Ok(file) => Box::new(std::io::BufWriter::new(file)),
+            Err(e) => {
+                eprintln!("overlay: {}: {}", output.display(), e);
+                return 1;
+            }
+        }
+    };
+    let mut export = Export {
+        sink: if options.manifest {
+            Sink::Manifest(out)
+        } else {
+            Sink::Tar(tar::Builder::new(out))
+        },
+        links: HashMap::new(),
+        errors: 0,
+    };
+    export.dir(&options.upper_dir, &options.lower_dir, Path::new(""));
+    let finished = match export.sink {
+        Sink::Tar(builder) => builder.finish().map(|_| ()),
+        Sink::Manifest(mut out) => out.flush(),
+    };
+    if let Err(e) = finished {
+        eprintln!("overlay: {}: {}", output.display(), e);
+        return 1;
+    }
+    if export.errors > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+impl Export {
+    /// Exports the changes in `dir_in_upper`, whose counterpart in the lower dir is `lower_dir` and
+    /// whose path in the export is `relative`.
+    fn dir(&mut self, dir_in_upper: &Path, lower_dir: &Path, relative: &Path) {
+        let mut names: Vec<OsString> = match std::fs::read_dir(dir_in_upper) {
+            Ok(entries) => entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.file_name())
+                .collect(),
+            Err(e) => return self.report(dir_in_upper, &e),
+        };
+        names.sort();
+        for name in &names {
+            let path = dir_in_upper.join(name);
+            let lower_path = lower_dir.join(name);
+            match whiteout::hidden_name(name.as_bytes()) {
+                // Bookkeeping entries are not part of the changes
+                Some(hidden) if hidden.starts_with(b".wh.") => {}
+                // A whiteout with an upper entry in place makes that entry opaque
+                Some(hidden) if names.iter().any(|name| name.as_bytes() == hidden) => {}
+                Some(hidden) => {
+                    let hidden = std::ffi::OsStr::from_bytes(hidden);
+                    if lower_dir.join(hidden).symlink_metadata().is_ok() {
+                        self.deleted(&relative.join(hidden), &relative.join(name));
+                    }
+                }
+                None => self.entry(&path, &lower_path, &relative.join(name)),
+            }
+        }
+    }
+
+    fn entry(&mut self, path: &Path, lower_path: &Path, relative: &Path) {
+        let upper = match path.symlink_metadata() {
+            Ok(upper) => upper,
+            Err(e) => return self.report(path, &e),
+        };
+        let lower = lower_path.symlink_metadata().ok();
+        let opaque = whiteout::exists(path);
+        let status = if lower.is_none() { "A" } else { "M" };
+        if upper.is_dir() {
+            let lower_is_dir = lower.as_ref().map_or(false, |lower| lower.is_dir());
+            let status = if opaque && lower.is_some() {
+                Some("R")
+            } else if !lower_is_dir {
+                Some(status)
+            } else {
+                None
+            };
+            self.write(status, relative, &upper, tar::Kind::Dir, None);
+            if opaque {
+                let marker = tar_entry(relative.join(OPAQUE_MARKER), &upper);
+                self.append(&marker, None);
+            }
+            return self.dir(path, lower_path, relative);
+        }
+        if upper.file_type().is_symlink() {
+            let target = match std::fs::read_link(path) {
+                Ok(target) => target.into_os_string().into_vec(),
+                Err(e) => return self.report(path, &e),
+            };
+            return self.write(
+                Some(status),
+                relative,
+                &upper,
+                tar::Kind::Symlink(target),
+                None,
+            );
+        }
+        // The contents of a stub are still those of the lower file
+        let source = if upper::is_stub(path, &upper) {
+            lower_path
+        } else {
+            path
+        };
+        if upper.nlink() > 1 && source == path {
+            let key = (upper.dev(), upper.ino());
+            if let Some(target) = self.links.get(&key).cloned() {
+                return self.write(
+                    Some(status),
+                    relative,
+                    &upper,
+                    tar::Kind::HardLink(target),
+                    None,
+                );
+            }
+            self.links
+                .insert(key, relative.as_os_str().as_bytes().to_vec());
+        }
+        let mut file = match File::open(source) {
+            Ok(file) => file,
+            Err(e) => return self.report(source, &e),
+        };
+        let size = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return self.report(source, &e),
+        };
+        self.write(
+            Some(status),
+            relative,
+            &upper,
+            tar::Kind::File(size),
+            Some(&mut file),
+        );
+    }
+
+    /// Exports the deletion of the lower entry `relative` by the whiteout `marker`.
+    fn deleted(&mut self, relative: &Path, marker: &Path) {
+        match &mut self.sink {
+            Sink::Manifest(out) => {
+                let listed = writeln!(out, "D {}", relative.display());
+                if let Err(e) = listed {
+                    self.report(relative, &e);
+                }
+            }
+            Sink::Tar(_) => {
+                let entry = tar::Entry {
+                    path: marker.as_os_str().as_bytes().to_vec(),
+                    kind: tar::Kind::File(0),
+                    mode: 0o644,
+                    uid: 0,
+                    gid: 0,
+                    mtime: 0,
+                };
+                self.append(&entry, Some(&mut std::io::empty()));
+            }
+        }
+    }
+
+    /// Exports the upper entry `relative`, and lists it as changed with `status`, if any.
+    fn write(
+        &mut self,
+        status: Option<&str>,
+        relative: &Path,
+        upper: &Metadata,
+        kind: tar::Kind,
+        contents: Option<&mut dyn std::io::Read>,
+    ) {
+        match &mut self.sink {
+            Sink::Manifest(out) => {
+                if let Some(status) = status {
+                    let slash = if upper.is_dir() { "/" } else { "" };
+                    let listed = writeln!(out, "{} {}{}", status, relative.display(), slash);
+                    if let Err(e) = listed {
+                        self.report(relative, &e);
+                    }
+                }
+            }
+            Sink::Tar(_) => {
+                let mut entry = tar_entry(relative.to_path_buf(), upper);
+                entry.kind = kind;
+                self.append(&entry, contents);
+            }
+        }
+    }
+
+    fn append(&mut self, entry: &tar::Entry, contents: Option<&mut dyn std::io::Read>) {
+        if let Sink::Tar(builder) = &mut self.sink {
+            if let Err(e) = builder.append(entry, contents) {
+                let path = PathBuf::from(OsString::from_vec(entry.path.clone()));
+                self.report(&path, &e);
+            }
+        }
+    }
+
+    fn report(&mut self, path: &Path, error: &std::io::Error) {
+        eprintln!("overlay: {}: {}", path.display(), error);
+        self.errors += 1;
+    }
+}
+
+/// Returns an empty archive entry `path` with the metadata of `upper`.
+fn tar_entry(path: PathBuf, upper: &Metadata) -> tar::Entry {
+    tar::Entry {
+        path: path.into_os_string().into_vec(),
+        kind: tar::Kind::File(0),
+        mode: upper.mode(),
+        uid: upper.uid(),
+        gid: upper.gid(),
+        mtime: upper.mtime(),
+    }
+}
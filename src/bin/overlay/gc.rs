@@ -0,0 +1,185 @@
+//! `overlay gc` removes the entries of an upper dir that are identical to their lower counterparts,
+//! e.g. files that were opened for writing but never changed, so that long-lived overlays do not
+//! keep growing. Removing them lets the lower entries show through again, which the merged view
+//! cannot tell apart.
+//!
+//! Files count as identical if they have the same contents, owner and mode, directories if they
+//! have the same owner and mode and nothing is left in them. The times are not compared. Copies
+//! with hard links and everything hidden by a whiteout are kept, as are the whiteouts and the
+//! other entries with reserved names, except for temporary copies left behind by a crash.
+
+use std::fs::{File, Metadata};
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use overlay::whiteout;
+
+use crate::Options;
+
+/// The prefix of the temporary copies that copy-ups rename into place.
+const TEMP_PREFIX: &[u8] = b".wh..wh.copy.";
+
+#[derive(Default)]
+struct Stats {
+    entries: u64,
+    bytes: u64,
+    errors: u64,
+}
+
+pub fn run(options: &Options) -> i32 {
+    let mut stats = Stats::default();
+    collect(options, &options.upper_dir, &options.lower_dir, &mut stats);
+    eprintln!(
+        "overlay: {} {} entries, {} bytes",
+        if options.dry_run {
+            "would remove"
+        } else {
+            "removed"
+        },
+        stats.entries,
+        stats.bytes
+    );
+    if stats.errors > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+/// Removes the redundant entries below `dir_in_upper`, whose counterpart in the lower dir is
+/// `lower_dir`. Returns whether nothing is left in `dir_in_upper`.
+fn collect(options: &Options, dir_in_upper: &Path, lower_dir: &Path, stats: &mut Stats) -> bool {
+    let entries = match std::fs::read_dir(dir_in_upper) {
+        Ok(entries) => entries,
+        Err(e) => {
+            report(dir_in_upper, &e, stats);
+            return false;
+        }
+    };
+    let mut emptied = true;
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                report(dir_in_upper, &e, stats);
+                emptied = false;
+                continue;
+            }
+        };
+        let (path, name) = (entry.path(), entry.file_name());
+        let upper = match path.symlink_metadata() {
+            Ok(upper) => upper,
+            Err(e) => {
+                report(&path, &e, stats);
+                emptied = false;
+                continue;
+            }
+        };
+        let redundant = if name.as_bytes().starts_with(TEMP_PREFIX) {
+            true
+        } else if whiteout::hidden_name(name.as_bytes()).is_some()
+            || whiteout::hides(&options.upper_dir, &path)
+        {
+            false
+        } else {
+            let lower_path = lower_dir.join(&name);
+            let lower = lower_path.symlink_metadata().ok();
+            if upper.is_dir() {
+                let empty = collect(options, &path, &lower_path, stats);
+                empty && lower.map_or(false, |lower| same_dir(&upper, &lower))
+            } else {
+                lower.map_or(false, |lower| {
+                    same_file(&path, &upper, &lower_path, &lower).unwrap_or_else(|e| {
+                        report(&path, &e, stats);
+                        false
+                    })
+                })
+            }
+        };
+        if redundant && remove(options, &path, &upper, stats) {
+            continue;
+        }
+        emptied = false;
+    }
+    emptied
+}
+
+fn same_dir(upper: &Metadata, lower: &Metadata) -> bool {
+    // Copy-ups make directories accessible to their owner
+    lower.is_dir()
+        && upper.uid() == lower.uid()
+        && upper.gid() == lower.gid()
+        && upper.mode() | 0o700 == lower.mode() | 0o700
+}
+
+fn same_file(
+    path: &Path,
+    upper: &Metadata,
+    lower_path: &Path,
+    lower: &Metadata,
+) -> std::io::Result<bool> {
+    // Copy-ups make files writable for their owner
+    let same_metadata = upper.is_file()
+        && lower.is_file()
+        && upper.nlink() == 1
+        && upper.len() == lower.len()
+        && upper.uid() == lower.uid()
+        && upper.gid() == lower.gid()
+        && upper.mode() | 0o200 == lower.mode() | 0o200;
+    if !same_metadata {
+        return Ok(false);
+    }
+    same_contents(&mut File::open(path)?, &mut File::open(lower_path)?)
+}
+
+fn same_contents(upper: &mut File, lower: &mut File) -> std::io::Result<bool> {
+    let mut upper_buf = vec![0; 1 << 16];
+    let mut lower_buf = vec![0; 1 << 16];
+    loop {
+        let len = read_full(upper, &mut upper_buf)?;
+        if len != read_full(lower, &mut lower_buf)? || upper_buf[..len] != lower_buf[..len] {
+            return Ok(false);
+        }
+        if len < upper_buf.len() {
+            return Ok(true);
+        }
+    }
+}
+
+/// Fills `buf` as far as the rest of `file` allows, and returns how much was read.
+fn read_full(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..])? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    Ok(len)
+}
+
+fn remove(options: &Options, path: &Path, metadata: &Metadata, stats: &mut Stats) -> bool {
+    let relative = path.strip_prefix(&options.upper_dir).unwrap_or(path);
+    if !options.dry_run {
+        let removed = if metadata.is_dir() {
+            std::fs::remove_dir(path)
+        } else {
+            std::fs::remove_file(path)
+        };
+        if let Err(e) = removed {
+            report(path, &e, stats);
+            return false;
+        }
+    }
+    println!("{}", relative.display());
+    stats.entries += 1;
+    stats.bytes += metadata.blocks() * 512;
+    true
+}
+
+fn report(path: &Path, error: &std::io::Error, stats: &mut Stats) {
+    eprintln!("overlay: {}: {}", path.display(), error);
+    stats.errors += 1;
+}
@@ -0,0 +1,120 @@
+//! The `overlay` tool maintains upper dirs outside of any overlaid process.
+//!
+//! Its commands work on the files of the upper dir directly, so they should only be run while no
+//! process uses the overlay.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+mod attrs;
+mod commit;
+mod export;
+mod gc;
+mod snapshot;
+mod tar;
+mod upper;
+
+const USAGE: &str = "usage: overlay gc [--dry-run] LOWER_DIR UPPER_DIR
+       overlay commit [--dry-run] [--force] LOWER_DIR UPPER_DIR
+       overlay export [--manifest] LOWER_DIR UPPER_DIR OUTPUT
+       overlay snapshot [--force | --delete] UPPER_DIR NAME
+       overlay snapshots UPPER_DIR
+       overlay rollback UPPER_DIR NAME";
+
+/// The arguments shared by all commands.
+#[derive(Default)]
+pub struct Options {
+    /// Only report what would be done.
+    pub dry_run: bool,
+    /// Overwrite conflicting changes, or an existing snapshot.
+    pub force: bool,
+    /// List the changes rather than archiving them.
+    pub manifest: bool,
+    /// Delete the snapshot rather than taking it.
+    pub delete: bool,
+    pub lower_dir: PathBuf,
+    pub upper_dir: PathBuf,
+    /// Where the result goes, `-` for stdout.
+    pub output: Option<PathBuf>,
+    /// The name of a snapshot.
+    pub name: OsString,
+}
+
+/// The positional arguments a command takes.
+enum Operand {
+    LowerDir,
+    UpperDir,
+    Output,
+    Name,
+}
+
+impl Options {
+    /// Parses the arguments of a command taking the `flags` and the `operands`.
+    fn parse(args: &[OsString], flags: &[&str], operands: &[Operand]) -> Option<Options> {
+        let mut options = Options::default();
+        let mut values = Vec::new();
+        for arg in args {
+            match arg.to_str() {
+                Some(flag) if flag.starts_with('-') && flag != "-" => {
+                    let flag = match flag {
+                        "-n" => "--dry-run",
+                        "-f" => "--force",
+                        flag => flag,
+                    };
+                    if !flags.contains(&flag) {
+                        return None;
+                    }
+                    match flag {
+                        "--dry-run" => options.dry_run = true,
+                        "--force" => options.force = true,
+                        "--manifest" => options.manifest = true,
+                        _ => options.delete = true,
+                    }
+                }
+                _ => values.push(arg.clone()),
+            }
+        }
+        if values.len() != operands.len() {
+            return None;
+        }
+        for (operand, value) in operands.iter().zip(values) {
+            match operand {
+                Operand::LowerDir => options.lower_dir = PathBuf::from(value),
+                Operand::UpperDir => options.upper_dir = PathBuf::from(value),
+                Operand::Output => options.output = Some(PathBuf::from(value)),
+                Operand::Name => options.name = value,
+            }
+        }
+        Some(options)
+    }
+}
+
+fn main() {
+    use Operand::*;
+
+    let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    let command = args.first().and_then(|command| command.to_str());
+    let args = args.get(1..).unwrap_or(&[]);
+    let code = match command {
+        Some("gc") => Options::parse(args, &["--dry-run"], &[LowerDir, UpperDir])
+            .map(|options| gc::run(&options)),
+        Some("commit") => Options::parse(args, &["--dry-run", "--force"], &[LowerDir, UpperDir])
+            .map(|options| commit::run(&options)),
+        Some("export") => Options::parse(args, &["--manifest"], &[LowerDir, UpperDir, Output])
+            .map(|options| export::run(&options)),
+        Some("snapshot") => Options::parse(args, &["--force", "--delete"], &[UpperDir, Name])
+            .filter(|options| !(options.force && options.delete))
+            .map(|options| snapshot::take(&options)),
+        Some("snapshots") => {
+            Options::parse(args, &[], &[UpperDir]).map(|options| snapshot::list(&options))
+        }
+        Some("rollback") => {
+            Options::parse(args, &[], &[UpperDir, Name]).map(|options| snapshot::rollback(&options))
+        }
+        _ => None,
+    };
+    std::process::exit(code.unwrap_or_else(|| {
+        eprintln!("{}", USAGE);
+        2
+    }));
+}
@@ -0,0 +1,204 @@
+//! `overlay snapshot` saves the state of an upper dir under a name, and `overlay rollback` restores
+//! it, so that an experiment made through the overlay can be reverted without discarding all of
+//! the changes made before it.
+//!
+//! Snapshots are full copies of the upper dir in `.wh..wh.snapshots/<name>` within it, which are
+//! never part of the merged view. Hard links within the upper dir, like those marking stubs, are
+//! kept in the copies.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::Metadata;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::attrs;
+use crate::Options;
+
+/// Snapshots use the reserved names of whiteouts, so they are never part of the merged view.
+const SNAPSHOT_DIR: &str = ".wh..wh.snapshots";
+
+/// Entries of the upper dir that are not part of its state.
+const LOCK_DIR: &str = ".wh..wh.locks";
+const TEMP_PREFIX: &[u8] = b".wh..wh.copy.";
+
+/// Snapshots are taken under this prefix, and only renamed into place once complete.
+const PARTIAL_PREFIX: &str = ".partial.";
+
+pub fn take(options: &Options) -> i32 {
+    let snapshot = match snapshot_path(options) {
+        Some(snapshot) => snapshot,
+        None => return 2,
+    };
+    let exists = snapshot.symlink_metadata().is_ok();
+    if options.delete {
+        if !exists {
+            eprintln!("overlay: no snapshot {}", options.name.to_string_lossy());
+            return 1;
+        }
+        return result(std::fs::remove_dir_all(&snapshot));
+    }
+    if exists && !options.force {
+        eprintln!(
+            "overlay: snapshot {} exists, use --force to replace it",
+            options.name.to_string_lossy()
+        );
+        return 1;
+    }
+
+    let mut partial = OsString::from(PARTIAL_PREFIX);
+    partial.push(&options.name);
+    let partial = snapshot.with_file_name(partial);
+    let taken = remove_if_exists(&partial)
+        .and_then(|_| std::fs::create_dir_all(&partial))
+        .and_then(|_| copy_tree(&options.upper_dir, &partial, true, &mut HashMap::new()))
+        .and_then(|_| {
+            if exists {
+                std::fs::remove_dir_all(&snapshot)?;
+            }
+            std::fs::rename(&partial, &snapshot)
+        });
+    if taken.is_err() {
+        let _ = remove_if_exists(&partial);
+    }
+    result(taken)
+}
+
+pub fn list(options: &Options) -> i32 {
+    let entries = match std::fs::read_dir(options.upper_dir.join(SNAPSHOT_DIR)) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return 0,
+        Err(e) => return result(Err(e)),
+    };
+    let mut names: Vec<_> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name())
+        .filter(|name| valid_name(name))
+        .collect();
+    names.sort();
+    for name in names {
+        println!("{}", name.to_string_lossy());
+    }
+    0
+}
+
+pub fn rollback(options: &Options) -> i32 {
+    let snapshot = match snapshot_path(options) {
+        Some(snapshot) => snapshot,
+        None => return 2,
+    };
+    if !snapshot.is_dir() {
+        eprintln!("overlay: no snapshot {}", options.name.to_string_lossy());
+        return 1;
+    }
+    let cleared = std::fs::read_dir(&options.upper_dir).and_then(|entries| {
+        for entry in entries {
+            let entry = entry?;
+            if !is_state(&entry.file_name()) {
+                continue;
+            }
+            remove_if_exists(&entry.path())?;
+        }
+        Ok(())
+    });
+    result(
+        cleared.and_then(|_| copy_tree(&snapshot, &options.upper_dir, false, &mut HashMap::new())),
+    )
+}
+
+/// Returns the path of the snapshot named in `options`, or reports an invalid name.
+fn snapshot_path(options: &Options) -> Option<PathBuf> {
+    if !valid_name(&options.name) {
+        eprintln!(
+            "overlay: invalid snapshot name {}",
+            options.name.to_string_lossy()
+        );
+        return None;
+    }
+    Some(options.upper_dir.join(SNAPSHOT_DIR).join(&options.name))
+}
+
+fn valid_name(name: &OsStr) -> bool {
+    let name = name.as_bytes();
+    !name.is_empty() && !name.starts_with(b".") && !name.contains(&b'/')
+}
+
+/// Checks whether the entry `name` at the root of the upper dir is part of its state, rather than
+/// a snapshot or a lock.
+fn is_state(name: &OsStr) -> bool {
+    name != SNAPSHOT_DIR && name != LOCK_DIR && !name.as_bytes().starts_with(TEMP_PREFIX)
+}
+
+/// Copies the entries of the directory `from` into the existing directory `to`, along with their
+/// owners, modes and times. If `from` is the root of the upper dir, only its state is copied.
+/// `links` maps the files with several links that have been copied to their copies.
+fn copy_tree(
+    from: &Path,
+    to: &Path,
+    root: bool,
+    links: &mut HashMap<(u64, u64), PathBuf>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(from).map_err(|e| at(from, e))? {
+        let entry = entry.map_err(|e| at(from, e))?;
+        let name = entry.file_name();
+        if root && !is_state(&name) {
+            continue;
+        }
+        let (source, target) = (entry.path(), to.join(&name));
+        let metadata = source.symlink_metadata().map_err(|e| at(&source, e))?;
+        if metadata.is_dir() {
+            std::fs::create_dir(&target).map_err(|e| at(&target, e))?;
+            copy_tree(&source, &target, false, links)?;
+        } else {
+            copy_entry(&source, &target, &metadata, links).map_err(|e| at(&source, e))?;
+        }
+        attrs::set(&target, &metadata, metadata.mode()).map_err(|e| at(&target, e))?;
+    }
+    Ok(())
+}
+
+/// Copies the file or symlink `source` to `target`, or links it to an earlier copy.
+fn copy_entry(
+    source: &Path,
+    target: &Path,
+    metadata: &Metadata,
+    links: &mut HashMap<(u64, u64), PathBuf>,
+) -> std::io::Result<()> {
+    let key = (metadata.dev(), metadata.ino());
+    if metadata.file_type().is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(source)?, target)
+    } else if let Some(copy) = links.get(&key) {
+        std::fs::hard_link(copy, target)
+    } else {
+        std::fs::copy(source, target)?;
+        if metadata.nlink() > 1 {
+            links.insert(key, target.to_path_buf());
+        }
+        Ok(())
+    }
+}
+
+/// Adds `path` to the message of `error`.
+fn at(path: &Path, error: std::io::Error) -> std::io::Error {
+    std::io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn result(result: std::io::Result<()>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("overlay: {}", e);
+            1
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Writes tar archives in the POSIX ustar format. Names, link targets and sizes that do not fit
+//! into the fixed fields of a ustar header are recorded in a pax extended header before it.
+
+use std::io::{Read, Write};
+
+const BLOCK_SIZE: usize = 512;
+
+/// The largest size that fits into the 11 octal digits of the size field.
+const MAX_USTAR_SIZE: u64 = 0o777_7777_7777;
+
+pub enum Kind {
+    /// A regular file with the given size.
+    File(u64),
+    Dir,
+    Symlink(Vec<u8>),
+    /// A hard link to an entry earlier in the archive.
+    HardLink(Vec<u8>),
+}
+
+pub struct Entry {
+    /// The path within the archive, relative and without a trailing slash.
+    pub path: Vec<u8>,
+    pub kind: Kind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+}
+
+pub struct Builder<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Builder<W> {
+    pub fn new(out: W) -> Builder<W> {
+        Builder { out }
+    }
+
+    /// Appends `entry`, followed by the contents read from `contents` for files.
+    pub fn append(
+        &mut self,
+        entry: &Entry,
+        contents: Option<&mut dyn Read>,
+    ) -> std::io::Result<()> {
+        let (typeflag, size, link): (u8, u64, &[u8]) = match &entry.kind {
+            Kind::File(size) => (b'0', *size, b""),
+            Kind::Dir => (b'5', 0, b""),
+            Kind::Symlink(target) => (b'2', 0, target),
+            Kind::HardLink(target) => (b'1', 0, target),
+        };
+        let mut path = entry.path.clone();
+        if let Kind::Dir = entry.kind {
+            path.push(b'/');
+        }
+
+        let mut records = Vec::new();
+        if path.len() > 100 {
+            pax_record(&mut records, b"path", &path);
+        }
+        if link.len() > 100 {
+            pax_record(&mut records, b"linkpath", link);
+        }
+        if size > MAX_USTAR_SIZE {
+            pax_record(&mut records, b"size", size.to_string().as_bytes());
+        }
+        if !records.is_empty() {
+            let header = header(
+                b"PaxHeader",
+                b'x',
+                records.len() as u64,
+                b"",
+                0o644,
+                0,
+                0,
+                0,
+            );
+            self.out.write_all(&header)?;
+            self.out.write_all(&records)?;
+            self.pad(records.len() as u64)?;
+        }
+
+        let header = header(
+            &path,
+            typeflag,
+            size,
+            link,
+            entry.mode,
+            entry.uid,
+            entry.gid,
+            entry.mtime,
+        );
+        self.out.write_all(&header)?;
+        if let Some(contents) = contents {
+            // The header promises exactly `size` bytes, even if the file changed in the meantime
+            let copied = std::io::copy(&mut contents.take(size), &mut self.out)?;
+            std::io::copy(&mut std::io::repeat(0).take(size - copied), &mut self.out)?;
+            self.pad(size)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the archive and returns the writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.out.write_all(&[0; 2 * BLOCK_SIZE])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Pads data of `len` bytes to a full block.
+    fn pad(&mut self, len: u64) -> std::io::Result<()> {
+        let rest = (len % BLOCK_SIZE as u64) as usize;
+        if rest > 0 {
+            self.out.write_all(&[0; BLOCK_SIZE][rest..])?;
+        }
+        Ok(())
+    }
+}
+
+/// Appends a pax record `"<len> <key>=<value>\n"`, whose length includes the length itself.
+fn pax_record(records: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    records.extend_from_slice(len.to_string().as_bytes());
+    records.push(b' ');
+    records.extend_from_slice(key);
+    records.push(b'=');
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+#[allow(clippy::too_many_arguments)]
+fn header(
+    path: &[u8],
+    typeflag: u8,
+    size: u64,
+    link: &[u8],
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+) -> [u8; BLOCK_SIZE] {
+    let mut header = [0; BLOCK_SIZE];
+    // Fields that are too long have been recorded in a pax header, and are truncated here
+    copy_field(&mut header[0..100], path);
+    octal_field(&mut header[100..108], u64::from(mode & 0o7777));
+    octal_field(&mut header[108..116], u64::from(uid));
+    octal_field(&mut header[116..124], u64::from(gid));
+    octal_field(&mut header[124..136], size.min(MAX_USTAR_SIZE));
+    octal_field(&mut header[136..148], mtime.max(0) as u64);
+    header[156] = typeflag;
+    copy_field(&mut header[157..257], link);
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    octal_field(&mut header[148..155], u64::from(checksum));
+    header
+}
+
+fn copy_field(field: &mut [u8], value: &[u8]) {
+    let len = value.len().min(field.len());
+    field[..len].copy_from_slice(&value[..len]);
+}
+
+/// Writes `value` as zero-padded octal digits, followed by a NUL.
+fn octal_field(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    let start = digits.len() - (field.len() - 1).min(digits.len());
+    copy_field(field, &digits.as_bytes()[start..]);
+    field[field.len() - 1] = 0;
+}
@@ -0,0 +1,33 @@
+//! Reads the bookkeeping entries that the library keeps in upper dirs.
+
+use std::ffi::OsString;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Metadata-only copies are marked by a hard link with this prefix, see `metacopy.rs`.
+const META_PREFIX: &str = ".wh..wh.meta.";
+
+/// Returns the marker of the metadata-only copy `path`, if it exists.
+pub fn meta_marker(path: &Path) -> Option<PathBuf> {
+    let mut marker = OsString::from(META_PREFIX);
+    marker.push(path.file_name()?);
+    let marker = path.with_file_name(marker);
+    if marker.symlink_metadata().is_ok() {
+        Some(marker)
+    } else {
+        None
+    }
+}
+
+/// Checks whether the upper file `path` with the metadata `upper` is a metadata-only copy, whose
+/// contents are still those of the lower file.
+pub fn is_stub(path: &Path, upper: &Metadata) -> bool {
+    upper.is_file()
+        && upper.nlink() > 1
+        && meta_marker(path)
+            .and_then(|marker| marker.symlink_metadata().ok())
+            .map_or(false, |marker| {
+                marker.dev() == upper.dev() && marker.ino() == upper.ino()
+            })
+}
@@ -1,53 +1,1405 @@
-use std::path::PathBuf;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
 
-#[derive(Debug)]
-pub struct Config {
+use crate::control;
+use crate::hide;
+use crate::log::{self, Category, Level};
+use crate::program;
+use crate::quota;
+use crate::reload;
+use crate::remote;
+use crate::rewrite;
+use crate::synthetic;
+use crate::toml;
+use crate::transform;
+
+/// A lower dir together with the upper dir that receives its modifications.
+#[derive(Debug, Default)]
+pub struct Mapping {
     pub lower_dir: PathBuf,
     pub upper_dir: PathBuf,
-    pub debug: bool,
+    /// The device of the file system holding the upper dir, if it exists.
+    pub upper_dev: Option<u64>,
+    /// Nothing of the merged view may be changed, writes fail with `EROFS`.
+    pub read_only: bool,
+    /// When lower files are copied up.
+    pub copy_up: CopyUp,
+    /// Missing entries are looked up by names that differ in case only.
+    pub case_insensitive: bool,
+    /// Patterns of lower entries that are passed through rather than overlaid.
+    pub(crate) excluded: Vec<hide::Rule>,
+}
+
+impl Mapping {
+    /// Creates a mapping of `lower_dir` to `upper_dir`, without any options.
+    pub fn new<L: Into<PathBuf>, U: Into<PathBuf>>(lower_dir: L, upper_dir: U) -> Mapping {
+        Mapping {
+            lower_dir: lower_dir.into(),
+            upper_dir: upper_dir.into(),
+            ..Mapping::default()
+        }
+    }
 }
 
+/// When the lower files of a mapping are copied up, which suits workloads differently, e.g. editing
+/// config files favours cheap copies of few files, while databases rewrite their files in place.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CopyUp {
+    /// Lower files are copied up as soon as they are opened, even for reading.
+    Eager,
+    /// Lower files are copied up as a whole once they are changed, even if only their metadata is.
+    Lazy,
+    /// Lower files are copied up once they are written, changing their metadata only copies that.
+    Metadata,
+    /// Like `Metadata`, but lower files are copied up as soon as they are opened if they can be
+    /// cloned, which takes neither time nor space.
+    Reflink,
+    /// Lower entries are never copied up, changing them fails with `EROFS`. New entries can still
+    /// be created.
+    Never,
+}
+
+impl Default for CopyUp {
+    fn default() -> CopyUp {
+        CopyUp::Metadata
+    }
+}
+
+const COPY_UP_STRATEGIES: &[(CopyUp, &str)] = &[
+    (CopyUp::Eager, "eager"),
+    (CopyUp::Lazy, "lazy"),
+    (CopyUp::Metadata, "metadata"),
+    (CopyUp::Reflink, "reflink"),
+    (CopyUp::Never, "never"),
+];
+
+/// Groups of hooks that can be disabled, e.g. to work around incompatibilities with a program. The
+/// hooks of a disabled group call libc directly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookGroup {
+    /// Merging directory listings, with `opendir`, `scandir`, `nftw`, `getdents64` and the like.
+    Readdir,
+    /// Running executables of the upper dirs, and passing the configuration on to children.
+    Exec,
+    /// Copying up watched entries for `inotify` and `fanotify`.
+    Watch,
+}
+
+const HOOK_GROUPS: &[(HookGroup, &str)] = &[
+    (HookGroup::Readdir, "readdir"),
+    (HookGroup::Exec, "exec"),
+    (HookGroup::Watch, "watch"),
+];
+
+#[derive(Debug)]
+pub struct Config {
+    /// The overlaid trees, the most specific lower dirs come first.
+    pub mappings: Vec<Mapping>,
+    /// Rules relocating paths before they are mapped, the first matching one applies.
+    pub(crate) rewrites: Vec<rewrite::Rule>,
+    /// Patterns of lower entries that are hidden from the merged view.
+    pub(crate) hidden: Vec<hide::Rule>,
+    /// Files that appear in the merged view without existing in either layer.
+    pub(crate) synthetic: Vec<synthetic::File>,
+    /// Lower dirs that mirror remote trees, fetching files on demand.
+    pub(crate) remotes: Vec<remote::Remote>,
+    /// Rules transforming the contents of lower files as they are copied up.
+    pub(crate) transforms: Vec<transform::Rule>,
+    /// Paths that are never overlaid, even when they lie within a lower dir.
+    pub excluded: Vec<PathBuf>,
+    /// Lower symlinks are copied up as the files they point to, rather than as symlinks.
+    pub follow_symlinks: bool,
+    /// Lower files opened for reading are copied up, making the upper dirs a cache of them.
+    pub copy_on_read: bool,
+    /// Directories of the merged view are listed sorted by name, independent of the file systems.
+    pub sort_dirs: bool,
+    /// How many levels of lower directories are copied up to rename a directory, unlimited if
+    /// `None`. Renaming deeper directories fails with `EXDEV`.
+    pub dir_copy_up_depth: Option<usize>,
+    /// Limits on the contents of the upper dirs, if any.
+    pub(crate) quota: Option<quota::Quota>,
+    /// The groups of hooks that call libc directly.
+    pub(crate) disabled_hooks: Vec<HookGroup>,
+    /// The programs that are overlaid, all of them if empty.
+    pub(crate) programs: Vec<program::Pattern>,
+    /// Which messages are logged.
+    pub(crate) log: log::Filter,
+    /// `SIGHUP` reloads the configuration, see `reload`.
+    pub reload: bool,
+    /// Where the control socket is served, see `control`.
+    pub control: Option<OsString>,
+    /// Child processes are started without liboverlay, on the real file system.
+    pub disable_for_children: bool,
+    /// The path liboverlay itself was loaded from.
+    pub library: Option<PathBuf>,
+    /// The variables among `INHERITED_VARS` that are set in this process.
+    pub inherited_env: Vec<(&'static str, OsString)>,
+}
+
+/// The variables that load and configure liboverlay, child processes receive them as well.
+pub const INHERITED_VARS: &[&str] = &[
+    "LD_PRELOAD",
+    "LIBOVERLAY_CONFIG",
+    "LIBOVERLAY_PROFILE",
+    "LIBOVERLAY_LOWER_DIR",
+    "LIBOVERLAY_UPPER_DIR",
+    "LIBOVERLAY_REMOVE_UPPER_DIR",
+    "LIBOVERLAY_MAPPINGS",
+    "LIBOVERLAY_MAPPING_OPTIONS",
+    "LIBOVERLAY_REWRITES",
+    "LIBOVERLAY_HIDE",
+    "LIBOVERLAY_FILES",
+    "LIBOVERLAY_TRANSFORMS",
+    "LIBOVERLAY_REMOTES",
+    "LIBOVERLAY_EXCLUDE",
+    "LIBOVERLAY_FOLLOW_SYMLINKS",
+    "LIBOVERLAY_COPY_ON_READ",
+    "LIBOVERLAY_SORT_DIRS",
+    "LIBOVERLAY_DIR_COPY_UP_DEPTH",
+    "LIBOVERLAY_QUOTA_BYTES",
+    "LIBOVERLAY_QUOTA_FILES",
+    "LIBOVERLAY_QUOTA_EVICT",
+    "LIBOVERLAY_SESSION",
+    "LIBOVERLAY_RELOAD",
+    "LIBOVERLAY_CONTROL",
+    "LIBOVERLAY_PROGRAMS",
+    "LIBOVERLAY_DISABLE_HOOKS",
+    "LIBOVERLAY_DISABLE_FOR_CHILDREN",
+    "LIBOVERLAY_STRICT",
+    "LIBOVERLAY_LOG",
+    "LIBOVERLAY_LOG_FILE",
+    "LIBOVERLAY_LOG_FD",
+    "LIBOVERLAY_LOG_FORMAT",
+    "LIBOVERLAY_DEBUG",
+];
+
 impl Config {
+    /// Creates a configuration of `mappings`, which leaves all other settings at the defaults of
+    /// their variables. Programs that link liboverlay install it with `configure` or
+    /// `with_overlay`.
+    pub fn new(mut mappings: Vec<Mapping>) -> Config {
+        mappings.sort_by_key(|mapping| std::cmp::Reverse(mapping.lower_dir.components().count()));
+        for mapping in &mut mappings {
+            mapping.upper_dev = std::fs::metadata(&mapping.upper_dir)
+                .ok()
+                .map(|upper| upper.dev());
+        }
+        let mut excluded: Vec<PathBuf> = PASSTHROUGH_DIRS.iter().map(PathBuf::from).collect();
+        excluded.extend(mappings.iter().map(|mapping| mapping.upper_dir.clone()));
+        Config {
+            mappings,
+            rewrites: Vec::new(),
+            hidden: Vec::new(),
+            synthetic: Vec::new(),
+            remotes: Vec::new(),
+            transforms: Vec::new(),
+            excluded,
+            follow_symlinks: false,
+            copy_on_read: false,
+            sort_dirs: false,
+            dir_copy_up_depth: None,
+            quota: None,
+            disabled_hooks: Vec::new(),
+            programs: Vec::new(),
+            log: log::Filter::default(),
+            reload: false,
+            control: None,
+            disable_for_children: false,
+            library: None,
+            inherited_env: Vec::new(),
+        }
+    }
+
     pub fn from_env() -> Option<Config> {
-        let lower_dir = match std::env::var("LIBOVERLAY_LOWER_DIR") {
-            Ok(path) => PathBuf::from(path),
-            Err(_) => {
-                eprintln!("liboverlay:  LIBOVERLAY_LOWER_DIR not specified");
+        Config::from_vars(&Vars::load())
+    }
+
+    fn from_vars(vars: &Result<Vars, String>) -> Option<Config> {
+        let vars = match vars {
+            Ok(vars) => vars,
+            Err(e) => {
+                log_println!("liboverlay: invalid LIBOVERLAY_CONFIG: {}", e);
                 return None;
             }
         };
+        let mut mappings = Vec::new();
 
-        let upper_dir = match std::env::var("LIBOVERLAY_UPPER_DIR") {
-            Ok(path) => PathBuf::from(path),
-            Err(_) => {
-                eprintln!("liboverlay:  LIBOVERLAY_UPPER_DIR not specified");
+        let lower_dir = vars.var_os("LIBOVERLAY_LOWER_DIR");
+        let mut upper_dir = vars.var_os("LIBOVERLAY_UPPER_DIR");
+        let remove = vars.var("LIBOVERLAY_REMOVE_UPPER_DIR");
+        if upper_dir.as_ref().map_or(false, |dir| dir == "auto") {
+            match create_auto_upper_dir(remove.as_ref().map_or(false, |val| val == "1")) {
+                Ok(dir) => upper_dir = Some(dir.into_os_string()),
+                Err(e) => {
+                    log_println!("liboverlay: cannot create upper dir: {}", e);
+                    return None;
+                }
+            }
+        } else if let (Some(dir), Ok(pid)) = (upper_dir.as_ref(), remove.as_ref()) {
+            // The process that created the upper dir has executed another program
+            if *pid == std::process::id().to_string() {
+                remove_at_exit(PathBuf::from(dir));
+            }
+        }
+        match (lower_dir, upper_dir) {
+            (Some(lower_dir), Some(upper_dir)) => mappings.push(Mapping {
+                lower_dir: PathBuf::from(lower_dir),
+                upper_dir: PathBuf::from(upper_dir),
+                ..Mapping::default()
+            }),
+            (Some(_), None) => {
+                log_println!("liboverlay: LIBOVERLAY_UPPER_DIR not specified");
+                return None;
+            }
+            (None, Some(_)) => {
+                log_println!("liboverlay: LIBOVERLAY_LOWER_DIR not specified");
                 return None;
             }
+            (None, None) => {}
+        }
+
+        if let Ok(list) = vars.var("LIBOVERLAY_MAPPINGS") {
+            for pair in list.split(';').filter(|pair| !pair.is_empty()) {
+                match parse_mapping(pair) {
+                    Some(mapping) => mappings.push(mapping),
+                    None => {
+                        log_println!(
+                            "liboverlay: invalid mapping `{}` in LIBOVERLAY_MAPPINGS",
+                            pair
+                        );
+                        return None;
+                    }
+                }
+            }
+        }
+        for mapping in &mut mappings {
+            let expanded = expand(&mapping.lower_dir)
+                .and_then(|lower_dir| Ok((lower_dir, expand(&mapping.upper_dir)?)));
+            match expanded {
+                Ok((lower_dir, upper_dir)) => {
+                    mapping.lower_dir = lower_dir;
+                    mapping.upper_dir = upper_dir;
+                }
+                Err(e) => {
+                    log_println!(
+                        "liboverlay: cannot expand mapping of {}: {}",
+                        mapping.lower_dir.display(),
+                        e
+                    );
+                    return None;
+                }
+            }
+        }
+
+        let overrides = control::overrides().lock().unwrap().clone();
+        mappings.extend(
+            overrides
+                .added
+                .iter()
+                .map(|(lower_dir, upper_dir)| Mapping {
+                    lower_dir: lower_dir.clone(),
+                    upper_dir: upper_dir.clone(),
+                    ..Mapping::default()
+                }),
+        );
+
+        if let Ok(list) = vars.var("LIBOVERLAY_MAPPING_OPTIONS") {
+            if let Err(e) = parse_mapping_options(&list, &mut mappings) {
+                log_println!("liboverlay: invalid LIBOVERLAY_MAPPING_OPTIONS: {}", e);
+                return None;
+            }
+        }
+        mappings.retain(|mapping| !overrides.removed.contains(&mapping.lower_dir));
+
+        let rewrites = match vars.var("LIBOVERLAY_REWRITES") {
+            Ok(list) => match rewrite::parse_rules(&list) {
+                Ok(rewrites) => rewrites,
+                Err(e) => {
+                    log_println!("liboverlay: invalid LIBOVERLAY_REWRITES: {}", e);
+                    return None;
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+
+        let hidden = vars
+            .var("LIBOVERLAY_HIDE")
+            .map_or(Vec::new(), |list| hide::parse_rules(&list));
+
+        if mappings.is_empty() && rewrites.is_empty() {
+            log_println!(
+                "liboverlay: none of LIBOVERLAY_LOWER_DIR, LIBOVERLAY_MAPPINGS or LIBOVERLAY_REWRITES specified"
+            );
+            return None;
+        }
+        let synthetic = match vars.var("LIBOVERLAY_FILES") {
+            Ok(list) => match synthetic::parse_files(&list) {
+                Ok(synthetic) => synthetic,
+                Err(e) => {
+                    log_println!("liboverlay: invalid LIBOVERLAY_FILES: {}", e);
+                    return None;
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+        let transforms = match vars.var("LIBOVERLAY_TRANSFORMS") {
+            Ok(list) => match transform::parse_rules(&list) {
+                Ok(transforms) => transforms,
+                Err(e) => {
+                    log_println!("liboverlay: invalid LIBOVERLAY_TRANSFORMS: {}", e);
+                    return None;
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+        let remotes = match vars.var("LIBOVERLAY_REMOTES") {
+            Ok(list) => match remote::parse_remotes(&list) {
+                Ok(remotes) => remotes,
+                Err(e) => {
+                    log_println!("liboverlay: invalid LIBOVERLAY_REMOTES: {}", e);
+                    return None;
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+        let unmapped = remotes.iter().find(|remote| {
+            !mappings
+                .iter()
+                .any(|mapping| mapping.lower_dir == remote.lower_dir)
+        });
+        if let Some(remote) = unmapped {
+            log_println!(
+                "liboverlay: remote {} is not a lower dir",
+                remote.lower_dir.display()
+            );
+            return None;
+        }
+        let outside = synthetic.iter().find(|file| {
+            !mappings.iter().any(|mapping| {
+                file.path
+                    .parent()
+                    .map_or(false, |parent| parent.starts_with(&mapping.lower_dir))
+            })
+        });
+        if let Some(file) = outside {
+            log_println!(
+                "liboverlay: synthetic file {} is not within a lower dir",
+                file.path.display()
+            );
+            return None;
+        }
+
+        // Nested lower dirs are matched by their longest prefix
+        mappings.sort_by_key(|mapping| std::cmp::Reverse(mapping.lower_dir.components().count()));
+
+        // Each session gets its own upper dirs within the shared ones, which stay excluded
+        let mut excluded: Vec<PathBuf> = PASSTHROUGH_DIRS.iter().map(PathBuf::from).collect();
+        excluded.extend(mappings.iter().map(|mapping| mapping.upper_dir.clone()));
+        if let Ok(list) = vars.var("LIBOVERLAY_EXCLUDE") {
+            for path in list.split(';').filter(|path| !path.is_empty()) {
+                if !Path::new(path).is_absolute() {
+                    log_println!("liboverlay: excluded path `{}` is not absolute", path);
+                    return None;
+                }
+                excluded.push(PathBuf::from(path));
+            }
+        }
+        if let Some(session) = vars.var_os("LIBOVERLAY_SESSION") {
+            if let Err(e) = start_session(&session, &mut mappings) {
+                log_println!(
+                    "liboverlay: cannot start session `{}`: {}",
+                    session.to_string_lossy(),
+                    e
+                );
+                return None;
+            }
+        }
+
+        // Renames between the upper dirs and other file systems are refused up front
+        for mapping in &mut mappings {
+            mapping.upper_dev = std::fs::metadata(&mapping.upper_dir)
+                .ok()
+                .map(|upper| upper.dev());
+        }
+
+        let mut limits = [None, None];
+        for (limit, name) in limits
+            .iter_mut()
+            .zip(&["LIBOVERLAY_QUOTA_BYTES", "LIBOVERLAY_QUOTA_FILES"])
+        {
+            if let Ok(value) = vars.var(name) {
+                match parse_size(&value) {
+                    Some(size) => *limit = Some(size),
+                    None => {
+                        log_println!("liboverlay: invalid {} `{}`", name, value);
+                        return None;
+                    }
+                }
+            }
+        }
+        let evict = vars
+            .var("LIBOVERLAY_QUOTA_EVICT")
+            .map_or(false, |val| &val == "1");
+        let quota = match limits {
+            [None, None] => None,
+            [bytes, files] => Some(quota::Quota {
+                bytes,
+                files,
+                evict,
+            }),
         };
 
-        let debug = std::env::var("LIBOVERLAY_DEBUG").map_or(false, |val| &val == "1");
+        let follow_symlinks = vars
+            .var("LIBOVERLAY_FOLLOW_SYMLINKS")
+            .map_or(false, |val| &val == "1");
+        let copy_on_read = vars
+            .var("LIBOVERLAY_COPY_ON_READ")
+            .map_or(false, |val| &val == "1");
+        let sort_dirs = vars
+            .var("LIBOVERLAY_SORT_DIRS")
+            .map_or(false, |val| &val == "1");
+        let dir_copy_up_depth = match vars.var("LIBOVERLAY_DIR_COPY_UP_DEPTH") {
+            Ok(value) => match value.parse() {
+                Ok(depth) => Some(depth),
+                Err(e) => {
+                    log_println!("liboverlay: invalid LIBOVERLAY_DIR_COPY_UP_DEPTH: {}", e);
+                    return None;
+                }
+            },
+            Err(_) => None,
+        };
+        // LIBOVERLAY_DEBUG=1 stands for logging everything
+        let log = match overrides
+            .log
+            .clone()
+            .or_else(|| vars.var("LIBOVERLAY_LOG").ok())
+        {
+            Some(spec) => match log::parse_filter(&spec) {
+                Ok(log) => log,
+                Err(e) => {
+                    log_println!("liboverlay: invalid LIBOVERLAY_LOG: {}", e);
+                    return None;
+                }
+            },
+            None if vars
+                .var("LIBOVERLAY_DEBUG")
+                .map_or(false, |val| &val == "1") =>
+            {
+                log::parse_filter("trace").expect("trace is a valid filter")
+            }
+            None => log::Filter::default(),
+        };
+        let disabled_hooks = match vars.var("LIBOVERLAY_DISABLE_HOOKS") {
+            Ok(list) => match parse_hook_groups(&list) {
+                Ok(disabled_hooks) => disabled_hooks,
+                Err(e) => {
+                    log_println!("liboverlay: invalid LIBOVERLAY_DISABLE_HOOKS: {}", e);
+                    return None;
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+        let programs = match vars.var("LIBOVERLAY_PROGRAMS") {
+            Ok(list) => match program::parse_patterns(&list) {
+                Ok(programs) => programs,
+                Err(e) => {
+                    log_println!("liboverlay: invalid LIBOVERLAY_PROGRAMS: {}", e);
+                    return None;
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+        let reload = vars
+            .var("LIBOVERLAY_RELOAD")
+            .map_or(false, |val| &val == "1");
+
+        let disable_for_children = vars
+            .var("LIBOVERLAY_DISABLE_FOR_CHILDREN")
+            .map_or(false, |val| &val == "1");
+
+        // Children are given the discovered config file, wherever they run
+        let inherited_env = INHERITED_VARS
+            .iter()
+            .filter_map(|name| match std::env::var_os(name) {
+                None if *name == "LIBOVERLAY_CONFIG" => {
+                    Some((*name, vars.discovered.clone()?.into_os_string()))
+                }
+                value => Some((*name, value?)),
+            })
+            .collect();
+
+        let library = own_library();
+        excluded.extend(library.clone());
 
         Some(Config {
-            lower_dir,
-            upper_dir,
-            debug,
+            mappings,
+            rewrites,
+            hidden,
+            synthetic,
+            remotes,
+            transforms,
+            excluded,
+            follow_symlinks,
+            copy_on_read,
+            sort_dirs,
+            dir_copy_up_depth,
+            quota,
+            disabled_hooks,
+            programs,
+            log,
+            reload,
+            control: vars.var_os("LIBOVERLAY_CONTROL"),
+            disable_for_children,
+            library,
+            inherited_env,
         })
     }
+
+    /// Finds the mapping whose lower dir contains `path`, and returns it together with the path
+    /// relative to that lower dir.
+    pub fn lower_mapping<'a>(&self, path: &'a Path) -> Option<(&Mapping, &'a Path)> {
+        if self
+            .excluded
+            .iter()
+            .any(|excluded| path.starts_with(excluded))
+        {
+            return None;
+        }
+        let (mapping, path_in_lower) = self.mappings.iter().find_map(|mapping| {
+            let path_in_lower = path.strip_prefix(&mapping.lower_dir).ok()?;
+            Some((mapping, path_in_lower))
+        })?;
+        // Excluding a directory passes all of its contents through
+        let excluded = path_in_lower
+            .ancestors()
+            .take_while(|ancestor| *ancestor != Path::new(""))
+            .any(|ancestor| {
+                mapping
+                    .excluded
+                    .iter()
+                    .any(|rule| rule.matches(&mapping.lower_dir, ancestor))
+            });
+        if excluded {
+            None
+        } else {
+            Some((mapping, path_in_lower))
+        }
+    }
+
+    /// Applies the first rewrite rule matching `path`, returns `None` if there is none.
+    pub fn rewrite(&self, path: &Path) -> Option<PathBuf> {
+        self.rewrites.iter().find_map(|rule| rule.apply(path))
+    }
+
+    /// Checks whether a hide rule matches the entry `path_in_lower` of the lower dir of `mapping`.
+    /// Rules matching one of its parent directories are not taken into account.
+    pub fn hides(&self, mapping: &Mapping, path_in_lower: &Path) -> bool {
+        self.hidden
+            .iter()
+            .any(|rule| rule.matches(&mapping.lower_dir, path_in_lower))
+    }
+
+    /// Returns the transformations of the first rule matching the entry `path_in_lower` of the
+    /// lower dir of `mapping`, if any.
+    pub fn transforms(
+        &self,
+        mapping: &Mapping,
+        path_in_lower: &Path,
+    ) -> Option<&[transform::Transform]> {
+        self.transforms
+            .iter()
+            .find_map(|rule| rule.transforms(&mapping.lower_dir, path_in_lower))
+    }
+
+    /// Returns the remote tree that the lower dir of `mapping` mirrors, if any.
+    pub fn remote(&self, mapping: &Mapping) -> Option<&remote::Remote> {
+        self.remotes
+            .iter()
+            .find(|remote| remote.lower_dir == mapping.lower_dir)
+    }
+
+    /// Returns the synthetic file at the absolute path `path`, if there is one.
+    pub fn synthetic_file(&self, path: &Path) -> Option<&synthetic::File> {
+        self.synthetic.iter().find(|file| file.path == path)
+    }
+
+    /// Finds the mapping whose upper dir contains `path`, and returns it together with the path
+    /// relative to that upper dir.
+    pub fn upper_mapping<'a>(&self, path: &'a Path) -> Option<(&Mapping, &'a Path)> {
+        self.mappings.iter().find_map(|mapping| {
+            let path_in_upper = path.strip_prefix(&mapping.upper_dir).ok()?;
+            Some((mapping, path_in_upper))
+        })
+    }
+
+    /// Checks the file system for what the configuration relies on: the lower dirs must be
+    /// directories, the upper dirs writable directories, and no two mappings may share a dir or
+    /// have their lower dir within an upper dir.
+    fn validate(&self) -> Result<(), String> {
+        for mapping in &self.mappings {
+            if !mapping.lower_dir.is_dir() {
+                return Err(format!(
+                    "lower dir {} is not a directory",
+                    mapping.lower_dir.display()
+                ));
+            }
+            if !mapping.upper_dir.is_dir() {
+                return Err(format!(
+                    "upper dir {} is not a directory",
+                    mapping.upper_dir.display()
+                ));
+            }
+            if !writable(&mapping.upper_dir) {
+                return Err(format!(
+                    "upper dir {} is not writable",
+                    mapping.upper_dir.display()
+                ));
+            }
+        }
+        for (i, mapping) in self.mappings.iter().enumerate() {
+            for other in &self.mappings[i + 1..] {
+                if mapping.lower_dir == other.lower_dir {
+                    return Err(format!(
+                        "lower dir {} is mapped twice",
+                        mapping.lower_dir.display()
+                    ));
+                }
+                if mapping.upper_dir == other.upper_dir {
+                    return Err(format!(
+                        "upper dir {} is shared by {} and {}",
+                        mapping.upper_dir.display(),
+                        mapping.lower_dir.display(),
+                        other.lower_dir.display()
+                    ));
+                }
+            }
+            let within = self
+                .mappings
+                .iter()
+                .find(|other| mapping.lower_dir.starts_with(&other.upper_dir));
+            if let Some(other) = within {
+                return Err(format!(
+                    "lower dir {} lies within upper dir {}",
+                    mapping.lower_dir.display(),
+                    other.upper_dir.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether `LIBOVERLAY_STRICT=1` asks to abort the process when the configuration is
+/// invalid, rather than to run it without the overlay.
+fn strict(vars: &Result<Vars, String>) -> bool {
+    let strict = match vars {
+        Ok(vars) => vars.var("LIBOVERLAY_STRICT"),
+        Err(_) => std::env::var("LIBOVERLAY_STRICT"),
+    };
+    strict.map_or(false, |val| &val == "1")
+}
+
+/// Sends the log to the file `LIBOVERLAY_LOG_FILE` names, or to the inherited file descriptor
+/// `LIBOVERLAY_LOG_FD`, rather than to stderr, in the format `LIBOVERLAY_LOG_FORMAT` names. Relative
+/// paths are made absolute for the children of the process, which append to the same file wherever
+/// they run.
+fn open_log(vars: &Result<Vars, String>) {
+    let var = |name: &str| match vars {
+        Ok(vars) => vars.var_os(name),
+        Err(_) => std::env::var_os(name),
+    };
+    if let Some(file) = var("LIBOVERLAY_LOG_FILE").filter(|file| !file.is_empty()) {
+        let file = match std::env::current_dir() {
+            Ok(cwd) if Path::new(&file).is_relative() => {
+                let file = cwd.join(file);
+                // The constructor runs before any other thread of the process exists
+                std::env::set_var("LIBOVERLAY_LOG_FILE", &file);
+                file
+            }
+            _ => PathBuf::from(file),
+        };
+        if let Err(e) = log::write_to_file(&file) {
+            log_println!(
+                "liboverlay: cannot open LIBOVERLAY_LOG_FILE {}: {}",
+                file.display(),
+                e
+            );
+        }
+    } else if let Some(fd) = var("LIBOVERLAY_LOG_FD") {
+        match fd.to_str().and_then(|fd| fd.parse::<c_int>().ok()) {
+            Some(fd) if fd >= 0 => log::write_to_fd(fd),
+            _ => log_println!(
+                "liboverlay: invalid LIBOVERLAY_LOG_FD: {}",
+                fd.to_string_lossy()
+            ),
+        }
+    }
+    if let Some(format) = var("LIBOVERLAY_LOG_FORMAT") {
+        match log::parse_format(&format.to_string_lossy()) {
+            Ok(format) => log::write_as(format),
+            Err(e) => log_println!("liboverlay: invalid LIBOVERLAY_LOG_FORMAT: {}", e),
+        }
+    }
+}
+
+/// Aborts the process after an invalid configuration has been reported.
+fn abort_invalid() -> ! {
+    log_println!("liboverlay: aborting, since LIBOVERLAY_STRICT is set");
+    std::process::abort()
+}
+
+/// Checks whether the process may create entries in `dir`.
+fn writable(dir: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    const W_OK: c_int = 2;
+    match std::ffi::CString::new(dir.as_os_str().as_bytes()) {
+        Ok(dir) => unsafe { access(dir.as_ptr(), W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+/// Sessions use the reserved names of whiteouts, so they are never part of the merged view of the
+/// shared upper dirs.
+const SESSION_DIR: &str = ".wh..wh.sessions";
+
+/// Moves the upper dirs of `mappings` to the session `LIBOVERLAY_SESSION` names, which they share
+/// with all processes of the same session. `pid` starts a new session named after this process,
+/// which its children join, since they inherit the name rather than `pid`.
+fn start_session(session: &OsStr, mappings: &mut [Mapping]) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = session.as_bytes();
+    if name.is_empty() || name.starts_with(b".") || name.contains(&b'/') {
+        return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+    }
+    let session_dir =
+        |mapping: &Mapping, session: &OsStr| mapping.upper_dir.join(SESSION_DIR).join(session);
+    let mut session = session.to_os_string();
+    if session == "pid" {
+        let pid = std::process::id();
+        session = OsString::from(pid.to_string());
+        // A session of an earlier process with the same PID is left alone
+        if let Some(mapping) = mappings.first() {
+            std::fs::create_dir_all(mapping.upper_dir.join(SESSION_DIR))?;
+            let mut attempt = 0;
+            while let Err(e) = std::fs::create_dir(session_dir(mapping, &session)) {
+                if e.kind() != std::io::ErrorKind::AlreadyExists {
+                    return Err(e);
+                }
+                attempt += 1;
+                session = OsString::from(format!("{}-{}", pid, attempt));
+            }
+        }
+        // The constructor runs before any other thread of the process exists
+        std::env::set_var("LIBOVERLAY_SESSION", &session);
+    }
+    for mapping in mappings {
+        mapping.upper_dir = session_dir(mapping, &session);
+        std::fs::create_dir_all(&mapping.upper_dir)?;
+    }
+    Ok(())
 }
 
-static mut CONFIG: Option<Config> = None;
+/// The upper dir that `LIBOVERLAY_UPPER_DIR=auto` creates, within `XDG_RUNTIME_DIR` or `/tmp`.
+const AUTO_UPPER_DIR: &str = "liboverlay.XXXXXX";
+
+/// The upper dir that this process created and removes at exit, along with the PID of the process,
+/// since forked children inherit it.
+static CREATED_UPPER_DIR: AtomicPtr<(PathBuf, u32)> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Creates a new upper dir for `LIBOVERLAY_UPPER_DIR=auto`, which the children of the process share,
+/// since they inherit its path rather than `auto`. With `remove`, it is removed at exit of the
+/// process, which is told by its PID in `LIBOVERLAY_REMOVE_UPPER_DIR`, even after an `exec`.
+fn create_auto_upper_dir(remove: bool) -> std::io::Result<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+
+    let parent = std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map_or_else(|| PathBuf::from("/tmp"), PathBuf::from);
+    let template = CString::new(parent.join(AUTO_UPPER_DIR).into_os_string().into_vec())?;
+    let template = template.into_raw();
+    let created = unsafe { mkdtemp(template) };
+    let template = unsafe { CString::from_raw(template) };
+    if created.is_null() {
+        return Err(std::io::Error::last_os_error());
+    }
+    let dir = PathBuf::from(OsString::from_vec(template.into_bytes()));
+    // The constructor runs before any other thread of the process exists
+    std::env::set_var("LIBOVERLAY_UPPER_DIR", &dir);
+    if remove {
+        let pid = std::process::id().to_string();
+        std::env::set_var("LIBOVERLAY_REMOVE_UPPER_DIR", pid);
+        remove_at_exit(dir.clone());
+    }
+    Ok(dir)
+}
+
+/// Removes the upper dir `dir` when the process exits.
+fn remove_at_exit(dir: PathBuf) {
+    let created = Box::new((dir, std::process::id()));
+    let previous = CREATED_UPPER_DIR.swap(Box::into_raw(created), Ordering::SeqCst);
+    // Reloads find the same upper dir again
+    if previous.is_null() {
+        unsafe { atexit(remove_created_upper_dir) };
+    }
+}
+
+/// Removes the upper dir this process created, if it is to be removed at exit. Also called by the
+/// `_exit` hook, which skips the handlers of `atexit`.
+pub extern "C" fn remove_created_upper_dir() {
+    let created = unsafe { CREATED_UPPER_DIR.load(Ordering::SeqCst).as_ref() };
+    if let Some((dir, _)) = created.filter(|(_, pid)| *pid == std::process::id()) {
+        let filter = get_config().map_or_else(log::Filter::default, |cfg| cfg.log.clone());
+        // The process is exiting, from now on the hooks pass all calls through, rather than
+        // removing the entries of the upper dir from the merged view
+        CONFIG.store(std::ptr::null_mut(), Ordering::SeqCst);
+        if let Err(e) = std::fs::remove_dir_all(dir) {
+            log::log_with(&filter, Category::Config, Level::Error, || {
+                log_println!("liboverlay: cannot remove {}: {}", dir.display(), e)
+            });
+        }
+    }
+}
+
+/// Virtual file systems, which are passed through even when the whole root is overlaid.
+const PASSTHROUGH_DIRS: &[&str] = &["/proc", "/sys", "/dev"];
+
+#[repr(C)]
+struct DlInfo {
+    dli_fname: *const c_char,
+    dli_fbase: *mut c_void,
+    dli_sname: *const c_char,
+    dli_saddr: *mut c_void,
+}
+
+extern "C" {
+    fn dladdr(addr: *const c_void, info: *mut DlInfo) -> c_int;
+    fn access(path: *const c_char, mode: c_int) -> c_int;
+    fn getuid() -> u32;
+    fn getpwuid(uid: u32) -> *const Passwd;
+    fn mkdtemp(template: *mut c_char) -> *mut c_char;
+    fn atexit(callback: extern "C" fn()) -> c_int;
+}
+
+/// Returns the path liboverlay itself was loaded from.
+fn own_library() -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut info = DlInfo {
+        dli_fname: std::ptr::null(),
+        dli_fbase: std::ptr::null_mut(),
+        dli_sname: std::ptr::null(),
+        dli_saddr: std::ptr::null_mut(),
+    };
+    let found = unsafe { dladdr(own_library as *const c_void, &mut info) };
+    if found == 0 || info.dli_fname.is_null() {
+        return None;
+    }
+    let fname = unsafe { CStr::from_ptr(info.dli_fname) };
+    Some(PathBuf::from(OsStr::from_bytes(fname.to_bytes())))
+}
+
+/// Parses a `lower:upper` pair of LIBOVERLAY_MAPPINGS.
+fn parse_mapping(pair: &str) -> Option<Mapping> {
+    let mut dirs = pair.splitn(2, ':');
+    let lower_dir = dirs.next().filter(|dir| !dir.is_empty())?;
+    let upper_dir = dirs.next().filter(|dir| !dir.is_empty())?;
+    Some(Mapping {
+        lower_dir: PathBuf::from(lower_dir),
+        upper_dir: PathBuf::from(upper_dir),
+        ..Mapping::default()
+    })
+}
+
+/// Applies the options of `LIBOVERLAY_MAPPING_OPTIONS` to `mappings`. The options of a mapping are
+/// given as `LOWER_DIR=OPTION,...`, separated by `;`.
+fn parse_mapping_options(list: &str, mappings: &mut [Mapping]) -> Result<(), String> {
+    for entry in list.split(';').filter(|entry| !entry.is_empty()) {
+        let split = entry
+            .find('=')
+            .ok_or_else(|| format!("missing `=` in `{}`", entry))?;
+        let (lower_dir, options) = (expand(Path::new(&entry[..split]))?, &entry[split + 1..]);
+        let mapping = mappings
+            .iter_mut()
+            .find(|mapping| mapping.lower_dir == lower_dir)
+            .ok_or_else(|| format!("{} is not a lower dir", lower_dir.display()))?;
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            match option {
+                "read_only" => mapping.read_only = true,
+                "no_copy_up" => mapping.copy_up = CopyUp::Never,
+                "case_insensitive" => mapping.case_insensitive = true,
+                _ if option.starts_with("copy_up=") => {
+                    let strategy = &option["copy_up=".len()..];
+                    mapping.copy_up = COPY_UP_STRATEGIES
+                        .iter()
+                        .find(|(_, name)| *name == strategy)
+                        .map(|(strategy, _)| *strategy)
+                        .ok_or_else(|| format!("unknown copy-up strategy `{}`", strategy))?;
+                }
+                _ if option.starts_with("exclude=") => {
+                    let pattern = &option["exclude=".len()..];
+                    let rule = hide::Rule::new(pattern)
+                        .ok_or_else(|| format!("invalid pattern `{}`", pattern))?;
+                    mapping.excluded.push(rule);
+                }
+                _ => return Err(format!("unknown option `{}`", option)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `${NAME}` in `path` with the value of the environment variable `NAME`. `${PID}` is the
+/// PID of the process, and `${USER}` and `${XDG_RUNTIME_DIR}` are those of the user if they are not
+/// set.
+fn expand(path: &Path) -> Result<PathBuf, String> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut expanded = Vec::new();
+    let mut rest = path.as_os_str().as_bytes();
+    while let Some(start) = rest.windows(2).position(|window| window == b"${") {
+        expanded.extend_from_slice(&rest[..start]);
+        rest = &rest[start + 2..];
+        let end = rest
+            .iter()
+            .position(|&b| b == b'}')
+            .ok_or_else(|| format!("unterminated variable in `{}`", path.display()))?;
+        let name = OsStr::from_bytes(&rest[..end]);
+        let value = variable(name)
+            .ok_or_else(|| format!("`${{{}}}` is not set", name.to_string_lossy()))?;
+        expanded.extend_from_slice(value.as_bytes());
+        rest = &rest[end + 1..];
+    }
+    expanded.extend_from_slice(rest);
+    Ok(PathBuf::from(OsStr::from_bytes(&expanded)))
+}
+
+/// Returns the value of the variable `name` for `expand`.
+fn variable(name: &OsStr) -> Option<OsString> {
+    if name == "PID" {
+        return Some(OsString::from(std::process::id().to_string()));
+    }
+    std::env::var_os(name).or_else(|| match name.to_str()? {
+        "USER" => {
+            let passwd = unsafe { getpwuid(getuid()).as_ref()? };
+            let user = unsafe { CStr::from_ptr(passwd.pw_name) };
+            Some(OsString::from(user.to_str().ok()?))
+        }
+        "XDG_RUNTIME_DIR" => Some(OsString::from(format!("/run/user/{}", unsafe { getuid() }))),
+        _ => None,
+    })
+}
+
+/// The start of `struct passwd`, the other fields are never read.
+#[repr(C)]
+struct Passwd {
+    pw_name: *const c_char,
+}
+
+/// Parses a list of hook groups separated by `;`.
+fn parse_hook_groups(list: &str) -> Result<Vec<HookGroup>, String> {
+    list.split(';')
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            HOOK_GROUPS
+                .iter()
+                .find(|(_, group)| *group == name)
+                .map(|(group, _)| *group)
+                .ok_or_else(|| format!("unknown hook group `{}`", name))
+        })
+        .collect()
+}
+
+/// Parses a number with an optional binary suffix `K`, `M`, `G` or `T`, e.g. `512M`.
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, shift) = match value.as_bytes().last()? {
+        b'K' => (&value[..value.len() - 1], 10),
+        b'M' => (&value[..value.len() - 1], 20),
+        b'G' => (&value[..value.len() - 1], 30),
+        b'T' => (&value[..value.len() - 1], 40),
+        _ => (value, 0),
+    };
+    let number: u64 = digits.parse().ok()?;
+    number.checked_mul(1 << shift)
+}
+
+/// The name of the config files that are discovered in the current directory or its ancestors.
+const PROJECT_FILE: &str = ".liboverlay.toml";
+
+/// The config file of the user, below `XDG_CONFIG_HOME` or `~/.config`.
+const USER_FILE: &str = "liboverlay/config.toml";
+
+/// The config file of the system.
+const SYSTEM_FILE: &str = "/etc/liboverlay.conf";
+
+/// The configuration variables, taken from the environment, or else from the config file named by
+/// `LIBOVERLAY_CONFIG`. Each setting of the file stands for one of the variables. Without the
+/// variable, the nearest `.liboverlay.toml` is used, or else the config file of the user or of the
+/// system, and an empty one disables config files. `LIBOVERLAY_PROFILE` selects a table of
+/// `profiles` in the file, whose settings override the others.
+struct Vars {
+    /// The config file that was discovered rather than named.
+    discovered: Option<PathBuf>,
+    file: Vec<(&'static str, String)>,
+}
+
+impl Vars {
+    fn load() -> Result<Vars, String> {
+        let (path, discovered) = chosen_file();
+        let profile = std::env::var("LIBOVERLAY_PROFILE").ok();
+        let path = match (path, profile.as_ref()) {
+            (Some(path), _) => path,
+            (None, Some(profile)) => {
+                return Err(format!(
+                    "profile `{}` not found, there is no config file",
+                    profile
+                ));
+            }
+            (None, None) => {
+                return Ok(Vars {
+                    discovered,
+                    file: Vec::new(),
+                })
+            }
+        };
+        let at = |e: String| format!("{}: {}", path.display(), e);
+        let extension = path.extension().and_then(OsStr::to_str);
+        if extension == Some("yaml") || extension == Some("yml") {
+            return Err(at(String::from("YAML is not supported, use TOML instead")));
+        }
+        let text = std::fs::read_to_string(&path).map_err(|e| at(e.to_string()))?;
+        let mut file = toml::parse(&text).map_err(at)?;
+        let dir = std::env::current_dir()
+            .map_err(|e| at(e.to_string()))?
+            .join(&path);
+        let dir = dir.parent().unwrap_or(&dir);
+        // The settings of the profile come first, so that they are found first
+        let mut vars = match (file.remove("profiles"), profile) {
+            (_, None) => Vec::new(),
+            (Some(toml::Value::Table(profiles)), Some(profile)) => match profiles.get(&profile) {
+                Some(toml::Value::Table(settings)) => file_vars(settings, dir).map_err(at)?,
+                Some(_) => return Err(at(format!("profile `{}` is not a table", profile))),
+                None => return Err(at(format!("unknown profile `{}`", profile))),
+            },
+            (Some(_), Some(_)) => return Err(at(String::from("`profiles` is not a table"))),
+            (None, Some(profile)) => return Err(at(format!("unknown profile `{}`", profile))),
+        };
+        vars.extend(file_vars(&file, dir).map_err(at)?);
+        Ok(Vars {
+            discovered,
+            file: vars,
+        })
+    }
+
+    fn var(&self, name: &str) -> Result<String, std::env::VarError> {
+        match std::env::var(name) {
+            Err(std::env::VarError::NotPresent) => self
+                .file_value(name)
+                .map(String::from)
+                .ok_or(std::env::VarError::NotPresent),
+            value => value,
+        }
+    }
+
+    fn var_os(&self, name: &str) -> Option<OsString> {
+        std::env::var_os(name).or_else(|| self.file_value(name).map(OsString::from))
+    }
+
+    fn file_value(&self, name: &str) -> Option<&str> {
+        self.file
+            .iter()
+            .find(|(var, _)| *var == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The config file that the first load chose, and the one it discovered, if any.
+static CHOSEN_FILE: AtomicPtr<(Option<PathBuf>, Option<PathBuf>)> =
+    AtomicPtr::new(std::ptr::null_mut());
+
+/// Returns the config file to read, and the one that was discovered rather than named. The file is
+/// chosen by the first load, and reloads read it again, wherever the process runs by then.
+fn chosen_file() -> (Option<PathBuf>, Option<PathBuf>) {
+    if let Some(chosen) = unsafe { CHOSEN_FILE.load(Ordering::SeqCst).as_ref() } {
+        return chosen.clone();
+    }
+    let chosen = match std::env::var_os("LIBOVERLAY_CONFIG") {
+        Some(ref path) if path.is_empty() => (None, None),
+        Some(path) => {
+            let path = match std::env::current_dir() {
+                Ok(cwd) => cwd.join(path),
+                Err(_) => PathBuf::from(path),
+            };
+            (Some(path), None)
+        }
+        None => {
+            let discovered = discover();
+            (discovered.clone(), discovered)
+        }
+    };
+    CHOSEN_FILE.store(Box::into_raw(Box::new(chosen.clone())), Ordering::SeqCst);
+    chosen
+}
+
+/// The settings of the config file by section, and the variables they stand for. The mappings are
+/// an array of tables `[[mappings]]` instead.
+const FILE_SETTINGS: &[(&str, &str, &str)] = &[
+    ("", "session", "LIBOVERLAY_SESSION"),
+    ("", "reload", "LIBOVERLAY_RELOAD"),
+    ("", "control", "LIBOVERLAY_CONTROL"),
+    ("", "programs", "LIBOVERLAY_PROGRAMS"),
+    (
+        "",
+        "disable_for_children",
+        "LIBOVERLAY_DISABLE_FOR_CHILDREN",
+    ),
+    ("", "strict", "LIBOVERLAY_STRICT"),
+    ("logging", "level", "LIBOVERLAY_LOG"),
+    ("logging", "file", "LIBOVERLAY_LOG_FILE"),
+    ("logging", "fd", "LIBOVERLAY_LOG_FD"),
+    ("logging", "format", "LIBOVERLAY_LOG_FORMAT"),
+    ("logging", "debug", "LIBOVERLAY_DEBUG"),
+    ("copy_up", "follow_symlinks", "LIBOVERLAY_FOLLOW_SYMLINKS"),
+    ("copy_up", "copy_on_read", "LIBOVERLAY_COPY_ON_READ"),
+    ("copy_up", "dir_depth", "LIBOVERLAY_DIR_COPY_UP_DEPTH"),
+    ("copy_up", "transforms", "LIBOVERLAY_TRANSFORMS"),
+    ("quota", "bytes", "LIBOVERLAY_QUOTA_BYTES"),
+    ("quota", "files", "LIBOVERLAY_QUOTA_FILES"),
+    ("quota", "evict", "LIBOVERLAY_QUOTA_EVICT"),
+    ("exclusions", "paths", "LIBOVERLAY_EXCLUDE"),
+    ("exclusions", "hide", "LIBOVERLAY_HIDE"),
+    ("view", "rewrites", "LIBOVERLAY_REWRITES"),
+    ("view", "files", "LIBOVERLAY_FILES"),
+    ("view", "sorted", "LIBOVERLAY_SORT_DIRS"),
+];
+
+/// Looks for a `.liboverlay.toml` in the current directory and its ancestors, and returns the
+/// nearest one. Without one, the config file of the user is used, or else that of the system.
+fn discover() -> Option<PathBuf> {
+    let project = std::env::current_dir().ok().and_then(|cwd| {
+        cwd.ancestors()
+            .map(|dir| dir.join(PROJECT_FILE))
+            .find(|path| path.is_file())
+    });
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")));
+    project
+        .into_iter()
+        .chain(config_home.map(|dir| dir.join(USER_FILE)))
+        .chain(Some(PathBuf::from(SYSTEM_FILE)))
+        .find(|path| path.is_file())
+}
+
+/// Translates the settings of a config file in `dir` into the values of the variables they stand
+/// for.
+fn file_vars(file: &toml::Table, dir: &Path) -> Result<Vec<(&'static str, String)>, String> {
+    let mut vars = Vec::new();
+    for (name, value) in &file.0 {
+        match value {
+            toml::Value::Array(mappings) if name == "mappings" => {
+                vars.extend(mapping_vars(mappings, dir)?);
+            }
+            toml::Value::Table(hooks) if name == "hooks" => vars.push(hook_var(hooks)?),
+            toml::Value::Table(section) => {
+                for (key, value) in &section.0 {
+                    vars.push((file_var(name, key)?, var_value(value)?));
+                }
+            }
+            value => vars.push((file_var("", name)?, var_value(value)?)),
+        }
+    }
+    Ok(vars)
+}
+
+/// Returns the variable that the setting `key` of `section` stands for.
+fn file_var(section: &str, key: &str) -> Result<&'static str, String> {
+    FILE_SETTINGS
+        .iter()
+        .find(|setting| setting.0 == section && setting.1 == key)
+        .map(|setting| setting.2)
+        .ok_or_else(|| {
+            if section.is_empty() {
+                format!("unknown setting `{}`", key)
+            } else {
+                format!("unknown setting `{}.{}`", section, key)
+            }
+        })
+}
+
+/// Translates the `[hooks]` of a config file, which enable or disable hook groups by name, into
+/// `LIBOVERLAY_DISABLE_HOOKS`.
+fn hook_var(hooks: &toml::Table) -> Result<(&'static str, String), String> {
+    let mut disabled = Vec::new();
+    for (name, value) in &hooks.0 {
+        if !HOOK_GROUPS.iter().any(|(_, group)| group == name) {
+            return Err(format!("unknown setting `hooks.{}`", name));
+        }
+        match value {
+            toml::Value::Boolean(true) => {}
+            toml::Value::Boolean(false) => disabled.push(name.as_str()),
+            _ => return Err(format!("`hooks.{}` must be a boolean", name)),
+        }
+    }
+    Ok(("LIBOVERLAY_DISABLE_HOOKS", disabled.join(";")))
+}
+
+/// The flags of `LIBOVERLAY_MAPPING_OPTIONS`, which are set in `[[mappings]]` by the same name.
+const MAPPING_FLAGS: &[&str] = &["read_only", "no_copy_up", "case_insensitive"];
+
+/// Translates the `[[mappings]]` of a config file in `dir` into `LIBOVERLAY_MAPPINGS`, the remote
+/// trees they mirror into `LIBOVERLAY_REMOTES`, and their options into
+/// `LIBOVERLAY_MAPPING_OPTIONS`. Relative dirs are relative to `dir`.
+fn mapping_vars(
+    mappings: &[toml::Value],
+    dir: &Path,
+) -> Result<Vec<(&'static str, String)>, String> {
+    let (mut pairs, mut remotes, mut options) = (Vec::new(), Vec::new(), Vec::new());
+    for mapping in mappings {
+        let mapping = match mapping {
+            toml::Value::Table(mapping) => mapping,
+            _ => return Err(String::from("`mappings` must be an array of tables")),
+        };
+        let string = |key: &str| match mapping.get(key) {
+            Some(toml::Value::String(value)) => Ok(Some(value.clone())),
+            None => Ok(None),
+            Some(_) => Err(format!("`mappings.{}` must be a string", key)),
+        };
+        let (lower, upper) = match (string("lower")?, string("upper")?) {
+            (Some(lower), Some(upper)) => (relative_to(dir, &lower), relative_to(dir, &upper)),
+            _ => return Err(String::from("mappings need a `lower` and an `upper` dir")),
+        };
+        if let Some(remote) = string("remote")? {
+            remotes.push(format!("{}={}", lower, remote));
+        }
+        let mut flags = Vec::new();
+        for (key, value) in &mapping.0 {
+            match (key.as_str(), value) {
+                ("lower", _) | ("upper", _) | ("remote", _) => {}
+                (flag, toml::Value::Boolean(set)) if MAPPING_FLAGS.contains(&flag) => {
+                    if *set {
+                        flags.push(String::from(flag));
+                    }
+                }
+                (flag, _) if MAPPING_FLAGS.contains(&flag) => {
+                    return Err(format!("`mappings.{}` must be a boolean", flag))
+                }
+                ("copy_up", toml::Value::String(strategy)) => {
+                    flags.push(format!("copy_up={}", strategy))
+                }
+                ("copy_up", _) => return Err(String::from("`mappings.copy_up` must be a string")),
+                ("exclude", toml::Value::Array(patterns)) => {
+                    for pattern in patterns {
+                        match pattern {
+                            toml::Value::String(pattern) => {
+                                flags.push(format!("exclude={}", pattern))
+                            }
+                            _ => return Err(String::from("`mappings.exclude` must list strings")),
+                        }
+                    }
+                }
+                ("exclude", _) => return Err(String::from("`mappings.exclude` must be an array")),
+                (key, _) => return Err(format!("unknown setting `mappings.{}`", key)),
+            }
+        }
+        if !flags.is_empty() {
+            options.push(format!("{}={}", lower, flags.join(",")));
+        }
+        pairs.push(format!("{}:{}", lower, upper));
+    }
+    let mut vars = vec![("LIBOVERLAY_MAPPINGS", pairs.join(";"))];
+    if !remotes.is_empty() {
+        vars.push(("LIBOVERLAY_REMOTES", remotes.join(";")));
+    }
+    if !options.is_empty() {
+        vars.push(("LIBOVERLAY_MAPPING_OPTIONS", options.join(";")));
+    }
+    Ok(vars)
+}
+
+/// Resolves `path` against `dir`, including `..` components. Paths starting with a variable are
+/// left as they are, for the variable to make them absolute.
+fn relative_to(dir: &Path, path: &str) -> String {
+    if path.starts_with("${") {
+        return path.to_string();
+    }
+    let mut resolved = PathBuf::new();
+    for component in dir.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    resolved.to_string_lossy().into_owned()
+}
+
+/// Formats a setting like the variable it stands for. Lists are separated by `;`, and flags are
+/// `1` or `0`.
+fn var_value(value: &toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(String::from(if *value { "1" } else { "0" })),
+        toml::Value::Array(items) => {
+            let items = items
+                .iter()
+                .map(|item| match item {
+                    toml::Value::Array(_) => Err(String::from("arrays cannot be nested")),
+                    item => var_value(item),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(items.join(";"))
+        }
+        toml::Value::Table(_) => Err(String::from("unexpected table")),
+    }
+}
+
+static CONFIG: AtomicPtr<Config> = AtomicPtr::new(std::ptr::null_mut());
 
 #[used]
-#[cfg_attr(target_os = "linux", link_section = ".ctors")]
+#[cfg_attr(target_os = "linux", link_section = ".init_array")]
 pub static INIT_CONFIG: extern "C" fn() = {
     extern "C" fn init_config_impl() {
-        unsafe {
-            CONFIG = Config::from_env();
-            if let Some(cfg) = CONFIG.as_ref() {
-                if cfg.debug {
-                    eprintln!("liboverlay: initialized: {:?}", CONFIG);
+        if !preloaded() {
+            return;
+        }
+        // The config file is read once for all of the settings
+        let vars = Vars::load();
+        open_log(&vars);
+        let cfg = Config::from_vars(&vars);
+        if cfg.is_none() && strict(&vars) {
+            abort_invalid();
+        }
+        if let Some(cfg) = cfg {
+            // Processes of other programs are not overlaid, nor reloaded or controlled
+            if !program::selected(&cfg.programs) {
+                return;
+            }
+            if strict(&vars) {
+                if let Err(e) = cfg.validate() {
+                    log_println!("liboverlay: invalid configuration: {}", e);
+                    abort_invalid();
                 }
             }
+            log::log_with(&cfg.log, Category::Config, Level::Debug, || {
+                log_println!("liboverlay: initialized: {:?}", cfg)
+            });
+            let (reload, control) = (cfg.reload, cfg.control.clone());
+            // The config is never freed, it lives as long as the process.
+            CONFIG.store(Box::into_raw(Box::new(cfg)), Ordering::SeqCst);
+            if reload {
+                reload::install();
+            }
+            if let Some(control) = control {
+                control::start(&control);
+            }
         }
     }
     init_config_impl
@@ -55,12 +1407,108 @@ pub static INIT_CONFIG: extern "C" fn() = {
 
 #[inline(always)]
 pub fn get_config() -> Option<&'static Config> {
-    unsafe { CONFIG.as_ref() }
+    if reload::requested() {
+        reload_config();
+    }
+    unsafe { CONFIG.load(Ordering::SeqCst).as_ref() }
 }
 
-#[inline(always)]
-pub fn if_debug<F: FnOnce()>(callback: F) {
-    if get_config().map_or(false, |cfg| cfg.debug) {
-        callback()
+/// Installs `cfg` in place of the current config, for all threads of the process. Like reloaded
+/// configs, replaced ones are never freed.
+pub fn configure(cfg: Config) {
+    CONFIG.store(Box::into_raw(Box::new(cfg)), Ordering::SeqCst);
+}
+
+/// Whether `with_overlay` is running, which cannot be nested.
+static OVERLAY_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Runs `f` with `cfg` installed, and then restores the config it replaced, even if `f` panics. A
+/// config installed by `configure` or a reload while `f` runs is kept instead. Like other replaced
+/// configs, `cfg` is never freed, since threads started by `f` may still use it.
+///
+/// # Panics
+///
+/// Panics if another call of `with_overlay` is running, in this thread or another one.
+pub fn with_overlay<R, F: FnOnce() -> R>(cfg: Config, f: F) -> R {
+    struct Restore {
+        installed: *mut Config,
+        previous: *mut Config,
+    }
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let _ = CONFIG.compare_exchange(
+                self.installed,
+                self.previous,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+            OVERLAY_RUNNING.store(false, Ordering::SeqCst);
+        }
+    }
+
+    if OVERLAY_RUNNING.swap(true, Ordering::SeqCst) {
+        panic!("with_overlay is already running");
+    }
+    let installed = Box::into_raw(Box::new(cfg));
+    let _restore = Restore {
+        installed,
+        previous: CONFIG.swap(installed, Ordering::SeqCst),
+    };
+    f()
+}
+
+/// Checks whether liboverlay is loaded as a library of its own, rather than linked into the
+/// program, which configures it with `configure` instead of variables.
+fn preloaded() -> bool {
+    let library = own_library().and_then(|library| std::fs::metadata(library).ok());
+    let program = std::env::current_exe().and_then(std::fs::metadata);
+    match (library, program) {
+        (Some(library), Ok(program)) => {
+            library.dev() != program.dev() || library.ino() != program.ino()
+        }
+        _ => true,
+    }
+}
+
+/// Swaps in a newly loaded config for the current one, which is kept if the new one is invalid, or
+/// fails the checks of `LIBOVERLAY_STRICT=1`. Unlike at startup, the process is not aborted then, and
+/// the log keeps being written where it was. Replaced configs are never freed either, since
+/// operations that are under way still use them. Returns whether the new config is used. If it no
+/// longer selects the program of the process, the process is not overlaid anymore.
+pub fn reload_config() -> bool {
+    let vars = Vars::load();
+    let cfg = Config::from_vars(&vars).filter(|cfg| {
+        let valid = if strict(&vars) {
+            cfg.validate()
+        } else {
+            Ok(())
+        };
+        match valid {
+            Ok(()) => true,
+            Err(e) => {
+                log_println!("liboverlay: invalid configuration: {}", e);
+                false
+            }
+        }
+    });
+    match cfg {
+        Some(cfg) if !program::selected(&cfg.programs) => {
+            CONFIG.store(std::ptr::null_mut(), Ordering::SeqCst);
+            true
+        }
+        Some(cfg) => {
+            log::log_with(&cfg.log, Category::Config, Level::Debug, || {
+                log_println!("liboverlay: reloaded: {:?}", cfg)
+            });
+            CONFIG.store(Box::into_raw(Box::new(cfg)), Ordering::SeqCst);
+            true
+        }
+        None => {
+            log::error(Category::Config, || {
+                log_println!("liboverlay: keeping the previous configuration")
+            });
+            false
+        }
     }
 }
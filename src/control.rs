@@ -0,0 +1,227 @@
+//! The control socket lets other tools inspect and change a running process. It is a Unix domain
+//! socket at the path `LIBOVERLAY_CONTROL` names, where `%p` stands for the PID, so that each
+//! process of a tree gets its own. A thread of the process serves it, accepting one command per
+//! line and answering each with one line, which starts with `ok` or `error:`:
+//!
+//! - `status` reports the PID, what is logged and the mappings,
+//! - `log FILTER` changes what is logged, like `LIBOVERLAY_LOG`, e.g. `log info,dir=trace`,
+//! - `map LOWER_DIR:UPPER_DIR` adds a mapping, `unmap LOWER_DIR` removes one,
+//! - `flush` forgets the remote files that could not be fetched, and the measured quota usage,
+//! - `reload` loads the configuration again, like `SIGHUP` does with `LIBOVERLAY_RELOAD`.
+//!
+//! Changes apply on top of the variables and the config file, and last through reloads. Like
+//! reloads, they only affect operations that start afterwards.
+
+use std::ffi::OsStr;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Mutex;
+
+use crate::config;
+use crate::log::{self, Category};
+use crate::quota;
+use crate::remote;
+
+/// The changes made through the control socket.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    /// The filter of the log messages, as given.
+    pub log: Option<String>,
+    /// Mappings that are added, as `(lower_dir, upper_dir)`.
+    pub added: Vec<(PathBuf, PathBuf)>,
+    /// The lower dirs of the mappings that are removed.
+    pub removed: Vec<PathBuf>,
+}
+
+static OVERRIDES: AtomicPtr<Mutex<Overrides>> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Returns the changes made through the control socket.
+pub fn overrides() -> &'static Mutex<Overrides> {
+    let current = OVERRIDES.load(Ordering::SeqCst);
+    if let Some(overrides) = unsafe { current.as_ref() } {
+        return overrides;
+    }
+    let created = Box::into_raw(Box::new(Mutex::new(Overrides::default())));
+    match OVERRIDES.compare_exchange(current, created, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => unsafe { &*created },
+        Err(existing) => {
+            drop(unsafe { Box::from_raw(created) });
+            unsafe { &*existing }
+        }
+    }
+}
+
+/// Starts serving the control socket at `path`, with `%p` replaced by the PID. Nothing is served
+/// if another process serves the socket already.
+pub fn start(path: &OsStr) {
+    let path = socket_path(path);
+    let listener = match bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::warn(Category::Config, || {
+                log_println!(
+                    "liboverlay: cannot serve control socket {}: {}",
+                    path.display(),
+                    e
+                )
+            });
+            return;
+        }
+    };
+    let spawned = std::thread::Builder::new()
+        .name(String::from("liboverlay-control"))
+        .spawn(move || {
+            for stream in listener.incoming().filter_map(Result::ok) {
+                serve(stream);
+            }
+        });
+    if let Err(e) = spawned {
+        log::error(Category::Config, || {
+            log_println!("liboverlay: cannot serve control socket: {}", e)
+        });
+    }
+}
+
+fn socket_path(path: &OsStr) -> PathBuf {
+    let pid = std::process::id().to_string();
+    let mut expanded = Vec::with_capacity(path.len());
+    let mut bytes = path.as_bytes().iter();
+    while let Some(&b) = bytes.next() {
+        if b != b'%' {
+            expanded.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'p') => expanded.extend_from_slice(pid.as_bytes()),
+            Some(&other) => expanded.extend_from_slice(&[b'%', other]),
+            None => expanded.push(b'%'),
+        }
+    }
+    PathBuf::from(OsStr::from_bytes(&expanded))
+}
+
+/// Binds the socket at `path`, replacing sockets that are left behind by processes that are gone.
+fn bind(path: &Path) -> std::io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(std::io::Error::from(std::io::ErrorKind::AddrInUse));
+            }
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        bound => bound,
+    }
+}
+
+fn serve(stream: UnixStream) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let reply = match run(line.trim()) {
+            Ok(reply) if reply.is_empty() => String::from("ok\n"),
+            Ok(reply) => format!("ok {}\n", reply),
+            Err(e) => format!("error: {}\n", e),
+        };
+        if writer.write_all(reply.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Runs a command, returning what to reply.
+fn run(command: &str) -> Result<String, String> {
+    let mut words = command.splitn(2, ' ');
+    let (name, argument) = (words.next().unwrap_or(""), words.next().map(str::trim));
+    match (name, argument) {
+        ("status", None) => status(),
+        ("log", Some(filter)) => {
+            log::parse_filter(filter)?;
+            change(|overrides| overrides.log = Some(filter.to_string()))
+        }
+        ("map", Some(pair)) => {
+            let split = pair
+                .find(':')
+                .filter(|&split| split > 0 && split + 1 < pair.len())
+                .ok_or_else(|| format!("invalid mapping `{}`", pair))?;
+            let (lower_dir, upper_dir) = (&pair[..split], &pair[split + 1..]);
+            change(|overrides| {
+                overrides
+                    .removed
+                    .retain(|removed| removed != Path::new(lower_dir));
+                overrides
+                    .added
+                    .push((PathBuf::from(lower_dir), PathBuf::from(upper_dir)));
+            })
+        }
+        ("unmap", Some(lower_dir)) => change(|overrides| {
+            overrides
+                .added
+                .retain(|(added, _)| added != Path::new(lower_dir));
+            overrides.removed.push(PathBuf::from(lower_dir));
+        }),
+        ("flush", None) => {
+            remote::forget_missed();
+            quota::remeasure();
+            Ok(String::new())
+        }
+        ("reload", None) => reload(),
+        _ => Err(format!("unknown command `{}`", command)),
+    }
+}
+
+fn status() -> Result<String, String> {
+    let cfg = config::get_config().ok_or_else(|| String::from("not configured"))?;
+    let mappings = cfg
+        .mappings
+        .iter()
+        .map(|mapping| {
+            format!(
+                "{}:{}",
+                mapping.lower_dir.display(),
+                mapping.upper_dir.display()
+            )
+        })
+        .collect::<Vec<_>>();
+    Ok(format!(
+        "pid={} log={} mappings={}",
+        std::process::id(),
+        cfg.log,
+        mappings.join(";")
+    ))
+}
+
+/// Applies `update` to the overrides, and reloads the configuration with them. The overrides are
+/// left as they were if the configuration is invalid with them.
+fn change<F: FnOnce(&mut Overrides)>(update: F) -> Result<String, String> {
+    let previous = {
+        let mut overrides = overrides().lock().unwrap();
+        let previous = overrides.clone();
+        update(&mut overrides);
+        previous
+    };
+    let reloaded = reload();
+    if reloaded.is_err() {
+        *overrides().lock().unwrap() = previous;
+    }
+    reloaded
+}
+
+fn reload() -> Result<String, String> {
+    if config::reload_config() {
+        Ok(String::new())
+    } else {
+        Err(String::from(
+            "invalid configuration, the previous one is kept",
+        ))
+    }
+}
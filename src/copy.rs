@@ -0,0 +1,347 @@
+//! Copies the contents of lower files into the upper dir.
+//!
+//! Files are opened and copied with the real functions rather than our hooks, so that no
+//! redirection applies, no matter in which order the files are opened.
+
+use std::ffi::{CString, OsString};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::raw::{c_char, c_int, c_long, c_ulong, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::log::{self, Category};
+use crate::sysno;
+
+extern "C" {
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    fn fchown(fd: c_int, owner: u32, group: u32) -> c_int;
+    fn flistxattr(fd: c_int, list: *mut c_char, size: usize) -> isize;
+    fn fgetxattr(fd: c_int, name: *const c_char, value: *mut c_void, size: usize) -> isize;
+}
+
+/// Makes the destination share the extents of the source, see ioctl_ficlone(2).
+const FICLONE: c_ulong = 0x4004_9409;
+
+const O_WRONLY: c_int = 0o1;
+const O_CREAT: c_int = 0o100;
+const O_EXCL: c_int = 0o200;
+const O_TRUNC: c_int = 0o1000;
+const O_CLOEXEC: c_int = 0o2000000;
+
+const SEEK_SET: c_int = 0;
+const SEEK_DATA: c_int = 3;
+const SEEK_HOLE: c_int = 4;
+const ENXIO: c_int = 6;
+const ERANGE: c_int = 34;
+
+const AT_FDCWD: c_int = -100;
+const AT_SYMLINK_NOFOLLOW: c_int = 0x100;
+
+/// The amount of data copied by one call to `copy_file_range`.
+const CHUNK_SIZE: u64 = 1 << 30;
+
+/// Copies the contents and metadata of `from` to `to`, which is created or replaced. Like
+/// `fs::copy`, but where both files live on a file system supporting reflinks, e.g. btrfs or XFS,
+/// the copy is a clone that shares the data of `from` and is made instantly.
+///
+/// The copy is made under a temporary name next to `to` and then renamed into place, so that it
+/// never shows up half-finished, neither to other processes nor after a crash.
+pub fn copy_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    copy_with(from, to, |source, target| copy_data(from, source, target))
+}
+
+/// Copies `from` to `to` like `copy_file`, but only as a clone. Fails where `from` cannot be cloned,
+/// without leaving a copy behind.
+pub fn clone_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    copy_with(from, to, |source, target| {
+        if unsafe { ioctl(target.as_raw_fd(), FICLONE, source.as_raw_fd()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    })
+}
+
+/// Copies `from` to `to` like `copy_file`, but with the contents passed through `transform`.
+pub fn copy_file_transformed<F: FnOnce(Vec<u8>) -> Vec<u8>>(
+    from: &Path,
+    to: &Path,
+    transform: F,
+) -> std::io::Result<()> {
+    copy_with(from, to, |source, target| {
+        let mut contents = Vec::new();
+        source.read_to_end(&mut contents)?;
+        target.write_all(&transform(contents))
+    })
+}
+
+/// Makes a copy of `from` at `to`, whose contents are written by `copy`.
+fn copy_with<F>(from: &Path, to: &Path, copy: F) -> std::io::Result<()>
+where
+    F: FnOnce(&mut File, &mut File) -> std::io::Result<()>,
+{
+    let temp = temp_path(to)?;
+    let mut source = open_real(from, O_CLOEXEC, 0)?;
+    let mut target = open_real(&temp, O_WRONLY | O_CREAT | O_EXCL | O_CLOEXEC, 0o600)?;
+    let copied = copy(&mut source, &mut target)
+        .and_then(|_| copy_attributes(&source, &target))
+        .and_then(|_| rename_real(&temp, to));
+    if copied.is_err() {
+        if let Ok(ctemp) = CString::new(temp.as_os_str().as_bytes()) {
+            unsafe { crate::C_UNLINK.call(ctemp.as_ptr()) };
+        }
+    }
+    copied
+}
+
+/// Temporary copies use the reserved names of whiteouts, so they are never part of the merged
+/// view, even when left behind.
+const TEMP_PREFIX: &str = ".wh..wh.copy.";
+
+/// Distinguishes the temporary copies made by the threads of one process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns a unique temporary name in the directory of `path`.
+fn temp_path(path: &Path) -> std::io::Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    let mut temp = OsString::from(format!(
+        "{}{}.{}.",
+        TEMP_PREFIX,
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    temp.push(name);
+    Ok(path.with_file_name(temp))
+}
+
+/// Copies the contents of `from` to the existing file `to`, which keeps its own metadata.
+pub fn copy_contents(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut source = open_real(from, O_CLOEXEC, 0)?;
+    let mut target = open_real(to, O_WRONLY | O_TRUNC | O_CLOEXEC, 0)?;
+    copy_data(from, &mut source, &mut target)
+}
+
+/// Copies the owner, permissions, times and extended attributes of `from` to `to`, which may be a
+/// file or a directory.
+pub fn copy_metadata(from: &Path, to: &Path) -> std::io::Result<()> {
+    let source = open_real(from, O_CLOEXEC, 0)?;
+    let target = open_real(to, O_CLOEXEC, 0)?;
+    copy_attributes(&source, &target)
+}
+
+/// Copies the owner and times of the symlink `from` to the symlink `to`, without following them.
+pub fn copy_symlink_metadata(from: &Path, to: &Path) -> std::io::Result<()> {
+    let metadata = from.symlink_metadata()?;
+    let cto = CString::new(to.as_os_str().as_bytes())
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    let (uid, gid) = (metadata.uid(), metadata.gid());
+    if unsafe { crate::C_LCHOWN.call(cto.as_ptr(), uid, gid) } != 0 {
+        unsafe { crate::C_LCHOWN.call(cto.as_ptr(), !0, gid) };
+    }
+    let times = [
+        [metadata.atime(), metadata.atime_nsec()],
+        [metadata.mtime(), metadata.mtime_nsec()],
+    ];
+    let ret = unsafe {
+        crate::C_UTIMENSAT.call(
+            AT_FDCWD,
+            cto.as_ptr(),
+            times.as_ptr() as *const c_void,
+            AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn copy_data(from: &Path, source: &mut File, target: &mut File) -> std::io::Result<()> {
+    // Clones never work across file systems, e.g. when the upper dir lives on a tmpfs
+    let same_fs = source.metadata()?.dev() == target.metadata()?.dev();
+    let cloned = same_fs && unsafe { ioctl(target.as_raw_fd(), FICLONE, source.as_raw_fd()) } == 0;
+    if cloned {
+        log::debug(Category::Copy, || {
+            log_println!("liboverlay: cloned {}", from.display())
+        });
+        Ok(())
+    } else {
+        copy_sparse(source, target)
+    }
+}
+
+fn copy_attributes(source: &File, target: &File) -> std::io::Result<()> {
+    let metadata = source.metadata()?;
+    // Only root may give files away, everybody else keeps owning the copy and at best can hand it
+    // to the group of the lower file.
+    let (fd, uid, gid) = (target.as_raw_fd(), metadata.uid(), metadata.gid());
+    if unsafe { fchown(fd, uid, gid) } != 0 {
+        unsafe { fchown(fd, !0, gid) };
+    }
+    copy_xattrs(source, target);
+    // Changing the owner clears the set-user-ID and set-group-ID bits, so the mode comes after it
+    target.set_permissions(metadata.permissions())?;
+    let times = [
+        [metadata.atime(), metadata.atime_nsec()],
+        [metadata.mtime(), metadata.mtime_nsec()],
+    ];
+    if unsafe { crate::C_FUTIMENS.call(fd, times.as_ptr() as *const c_void) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Copies the extended attributes of `source` to `target`, skipping the ones that cannot be set,
+/// e.g. those in the `trusted` namespace for unprivileged users.
+fn copy_xattrs(source: &File, target: &File) {
+    let names = match read_xattr(|buf, size| unsafe {
+        flistxattr(source.as_raw_fd(), buf as *mut c_char, size)
+    }) {
+        Some(names) => names,
+        None => return,
+    };
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let value = read_xattr(|buf, size| unsafe {
+            fgetxattr(source.as_raw_fd(), name.as_ptr(), buf as *mut c_void, size)
+        });
+        if let Some(value) = value {
+            let ret = unsafe {
+                crate::C_FSETXATTR.call(
+                    target.as_raw_fd(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const c_void,
+                    value.len(),
+                    0,
+                )
+            };
+            if ret != 0 {
+                log::warn(Category::Copy, || {
+                    log_println!(
+                        "liboverlay: could not copy xattr {}: {}",
+                        name.to_string_lossy(),
+                        std::io::Error::last_os_error()
+                    )
+                });
+            }
+        }
+    }
+}
+
+/// Reads a list or value of extended attributes with `read`, which follows the protocol of
+/// `getxattr` and returns the required size when passed an empty buffer.
+fn read_xattr<F: Fn(*mut u8, usize) -> isize>(read: F) -> Option<Vec<u8>> {
+    loop {
+        let size = read(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return None;
+        }
+        let mut buf = vec![0; size as usize];
+        let ret = read(buf.as_mut_ptr(), buf.len());
+        if ret >= 0 {
+            buf.truncate(ret as usize);
+            return Some(buf);
+        }
+        // The attributes changed in between, so the buffer may be too small now
+        if std::io::Error::last_os_error().raw_os_error() != Some(ERANGE) {
+            return None;
+        }
+    }
+}
+
+/// Copies the data of `source` to the empty file `target`, skipping the holes of sparse files, e.g.
+/// disk images, so that they stay holes rather than taking up space.
+fn copy_sparse(source: &mut File, target: &mut File) -> std::io::Result<()> {
+    let size = source.metadata()?.len() as i64;
+    let mut offset = 0;
+    let mut in_kernel = true;
+    while offset < size {
+        let start = match seek(source, offset, SEEK_DATA) {
+            Ok(start) => start,
+            // There is no more data after `offset`
+            Err(ref e) if e.raw_os_error() == Some(ENXIO) => break,
+            // The file system cannot tell, so everything is data
+            Err(_) => offset,
+        };
+        let end = seek(source, start, SEEK_HOLE).unwrap_or(size);
+        seek(source, start, SEEK_SET)?;
+        seek(target, start, SEEK_SET)?;
+        copy_range(source, target, (end - start) as u64, &mut in_kernel)?;
+        offset = end;
+    }
+    // Trailing holes have no data segment to end them
+    target.set_len(size as u64)
+}
+
+fn seek(file: &File, offset: i64, whence: c_int) -> std::io::Result<i64> {
+    let ret = unsafe { crate::C_LSEEK.call(file.as_raw_fd(), offset, whence) };
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Copies `len` bytes from the current position of `source` to the current position of `target`.
+/// This leaves the work to the kernel with `copy_file_range` while `in_kernel` is set, which is
+/// cleared once that fails.
+fn copy_range(
+    source: &mut File,
+    target: &mut File,
+    len: u64,
+    in_kernel: &mut bool,
+) -> std::io::Result<()> {
+    let mut remaining = len;
+    while *in_kernel && remaining > 0 {
+        let ret = unsafe {
+            crate::C_SYSCALL.call(
+                sysno::COPY_FILE_RANGE,
+                source.as_raw_fd() as c_long,
+                0,
+                target.as_raw_fd() as c_long,
+                0,
+                remaining.min(CHUNK_SIZE) as c_long,
+                0,
+            )
+        };
+        if ret <= 0 {
+            // Unsupported, e.g. across file systems on older kernels, or the file shrank. Both
+            // positions have advanced by what has been copied, so the rest is copied by hand.
+            *in_kernel = false;
+        } else {
+            remaining -= ret as u64;
+        }
+    }
+    std::io::copy(&mut (&*source).take(remaining), target)?;
+    Ok(())
+}
+
+/// Renames `from` to `to` with the real `rename`.
+fn rename_real(from: &Path, to: &Path) -> std::io::Result<()> {
+    let invalid = |_| std::io::Error::from(std::io::ErrorKind::InvalidInput);
+    let cfrom = CString::new(from.as_os_str().as_bytes()).map_err(invalid)?;
+    let cto = CString::new(to.as_os_str().as_bytes()).map_err(invalid)?;
+    if unsafe { crate::C_RENAME.call(cfrom.as_ptr(), cto.as_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Opens `path` with the real `open`.
+fn open_real(path: &Path, flags: c_int, mode: c_int) -> std::io::Result<File> {
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    let fd = unsafe { crate::C_OPEN.call(cpath.as_ptr(), flags, mode) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
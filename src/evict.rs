@@ -0,0 +1,149 @@
+//! Frees room in the upper dirs when they exceed their quota, by removing copies that were never
+//! modified, least recently used first. This turns the upper dirs into a cache of the lower dirs
+//! that keeps only the files with real modifications for good.
+//!
+//! A copy counts as unmodified while it is a regular file with the size, modification time, mode
+//! and owner of its lower file, which copy-ups preserve. Removing it lets the lower file show
+//! through again.
+//! Copies that are open anywhere are kept, since the process that has them open may still write
+//! to them: the copy is only removed while holding a write lease on it, which the kernel grants
+//! only if nobody else has the file open.
+
+use std::ffi::CString;
+use std::os::raw::{c_int, c_long};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::lock;
+use crate::log::{self, Category};
+use crate::whiteout;
+
+extern "C" {
+    fn geteuid() -> u32;
+    fn getegid() -> u32;
+}
+
+const O_CLOEXEC: c_int = 0o2000000;
+
+const F_SETLEASE: c_int = 1024;
+const F_WRLCK: c_long = 1;
+const F_UNLCK: c_long = 2;
+
+/// An unmodified copy that may be evicted.
+struct Candidate {
+    path_to_upper: PathBuf,
+    lower_path: PathBuf,
+    /// When the copy was last read or copied up, in seconds since the epoch.
+    last_used: i64,
+    bytes: u64,
+}
+
+/// Removes unmodified copies until at least `bytes` bytes and `files` entries are freed, or no
+/// more copies can be removed. Returns the bytes and entries that were freed.
+pub fn evict(bytes: u64, files: u64) -> (u64, u64) {
+    let cfg = match config::get_config() {
+        Some(cfg) => cfg,
+        None => return (0, 0),
+    };
+    let mut candidates = Vec::new();
+    for mapping in &cfg.mappings {
+        find_candidates(&mapping.upper_dir, &mapping.lower_dir, &mut candidates);
+    }
+    candidates.sort_by_key(|candidate| candidate.last_used);
+    let (mut freed_bytes, mut freed_files) = (0, 0);
+    for candidate in candidates {
+        if freed_bytes >= bytes && freed_files >= files {
+            break;
+        }
+        if remove(&candidate) {
+            log::info(Category::Copy, || {
+                log_println!(
+                    "liboverlay: evicted unmodified copy {}",
+                    candidate.path_to_upper.display()
+                )
+            });
+            freed_bytes += candidate.bytes;
+            freed_files += 1;
+        }
+    }
+    (freed_bytes, freed_files)
+}
+
+/// Collects the unmodified copies below the upper dir `upper` of the lower dir `lower`.
+fn find_candidates(upper: &Path, lower: &Path, candidates: &mut Vec<Candidate>) {
+    let entries = match std::fs::read_dir(upper) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name();
+        // Whiteouts and bookkeeping entries are never evicted
+        if whiteout::hidden_name(name.as_bytes()).is_some() {
+            continue;
+        }
+        let copy = match entry.metadata() {
+            Ok(copy) => copy,
+            Err(_) => continue,
+        };
+        let lower_path = lower.join(&name);
+        if copy.is_dir() {
+            find_candidates(&entry.path(), &lower_path, candidates);
+        } else if is_unmodified(&copy, &lower_path) {
+            candidates.push(Candidate {
+                path_to_upper: entry.path(),
+                lower_path,
+                last_used: copy.atime().max(copy.ctime()),
+                bytes: copy.blocks() * 512,
+            });
+        }
+    }
+}
+
+/// Checks whether the upper entry with the metadata `copy` is an unmodified copy of `lower_path`.
+/// Copies with hard links, i.e. stubs and the copies of linked lower files, are left alone.
+fn is_unmodified(copy: &std::fs::Metadata, lower_path: &Path) -> bool {
+    if !copy.file_type().is_file() || copy.nlink() != 1 {
+        return false;
+    }
+    std::fs::metadata(lower_path).map_or(false, |lower| {
+        lower.is_file()
+            && lower.len() == copy.len()
+            && lower.mtime() == copy.mtime()
+            && lower.mtime_nsec() == copy.mtime_nsec()
+            && lower.mode() == copy.mode()
+            && copied_owner(lower.uid(), copy.uid(), unsafe { geteuid() })
+            && copied_owner(lower.gid(), copy.gid(), unsafe { getegid() })
+    })
+}
+
+/// Checks whether a copy owned by `copy` may have been made of a file owned by `lower`. Copy-ups
+/// give the copy the owner of the lower file where they may, and leave it to the process otherwise.
+fn copied_owner(lower: u32, copy: u32, process: u32) -> bool {
+    copy == lower || copy == process
+}
+
+/// Removes the copy of `candidate`, unless it was modified or opened in the meantime.
+fn remove(candidate: &Candidate) -> bool {
+    let _lock = lock::copy_up(&candidate.path_to_upper);
+    let cpath = match CString::new(candidate.path_to_upper.as_os_str().as_bytes()) {
+        Ok(cpath) => cpath,
+        Err(_) => return false,
+    };
+    let fd = unsafe { crate::C_OPEN.call(cpath.as_ptr(), O_CLOEXEC, 0) };
+    if fd < 0 {
+        return false;
+    }
+    let removed = unsafe { crate::C_FCNTL.call(fd, F_SETLEASE, F_WRLCK) } == 0
+        && candidate
+            .path_to_upper
+            .symlink_metadata()
+            .map_or(false, |copy| is_unmodified(&copy, &candidate.lower_path))
+        && unsafe { crate::C_UNLINK.call(cpath.as_ptr()) } == 0;
+    unsafe {
+        crate::C_FCNTL.call(fd, F_SETLEASE, F_UNLCK);
+        crate::C_CLOSE.call(fd);
+    }
+    removed
+}
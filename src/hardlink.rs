@@ -0,0 +1,82 @@
+//! Keeps hard links of lower files together when they are copied up.
+//!
+//! The copy of a lower file with several links is indexed by the device and inode number of the
+//! lower file, as a hard link `.wh..wh.index/dev-ino` in the upper dir. Any other link of the same
+//! lower file is then linked to that copy rather than copied on its own, so the upper paths share
+//! one inode like their lower counterparts do, and changes through one link show through all.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::log::{self, Category};
+use crate::redir;
+
+/// The index uses the reserved names of whiteouts, so it is never part of the merged view.
+const INDEX_DIR: &str = ".wh..wh.index";
+
+/// Returns the index entry for the copy of the lower file `path` at `path_to_upper`, if the lower
+/// file has other links that have to share it.
+fn index_path(path: &Path, path_to_upper: &Path) -> Option<PathBuf> {
+    let lower = std::fs::metadata(path).ok()?;
+    if !lower.is_file() || lower.nlink() < 2 {
+        return None;
+    }
+    let (mapping, _) = config::get_config()?.upper_mapping(path_to_upper)?;
+    let name = format!("{}-{}", lower.dev(), lower.ino());
+    Some(mapping.upper_dir.join(INDEX_DIR).join(name))
+}
+
+/// Checks whether the lower file `path` has several links.
+pub fn has_links(path: &Path) -> bool {
+    std::fs::metadata(path).map_or(false, |lower| lower.is_file() && lower.nlink() > 1)
+}
+
+/// Links the copy of another link of the lower file `path` to `path_to_upper`. Returns false if
+/// none of the links has been copied up yet.
+pub fn link_copy(path: &Path, path_to_upper: &Path) -> bool {
+    let entry = match index_path(path, path_to_upper) {
+        Some(entry) if entry.is_file() => entry,
+        _ => return false,
+    };
+    log::debug(Category::Copy, || {
+        log_println!("liboverlay: linking copy of {}", entry.display())
+    });
+    if redir::create_upper_parent(path_to_upper).is_none() {
+        return false;
+    }
+    std::fs::hard_link(&entry, path_to_upper)
+        .map_err(|e| {
+            log::warn(Category::Copy, || {
+                log_println!(
+                    "liboverlay: failed to link {} to {}: {}",
+                    entry.display(),
+                    path_to_upper.display(),
+                    e
+                )
+            })
+        })
+        .is_ok()
+}
+
+/// Indexes the new copy of the lower file `path` at `path_to_upper`, for the other links of the
+/// lower file to share it.
+pub fn add_copy(path: &Path, path_to_upper: &Path) {
+    let entry = match index_path(path, path_to_upper) {
+        Some(entry) => entry,
+        None => return,
+    };
+    let indexed = entry
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::hard_link(path_to_upper, &entry));
+    if let Err(e) = indexed {
+        log::warn(Category::Copy, || {
+            log_println!(
+                "liboverlay: failed to index {}: {}",
+                path_to_upper.display(),
+                e
+            )
+        });
+    }
+}
@@ -0,0 +1,63 @@
+//! Hide rules make lower entries invisible in the merged view without any whiteouts, e.g. to test a
+//! program without some of its plugins. Unlike whiteouts, they only apply to the processes that are
+//! configured with them, and leave the upper dir untouched.
+//!
+//! A rule is a shell glob as understood by `fnmatch` with `FNM_PATHNAME`, so `*`, `?` and classes
+//! like `[a-z]` never match a `/`. Absolute patterns are matched against the paths of lower
+//! entries, relative ones against their paths relative to the lower dir of their mapping. Hiding a
+//! directory hides all of its contents. Entries in the upper dir are never hidden.
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+const FNM_PATHNAME: c_int = 1;
+
+extern "C" {
+    fn fnmatch(pattern: *const c_char, string: *const c_char, flags: c_int) -> c_int;
+}
+
+/// A pattern hiding the lower entries it matches.
+pub struct Rule {
+    pattern: CString,
+}
+
+impl std::fmt::Debug for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.pattern.to_string_lossy())
+    }
+}
+
+impl Rule {
+    /// Creates a rule from a pattern, a trailing slash is ignored. Returns `None` for empty
+    /// patterns.
+    pub fn new(pattern: &str) -> Option<Rule> {
+        // Paths are matched without a trailing slash
+        let pattern = pattern.trim_end_matches('/');
+        if pattern.is_empty() {
+            return None;
+        }
+        let pattern = CString::new(pattern).ok()?;
+        Some(Rule { pattern })
+    }
+
+    /// Checks whether the rule matches the entry `path_in_lower` of the lower dir `lower_dir`.
+    pub fn matches(&self, lower_dir: &Path, path_in_lower: &Path) -> bool {
+        let path = if self.pattern.as_bytes().starts_with(b"/") {
+            lower_dir.join(path_in_lower)
+        } else {
+            path_in_lower.to_path_buf()
+        };
+        let path = match CString::new(path.into_os_string().as_bytes()) {
+            Ok(path) => path,
+            Err(_) => return false,
+        };
+        unsafe { fnmatch(self.pattern.as_ptr(), path.as_ptr(), FNM_PATHNAME) == 0 }
+    }
+}
+
+/// Parses a list of patterns separated by `;`.
+pub fn parse_rules(list: &str) -> Vec<Rule> {
+    list.split(';').filter_map(Rule::new).collect()
+}
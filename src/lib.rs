@@ -1,14 +1,46 @@
 use std::cell::Cell;
 use std::collections::{HashMap, HashSet};
-use std::ffi::{CStr, CString};
-use std::os::raw::{c_char, c_int, c_uchar, c_ushort, c_void};
-use std::path::Path;
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::os::raw::{c_char, c_int, c_long, c_short, c_uchar, c_uint, c_ushort, c_void};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use std::sync::Mutex;
 use std::thread_local;
 
+use config::HookGroup;
+use log::{Arg, Category};
+
+// Programs that link liboverlay, like test harnesses, configure it directly instead of through the
+// variables.
+pub use config::{configure, with_overlay, Config, CopyUp, Mapping};
+
+/// Like `eprintln!`, but writes to the destination of the log, see `log::write`.
+macro_rules! log_println {
+    ($($arg:tt)*) => {
+        $crate::log::write(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
 mod config;
+mod control;
+mod copy;
+mod evict;
+mod hardlink;
+mod hide;
+mod lock;
+mod log;
+mod metacopy;
+mod program;
+mod quota;
 mod redir;
+mod reload;
+mod remote;
+mod rewrite;
+mod synthetic;
+mod sysno;
+mod toml;
+mod transform;
+pub mod whiteout;
 
 /////////////////////////////////////// Symbol lookup/redirection ///////////////////////////////////////
 
@@ -21,7 +53,7 @@ macro_rules! as_char_ptr {
 extern "C" {
     fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
 }
-const RTLD_NEXT: *mut c_void = -1 as isize as usize as *mut c_void;
+const RTLD_NEXT: *mut c_void = -1isize as usize as *mut c_void;
 
 #[allow(non_camel_case_types)]
 type mode_t = c_int;
@@ -34,6 +66,7 @@ macro_rules! import_real {
         }
 
         impl $call_real {
+            #[allow(clippy::too_many_arguments)]
             unsafe fn call(&self, $($names : $tys),*) -> $ret {
                 let mut real_fn = self.real.load(Ordering::SeqCst);
                 if real_fn.is_null() {
@@ -44,7 +77,7 @@ macro_rules! import_real {
                         self.real.store(real_fn, Ordering::SeqCst)
                     }
                 }
-                let func: extern fn($($tys),*) -> $ret = std::mem::transmute(real_fn);
+                let func: extern "C" fn($($tys),*) -> $ret = std::mem::transmute(real_fn);
                 func($($names),*)
             }
         }
@@ -57,15 +90,81 @@ macro_rules! import_real {
 
 /////////////////////////////////////// Actual hooks ///////////////////////////////////////
 
-const O_WRONLY: c_int = 01;
-const O_RDWR: c_int = 02;
-const O_CREAT: c_int = 0x0200;
+// The hooks are exported through `#[no_mangle]` alone; they are not part of the Rust API of the
+// library.
+
+const O_WRONLY: c_int = 0o1;
+const O_RDWR: c_int = 0o2;
+const O_CREAT: c_int = 0o100;
+const O_TRUNC: c_int = 0o1000;
+const O_DIRECTORY: c_int = 0o200000;
+const O_NOFOLLOW: c_int = 0o400000;
+const O_PATH: c_int = 0o10000000;
+const O_TMPFILE: c_int = 0o20200000;
+
+const AT_FDCWD: c_int = -100;
+const AT_SYMLINK_NOFOLLOW: c_int = 0x100;
+const AT_REMOVEDIR: c_int = 0x200;
+const AT_SYMLINK_FOLLOW: c_int = 0x400;
+const AT_EMPTY_PATH: c_int = 0x1000;
+
+const EPERM: c_int = 1;
+const ENOENT: c_int = 2;
+const EIO: c_int = 5;
+const ENOEXEC: c_int = 8;
+const ENOMEM: c_int = 12;
+const EACCES: c_int = 13;
+const EEXIST: c_int = 17;
+const EXDEV: c_int = 18;
+const ENOTDIR: c_int = 20;
+const EISDIR: c_int = 21;
+const EINVAL: c_int = 22;
+const ENOSPC: c_int = 28;
+const EROFS: c_int = 30;
+const ERANGE: c_int = 34;
+const ENOTEMPTY: c_int = 39;
+
+extern "C" {
+    static environ: *const *const c_char;
+    fn __errno_location() -> *mut c_int;
+    fn malloc(size: usize) -> *mut c_void;
+    fn free(ptr: *mut c_void);
+    fn fdopen(fd: c_int, mode: *const c_char) -> *mut c_void;
+}
+
+fn errno() -> c_int {
+    unsafe { *__errno_location() }
+}
+
+/// Returns `errno` if a hook `failed`, for tracing its result.
+fn errno_if(failed: bool) -> Option<c_int> {
+    if failed {
+        Some(errno())
+    } else {
+        None
+    }
+}
+
+fn set_errno(errno: c_int) {
+    unsafe { *__errno_location() = errno };
+}
+
+/// Sets `errno` and returns the `-1` that libc functions use to signal failure.
+fn fail(errno: c_int) -> c_int {
+    set_errno(errno);
+    -1
+}
 
 // Skip hooks while executing a hook
 thread_local! {
     static IS_HOOKED: Cell<bool> = Cell::new(false);
 }
 
+/// Checks whether the hooks of `group` are enabled, the hooks of disabled groups call libc directly.
+fn hooks_enabled(group: HookGroup) -> bool {
+    config::get_config().map_or(true, |cfg| !cfg.disabled_hooks.contains(&group))
+}
+
 fn with_reentrancy_guard<R, F: FnOnce() -> R>(default_: R, call: F) -> R {
     IS_HOOKED.with(|is_hooked: &Cell<bool>| {
         if is_hooked.get() {
@@ -79,24 +178,28 @@ fn with_reentrancy_guard<R, F: FnOnce() -> R>(default_: R, call: F) -> R {
     })
 }
 
-// HACK: open is actually a varargs function, and `mode` only has to be passed
-// when flags contains O_CREAT. It seems to work anyway...
+// HACK: open is actually a varargs function, and `mode` is only passed when flags contains O_CREAT
+// or O_TMPFILE. Stable Rust cannot define varargs functions, so the hooks take `mode` as a fixed
+// argument instead. On x86_64 and aarch64 Linux, varargs are passed in the same registers as fixed
+// arguments, so this reads whatever the register holds when no mode was passed. `open_mode` makes
+// sure that such a value is never used.
 import_real!(C_OPEN, b"open\0", (path: *const c_char, flags: c_int, mode: mode_t) -> c_int);
 
 #[no_mangle]
-pub unsafe extern "C" fn open(path: *const c_char, flags: c_int, mode: mode_t) -> c_int {
-    config::if_debug(|| {
-        eprint!(
-            "open({}, {:b}, {:b}) = ",
-            CStr::from_ptr(path).to_string_lossy(),
-            flags,
-            mode
+unsafe extern "C" fn open(path: *const c_char, flags: c_int, mode: mode_t) -> c_int {
+    let mode = open_mode(flags, mode);
+    log::trace(Category::Hook, || {
+        log::call(
+            "open",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("flags", Arg::Bin(&flags)),
+                ("mode", Arg::Bin(&mode)),
+            ],
         )
     });
-    let redir_path = with_reentrancy_guard(None, || {
-        redirect_path_raw(path, (flags & (O_RDWR | O_WRONLY | O_CREAT)) != 0)
-    });
-    let ret = match redir_path {
+    let redir_path = with_reentrancy_guard(None, || redirect_open(path, flags));
+    let ret = match &redir_path {
         Some(redir) => C_OPEN.call(
             redir.to_bytes_with_nul().as_ptr() as *const c_char,
             flags,
@@ -104,26 +207,32 @@ pub unsafe extern "C" fn open(path: *const c_char, flags: c_int, mode: mode_t) -
         ),
         None => C_OPEN.call(path, flags, mode),
     };
-    config::if_debug(|| eprintln!("{}", ret));
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
     ret
 }
 
 import_real!(C_OPEN64, b"open64\0", (path: *const c_char, flags: c_int, mode: mode_t) -> c_int);
 
 #[no_mangle]
-pub unsafe extern "C" fn open64(path: *const c_char, flags: c_int, mode: mode_t) -> c_int {
-    config::if_debug(|| {
-        eprint!(
-            "open64({}, {:b}, {:b}) = ",
-            CStr::from_ptr(path).to_string_lossy(),
-            flags,
-            mode
+unsafe extern "C" fn open64(path: *const c_char, flags: c_int, mode: mode_t) -> c_int {
+    let mode = open_mode(flags, mode);
+    log::trace(Category::Hook, || {
+        log::call(
+            "open64",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("flags", Arg::Bin(&flags)),
+                ("mode", Arg::Bin(&mode)),
+            ],
         )
     });
-    let redir_path = with_reentrancy_guard(None, || {
-        redirect_path_raw(path, (flags & (O_RDWR | O_WRONLY | O_CREAT)) != 0)
-    });
-    let ret = match redir_path {
+    let redir_path = with_reentrancy_guard(None, || redirect_open(path, flags));
+    let ret = match &redir_path {
         Some(redir) => C_OPEN64.call(
             redir.to_bytes_with_nul().as_ptr() as *const c_char,
             flags,
@@ -131,33 +240,41 @@ pub unsafe extern "C" fn open64(path: *const c_char, flags: c_int, mode: mode_t)
         ),
         None => C_OPEN64.call(path, flags, mode),
     };
-    config::if_debug(|| eprintln!("{}", ret));
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
     ret
 }
 
 import_real!(C_OPENAT, b"openat\0", (dirfd: c_int, path: *const c_char, flags: c_int, mode: mode_t) -> c_int);
 
 #[no_mangle]
-pub unsafe extern "C" fn openat(
+unsafe extern "C" fn openat(
     dirfd: c_int,
     path: *const c_char,
     flags: c_int,
     mode: mode_t,
 ) -> c_int {
-    config::if_debug(|| {
-        eprint!(
-            "openat({}, {}, {:b}, {:b}) = ",
-            dirfd,
-            CStr::from_ptr(path).to_string_lossy(),
-            flags,
-            mode
+    let mode = open_mode(flags, mode);
+    log::trace(Category::Hook, || {
+        log::call(
+            "openat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("flags", Arg::Bin(&flags)),
+                ("mode", Arg::Bin(&mode)),
+            ],
         )
     });
-    // When path is absolute, dirfd will be ignored.
-    let redir_path = with_reentrancy_guard(None, || {
-        redirect_path_raw(path, (flags & (O_RDWR | O_WRONLY | O_CREAT)) != 0)
-    });
-    let ret = match redir_path {
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let redir_path = with_reentrancy_guard(None, || redirect_open(path, flags));
+    let ret = match &redir_path {
         Some(redir) => C_OPENAT.call(
             dirfd,
             redir.to_bytes_with_nul().as_ptr() as *const c_char,
@@ -166,47 +283,419 @@ pub unsafe extern "C" fn openat(
         ),
         None => C_OPENAT.call(dirfd, path, flags, mode),
     };
-    config::if_debug(|| eprintln!("{}", ret));
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_OPENAT64, b"openat64\0", (dirfd: c_int, path: *const c_char, flags: c_int, mode: mode_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn openat64(
+    dirfd: c_int,
+    path: *const c_char,
+    flags: c_int,
+    mode: mode_t,
+) -> c_int {
+    let mode = open_mode(flags, mode);
+    log::trace(Category::Hook, || {
+        log::call(
+            "openat64",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("flags", Arg::Bin(&flags)),
+                ("mode", Arg::Bin(&mode)),
+            ],
+        )
+    });
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let redir_path = with_reentrancy_guard(None, || redirect_open(path, flags));
+    let ret = match &redir_path {
+        Some(redir) => C_OPENAT64.call(
+            dirfd,
+            redir.to_bytes_with_nul().as_ptr() as *const c_char,
+            flags,
+            mode,
+        ),
+        None => C_OPENAT64.call(dirfd, path, flags, mode),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+/// The argument of `openat2` that `open` and `openat` take as separate arguments.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct open_how {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+const RESOLVE_BENEATH: u64 = 0x08;
+const RESOLVE_IN_ROOT: u64 = 0x10;
+
+// Older versions of glibc have no wrapper, so the real function is always the system call.
+#[no_mangle]
+unsafe extern "C" fn openat2(
+    dirfd: c_int,
+    path: *const c_char,
+    how: *mut open_how,
+    size: usize,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "openat2",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+            ],
+        )
+    });
+    // Leave malformed arguments to the kernel to reject
+    let target = if how.is_null() || size < std::mem::size_of::<open_how>() {
+        None
+    } else {
+        with_reentrancy_guard(None, || openat2_target(dirfd, path, &*how))
+    };
+    let ret = match target {
+        Some(Ok((root, path, how))) => {
+            use std::os::unix::io::AsRawFd;
+            C_SYSCALL.call(
+                sysno::OPENAT2,
+                root.as_raw_fd() as c_long,
+                path.as_ptr() as c_long,
+                &how as *const open_how as c_long,
+                std::mem::size_of::<open_how>() as c_long,
+                0,
+                0,
+            ) as c_int
+        }
+        Some(Err(errno)) => fail(errno),
+        None => C_SYSCALL.call(
+            sysno::OPENAT2,
+            dirfd as c_long,
+            path as c_long,
+            how as c_long,
+            size as c_long,
+            0,
+            0,
+        ) as c_int,
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
     ret
 }
 
+/// Decides where `openat2` opens a path of the merged view. The result is a layer root, the path
+/// relative to it, and the arguments to pass along. The `RESOLVE_*` flags are applied to the path
+/// relative to the layer root, so that they neither trip over symlinks in the configured paths nor
+/// over redirections that leave the directory of `dirfd`.
+fn openat2_target(
+    dirfd: c_int,
+    raw_path: *const c_char,
+    how: &open_how,
+) -> Option<Result<(std::fs::File, CString, open_how), c_int>> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let cfg = config::get_config()?;
+    let path = c_char_ptr_to_path(raw_path);
+    let scoped = how.resolve & (RESOLVE_BENEATH | RESOLVE_IN_ROOT) != 0;
+    let base = if path.is_absolute() && !scoped {
+        PathBuf::new()
+    } else if dirfd == AT_FDCWD {
+        redir::current_dir()?
+    } else {
+        redir::fd_path(dirfd)?
+    };
+    // Scoped lookups are checked against the merged tree before anything is redirected
+    let resolved = if scoped {
+        match join_beneath(&base, path, how.resolve & RESOLVE_IN_ROOT != 0) {
+            Some(resolved) => resolved,
+            None => return Some(Err(EXDEV)),
+        }
+    } else {
+        base.join(path)
+    };
+    cfg.lower_mapping(&resolved)?;
+
+    let flags = how.flags as c_int;
+    let target = if how.resolve & RESOLVE_NO_SYMLINKS != 0 || flags & O_NOFOLLOW != 0 {
+        resolved
+    } else {
+        match redir::follow_symlinks(&resolved) {
+            Some(followed) if scoped && !followed.starts_with(&base) => return Some(Err(EXDEV)),
+            Some(followed) => followed,
+            None => resolved,
+        }
+    };
+    let write = (flags & (O_RDWR | O_WRONLY | O_CREAT)) != 0;
+    let redirected = redirect_opened(&target, flags).map(|redirected| {
+        if write {
+            redirected
+        } else {
+            redir::contents_path(redirected)
+        }
+    });
+    if let Some(redirected) = &redirected {
+        if quota::is_refused(redirected) {
+            return Some(Err(ENOSPC));
+        }
+        if redir::is_read_only(redirected) {
+            return Some(Err(EROFS));
+        }
+    }
+    let (root, target) = match redirected {
+        Some(redirected) => match cfg.upper_mapping(&redirected) {
+            Some((mapping, _)) => (mapping.upper_dir.as_path(), redirected),
+            None => (Path::new("/"), redirected),
+        },
+        None => match cfg.lower_mapping(&target) {
+            Some((mapping, _)) => (mapping.lower_dir.as_path(), target),
+            None => (Path::new("/"), target),
+        },
+    };
+    let relative = target.strip_prefix(root).ok()?;
+    let relative = if relative.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        relative
+    };
+
+    let root = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(O_PATH | O_DIRECTORY)
+        .open(root)
+        .ok()?;
+    let mut how = *how;
+    if scoped {
+        how.resolve = how.resolve & !RESOLVE_IN_ROOT | RESOLVE_BENEATH;
+    }
+    Some(Ok((root, path_to_cstring(relative)?, how)))
+}
+
+/// Joins `path` to `root` without leaving `root`, like `RESOLVE_BENEATH` does. With `in_root`,
+/// absolute paths and `..` are clamped to `root` instead, like `RESOLVE_IN_ROOT` does. Returns
+/// `None` if the path escapes `root`.
+fn join_beneath(root: &Path, path: &Path, in_root: bool) -> Option<PathBuf> {
+    use std::path::Component;
+
+    let mut joined = root.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::RootDir if in_root => joined = root.to_path_buf(),
+            Component::ParentDir if joined == root => {
+                if !in_root {
+                    return None;
+                }
+            }
+            Component::ParentDir => {
+                joined.pop();
+            }
+            Component::Normal(name) => joined.push(name),
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(joined)
+}
+
+/// Returns the `mode` argument of an open call if `flags` say that one was passed, and 0 otherwise.
+fn open_mode(flags: c_int, mode: mode_t) -> mode_t {
+    if flags & O_CREAT != 0 || flags & O_TMPFILE == O_TMPFILE {
+        mode
+    } else {
+        0
+    }
+}
+
+// The variants used with _FORTIFY_SOURCE take no mode at all, it is only passed to `open` itself.
+
+#[no_mangle]
+unsafe extern "C" fn __open_2(path: *const c_char, flags: c_int) -> c_int {
+    open(path, flags, 0)
+}
+
+#[no_mangle]
+unsafe extern "C" fn __open64_2(path: *const c_char, flags: c_int) -> c_int {
+    open64(path, flags, 0)
+}
+
+#[no_mangle]
+unsafe extern "C" fn __openat_2(dirfd: c_int, path: *const c_char, flags: c_int) -> c_int {
+    openat(dirfd, path, flags, 0)
+}
+
+#[no_mangle]
+unsafe extern "C" fn __openat64_2(dirfd: c_int, path: *const c_char, flags: c_int) -> c_int {
+    openat64(dirfd, path, flags, 0)
+}
+
+#[no_mangle]
+unsafe extern "C" fn creat(path: *const c_char, mode: mode_t) -> c_int {
+    open(path, O_CREAT | O_WRONLY | O_TRUNC, mode)
+}
+
+#[no_mangle]
+unsafe extern "C" fn creat64(path: *const c_char, mode: mode_t) -> c_int {
+    open64(path, O_CREAT | O_WRONLY | O_TRUNC, mode)
+}
+
 import_real!(C_FOPEN, b"fopen\0", (path: *const c_char, mode: *const c_char) -> *mut c_void);
 
 #[no_mangle]
-pub unsafe extern "C" fn fopen(path: *const c_char, mode: *const c_char) -> *mut c_void {
-    config::if_debug(|| {
-        eprint!(
-            "fopen({}, {}) = ",
-            CStr::from_ptr(path).to_string_lossy(),
-            CStr::from_ptr(mode).to_string_lossy(),
+unsafe extern "C" fn fopen(path: *const c_char, mode: *const c_char) -> *mut c_void {
+    log::trace(Category::Hook, || {
+        log::call(
+            "fopen",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Str(CStr::from_ptr(mode).to_string_lossy())),
+            ],
         )
     });
     let redir_path = with_reentrancy_guard(None, || redirect_fopen(path, mode));
-    let ret = match redir_path {
+    let ret = match &redir_path {
         Some(redir) => C_FOPEN.call(redir.to_bytes_with_nul().as_ptr() as *const c_char, mode),
         None => C_FOPEN.call(path, mode),
     };
-    config::if_debug(|| eprintln!("{:x}", ret as usize));
+    if ret.is_null() {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null()))
+    });
     ret
 }
 
-import_real!(C_STAT, b"__xstat\0", (version: c_int, path: *const c_char, statbuf: *mut c_void) -> c_int);
+import_real!(C_FOPEN64, b"fopen64\0", (path: *const c_char, mode: *const c_char) -> *mut c_void);
 
 #[no_mangle]
-pub unsafe extern "C" fn __xstat(
-    version: c_int,
+unsafe extern "C" fn fopen64(path: *const c_char, mode: *const c_char) -> *mut c_void {
+    log::trace(Category::Hook, || {
+        log::call(
+            "fopen64",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Str(CStr::from_ptr(mode).to_string_lossy())),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_fopen(path, mode));
+    let ret = match &redir_path {
+        Some(redir) => C_FOPEN64.call(redir.as_ptr(), mode),
+        None => C_FOPEN64.call(path, mode),
+    };
+    if ret.is_null() {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null()))
+    });
+    ret
+}
+
+import_real!(C_FREOPEN, b"freopen\0", (path: *const c_char, mode: *const c_char, stream: *mut c_void) -> *mut c_void);
+
+#[no_mangle]
+unsafe extern "C" fn freopen(
     path: *const c_char,
-    statbuf: *mut c_void,
-) -> c_int {
-    config::if_debug(|| {
-        eprint!(
-            "__xstat({}, {}, {:x}) = ",
-            version,
-            CStr::from_ptr(path).to_string_lossy(),
-            statbuf as usize,
+    mode: *const c_char,
+    stream: *mut c_void,
+) -> *mut c_void {
+    // Without a path, only the mode of the stream is changed
+    if path.is_null() {
+        return C_FREOPEN.call(path, mode, stream);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "freopen",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Str(CStr::from_ptr(mode).to_string_lossy())),
+                ("stream", Arg::Hex(&(stream as usize))),
+            ],
         )
     });
-    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+    let redir_path = with_reentrancy_guard(None, || redirect_fopen(path, mode));
+    let ret = match &redir_path {
+        Some(redir) => C_FREOPEN.call(redir.as_ptr(), mode, stream),
+        None => C_FREOPEN.call(path, mode, stream),
+    };
+    if ret.is_null() {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null()))
+    });
+    ret
+}
+
+import_real!(C_FREOPEN64, b"freopen64\0", (path: *const c_char, mode: *const c_char, stream: *mut c_void) -> *mut c_void);
+
+#[no_mangle]
+unsafe extern "C" fn freopen64(
+    path: *const c_char,
+    mode: *const c_char,
+    stream: *mut c_void,
+) -> *mut c_void {
+    if path.is_null() {
+        return C_FREOPEN64.call(path, mode, stream);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "freopen64",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Str(CStr::from_ptr(mode).to_string_lossy())),
+                ("stream", Arg::Hex(&(stream as usize))),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_fopen(path, mode));
+    let ret = match &redir_path {
+        Some(redir) => C_FREOPEN64.call(redir.as_ptr(), mode, stream),
+        None => C_FREOPEN64.call(path, mode, stream),
+    };
+    if ret.is_null() {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null()))
+    });
+    ret
+}
+
+import_real!(C_STAT, b"__xstat\0", (version: c_int, path: *const c_char, statbuf: *mut c_void) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn __xstat(version: c_int, path: *const c_char, statbuf: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "__xstat",
+            &[
+                ("version", Arg::Dec(&version)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("statbuf", Arg::Hex(&(statbuf as usize))),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_followed_raw(path, false));
     let ret = match redir_path {
         Some(redir) => C_STAT.call(
             version,
@@ -215,24 +704,24 @@ pub unsafe extern "C" fn __xstat(
         ),
         None => C_STAT.call(version, path, statbuf),
     };
-    config::if_debug(|| eprintln!("{}", ret));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
     ret
 }
 
 import_real!(C_LSTAT, b"__lxstat\0", (version: c_int, path: *const c_char, statbuf: *mut c_void) -> c_int);
 
 #[no_mangle]
-pub unsafe extern "C" fn __lxstat(
-    version: c_int,
-    path: *const c_char,
-    statbuf: *mut c_void,
-) -> c_int {
-    config::if_debug(|| {
-        eprint!(
-            "__lxstat({}, {}, {:x}) = ",
-            version,
-            CStr::from_ptr(path).to_string_lossy(),
-            statbuf as usize,
+unsafe extern "C" fn __lxstat(version: c_int, path: *const c_char, statbuf: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "__lxstat",
+            &[
+                ("version", Arg::Dec(&version)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("statbuf", Arg::Hex(&(statbuf as usize))),
+            ],
         )
     });
     let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
@@ -244,30 +733,36 @@ pub unsafe extern "C" fn __lxstat(
         ),
         None => C_LSTAT.call(version, path, statbuf),
     };
-    config::if_debug(|| eprintln!("{}", ret));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
     ret
 }
 
 import_real!(C_FSTATAT, b"__fxstatat\0", (version: c_int, dirfd: c_int, path: *const c_char, statbuf: *mut c_void, flags: c_int) -> c_int);
 
 #[no_mangle]
-pub unsafe extern "C" fn __fxstatat(
+unsafe extern "C" fn __fxstatat(
     version: c_int,
     dirfd: c_int,
     path: *const c_char,
     statbuf: *mut c_void,
     flags: c_int,
 ) -> c_int {
-    config::if_debug(|| {
-        eprint!(
-            "__fxstatat({}, {}, {:x}, {}) = ",
-            dirfd,
-            CStr::from_ptr(path).to_string_lossy(),
-            statbuf as usize,
-            flags,
+    log::trace(Category::Hook, || {
+        log::call(
+            "__fxstatat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("statbuf", Arg::Hex(&(statbuf as usize))),
+                ("flags", Arg::Dec(&flags)),
+            ],
         )
     });
-    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+    let redir_path = with_reentrancy_guard(None, || {
+        redirect_at_raw(dirfd, path, false, flags & AT_SYMLINK_NOFOLLOW == 0)
+    });
     let ret = match redir_path {
         Some(redir) => C_FSTATAT.call(
             version,
@@ -278,249 +773,5640 @@ pub unsafe extern "C" fn __fxstatat(
         ),
         None => C_FSTATAT.call(version, dirfd, path, statbuf, flags),
     };
-    config::if_debug(|| eprintln!("{}", ret));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
     ret
 }
 
-/////////////////////////////////////// Redirection logic ///////////////////////////////////////
+// The LFS variants fill a `struct stat64`. It is the same as `struct stat` on 64-bit targets, but
+// not on 32-bit ones, so each variant is passed on to its own real counterpart.
 
-fn c_char_ptr_to_path(raw_path: *const c_char) -> &'static Path {
-    use std::os::unix::ffi::OsStrExt;
+import_real!(C_XSTAT64, b"__xstat64\0", (version: c_int, path: *const c_char, statbuf: *mut c_void) -> c_int);
 
-    let cpath = unsafe { CStr::from_ptr(raw_path) };
-    let ospath: &std::ffi::OsStr = { std::ffi::OsStr::from_bytes(cpath.to_bytes()) };
-    Path::new(ospath)
+#[no_mangle]
+unsafe extern "C" fn __xstat64(version: c_int, path: *const c_char, statbuf: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "__xstat64",
+            &[
+                ("version", Arg::Dec(&version)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("statbuf", Arg::Hex(&(statbuf as usize))),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_followed_raw(path, false));
+    let ret = match redir_path {
+        Some(redir) => C_XSTAT64.call(
+            version,
+            redir.to_bytes_with_nul().as_ptr() as *const c_char,
+            statbuf,
+        ),
+        None => C_XSTAT64.call(version, path, statbuf),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
 }
 
-fn redirect_path_raw(raw_path: *const c_char, write: bool) -> Option<CString> {
-    use std::os::unix::ffi::OsStrExt;
-    let path = c_char_ptr_to_path(raw_path);
-    let redirected = redir::redirect_path(path, write)?;
-
-    let credir = CString::new(redirected.as_os_str().as_bytes()).ok()?;
-    Some(credir)
-}
+import_real!(C_LXSTAT64, b"__lxstat64\0", (version: c_int, path: *const c_char, statbuf: *mut c_void) -> c_int);
 
-fn redirect_fopen(raw_path: *const c_char, raw_mode: *const c_char) -> Option<CString> {
-    let cmode = unsafe { CStr::from_ptr(raw_mode) };
-    redirect_path_raw(raw_path, cmode.to_bytes() != b"r")
+#[no_mangle]
+unsafe extern "C" fn __lxstat64(
+    version: c_int,
+    path: *const c_char,
+    statbuf: *mut c_void,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "__lxstat64",
+            &[
+                ("version", Arg::Dec(&version)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("statbuf", Arg::Hex(&(statbuf as usize))),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+    let ret = match redir_path {
+        Some(redir) => C_LXSTAT64.call(
+            version,
+            redir.to_bytes_with_nul().as_ptr() as *const c_char,
+            statbuf,
+        ),
+        None => C_LXSTAT64.call(version, path, statbuf),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
 }
 
-////////////////////////////////////////////////////////////////////////////
-
-import_real!(C_MKDIR, b"mkdir\0", (path: *const c_char, mode: mode_t) -> c_int);
+import_real!(C_FXSTATAT64, b"__fxstatat64\0", (version: c_int, dirfd: c_int, path: *const c_char, statbuf: *mut c_void, flags: c_int) -> c_int);
 
 #[no_mangle]
-pub unsafe extern "C" fn mkdir(path: *const c_char, mode: mode_t) -> c_int {
-    config::if_debug(|| {
-        eprint!(
-            "mkdir({}, {:o}) = ",
-            CStr::from_ptr(path).to_string_lossy(),
-            mode,
+unsafe extern "C" fn __fxstatat64(
+    version: c_int,
+    dirfd: c_int,
+    path: *const c_char,
+    statbuf: *mut c_void,
+    flags: c_int,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "__fxstatat64",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("statbuf", Arg::Hex(&(statbuf as usize))),
+                ("flags", Arg::Dec(&flags)),
+            ],
         )
     });
-    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, true));
+    let redir_path = with_reentrancy_guard(None, || {
+        redirect_at_raw(dirfd, path, false, flags & AT_SYMLINK_NOFOLLOW == 0)
+    });
     let ret = match redir_path {
-        Some(redir) => C_MKDIR.call(redir.to_bytes_with_nul().as_ptr() as *const c_char, mode),
-        None => C_MKDIR.call(path, mode),
+        Some(redir) => C_FXSTATAT64.call(
+            version,
+            dirfd,
+            redir.to_bytes_with_nul().as_ptr() as *const c_char,
+            statbuf,
+            flags,
+        ),
+        None => C_FXSTATAT64.call(version, dirfd, path, statbuf, flags),
     };
-    config::if_debug(|| eprintln!("{}", ret));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
     ret
 }
 
-// TODO: provide view across both upper and lower dir when using opendir etc.
+// Since glibc 2.33, the stat functions are exported directly instead of being wrappers around
+// the __xstat family.
 
-import_real!(C_OPENDIR, b"opendir\0", (path: *const c_char, mode: mode_t) -> *mut c_void);
+import_real!(C_STAT_PLAIN, b"stat\0", (path: *const c_char, statbuf: *mut c_void) -> c_int);
 
 #[no_mangle]
-pub unsafe extern "C" fn opendir(path: *const c_char, mode: mode_t) -> *mut c_void {
-    config::if_debug(|| {
-        eprint!(
-            "opendir({}, {:o}) = ",
-            CStr::from_ptr(path).to_string_lossy(),
-            mode,
+unsafe extern "C" fn stat(path: *const c_char, statbuf: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "stat",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("statbuf", Arg::Hex(&(statbuf as usize))),
+            ],
         )
     });
-    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+    let redir_path = with_reentrancy_guard(None, || redirect_followed_raw(path, false));
     let ret = match redir_path {
-        Some(redir) => {
-            let lower_dir = C_OPENDIR.call(path, mode);
+        Some(redir) => C_STAT_PLAIN.call(redir.as_ptr(), statbuf),
+        None => C_STAT_PLAIN.call(path, statbuf),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
 
-            let upper_dir =
-                C_OPENDIR.call(redir.to_bytes_with_nul().as_ptr() as *const c_char, mode);
+import_real!(C_STAT64, b"stat64\0", (path: *const c_char, statbuf: *mut c_void) -> c_int);
 
-            if !lower_dir.is_null() {
-                config::if_debug(|| eprintln!("liboverlayf: merging opendir"));
-                // If the lower dir exists, we need to merge the contents of the two dirs
-                let mut opendirs = opendirs().lock().unwrap();
+#[no_mangle]
+unsafe extern "C" fn stat64(path: *const c_char, statbuf: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "stat64",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("statbuf", Arg::Hex(&(statbuf as usize))),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_followed_raw(path, false));
+    let ret = match redir_path {
+        Some(redir) => C_STAT64.call(redir.as_ptr(), statbuf),
+        None => C_STAT64.call(path, statbuf),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
 
-                let opendir = OpenDir {
-                    upper: upper_dir,
-                    lower: lower_dir,
-                    seen: HashSet::new(),
-                };
-                opendirs.insert(upper_dir as usize, opendir);
-            }
-            upper_dir
-        }
-        None => C_OPENDIR.call(path, mode),
+import_real!(C_LSTAT_PLAIN, b"lstat\0", (path: *const c_char, statbuf: *mut c_void) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn lstat(path: *const c_char, statbuf: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "lstat",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("statbuf", Arg::Hex(&(statbuf as usize))),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+    let ret = match redir_path {
+        Some(redir) => C_LSTAT_PLAIN.call(redir.as_ptr(), statbuf),
+        None => C_LSTAT_PLAIN.call(path, statbuf),
     };
-    config::if_debug(|| eprintln!("{:x}", ret as usize));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
     ret
 }
 
-#[allow(non_camel_case_types)]
-pub type ino_t = u64;
-#[allow(non_camel_case_types)]
-pub type off_t = i64;
-#[repr(C)]
-pub struct dirent {
-    pub d_ino: ino_t,
-    pub d_off: off_t,
-    pub d_reclen: c_ushort,
-    pub d_type: c_uchar,
-    pub d_name: [c_char; 256],
+import_real!(C_LSTAT64, b"lstat64\0", (path: *const c_char, statbuf: *mut c_void) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn lstat64(path: *const c_char, statbuf: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "lstat64",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("statbuf", Arg::Hex(&(statbuf as usize))),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+    let ret = match redir_path {
+        Some(redir) => C_LSTAT64.call(redir.as_ptr(), statbuf),
+        None => C_LSTAT64.call(path, statbuf),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
 }
 
-import_real!(C_READDIR, b"readdir\0", (dir: *mut c_void) -> *mut dirent);
+import_real!(C_FSTATAT_PLAIN, b"fstatat\0", (dirfd: c_int, path: *const c_char, statbuf: *mut c_void, flags: c_int) -> c_int);
 
 #[no_mangle]
-pub unsafe extern "C" fn readdir(dir: *mut c_void) -> *mut dirent {
-    config::if_debug(|| eprint!("readdir({:x}) = ", dir as usize,));
-    let ret = IS_HOOKED.with(|is_hooked: &Cell<bool>| {
-        if is_hooked.get() {
-            C_READDIR.call(dir)
+unsafe extern "C" fn fstatat(
+    dirfd: c_int,
+    path: *const c_char,
+    statbuf: *mut c_void,
+    flags: c_int,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "fstatat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("statbuf", Arg::Hex(&(statbuf as usize))),
+                ("flags", Arg::Dec(&flags)),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || {
+        redirect_at_raw(dirfd, path, false, flags & AT_SYMLINK_NOFOLLOW == 0)
+    });
+    let ret = match redir_path {
+        Some(redir) => C_FSTATAT_PLAIN.call(dirfd, redir.as_ptr(), statbuf, flags),
+        None => C_FSTATAT_PLAIN.call(dirfd, path, statbuf, flags),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FSTATAT64, b"fstatat64\0", (dirfd: c_int, path: *const c_char, statbuf: *mut c_void, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn fstatat64(
+    dirfd: c_int,
+    path: *const c_char,
+    statbuf: *mut c_void,
+    flags: c_int,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "fstatat64",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("statbuf", Arg::Hex(&(statbuf as usize))),
+                ("flags", Arg::Dec(&flags)),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || {
+        redirect_at_raw(dirfd, path, false, flags & AT_SYMLINK_NOFOLLOW == 0)
+    });
+    let ret = match redir_path {
+        Some(redir) => C_FSTATAT64.call(dirfd, redir.as_ptr(), statbuf, flags),
+        None => C_FSTATAT64.call(dirfd, path, statbuf, flags),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_STATX, b"statx\0", (dirfd: c_int, path: *const c_char, flags: c_int, mask: c_uint, statxbuf: *mut c_void) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn statx(
+    dirfd: c_int,
+    path: *const c_char,
+    flags: c_int,
+    mask: c_uint,
+    statxbuf: *mut c_void,
+) -> c_int {
+    // A null path is used to probe for statx support, and with AT_EMPTY_PATH on newer kernels
+    if path.is_null() {
+        return C_STATX.call(dirfd, path, flags, mask, statxbuf);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "statx",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("flags", Arg::Hex(&flags)),
+                ("mask", Arg::Hex(&mask)),
+                ("statxbuf", Arg::Hex(&(statxbuf as usize))),
+            ],
+        )
+    });
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards. An empty
+    // path with AT_EMPTY_PATH refers to dirfd itself, which is left alone.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let redir_path = with_reentrancy_guard(None, || {
+        if flags & AT_SYMLINK_NOFOLLOW != 0 {
+            redirect_path_raw(path, false)
         } else {
-            let mut opendirs = opendirs().lock().unwrap();
-            if let Some(merged) = opendirs.get_mut(&(dir as usize)) {
-                // First try upper
-                let entry: *mut dirent = C_READDIR.call(dir);
-                if entry.is_null() {
-                    // Now try lower
-                    loop {
-                        let entry_lower = C_READDIR.call(merged.lower);
-                        if entry_lower.is_null() {
-                            break entry_lower;
-                        } else {
-                            // filter out entries from top level
-                            let name =
-                                CStr::from_ptr(&std::ptr::read(entry_lower).d_name[0] as *const i8);
-                            if !merged.seen.contains(name) {
-                                break entry_lower;
-                            }
-                        }
-                    }
-                } else {
-                    // remember name
-                    let name = CStr::from_ptr(&std::ptr::read(entry).d_name[0] as *const i8);
-                    merged.seen.insert(name.to_owned());
-                    entry
-                }
-            } else {
-                C_READDIR.call(dir)
-            }
+            redirect_followed_raw(path, false)
         }
     });
-    config::if_debug(|| eprintln!("{:x}", ret as usize));
+    let ret = match redir_path {
+        Some(redir) => C_STATX.call(dirfd, redir.as_ptr(), flags, mask, statxbuf),
+        None => C_STATX.call(dirfd, path, flags, mask, statxbuf),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
     ret
 }
 
-import_real!(C_CLOSEDIR, b"closedir\0", (dir: *mut c_void) -> c_int);
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_NAME_TO_HANDLE_AT, b"name_to_handle_at\0", (dirfd: c_int, path: *const c_char, handle: *mut c_void, mount_id: *mut c_int, flags: c_int) -> c_int);
 
+// Handles of upper files are what the merged view shows, so `open_by_handle_at` needs no hook.
 #[no_mangle]
-pub unsafe extern "C" fn closedir(dir: *mut c_void) -> c_int {
-    config::if_debug(|| eprint!("closedir({:x}) = ", dir as usize,));
-    with_reentrancy_guard((), || {
-        let removed = opendirs().lock().unwrap().remove(&(dir as usize));
-        if let Some(od) = removed {
-            // Only close lower dir as the upper dir is used as key and will be closed down below
-            config::if_debug(|| eprintln!("liboverlay: closing merged opendir"));
-            C_CLOSEDIR.call(od.lower);
+unsafe extern "C" fn name_to_handle_at(
+    dirfd: c_int,
+    path: *const c_char,
+    handle: *mut c_void,
+    mount_id: *mut c_int,
+    flags: c_int,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "name_to_handle_at",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("flags", Arg::Hex(&flags)),
+            ],
+        )
+    });
+    // An empty path with AT_EMPTY_PATH refers to dirfd itself, which may have been opened before
+    // the file was copied up.
+    if flags & AT_EMPTY_PATH != 0 && *path == 0 {
+        let redir_path = with_reentrancy_guard(None, || redirect_fd(dirfd, false));
+        let ret = match redir_path {
+            Some(redir) => C_NAME_TO_HANDLE_AT.call(
+                AT_FDCWD,
+                redir.as_ptr(),
+                handle,
+                mount_id,
+                flags & !AT_EMPTY_PATH,
+            ),
+            None => C_NAME_TO_HANDLE_AT.call(dirfd, path, handle, mount_id, flags),
+        };
+        log::trace(Category::Hook, || {
+            log::returned(i64::from(ret), errno_if(ret == -1))
+        });
+        return ret;
+    }
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let redir_path = with_reentrancy_guard(None, || {
+        if flags & AT_SYMLINK_FOLLOW != 0 {
+            redirect_followed_raw(path, false)
+        } else {
+            redirect_path_raw(path, false)
         }
     });
-    let ret = C_CLOSEDIR.call(dir);
-    config::if_debug(|| eprintln!("{}", ret));
+    let ret = match redir_path {
+        Some(redir) => C_NAME_TO_HANDLE_AT.call(dirfd, redir.as_ptr(), handle, mount_id, flags),
+        None => C_NAME_TO_HANDLE_AT.call(dirfd, path, handle, mount_id, flags),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
     ret
 }
 
-static mut OPENDIRS: Option<Mutex<HashMap<usize, OpenDir>>> = None;
+////////////////////////////////////////////////////////////////////////////
 
-#[used]
-#[cfg_attr(target_os = "linux", link_section = ".ctors")]
-pub static INIT_OPENDIRS: extern "C" fn() = {
-    extern "C" fn init() {
-        unsafe {
-            OPENDIRS = Some(Mutex::new(HashMap::new()));
-        }
-    }
-    init
-};
+import_real!(C_STATFS, b"statfs\0", (path: *const c_char, buf: *mut c_void) -> c_int);
 
-fn opendirs() -> &'static Mutex<HashMap<usize, OpenDir>> {
-    unsafe { OPENDIRS.as_ref().unwrap() }
+#[no_mangle]
+unsafe extern "C" fn statfs(path: *const c_char, buf: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "statfs",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_statfs(path));
+    let ret = match redir_path {
+        Some(redir) => C_STATFS.call(redir.as_ptr(), buf),
+        None => C_STATFS.call(path, buf),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
 }
 
-#[derive(Clone)]
-struct OpenDir {
-    upper: *mut c_void,
-    lower: *mut c_void,
-    seen: HashSet<CString>,
+import_real!(C_STATFS64, b"statfs64\0", (path: *const c_char, buf: *mut c_void) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn statfs64(path: *const c_char, buf: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "statfs64",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_statfs(path));
+    let ret = match redir_path {
+        Some(redir) => C_STATFS64.call(redir.as_ptr(), buf),
+        None => C_STATFS64.call(path, buf),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
 }
 
-unsafe impl Send for OpenDir {}
-unsafe impl Sync for OpenDir {}
+import_real!(C_STATVFS, b"statvfs\0", (path: *const c_char, buf: *mut c_void) -> c_int);
 
-////////////////////////////////////////////////////////////////////////////
+#[no_mangle]
+unsafe extern "C" fn statvfs(path: *const c_char, buf: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "statvfs",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_statfs(path));
+    let ret = match redir_path {
+        Some(redir) => C_STATVFS.call(redir.as_ptr(), buf),
+        None => C_STATVFS.call(path, buf),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
 
-import_real!(C_UNLINK, b"unlink\0", (path: *const c_char) -> c_int);
+import_real!(C_STATVFS64, b"statvfs64\0", (path: *const c_char, buf: *mut c_void) -> c_int);
 
 #[no_mangle]
-pub unsafe extern "C" fn unlink(path: *const c_char) -> c_int {
-    config::if_debug(|| eprint!("unlink({}) = ", CStr::from_ptr(path).to_string_lossy(),));
-    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+unsafe extern "C" fn statvfs64(path: *const c_char, buf: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "statvfs64",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_statfs(path));
     let ret = match redir_path {
-        Some(redir) => C_UNLINK.call(redir.to_bytes_with_nul().as_ptr() as *const c_char),
-        None => C_UNLINK.call(path),
+        Some(redir) => C_STATVFS64.call(redir.as_ptr(), buf),
+        None => C_STATVFS64.call(path, buf),
     };
-    config::if_debug(|| eprintln!("{}", ret));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
     ret
 }
 
-import_real!(C_UNLINKAT, b"unlinkat\0", (dirfd: c_int, path: *const c_char, flags: c_int) -> c_int);
+import_real!(C_PATHCONF, b"pathconf\0", (path: *const c_char, name: c_int) -> c_long);
 
 #[no_mangle]
-pub unsafe extern "C" fn unlinkat(dirfd: c_int, path: *const c_char, flags: c_int) -> c_int {
-    config::if_debug(|| {
-        eprint!(
-            "unlinkat({}, {}, {}) = ",
-            dirfd,
-            CStr::from_ptr(path).to_string_lossy(),
-            flags,
+unsafe extern "C" fn pathconf(path: *const c_char, name: c_int) -> c_long {
+    log::trace(Category::Hook, || {
+        log::call(
+            "pathconf",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("name", Arg::Dec(&name)),
+            ],
         )
     });
-    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+    let redir_path = with_reentrancy_guard(None, || redirect_statfs(path));
     let ret = match redir_path {
-        Some(redir) => C_UNLINKAT.call(
-            dirfd,
-            redir.to_bytes_with_nul().as_ptr() as *const c_char,
-            flags,
-        ),
-        None => C_UNLINKAT.call(dirfd, path, flags),
+        Some(redir) => C_PATHCONF.call(redir.as_ptr(), name),
+        None => C_PATHCONF.call(path, name),
     };
-    config::if_debug(|| eprintln!("{}", ret));
+    log::trace(Category::Hook, || log::returned(ret, errno_if(ret == -1)));
     ret
 }
 
-import_real!(C_RMDIR, b"rmdir\0", (path: *const c_char) -> c_int);
+import_real!(C_FPATHCONF, b"fpathconf\0", (fd: c_int, name: c_int) -> c_long);
 
 #[no_mangle]
-pub unsafe extern "C" fn rmdir(path: *const c_char) -> c_int {
-    config::if_debug(|| eprint!("rmdir({}) = ", CStr::from_ptr(path).to_string_lossy(),));
-    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+unsafe extern "C" fn fpathconf(fd: c_int, name: c_int) -> c_long {
+    log::trace(Category::Hook, || {
+        log::call(
+            "fpathconf",
+            &[("fd", Arg::Dec(&fd)), ("name", Arg::Dec(&name))],
+        )
+    });
+    // Descriptors opened for reading only may still refer to the lower dir
+    let redir_path = with_reentrancy_guard(None, || {
+        let path = path_to_cstring(&redir::fd_in_lower(fd)?)?;
+        redirect_statfs(path.as_ptr())
+    });
     let ret = match redir_path {
-        Some(redir) => C_RMDIR.call(redir.to_bytes_with_nul().as_ptr() as *const c_char),
-        None => C_RMDIR.call(path),
+        Some(redir) => C_PATHCONF.call(redir.as_ptr(), name),
+        None => C_FPATHCONF.call(fd, name),
     };
-    config::if_debug(|| eprintln!("{}", ret));
+    log::trace(Category::Hook, || log::returned(ret, errno_if(ret == -1)));
     ret
 }
+
+/////////////////////////////////////// Redirection logic ///////////////////////////////////////
+
+fn c_char_ptr_to_path(raw_path: *const c_char) -> &'static Path {
+    use std::os::unix::ffi::OsStrExt;
+
+    let cpath = unsafe { CStr::from_ptr(raw_path) };
+    let ospath: &std::ffi::OsStr = { std::ffi::OsStr::from_bytes(cpath.to_bytes()) };
+    Path::new(ospath)
+}
+
+fn redirect_path_raw(raw_path: *const c_char, write: bool) -> Option<CString> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = c_char_ptr_to_path(raw_path);
+    let redirected = redir::redirect_path(path, write)?;
+
+    let credir = CString::new(redirected.as_os_str().as_bytes()).ok()?;
+    Some(credir)
+}
+
+fn is_in_lower(raw_path: *const c_char) -> bool {
+    redir::layers(c_char_ptr_to_path(raw_path)).is_some()
+}
+
+/// Checks whether the options of its mapping refuse changes to `raw_path`, following symlinks
+/// within the merged view.
+fn refuses_changes(raw_path: *const c_char) -> bool {
+    let path = c_char_ptr_to_path(raw_path);
+    let followed = redir::follow_symlinks(path);
+    redir::refuses_changes(followed.as_ref().map_or(path, |followed| followed))
+}
+
+fn path_to_cstring(path: &Path) -> Option<CString> {
+    use std::os::unix::ffi::OsStrExt;
+    CString::new(path.as_os_str().as_bytes()).ok()
+}
+
+/// Like `redir::redirect_path`, but symlinks are followed within the merged view.
+fn redirect_followed(path: &Path, write: bool) -> Option<PathBuf> {
+    redirect_followed_with(path, |path| redir::redirect_path(path, write))
+}
+
+/// Follows the symlinks of `path` before redirecting it with `redirect`.
+fn redirect_followed_with(
+    path: &Path,
+    redirect: impl Fn(&Path) -> Option<PathBuf>,
+) -> Option<PathBuf> {
+    match redir::follow_symlinks(path) {
+        Some(followed) => Some(redirect(&followed).unwrap_or(followed)),
+        None => redirect(path),
+    }
+}
+
+fn redirect_followed_raw(raw_path: *const c_char, write: bool) -> Option<CString> {
+    let redirected = redirect_followed(c_char_ptr_to_path(raw_path), write)?;
+    path_to_cstring(&redirected)
+}
+
+/// Redirects a path that is watched for changes, which happen in the upper dir once it is copied
+/// up with `write`. Entries of mappings that refuse changes are watched where they are.
+fn redirect_watched_raw(raw_path: *const c_char, follow: bool, write: bool) -> Option<CString> {
+    let redirect = |write| {
+        if follow {
+            redirect_followed_raw(raw_path, write)
+        } else {
+            redirect_path_raw(raw_path, write)
+        }
+    };
+    match redirect(write) {
+        Some(redirected) if redir::is_read_only(c_char_ptr_to_path(redirected.as_ptr())) => {
+            redirect(false)
+        }
+        redirected => redirected,
+    }
+}
+
+/// Redirects the path argument of one of the `*at` functions. Relative paths are resolved against
+/// the directory that `dirfd` refers to, the result is always an absolute path.
+fn redirect_at_raw(
+    dirfd: c_int,
+    raw_path: *const c_char,
+    write: bool,
+    follow: bool,
+) -> Option<CString> {
+    let path = c_char_ptr_to_path(raw_path);
+    if path.is_absolute() || dirfd == AT_FDCWD {
+        let redirected = if follow {
+            redirect_followed(path, write)?
+        } else {
+            redir::redirect_path(path, write)?
+        };
+        return path_to_cstring(&redirected);
+    }
+    // dirfd may refer to either layer, so the resolved path is used even if it is not redirected
+    let resolved = redir::fd_path(dirfd)?.join(path);
+    let redirected = if follow {
+        redirect_followed(&resolved, write)
+    } else {
+        redir::redirect_path(&resolved, write)
+    };
+    path_to_cstring(&redirected.unwrap_or(resolved))
+}
+
+/// Redirects a path whose file system is queried. Writes to the lower dir end up in the upper dir,
+/// so files that only exist in the lower dir are reported with the file system of the upper dir.
+fn redirect_statfs(raw_path: *const c_char) -> Option<CString> {
+    if let Some(redirected) = redirect_followed_raw(raw_path, false) {
+        return Some(redirected);
+    }
+    let layers = redir::layers(c_char_ptr_to_path(raw_path))?;
+    layers.lower?;
+    path_to_cstring(&layers.mapping.upper_dir)
+}
+
+/// Resolves a path relative to `dirfd` into an absolute path, so that the `*at` functions can
+/// redirect it like any other path. Returns `None` for paths that need no resolving or that do not
+/// end up in the lower dir.
+fn resolve_at(dirfd: c_int, raw_path: *const c_char) -> Option<CString> {
+    let path = c_char_ptr_to_path(raw_path);
+    // An empty path refers to dirfd itself when used with AT_EMPTY_PATH
+    if path.is_absolute() || dirfd == AT_FDCWD || path.as_os_str().is_empty() {
+        return None;
+    }
+    let resolved = redir::fd_path(dirfd)?.join(path);
+    redir::layers(&resolved)?;
+    path_to_cstring(&resolved)
+}
+
+/// Redirects a path whose metadata is changed, see `redir::redirect_metadata`.
+fn redirect_metadata_raw(raw_path: *const c_char, follow: bool) -> Option<CString> {
+    let path = c_char_ptr_to_path(raw_path);
+    let followed = if follow {
+        redir::follow_symlinks(path)
+    } else {
+        None
+    };
+    let redirected = match followed {
+        Some(followed) => redir::redirect_metadata(&followed).unwrap_or(followed),
+        None => redir::redirect_metadata(path)?,
+    };
+    path_to_cstring(&redirected)
+}
+
+/// Redirects a path whose contents are read, following symlinks within the merged view.
+fn redirect_contents_raw(raw_path: *const c_char) -> Option<CString> {
+    let redirected = redirect_followed(c_char_ptr_to_path(raw_path), false)?;
+    path_to_cstring(&redir::contents_path(redirected))
+}
+
+/// Redirects a path that is executed. The kernel checks the permissions of the file it executes,
+/// so metadata-only copies are completed first.
+fn redirect_executable_raw(raw_path: *const c_char) -> Option<CString> {
+    let redirected = redirect_followed(c_char_ptr_to_path(raw_path), false)?;
+    if metacopy::is_stub(&redirected) {
+        let lower = redir::to_merged(redirected.clone());
+        metacopy::copy_contents(&lower, &redirected)?;
+    }
+    path_to_cstring(&redirected)
+}
+
+/// Redirects the file that `fd` refers to, if it lives in the lower dir.
+fn redirect_fd(fd: c_int, write: bool) -> Option<CString> {
+    let path = redir::fd_path(fd)?;
+    let redirected = redir::redirect_path(&path, write)?;
+    path_to_cstring(&redirected)
+}
+
+/// Redirects the file that `fd` refers to for changing its metadata.
+fn redirect_metadata_fd(fd: c_int) -> Option<CString> {
+    let path = redir::fd_path(fd)?;
+    let redirected = redir::redirect_metadata(&path)?;
+    path_to_cstring(&redirected)
+}
+
+fn redirect_open(raw_path: *const c_char, flags: c_int) -> Option<CString> {
+    // O_TMPFILE requires write access as well, so its directory is copied up like for other writes.
+    // Other directories cannot be opened for writing, so nothing is copied up for them.
+    let directory = flags & O_TMPFILE == O_DIRECTORY;
+    let write = !directory && (flags & (O_RDWR | O_WRONLY | O_CREAT)) != 0;
+    let follow = flags & O_NOFOLLOW == 0;
+    if flags & O_TRUNC != 0 && flags & (O_RDWR | O_WRONLY) != 0 {
+        let path = c_char_ptr_to_path(raw_path);
+        let followed = if follow {
+            redir::follow_symlinks(path)
+        } else {
+            None
+        };
+        redir::create_truncated(followed.as_ref().map_or(path, |followed| followed));
+    }
+    let path = c_char_ptr_to_path(raw_path);
+    let redirect = |path: &Path| redirect_opened(path, flags);
+    let redirected = if follow {
+        redirect_followed_with(path, redirect)?
+    } else {
+        redirect(path)?
+    };
+    if write {
+        path_to_cstring(&redirected)
+    } else {
+        path_to_cstring(&redir::contents_path(redirected))
+    }
+}
+
+/// Redirects a path opened with `flags`. Only files opened for their contents may be cached,
+/// opening a directory or an `O_PATH` descriptor does not copy anything up.
+fn redirect_opened(path: &Path, flags: c_int) -> Option<PathBuf> {
+    let write = flags & O_TMPFILE != O_DIRECTORY && (flags & (O_RDWR | O_WRONLY | O_CREAT)) != 0;
+    if write || flags & (O_DIRECTORY | O_PATH) == 0 {
+        redir::redirect_opened(path, write)
+    } else {
+        redir::redirect_path(path, false)
+    }
+}
+
+/// Makes a change that was refused fail with `ENOSPC` for lack of quota, or with `EROFS` for the
+/// options of its mapping, rather than with the error for the path it was redirected to in its place.
+fn refused_errno(redirected: Option<&CString>) {
+    let redirected = match redirected {
+        Some(redirected) => c_char_ptr_to_path(redirected.as_ptr()),
+        None => return,
+    };
+    if quota::is_refused(redirected) {
+        set_errno(ENOSPC);
+    } else if redir::is_read_only(redirected) {
+        set_errno(EROFS);
+    }
+}
+
+fn redirect_fopen(raw_path: *const c_char, raw_mode: *const c_char) -> Option<CString> {
+    let cmode = unsafe { CStr::from_ptr(raw_mode) }.to_bytes();
+    // "w" and "a" create the file, "w" also truncates it, "+" opens it for reading and writing
+    let flags = match cmode.first() {
+        Some(b'w') => O_WRONLY | O_CREAT | O_TRUNC,
+        Some(b'a') => O_WRONLY | O_CREAT,
+        _ => 0,
+    };
+    let flags = if cmode.contains(&b'+') {
+        flags & !O_WRONLY | O_RDWR
+    } else {
+        flags
+    };
+    redirect_open(raw_path, flags)
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_CHDIR, b"chdir\0", (path: *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn chdir(path: *const c_char) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "chdir",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let target = with_reentrancy_guard(None, || chdir_target(c_char_ptr_to_path(path)));
+    let ret = match target {
+        Some(target) => C_CHDIR.call(target.as_ptr()),
+        None => C_CHDIR.call(path),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FCHDIR, b"fchdir\0", (fd: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn fchdir(fd: c_int) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call("fchdir", &[("fd", Arg::Dec(&fd))])
+    });
+    // Directories opened in the merged view usually refer to the upper dir
+    let target = with_reentrancy_guard(None, || chdir_target(&redir::fd_path(fd)?));
+    let ret = match target {
+        Some(target) => C_CHDIR.call(target.as_ptr()),
+        None => C_FCHDIR.call(fd),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+/// Returns the directory to change into for `path` in the merged view. The lower directory is
+/// preferred, so that relative paths that are not redirected still resolve to the lower dir. Only
+/// directories without a visible lower counterpart are entered in the upper dir.
+fn chdir_target(path: &Path) -> Option<CString> {
+    let followed = redir::follow_symlinks(path);
+    let layers = redir::layers(followed.as_ref().map_or(path, |followed| followed))?;
+    if layers.lower.map_or(false, |lower| lower.is_dir()) {
+        path_to_cstring(&layers.path)
+    } else {
+        path_to_cstring(&layers.upper_path)
+    }
+}
+
+import_real!(C_GETCWD, b"getcwd\0", (buf: *mut c_char, size: usize) -> *mut c_char);
+
+#[no_mangle]
+unsafe extern "C" fn getcwd(buf: *mut c_char, size: usize) -> *mut c_char {
+    let ret = C_GETCWD.call(buf, size);
+    if ret.is_null() {
+        return ret;
+    }
+    // The current directory is reported in the merged view, even if it lies in the upper dir
+    let merged = with_reentrancy_guard(None, || {
+        let cwd = c_char_ptr_to_path(ret);
+        let merged = redir::to_merged(cwd.to_owned());
+        if merged == cwd {
+            None
+        } else {
+            path_to_cstring(&merged)
+        }
+    });
+    let merged = match merged {
+        Some(merged) => merged,
+        None => return ret,
+    };
+    let bytes = merged.as_bytes_with_nul();
+    if buf.is_null() {
+        // The buffer allocated by libc may be too small for the merged path
+        free(ret as *mut c_void);
+        let merged_buf = malloc(bytes.len()) as *mut c_char;
+        if merged_buf.is_null() {
+            set_errno(ENOMEM);
+            return merged_buf;
+        }
+        std::ptr::copy_nonoverlapping(bytes.as_ptr() as *const c_char, merged_buf, bytes.len());
+        merged_buf
+    } else if bytes.len() > size {
+        set_errno(ERANGE);
+        std::ptr::null_mut()
+    } else {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr() as *const c_char, buf, bytes.len());
+        buf
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn get_current_dir_name() -> *mut c_char {
+    // glibc prefers $PWD, which the caller may have set to a path in the upper dir
+    getcwd(std::ptr::null_mut(), 0)
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_MKDIR, b"mkdir\0", (path: *const c_char, mode: mode_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn mkdir(path: *const c_char, mode: mode_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mkdir",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, true));
+    let ret = match &redir_path {
+        Some(redir) => C_MKDIR.call(redir.to_bytes_with_nul().as_ptr() as *const c_char, mode),
+        None => C_MKDIR.call(path, mode),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_MKDIRAT, b"mkdirat\0", (dirfd: c_int, path: *const c_char, mode: mode_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn mkdirat(dirfd: c_int, path: *const c_char, mode: mode_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mkdirat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+            ],
+        )
+    });
+    // The redirected path is absolute, so dirfd will be ignored.
+    let redir_path = with_reentrancy_guard(None, || redirect_at_raw(dirfd, path, true, false));
+    let ret = match &redir_path {
+        Some(redir) => C_MKDIRAT.call(dirfd, redir.as_ptr(), mode),
+        None => C_MKDIRAT.call(dirfd, path, mode),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_OPENDIR, b"opendir\0", (path: *const c_char, mode: mode_t) -> *mut c_void);
+
+#[no_mangle]
+unsafe extern "C" fn opendir(path: *const c_char, mode: mode_t) -> *mut c_void {
+    if !hooks_enabled(HookGroup::Readdir) {
+        return C_OPENDIR.call(path, mode);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "opendir",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_followed_raw(path, false));
+    let ret = match redir_path {
+        Some(redir) => {
+            let upper_dir =
+                C_OPENDIR.call(redir.to_bytes_with_nul().as_ptr() as *const c_char, mode);
+
+            // A lower dir that has been whited out must not show through
+            let layers = with_reentrancy_guard(None, || redir::layers(c_char_ptr_to_path(path)));
+            let lower_visible = layers
+                .as_ref()
+                .map_or(false, |layers| layers.lower.is_some());
+            let lower_dir = if upper_dir.is_null() || !lower_visible {
+                std::ptr::null_mut()
+            } else {
+                C_OPENDIR.call(path, mode)
+            };
+
+            if let (false, Some(layers)) = (upper_dir.is_null(), layers) {
+                if !lower_dir.is_null() {
+                    log::debug(Category::Dir, || {
+                        log_println!("liboverlay: merging opendir")
+                    });
+                }
+                // If the lower dir exists, we need to merge the contents of the two dirs. Directories
+                // that only exist in the upper dir are merged as well if they have to be sorted.
+                if !lower_dir.is_null() || sort_dirs() {
+                    with_reentrancy_guard((), || {
+                        register_merged(upper_dir, upper_dir, lower_dir, layers.path)
+                    });
+                }
+            }
+            upper_dir
+        }
+        None => {
+            let dir = C_OPENDIR.call(path, mode);
+            if !dir.is_null() {
+                with_reentrancy_guard(None, || merge_lower_only(c_char_ptr_to_path(path), dir));
+            }
+            dir
+        }
+    };
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null()))
+    });
+    ret
+}
+
+import_real!(C_FDOPENDIR, b"fdopendir\0", (fd: c_int) -> *mut c_void);
+
+#[no_mangle]
+unsafe extern "C" fn fdopendir(fd: c_int) -> *mut c_void {
+    if !hooks_enabled(HookGroup::Readdir) {
+        return C_FDOPENDIR.call(fd);
+    }
+    log::trace(Category::Hook, || {
+        log::call("fdopendir", &[("fd", Arg::Dec(&fd))])
+    });
+    let ret = C_FDOPENDIR.call(fd);
+    if !ret.is_null() {
+        with_reentrancy_guard(None, || merge_fdopendir(fd, ret));
+    }
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null()))
+    });
+    ret
+}
+
+/// Sets up merging for the stream `dir` opened on `fd`, which may refer to the directory in either
+/// layer. The stream for the other layer is opened by path.
+unsafe fn merge_fdopendir(fd: c_int, dir: *mut c_void) -> Option<()> {
+    let path = redir::fd_path(fd)?;
+    let layers = redir::layers(&path)?;
+    if !layers.upper.map_or(false, |upper| upper.is_dir()) {
+        return merge_lower_only(&path, dir);
+    }
+    if !layers.lower.map_or(false, |lower| lower.is_dir()) {
+        if sort_dirs() {
+            register_merged(dir, dir, std::ptr::null_mut(), layers.path);
+        }
+        return None;
+    }
+    let in_upper = std::fs::read_link(format!("/proc/self/fd/{}", fd)).ok()? == layers.upper_path;
+    let other_path = if in_upper { &path } else { &layers.upper_path };
+    let other_dir = C_OPENDIR.call(path_to_cstring(other_path)?.as_ptr(), 0);
+    if other_dir.is_null() {
+        return None;
+    }
+    log::debug(Category::Dir, || {
+        log_println!("liboverlay: merging fdopendir")
+    });
+    if in_upper {
+        register_merged(dir, dir, other_dir, layers.path);
+    } else {
+        register_merged(dir, other_dir, dir, layers.path);
+    }
+    Some(())
+}
+
+/// Merges the stream `dir` of the lower directory `path`, which has no counterpart in the upper
+/// dir, with no upper stream, so that hide rules, synthetic files and sorting apply to it.
+fn merge_lower_only(path: &Path, dir: *mut c_void) -> Option<()> {
+    let cfg = config::get_config()?;
+    if cfg.hidden.is_empty() && cfg.synthetic.is_empty() && !cfg.sort_dirs {
+        return None;
+    }
+    let layers = redir::layers(path)?;
+    if layers.upper.is_some() || !layers.lower.map_or(false, |lower| lower.is_dir()) {
+        return None;
+    }
+    register_merged(dir, std::ptr::null_mut(), dir, layers.path);
+    Some(())
+}
+
+fn sort_dirs() -> bool {
+    config::get_config().map_or(false, |cfg| cfg.sort_dirs)
+}
+
+/// Makes `dir` a merged directory stream that reads `upper` and then `lower`, one of which is
+/// `dir` itself, followed by the synthetic files. `upper` is null for lower directories that are
+/// only filtered. `lower_path` is the path of the lower directory, whose entries may be hidden by
+/// hide rules or replaced by synthetic files.
+fn register_merged(dir: *mut c_void, upper: *mut c_void, lower: *mut c_void, lower_path: PathBuf) {
+    let opendir = OpenDir {
+        upper,
+        lower,
+        synthetic: redir::synthetic_entries(&lower_path),
+        next_synthetic: 0,
+        entry: Vec::new(),
+        sort: sort_dirs(),
+        sorted: None,
+        lower_path,
+        seen: HashSet::new(),
+        position: 0,
+    };
+    opendirs().lock().unwrap().insert(dir as usize, opendir);
+}
+
+#[allow(non_camel_case_types)]
+pub type ino_t = u64;
+#[allow(non_camel_case_types)]
+pub type off_t = i64;
+/// Only the first `d_reclen` bytes of an entry are valid, so the name is declared without a size
+/// to avoid referring to memory past the end of the entry.
+#[repr(C)]
+pub struct dirent {
+    pub d_ino: ino_t,
+    pub d_off: off_t,
+    pub d_reclen: c_ushort,
+    pub d_type: c_uchar,
+    pub d_name: [c_char; 0],
+}
+
+/// The `d_type` of regular files.
+const DT_REG: c_uchar = 8;
+
+/// The entry returned by `readdir64`, which always uses 64-bit inode numbers and offsets.
+#[repr(C)]
+pub struct dirent64 {
+    pub d_ino: u64,
+    pub d_off: i64,
+    pub d_reclen: c_ushort,
+    pub d_type: c_uchar,
+    pub d_name: [c_char; 0],
+}
+
+trait DirEntry {
+    unsafe fn name<'a>(entry: *const Self) -> &'a CStr;
+    unsafe fn reclen(entry: *const Self) -> usize;
+}
+
+impl DirEntry for dirent {
+    unsafe fn name<'a>(entry: *const Self) -> &'a CStr {
+        CStr::from_ptr((*entry).d_name.as_ptr())
+    }
+
+    unsafe fn reclen(entry: *const Self) -> usize {
+        (*entry).d_reclen as usize
+    }
+}
+
+impl DirEntry for dirent64 {
+    unsafe fn name<'a>(entry: *const Self) -> &'a CStr {
+        CStr::from_ptr((*entry).d_name.as_ptr())
+    }
+
+    unsafe fn reclen(entry: *const Self) -> usize {
+        (*entry).d_reclen as usize
+    }
+}
+
+import_real!(C_READDIR, b"readdir\0", (dir: *mut c_void) -> *mut dirent);
+
+#[no_mangle]
+unsafe extern "C" fn readdir(dir: *mut c_void) -> *mut dirent {
+    log::trace(Category::Hook, || {
+        log::call("readdir", &[("dir", Arg::Hex(&(dir as usize)))])
+    });
+    let ret = readdir_merged(dir, |dir| C_READDIR.call(dir));
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null() && errno() != 0))
+    });
+    ret
+}
+
+import_real!(C_READDIR64, b"readdir64\0", (dir: *mut c_void) -> *mut dirent64);
+
+#[no_mangle]
+unsafe extern "C" fn readdir64(dir: *mut c_void) -> *mut dirent64 {
+    log::trace(Category::Hook, || {
+        log::call("readdir64", &[("dir", Arg::Hex(&(dir as usize)))])
+    });
+    let ret = readdir_merged(dir, |dir| C_READDIR64.call(dir));
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null() && errno() != 0))
+    });
+    ret
+}
+
+import_real!(C_READDIR_R, b"readdir_r\0", (dir: *mut c_void, entry: *mut dirent, result: *mut *mut dirent) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn readdir_r(
+    dir: *mut c_void,
+    entry: *mut dirent,
+    result: *mut *mut dirent,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call("readdir_r", &[("dir", Arg::Hex(&(dir as usize)))])
+    });
+    let ret = readdir_r_merged(
+        dir,
+        entry,
+        result,
+        |dir| C_READDIR.call(dir),
+        |dir, entry, result| C_READDIR_R.call(dir, entry, result),
+    );
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), Some(ret).filter(|&ret| ret != 0))
+    });
+    ret
+}
+
+import_real!(C_READDIR64_R, b"readdir64_r\0", (dir: *mut c_void, entry: *mut dirent64, result: *mut *mut dirent64) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn readdir64_r(
+    dir: *mut c_void,
+    entry: *mut dirent64,
+    result: *mut *mut dirent64,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call("readdir64_r", &[("dir", Arg::Hex(&(dir as usize)))])
+    });
+    let ret = readdir_r_merged(
+        dir,
+        entry,
+        result,
+        |dir| C_READDIR64.call(dir),
+        |dir, entry, result| C_READDIR64_R.call(dir, entry, result),
+    );
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), Some(ret).filter(|&ret| ret != 0))
+    });
+    ret
+}
+
+/// Implements the reentrant readdir variants on top of `readdir_merged` for merged directories,
+/// the entry is copied into the buffer provided by the caller.
+unsafe fn readdir_r_merged<E, F, G>(
+    dir: *mut c_void,
+    entry: *mut E,
+    result: *mut *mut E,
+    read: F,
+    read_r: G,
+) -> c_int
+where
+    E: DirEntry,
+    F: Fn(*mut c_void) -> *mut E,
+    G: FnOnce(*mut c_void, *mut E, *mut *mut E) -> c_int,
+{
+    let is_merged = with_reentrancy_guard(false, || {
+        opendirs().lock().unwrap().contains_key(&(dir as usize))
+    });
+    if !is_merged {
+        return read_r(dir, entry, result);
+    }
+    // readdir only reports errors through errno
+    set_errno(0);
+    let next = readdir_merged(dir, read);
+    if next.is_null() {
+        *result = std::ptr::null_mut();
+        return errno();
+    }
+    std::ptr::copy_nonoverlapping(next as *const u8, entry as *mut u8, E::reclen(next));
+    *result = entry;
+    0
+}
+
+/// Reads the next entry of a directory stream with `read`, merging both layers if the stream
+/// belongs to a merged directory.
+unsafe fn readdir_merged<E: DirEntry, F: Fn(*mut c_void) -> *mut E>(
+    dir: *mut c_void,
+    read: F,
+) -> *mut E {
+    IS_HOOKED.with(|is_hooked: &Cell<bool>| {
+        if is_hooked.get() {
+            read(dir)
+        } else {
+            let mut opendirs = opendirs().lock().unwrap();
+            if let Some(merged) = opendirs.get_mut(&(dir as usize)) {
+                let entry = if merged.sort {
+                    if merged.sorted.is_none() {
+                        merged.sorted = Some(sorted_entries(merged, &read));
+                    }
+                    let position = merged.position as usize;
+                    merged
+                        .sorted
+                        .as_mut()
+                        .and_then(|sorted| sorted.get_mut(position))
+                        .map_or(std::ptr::null_mut(), |entry| entry.as_mut_ptr() as *mut E)
+                } else {
+                    next_merged(merged, &read)
+                };
+                if !entry.is_null() {
+                    merged.position += 1;
+                }
+                entry
+            } else {
+                read(dir)
+            }
+        }
+    })
+}
+
+/// Reads the next entry of the merged directory `merged`, from the upper stream, the lower stream
+/// and the synthetic files in turn.
+unsafe fn next_merged<E: DirEntry, F: Fn(*mut c_void) -> *mut E>(
+    merged: &mut OpenDir,
+    read: &F,
+) -> *mut E {
+    use std::os::unix::ffi::OsStrExt;
+
+    // First try upper, remembering which lower entries are shadowed
+    let entry = loop {
+        if merged.upper.is_null() {
+            break std::ptr::null_mut();
+        }
+        let entry = read(merged.upper);
+        if entry.is_null() {
+            break entry;
+        }
+        let name = E::name(entry);
+        match whiteout::hidden_name(name.to_bytes()) {
+            // whiteout markers are not part of the merged view
+            Some(hidden) => {
+                merged.seen.insert(CString::new(hidden).unwrap());
+            }
+            None => {
+                merged.seen.insert(name.to_owned());
+                break entry;
+            }
+        }
+    };
+    let entry = if entry.is_null() {
+        // Now try lower
+        loop {
+            if merged.lower.is_null() {
+                break std::ptr::null_mut();
+            }
+            let entry_lower = read(merged.lower);
+            if entry_lower.is_null() {
+                break entry_lower;
+            } else {
+                // filter out entries from top level, those hidden by hide rules and
+                // those replaced by synthetic files
+                let name = E::name(entry_lower);
+                let synthetic = merged
+                    .synthetic
+                    .iter()
+                    .any(|(synthetic, _)| synthetic.as_bytes() == name.to_bytes());
+                if !merged.seen.contains(name)
+                    && !synthetic
+                    && !redir::hidden_entry(&merged.lower_path, OsStr::from_bytes(name.to_bytes()))
+                {
+                    break entry_lower;
+                }
+            }
+        }
+    } else {
+        entry
+    };
+    match merged.synthetic.get(merged.next_synthetic) {
+        Some((name, ino)) if entry.is_null() => {
+            merged.next_synthetic += 1;
+            synthetic_entry(&mut merged.entry, name, *ino) as *mut E
+        }
+        _ => entry,
+    }
+}
+
+/// Reads the remaining entries of the merged directory `merged`, and returns copies of them sorted
+/// by name.
+unsafe fn sorted_entries<E: DirEntry, F: Fn(*mut c_void) -> *mut E>(
+    merged: &mut OpenDir,
+    read: &F,
+) -> Vec<Vec<u64>> {
+    let mut entries = Vec::new();
+    loop {
+        let entry = next_merged(merged, read);
+        if entry.is_null() {
+            break;
+        }
+        // The copies are kept as words, which are aligned like the entries themselves
+        let reclen = E::reclen(entry);
+        let mut copy = vec![0u64; ((reclen + 7) & !7) / 8];
+        std::ptr::copy_nonoverlapping(entry as *const u8, copy.as_mut_ptr() as *mut u8, reclen);
+        entries.push(copy);
+    }
+    entries.sort_by(|a, b| E::name(a.as_ptr() as *const E).cmp(E::name(b.as_ptr() as *const E)));
+    entries
+}
+
+/// Builds the entry of a synthetic regular file in `buf`. Both entry types share the same layout.
+unsafe fn synthetic_entry(buf: &mut Vec<u64>, name: &OsStr, ino: u64) -> *mut dirent64 {
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = name.as_bytes();
+    let name_offset = std::mem::size_of::<dirent64>();
+    let reclen = (name_offset + name.len() + 1 + 7) & !7;
+    buf.clear();
+    buf.resize(reclen / 8, 0);
+    let entry = buf.as_mut_ptr() as *mut dirent64;
+    (*entry).d_ino = ino;
+    (*entry).d_reclen = reclen as c_ushort;
+    (*entry).d_type = DT_REG;
+    std::ptr::copy_nonoverlapping(
+        name.as_ptr() as *const c_char,
+        (*entry).d_name.as_mut_ptr(),
+        name.len(),
+    );
+    entry
+}
+
+type ScandirFilter<E> = Option<unsafe extern "C" fn(*const E) -> c_int>;
+type ScandirCompar<E> = Option<unsafe extern "C" fn(*mut *const E, *mut *const E) -> c_int>;
+
+import_real!(C_SCANDIR, b"scandir\0", (path: *const c_char, namelist: *mut *mut *mut dirent, filter: ScandirFilter<dirent>, compar: ScandirCompar<dirent>) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn scandir(
+    path: *const c_char,
+    namelist: *mut *mut *mut dirent,
+    filter: ScandirFilter<dirent>,
+    compar: ScandirCompar<dirent>,
+) -> c_int {
+    if !hooks_enabled(HookGroup::Readdir) {
+        return C_SCANDIR.call(path, namelist, filter, compar);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "scandir",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let ret = if with_reentrancy_guard(false, || is_in_lower(path)) {
+        scandir_merged(path, namelist, filter, compar, |dir| readdir(dir))
+    } else {
+        C_SCANDIR.call(path, namelist, filter, compar)
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_SCANDIR64, b"scandir64\0", (path: *const c_char, namelist: *mut *mut *mut dirent64, filter: ScandirFilter<dirent64>, compar: ScandirCompar<dirent64>) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn scandir64(
+    path: *const c_char,
+    namelist: *mut *mut *mut dirent64,
+    filter: ScandirFilter<dirent64>,
+    compar: ScandirCompar<dirent64>,
+) -> c_int {
+    if !hooks_enabled(HookGroup::Readdir) {
+        return C_SCANDIR64.call(path, namelist, filter, compar);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "scandir64",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let ret = if with_reentrancy_guard(false, || is_in_lower(path)) {
+        scandir_merged(path, namelist, filter, compar, |dir| readdir64(dir))
+    } else {
+        C_SCANDIR64.call(path, namelist, filter, compar)
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+/// Implements scandir on top of the merging opendir and `read`. The entries are copies allocated
+/// with malloc, so that the caller can release them with free. They are plain glibc entries, hence
+/// `alphasort` and `versionsort` work without being hooked.
+unsafe fn scandir_merged<E: DirEntry, F: Fn(*mut c_void) -> *mut E>(
+    path: *const c_char,
+    namelist: *mut *mut *mut E,
+    filter: ScandirFilter<E>,
+    compar: ScandirCompar<E>,
+    read: F,
+) -> c_int {
+    let dir = opendir(path, 0);
+    if dir.is_null() {
+        return -1;
+    }
+    let mut entries: Vec<*mut E> = Vec::new();
+    loop {
+        let entry = read(dir);
+        if entry.is_null() {
+            break;
+        }
+        if filter.map_or(false, |filter| filter(entry) == 0) {
+            continue;
+        }
+        let len = E::reclen(entry);
+        let copy = malloc(len) as *mut E;
+        if copy.is_null() {
+            entries
+                .into_iter()
+                .for_each(|entry| free(entry as *mut c_void));
+            closedir(dir);
+            return fail(ENOMEM);
+        }
+        std::ptr::copy_nonoverlapping(entry as *const u8, copy as *mut u8, len);
+        entries.push(copy);
+    }
+    closedir(dir);
+
+    if let Some(compar) = compar {
+        entries.sort_by(|a, b| {
+            let mut a = *a as *const E;
+            let mut b = *b as *const E;
+            compar(&mut a, &mut b).cmp(&0)
+        });
+    }
+    let list = malloc(std::mem::size_of::<*mut E>() * entries.len().max(1)) as *mut *mut E;
+    if list.is_null() {
+        entries
+            .into_iter()
+            .for_each(|entry| free(entry as *mut c_void));
+        return fail(ENOMEM);
+    }
+    std::ptr::copy_nonoverlapping(entries.as_ptr(), list, entries.len());
+    *namelist = list;
+    entries.len() as c_int
+}
+
+import_real!(C_REWINDDIR, b"rewinddir\0", (dir: *mut c_void) -> ());
+
+#[no_mangle]
+unsafe extern "C" fn rewinddir(dir: *mut c_void) {
+    log::trace(Category::Hook, || {
+        log::void_call("rewinddir", &[("dir", Arg::Hex(&(dir as usize)))])
+    });
+    if !with_reentrancy_guard(false, || rewind_merged(dir)) {
+        C_REWINDDIR.call(dir);
+    }
+}
+
+/// Rewinds both streams of a merged directory, returns `false` if `dir` is not merged.
+unsafe fn rewind_merged(dir: *mut c_void) -> bool {
+    match opendirs().lock().unwrap().get_mut(&(dir as usize)) {
+        Some(merged) => {
+            // Both streams start over, so no entry has been seen yet
+            if !merged.upper.is_null() {
+                C_REWINDDIR.call(merged.upper);
+            }
+            if !merged.lower.is_null() {
+                C_REWINDDIR.call(merged.lower);
+            }
+            merged.seen.clear();
+            merged.next_synthetic = 0;
+            merged.sorted = None;
+            merged.position = 0;
+            true
+        }
+        None => false,
+    }
+}
+
+// The positions of the two streams cannot be combined into a single offset, so merged directories
+// use the number of entries read so far instead.
+
+import_real!(C_TELLDIR, b"telldir\0", (dir: *mut c_void) -> c_long);
+
+#[no_mangle]
+unsafe extern "C" fn telldir(dir: *mut c_void) -> c_long {
+    log::trace(Category::Hook, || {
+        log::call("telldir", &[("dir", Arg::Hex(&(dir as usize)))])
+    });
+    let position = with_reentrancy_guard(None, || {
+        opendirs()
+            .lock()
+            .unwrap()
+            .get(&(dir as usize))
+            .map(|merged| merged.position)
+    });
+    let ret = match position {
+        Some(position) => position,
+        None => C_TELLDIR.call(dir),
+    };
+    log::trace(Category::Hook, || log::returned(ret, errno_if(ret == -1)));
+    ret
+}
+
+import_real!(C_SEEKDIR, b"seekdir\0", (dir: *mut c_void, position: c_long) -> ());
+
+#[no_mangle]
+unsafe extern "C" fn seekdir(dir: *mut c_void, position: c_long) {
+    log::trace(Category::Hook, || {
+        log::void_call(
+            "seekdir",
+            &[
+                ("dir", Arg::Hex(&(dir as usize))),
+                ("position", Arg::Dec(&position)),
+            ],
+        )
+    });
+    if with_reentrancy_guard(false, || rewind_merged(dir)) {
+        // Replay the merge up to the requested position, so that the set of seen entries matches
+        for _ in 0..position {
+            if readdir_merged(dir, |dir| C_READDIR.call(dir)).is_null() {
+                break;
+            }
+        }
+    } else {
+        C_SEEKDIR.call(dir, position);
+    }
+}
+
+import_real!(C_CLOSEDIR, b"closedir\0", (dir: *mut c_void) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn closedir(dir: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call("closedir", &[("dir", Arg::Hex(&(dir as usize)))])
+    });
+    with_reentrancy_guard((), || {
+        let removed = opendirs().lock().unwrap().remove(&(dir as usize));
+        if let Some(od) = removed {
+            // Only close the other stream, the one used as key will be closed down below
+            log::debug(Category::Dir, || {
+                log_println!("liboverlay: closing merged opendir")
+            });
+            if od.upper != dir && !od.upper.is_null() {
+                C_CLOSEDIR.call(od.upper);
+            }
+            if od.lower != dir && !od.lower.is_null() {
+                C_CLOSEDIR.call(od.lower);
+            }
+        }
+    });
+    let ret = C_CLOSEDIR.call(dir);
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+static OPENDIRS: AtomicPtr<Mutex<HashMap<usize, OpenDir>>> = AtomicPtr::new(std::ptr::null_mut());
+
+#[used]
+#[cfg_attr(target_os = "linux", link_section = ".init_array")]
+pub static INIT_OPENDIRS: extern "C" fn() = {
+    extern "C" fn init() {
+        let opendirs = Box::new(Mutex::new(HashMap::new()));
+        OPENDIRS.store(Box::into_raw(opendirs), Ordering::SeqCst);
+    }
+    init
+};
+
+fn opendirs() -> &'static Mutex<HashMap<usize, OpenDir>> {
+    unsafe { OPENDIRS.load(Ordering::SeqCst).as_ref().unwrap() }
+}
+
+#[derive(Clone)]
+struct OpenDir {
+    upper: *mut c_void,
+    lower: *mut c_void,
+    lower_path: PathBuf,
+    /// The names and inode numbers of the synthetic files in the directory.
+    synthetic: Vec<(OsString, u64)>,
+    next_synthetic: usize,
+    /// Holds the last synthetic entry returned.
+    entry: Vec<u64>,
+    /// The entries are returned sorted by name, from `sorted` once all of them have been read.
+    sort: bool,
+    sorted: Option<Vec<Vec<u64>>>,
+    seen: HashSet<CString>,
+    /// The number of entries read since the stream was opened or rewound.
+    position: c_long,
+}
+
+unsafe impl Send for OpenDir {}
+unsafe impl Sync for OpenDir {}
+
+////////////////////////////////////////////////////////////////////////////
+
+// glibc walks trees through internal calls that bypass the directory hooks, so nftw and ftw are
+// reimplemented on top of the hooks for trees in the lower dir.
+
+/// The layout of `struct stat` on x86_64.
+#[repr(C)]
+pub struct stat_buf {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_nlink: u64,
+    pub st_mode: u32,
+    pub st_uid: uid_t,
+    pub st_gid: gid_t,
+    __pad0: c_int,
+    pub st_rdev: u64,
+    pub st_size: i64,
+    pub st_blksize: i64,
+    pub st_blocks: i64,
+    pub st_atime: [i64; 2],
+    pub st_mtime: [i64; 2],
+    pub st_ctime: [i64; 2],
+    __unused: [i64; 3],
+}
+
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+const FTW_F: c_int = 0;
+const FTW_D: c_int = 1;
+const FTW_DNR: c_int = 2;
+const FTW_NS: c_int = 3;
+const FTW_SL: c_int = 4;
+const FTW_DP: c_int = 5;
+const FTW_SLN: c_int = 6;
+
+const FTW_PHYS: c_int = 1;
+const FTW_CHDIR: c_int = 4;
+const FTW_DEPTH: c_int = 8;
+const FTW_ACTIONRETVAL: c_int = 16;
+
+const FTW_SKIP_SUBTREE: c_int = 2;
+const FTW_SKIP_SIBLINGS: c_int = 3;
+
+#[repr(C)]
+pub struct FTW {
+    pub base: c_int,
+    pub level: c_int,
+}
+
+type NftwFn = unsafe extern "C" fn(*const c_char, *const stat_buf, c_int, *mut FTW) -> c_int;
+type FtwFn = unsafe extern "C" fn(*const c_char, *const stat_buf, c_int) -> c_int;
+
+import_real!(C_NFTW, b"nftw\0", (path: *const c_char, visit: NftwFn, nopenfd: c_int, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn nftw(
+    path: *const c_char,
+    visit: NftwFn,
+    nopenfd: c_int,
+    flags: c_int,
+) -> c_int {
+    if !hooks_enabled(HookGroup::Readdir) {
+        return C_NFTW.call(path, visit, nopenfd, flags);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "nftw",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("nopenfd", Arg::Dec(&nopenfd)),
+                ("flags", Arg::Bin(&flags)),
+            ],
+        )
+    });
+    // Changing into directories that only exist in the upper dir is not supported
+    let ret = if flags & FTW_CHDIR == 0 && with_reentrancy_guard(false, || is_in_lower(path)) {
+        walk_merged(path, flags, &mut |path, buf, typeflag, ftw| {
+            visit(path, buf, typeflag, ftw)
+        })
+    } else {
+        C_NFTW.call(path, visit, nopenfd, flags)
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_NFTW64, b"nftw64\0", (path: *const c_char, visit: NftwFn, nopenfd: c_int, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn nftw64(
+    path: *const c_char,
+    visit: NftwFn,
+    nopenfd: c_int,
+    flags: c_int,
+) -> c_int {
+    if !hooks_enabled(HookGroup::Readdir) {
+        return C_NFTW64.call(path, visit, nopenfd, flags);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "nftw64",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("nopenfd", Arg::Dec(&nopenfd)),
+                ("flags", Arg::Bin(&flags)),
+            ],
+        )
+    });
+    let ret = if flags & FTW_CHDIR == 0 && with_reentrancy_guard(false, || is_in_lower(path)) {
+        walk_merged(path, flags, &mut |path, buf, typeflag, ftw| {
+            visit(path, buf, typeflag, ftw)
+        })
+    } else {
+        C_NFTW64.call(path, visit, nopenfd, flags)
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FTW, b"ftw\0", (path: *const c_char, visit: FtwFn, nopenfd: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn ftw(path: *const c_char, visit: FtwFn, nopenfd: c_int) -> c_int {
+    if !hooks_enabled(HookGroup::Readdir) {
+        return C_FTW.call(path, visit, nopenfd);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "ftw",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("nopenfd", Arg::Dec(&nopenfd)),
+            ],
+        )
+    });
+    let ret = if with_reentrancy_guard(false, || is_in_lower(path)) {
+        walk_merged(path, 0, &mut |path, buf, typeflag, _| {
+            visit(path, buf, ftw_typeflag(typeflag))
+        })
+    } else {
+        C_FTW.call(path, visit, nopenfd)
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FTW64, b"ftw64\0", (path: *const c_char, visit: FtwFn, nopenfd: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn ftw64(path: *const c_char, visit: FtwFn, nopenfd: c_int) -> c_int {
+    if !hooks_enabled(HookGroup::Readdir) {
+        return C_FTW64.call(path, visit, nopenfd);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "ftw64",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("nopenfd", Arg::Dec(&nopenfd)),
+            ],
+        )
+    });
+    let ret = if with_reentrancy_guard(false, || is_in_lower(path)) {
+        walk_merged(path, 0, &mut |path, buf, typeflag, _| {
+            visit(path, buf, ftw_typeflag(typeflag))
+        })
+    } else {
+        C_FTW64.call(path, visit, nopenfd)
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+/// ftw does not distinguish dangling symlinks from other entries that cannot be stat'ed.
+fn ftw_typeflag(typeflag: c_int) -> c_int {
+    if typeflag == FTW_SLN {
+        FTW_NS
+    } else {
+        typeflag
+    }
+}
+
+/// Walks the merged tree below `path` like nftw, directories are read through the merging
+/// opendir and readdir hooks. `FTW_MOUNT` is ignored, as the merged view spans two file systems
+/// anyway.
+unsafe fn walk_merged<F>(path: *const c_char, flags: c_int, visit: &mut F) -> c_int
+where
+    F: FnMut(*const c_char, *const stat_buf, c_int, *mut FTW) -> c_int,
+{
+    let root = CStr::from_ptr(path).to_bytes();
+    let mut buf: stat_buf = std::mem::zeroed();
+    // Unlike the entries below it, a root that does not exist is an error
+    if lstat(path, &mut buf as *mut stat_buf as *mut c_void) != 0 {
+        return -1;
+    }
+    let base = match root.iter().rposition(|c| *c == b'/') {
+        Some(slash) if slash + 1 < root.len() => slash + 1,
+        _ => 0,
+    };
+    let ret = walk_entry(root.to_owned(), base, 0, flags, visit);
+    if flags & FTW_ACTIONRETVAL != 0 && (ret == FTW_SKIP_SUBTREE || ret == FTW_SKIP_SIBLINGS) {
+        0
+    } else {
+        ret
+    }
+}
+
+unsafe fn walk_entry<F>(
+    path: Vec<u8>,
+    base: usize,
+    level: c_int,
+    flags: c_int,
+    visit: &mut F,
+) -> c_int
+where
+    F: FnMut(*const c_char, *const stat_buf, c_int, *mut FTW) -> c_int,
+{
+    let cpath = match CString::new(path) {
+        Ok(cpath) => cpath,
+        Err(_) => return fail(EINVAL),
+    };
+    let mut buf: stat_buf = std::mem::zeroed();
+    let statbuf = &mut buf as *mut stat_buf as *mut c_void;
+    let typeflag = if flags & FTW_PHYS != 0 {
+        if lstat(cpath.as_ptr(), statbuf) != 0 {
+            FTW_NS
+        } else if buf.st_mode & S_IFMT == S_IFLNK {
+            FTW_SL
+        } else if buf.st_mode & S_IFMT == S_IFDIR {
+            FTW_D
+        } else {
+            FTW_F
+        }
+    } else if stat(cpath.as_ptr(), statbuf) != 0 {
+        if lstat(cpath.as_ptr(), statbuf) == 0 {
+            FTW_SLN
+        } else {
+            FTW_NS
+        }
+    } else if buf.st_mode & S_IFMT == S_IFDIR {
+        FTW_D
+    } else {
+        FTW_F
+    };
+    let mut ftw = FTW {
+        base: base as c_int,
+        level,
+    };
+    if typeflag != FTW_D {
+        return visit(cpath.as_ptr(), &buf, typeflag, &mut ftw);
+    }
+
+    let dir = opendir(cpath.as_ptr(), 0);
+    if dir.is_null() {
+        return visit(cpath.as_ptr(), &buf, FTW_DNR, &mut ftw);
+    }
+    // Read the whole directory up front, so that only one directory is open at a time
+    let mut names = Vec::new();
+    loop {
+        let entry = readdir(dir);
+        if entry.is_null() {
+            break;
+        }
+        let name = dirent::name(entry).to_bytes();
+        if name != b"." && name != b".." {
+            names.push(name.to_owned());
+        }
+    }
+    closedir(dir);
+
+    let action_retval = flags & FTW_ACTIONRETVAL != 0;
+    if flags & FTW_DEPTH == 0 {
+        let ret = visit(cpath.as_ptr(), &buf, FTW_D, &mut ftw);
+        if action_retval && ret == FTW_SKIP_SUBTREE {
+            return 0;
+        } else if ret != 0 {
+            return ret;
+        }
+    }
+    let dir_path = cpath.as_bytes();
+    for name in names {
+        let mut child = dir_path.to_owned();
+        if child.last() != Some(&b'/') {
+            child.push(b'/');
+        }
+        let child_base = child.len();
+        child.extend_from_slice(&name);
+        let ret = walk_entry(child, child_base, level + 1, flags, visit);
+        if action_retval && ret == FTW_SKIP_SIBLINGS {
+            break;
+        } else if ret != 0 {
+            return ret;
+        }
+    }
+    if flags & FTW_DEPTH != 0 {
+        visit(cpath.as_ptr(), &buf, FTW_DP, &mut ftw)
+    } else {
+        0
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+// Like nftw, glibc's fts reads directories through internal calls. It is replaced entirely, which
+// works for trees outside the lower dir just as well. Entries are reported with their full path
+// as `fts_accpath` and the current directory is never changed, as if `FTS_NOCHDIR` was given.
+
+const FTS_COMFOLLOW: c_int = 0x1;
+const FTS_LOGICAL: c_int = 0x2;
+const FTS_NOSTAT: c_int = 0x8;
+const FTS_SEEDOT: c_int = 0x20;
+
+const FTS_ROOTPARENTLEVEL: c_short = -1;
+const FTS_ROOTLEVEL: c_short = 0;
+
+const FTS_D: c_ushort = 1;
+const FTS_DC: c_ushort = 2;
+const FTS_DEFAULT: c_ushort = 3;
+const FTS_DNR: c_ushort = 4;
+const FTS_DOT: c_ushort = 5;
+const FTS_DP: c_ushort = 6;
+const FTS_F: c_ushort = 8;
+const FTS_NS: c_ushort = 10;
+const FTS_NSOK: c_ushort = 11;
+const FTS_SL: c_ushort = 12;
+const FTS_SLNONE: c_ushort = 13;
+
+const FTS_AGAIN: c_ushort = 1;
+const FTS_FOLLOW: c_ushort = 2;
+const FTS_NOINSTR: c_ushort = 3;
+const FTS_SKIP: c_ushort = 4;
+
+/// The layout of `FTSENT` in glibc, which is shared with `FTSENT64` on 64-bit targets.
+#[repr(C)]
+pub struct FTSENT {
+    pub fts_cycle: *mut FTSENT,
+    pub fts_parent: *mut FTSENT,
+    pub fts_link: *mut FTSENT,
+    pub fts_number: c_long,
+    pub fts_pointer: *mut c_void,
+    pub fts_accpath: *mut c_char,
+    pub fts_path: *mut c_char,
+    pub fts_errno: c_int,
+    pub fts_symfd: c_int,
+    pub fts_pathlen: c_ushort,
+    pub fts_namelen: c_ushort,
+    pub fts_ino: u64,
+    pub fts_dev: u64,
+    pub fts_nlink: u64,
+    pub fts_level: c_short,
+    pub fts_info: c_ushort,
+    pub fts_flags: c_ushort,
+    pub fts_instr: c_ushort,
+    pub fts_statp: *mut stat_buf,
+    pub fts_name: [c_char; 1],
+}
+
+type FtsCompar = Option<unsafe extern "C" fn(*mut *const FTSENT, *mut *const FTSENT) -> c_int>;
+
+/// The state behind the `FTS` handle, which is opaque to the caller.
+struct Fts {
+    options: c_int,
+    compar: FtsCompar,
+    /// The parent of the roots, it is never returned.
+    root_parent: *mut FTSENT,
+    roots: *mut FTSENT,
+    /// The entry returned last, null before the first call to `fts_read`.
+    cur: *mut FTSENT,
+    /// Children of `cur` that were already read by `fts_children`.
+    child: *mut FTSENT,
+    /// The children of each directory that is currently being descended into.
+    levels: Vec<*mut FTSENT>,
+    done: bool,
+}
+
+#[no_mangle]
+unsafe extern "C" fn fts_open(
+    paths: *const *const c_char,
+    options: c_int,
+    compar: FtsCompar,
+) -> *mut c_void {
+    log::trace(Category::Hook, || {
+        log::call("fts_open", &[("options", Arg::Bin(&options))])
+    });
+    let root_parent = fts_alloc(b"", b"", FTS_ROOTPARENTLEVEL, std::ptr::null_mut());
+    let mut roots = Vec::new();
+    let mut i = 0;
+    while !(*paths.add(i)).is_null() {
+        let path = CStr::from_ptr(*paths.add(i)).to_bytes();
+        let root = fts_alloc(path, path, FTS_ROOTLEVEL, root_parent);
+        let follow = options & (FTS_COMFOLLOW | FTS_LOGICAL) != 0;
+        (*root).fts_info = fts_stat(options, root, follow);
+        roots.push(root);
+        i += 1;
+    }
+    let fts = Box::new(Fts {
+        options,
+        compar,
+        root_parent,
+        roots: fts_link_entries(roots, compar),
+        cur: std::ptr::null_mut(),
+        child: std::ptr::null_mut(),
+        levels: Vec::new(),
+        done: false,
+    });
+    let ret = Box::into_raw(fts) as *mut c_void;
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null()))
+    });
+    ret
+}
+
+#[no_mangle]
+unsafe extern "C" fn fts64_open(
+    paths: *const *const c_char,
+    options: c_int,
+    compar: FtsCompar,
+) -> *mut c_void {
+    fts_open(paths, options, compar)
+}
+
+#[no_mangle]
+unsafe extern "C" fn fts_read(fts: *mut c_void) -> *mut FTSENT {
+    log::trace(Category::Hook, || {
+        log::call("fts_read", &[("fts", Arg::Hex(&(fts as usize)))])
+    });
+    let ret = fts_next(&mut *(fts as *mut Fts));
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null() && errno() != 0))
+    });
+    ret
+}
+
+#[no_mangle]
+unsafe extern "C" fn fts64_read(fts: *mut c_void) -> *mut FTSENT {
+    fts_read(fts)
+}
+
+#[no_mangle]
+unsafe extern "C" fn fts_children(fts: *mut c_void, _options: c_int) -> *mut FTSENT {
+    log::trace(Category::Hook, || {
+        log::void_call("fts_children", &[("fts", Arg::Hex(&(fts as usize)))])
+    });
+    let fts = &mut *(fts as *mut Fts);
+    set_errno(0);
+    if fts.done {
+        return std::ptr::null_mut();
+    }
+    if fts.cur.is_null() {
+        return fts.roots;
+    }
+    if (*fts.cur).fts_info != FTS_D {
+        return std::ptr::null_mut();
+    }
+    if fts.child.is_null() {
+        fts.child = fts_build(fts, fts.cur);
+    }
+    fts.child
+}
+
+#[no_mangle]
+unsafe extern "C" fn fts64_children(fts: *mut c_void, options: c_int) -> *mut FTSENT {
+    fts_children(fts, options)
+}
+
+#[no_mangle]
+unsafe extern "C" fn fts_set(_fts: *mut c_void, entry: *mut FTSENT, instr: c_int) -> c_int {
+    if instr != 0
+        && instr != FTS_AGAIN as c_int
+        && instr != FTS_FOLLOW as c_int
+        && instr != FTS_NOINSTR as c_int
+        && instr != FTS_SKIP as c_int
+    {
+        return fail(EINVAL);
+    }
+    (*entry).fts_instr = instr as c_ushort;
+    0
+}
+
+#[no_mangle]
+unsafe extern "C" fn fts64_set(fts: *mut c_void, entry: *mut FTSENT, instr: c_int) -> c_int {
+    fts_set(fts, entry, instr)
+}
+
+#[no_mangle]
+unsafe extern "C" fn fts_close(fts: *mut c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::void_call("fts_close", &[("fts", Arg::Hex(&(fts as usize)))])
+    });
+    let fts = Box::from_raw(fts as *mut Fts);
+    fts_free_list(fts.child);
+    for children in fts.levels {
+        fts_free_list(children);
+    }
+    fts_free_list(fts.roots);
+    fts_free(fts.root_parent);
+    0
+}
+
+#[no_mangle]
+unsafe extern "C" fn fts64_close(fts: *mut c_void) -> c_int {
+    fts_close(fts)
+}
+
+/// Advances to the next entry in the order of `fts_read`.
+unsafe fn fts_next(fts: &mut Fts) -> *mut FTSENT {
+    set_errno(0);
+    if fts.done {
+        return std::ptr::null_mut();
+    }
+    let cur = fts.cur;
+    if cur.is_null() {
+        fts.cur = fts.roots;
+        fts.done = fts.cur.is_null();
+        return fts.cur;
+    }
+
+    let instr = (*cur).fts_instr;
+    (*cur).fts_instr = FTS_NOINSTR;
+    if instr == FTS_AGAIN {
+        (*cur).fts_info = fts_stat(fts.options, cur, fts.options & FTS_LOGICAL != 0);
+        return cur;
+    }
+    if instr == FTS_FOLLOW && ((*cur).fts_info == FTS_SL || (*cur).fts_info == FTS_SLNONE) {
+        (*cur).fts_info = fts_stat(fts.options, cur, true);
+        return cur;
+    }
+
+    if (*cur).fts_info == FTS_D {
+        let children = std::mem::replace(&mut fts.child, std::ptr::null_mut());
+        if instr == FTS_SKIP {
+            fts_free_list(children);
+        } else {
+            let children = if children.is_null() {
+                fts_build(fts, cur)
+            } else {
+                children
+            };
+            if !children.is_null() {
+                fts.levels.push(children);
+                fts.cur = children;
+                return children;
+            }
+            // Unreadable directories have been marked as such
+            if (*cur).fts_info == FTS_D {
+                (*cur).fts_info = FTS_DP;
+            }
+            return cur;
+        }
+    }
+    fts.child = std::ptr::null_mut();
+
+    // Continue with the next sibling or return to the parent
+    if !(*cur).fts_link.is_null() {
+        fts.cur = (*cur).fts_link;
+        return fts.cur;
+    }
+    let parent = (*cur).fts_parent;
+    if (*parent).fts_level == FTS_ROOTPARENTLEVEL {
+        fts.done = true;
+        return std::ptr::null_mut();
+    }
+    if let Some(children) = fts.levels.pop() {
+        fts_free_list(children);
+    }
+    (*parent).fts_info = FTS_DP;
+    fts.cur = parent;
+    parent
+}
+
+/// Reads the children of the directory `parent` through the merging directory hooks.
+unsafe fn fts_build(fts: &Fts, parent: *mut FTSENT) -> *mut FTSENT {
+    let dir = opendir((*parent).fts_accpath, 0);
+    if dir.is_null() {
+        (*parent).fts_info = FTS_DNR;
+        (*parent).fts_errno = errno();
+        return std::ptr::null_mut();
+    }
+    let parent_path = CStr::from_ptr((*parent).fts_path).to_bytes();
+    let mut children = Vec::new();
+    loop {
+        let entry = readdir(dir);
+        if entry.is_null() {
+            break;
+        }
+        let name = dirent::name(entry).to_bytes();
+        let is_dot = name == b"." || name == b"..";
+        if is_dot && fts.options & FTS_SEEDOT == 0 {
+            continue;
+        }
+        let mut path = parent_path.to_owned();
+        if path.last() != Some(&b'/') {
+            path.push(b'/');
+        }
+        path.extend_from_slice(name);
+        let child = fts_alloc(&path, name, (*parent).fts_level + 1, parent);
+        (*child).fts_info = if is_dot {
+            FTS_DOT
+        } else {
+            fts_stat(fts.options, child, fts.options & FTS_LOGICAL != 0)
+        };
+        children.push(child);
+    }
+    closedir(dir);
+    fts_link_entries(children, fts.compar)
+}
+
+/// Stats `entry` and returns the `fts_info` that describes it.
+unsafe fn fts_stat(options: c_int, entry: *mut FTSENT, follow: bool) -> c_ushort {
+    let statp = (*entry).fts_statp as *mut c_void;
+    let path = (*entry).fts_accpath;
+    if follow {
+        if stat(path, statp) != 0 {
+            let stat_errno = errno();
+            if lstat(path, statp) == 0 {
+                set_errno(0);
+                return FTS_SLNONE;
+            }
+            (*entry).fts_errno = stat_errno;
+            return FTS_NS;
+        }
+    } else if lstat(path, statp) != 0 {
+        (*entry).fts_errno = errno();
+        return FTS_NS;
+    }
+    let buf = &*(*entry).fts_statp;
+    (*entry).fts_ino = buf.st_ino;
+    (*entry).fts_dev = buf.st_dev;
+    (*entry).fts_nlink = buf.st_nlink;
+    match buf.st_mode & S_IFMT {
+        S_IFDIR => {
+            // A directory that is also one of its ancestors would be descended into forever
+            let mut ancestor = (*entry).fts_parent;
+            while !ancestor.is_null() && (*ancestor).fts_level >= FTS_ROOTLEVEL {
+                if (*ancestor).fts_ino == buf.st_ino && (*ancestor).fts_dev == buf.st_dev {
+                    (*entry).fts_cycle = ancestor;
+                    return FTS_DC;
+                }
+                ancestor = (*ancestor).fts_parent;
+            }
+            FTS_D
+        }
+        S_IFLNK => FTS_SL,
+        S_IFREG if options & FTS_NOSTAT != 0 => FTS_NSOK,
+        S_IFREG => FTS_F,
+        _ => FTS_DEFAULT,
+    }
+}
+
+/// Sorts the entries if requested and links them into a list.
+unsafe fn fts_link_entries(mut entries: Vec<*mut FTSENT>, compar: FtsCompar) -> *mut FTSENT {
+    if let Some(compar) = compar {
+        entries.sort_by(|a, b| {
+            let mut a = *a as *const FTSENT;
+            let mut b = *b as *const FTSENT;
+            compar(&mut a, &mut b).cmp(&0)
+        });
+    }
+    let mut head = std::ptr::null_mut();
+    for entry in entries.into_iter().rev() {
+        (*entry).fts_link = head;
+        head = entry;
+    }
+    head
+}
+
+/// Allocates an entry with room for its name, its path and its stat buffer.
+unsafe fn fts_alloc(path: &[u8], name: &[u8], level: c_short, parent: *mut FTSENT) -> *mut FTSENT {
+    let entry = malloc(std::mem::size_of::<FTSENT>() + name.len()) as *mut FTSENT;
+    let fts_path = malloc(path.len() + 1) as *mut c_char;
+    let fts_statp = malloc(std::mem::size_of::<stat_buf>()) as *mut stat_buf;
+    if entry.is_null() || fts_path.is_null() || fts_statp.is_null() {
+        // Like any other allocation failure in Rust
+        std::process::abort();
+    }
+    std::ptr::write_bytes(entry as *mut u8, 0, std::mem::size_of::<FTSENT>());
+    std::ptr::write_bytes(fts_statp as *mut u8, 0, std::mem::size_of::<stat_buf>());
+    std::ptr::copy_nonoverlapping(path.as_ptr() as *const c_char, fts_path, path.len());
+    *fts_path.add(path.len()) = 0;
+    let fts_name = (*entry).fts_name.as_mut_ptr();
+    std::ptr::copy_nonoverlapping(name.as_ptr() as *const c_char, fts_name, name.len());
+    *fts_name.add(name.len()) = 0;
+
+    (*entry).fts_parent = parent;
+    (*entry).fts_accpath = fts_path;
+    (*entry).fts_path = fts_path;
+    (*entry).fts_pathlen = path.len() as c_ushort;
+    (*entry).fts_namelen = name.len() as c_ushort;
+    (*entry).fts_level = level;
+    (*entry).fts_instr = FTS_NOINSTR;
+    (*entry).fts_statp = fts_statp;
+    entry
+}
+
+unsafe fn fts_free(entry: *mut FTSENT) {
+    free((*entry).fts_statp as *mut c_void);
+    free((*entry).fts_path as *mut c_void);
+    free(entry as *mut c_void);
+}
+
+unsafe fn fts_free_list(mut entry: *mut FTSENT) {
+    while !entry.is_null() {
+        let next = (*entry).fts_link;
+        fts_free(entry);
+        entry = next;
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_UNLINK, b"unlink\0", (path: *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn unlink(path: *const c_char) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "unlink",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let layers = with_reentrancy_guard(None, || redir::layers(c_char_ptr_to_path(path)));
+    let ret = match layers {
+        Some(layers) => remove_merged(layers, |upper| C_UNLINK.call(upper)),
+        None => C_UNLINK.call(path),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_UNLINKAT, b"unlinkat\0", (dirfd: c_int, path: *const c_char, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn unlinkat(dirfd: c_int, path: *const c_char, flags: c_int) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "unlinkat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("flags", Arg::Dec(&flags)),
+            ],
+        )
+    });
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let layers = with_reentrancy_guard(None, || redir::layers(c_char_ptr_to_path(path)));
+    let ret = match layers {
+        Some(layers) if flags & AT_REMOVEDIR != 0 => {
+            remove_dir_merged(layers, |upper| C_UNLINKAT.call(dirfd, upper, flags))
+        }
+        Some(layers) => remove_merged(layers, |upper| C_UNLINKAT.call(dirfd, upper, flags)),
+        None => C_UNLINKAT.call(dirfd, path, flags),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+/// Removes a file from the merged view. The upper entry is removed with `remove_upper`, and the
+/// lower entry is hidden behind a whiteout, so that the lower dir is never modified.
+unsafe fn remove_merged<F: FnOnce(*const c_char) -> c_int>(
+    layers: redir::Layers,
+    remove_upper: F,
+) -> c_int {
+    if layers.mapping.read_only {
+        return fail(EROFS);
+    }
+    if layers.upper.is_some() {
+        let cupper = match path_to_cstring(&layers.upper_path) {
+            Some(cupper) => cupper,
+            None => return fail(EINVAL),
+        };
+        let ret = remove_upper(cupper.as_ptr());
+        if ret != 0 || layers.lower.is_none() {
+            return ret;
+        }
+    } else {
+        match layers.lower {
+            None => return fail(ENOENT),
+            Some(lower) if lower.is_dir() => return fail(EISDIR),
+            Some(_) => {}
+        }
+    }
+    create_whiteout(&layers.upper_path)
+}
+
+/// Removes a directory from the merged view, which requires it to be empty in both layers. Like
+/// with files, the lower directory is hidden behind a whiteout.
+unsafe fn remove_dir_merged<F: FnOnce(*const c_char) -> c_int>(
+    layers: redir::Layers,
+    remove_upper: F,
+) -> c_int {
+    if layers.mapping.read_only {
+        return fail(EROFS);
+    }
+    match (layers.upper, layers.lower) {
+        (None, None) => return fail(ENOENT),
+        (Some(upper), _) if !upper.is_dir() => return fail(ENOTDIR),
+        (None, Some(lower)) if !lower.is_dir() => return fail(ENOTDIR),
+        _ => {}
+    }
+    let empty = with_reentrancy_guard(None, || Some(redir::is_empty_dir(&layers)));
+    match empty {
+        Some(Ok(true)) => {}
+        Some(Ok(false)) => return fail(ENOTEMPTY),
+        Some(Err(err)) => return fail(err.raw_os_error().unwrap_or(EIO)),
+        None => return fail(EIO),
+    }
+
+    if layers.upper.is_some() {
+        // The only entries left are whiteouts of lower entries, which go away with the directory
+        let cleared = with_reentrancy_guard(None, || Some(whiteout::clear(&layers.upper_path)));
+        if let Some(Err(err)) = cleared {
+            return fail(err.raw_os_error().unwrap_or(EIO));
+        }
+        let cupper = match path_to_cstring(&layers.upper_path) {
+            Some(cupper) => cupper,
+            None => return fail(EINVAL),
+        };
+        let ret = remove_upper(cupper.as_ptr());
+        if ret != 0 || layers.lower.is_none() {
+            return ret;
+        }
+    }
+    create_whiteout(&layers.upper_path)
+}
+
+/// Hides the lower entry corresponding to `path_to_upper`.
+fn create_whiteout(path_to_upper: &Path) -> c_int {
+    log::debug(Category::Dir, || {
+        log_println!("liboverlay: whiting out {}", path_to_upper.display())
+    });
+    let created = with_reentrancy_guard(None, || Some(whiteout::create(path_to_upper)));
+    match created {
+        Some(Ok(())) => 0,
+        Some(Err(err)) => fail(err.raw_os_error().unwrap_or(EIO)),
+        None => fail(EIO),
+    }
+}
+
+import_real!(C_RMDIR, b"rmdir\0", (path: *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn rmdir(path: *const c_char) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "rmdir",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let layers = with_reentrancy_guard(None, || redir::layers(c_char_ptr_to_path(path)));
+    let ret = match layers {
+        Some(layers) => remove_dir_merged(layers, |upper| C_RMDIR.call(upper)),
+        None => C_RMDIR.call(path),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+const RENAME_NOREPLACE: c_uint = 1;
+const RENAME_EXCHANGE: c_uint = 2;
+
+import_real!(C_RENAME, b"rename\0", (old: *const c_char, new: *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn rename(old: *const c_char, new: *const c_char) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "rename",
+            &[
+                ("old", Arg::Str(CStr::from_ptr(old).to_string_lossy())),
+                ("new", Arg::Str(CStr::from_ptr(new).to_string_lossy())),
+            ],
+        )
+    });
+    let ret = rename_merged(old, new, 0, |old, new| C_RENAME.call(old, new));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_RENAMEAT, b"renameat\0", (olddirfd: c_int, old: *const c_char, newdirfd: c_int, new: *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn renameat(
+    olddirfd: c_int,
+    old: *const c_char,
+    newdirfd: c_int,
+    new: *const c_char,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "renameat",
+            &[
+                ("olddirfd", Arg::Dec(&olddirfd)),
+                ("old", Arg::Str(CStr::from_ptr(old).to_string_lossy())),
+                ("newdirfd", Arg::Dec(&newdirfd)),
+                ("new", Arg::Str(CStr::from_ptr(new).to_string_lossy())),
+            ],
+        )
+    });
+    // Relative paths are resolved against the corresponding dirfd, which will be ignored afterwards.
+    let resolved_old = with_reentrancy_guard(None, || resolve_at(olddirfd, old));
+    let old = resolved_old
+        .as_ref()
+        .map_or(old, |resolved| resolved.as_ptr());
+    let resolved_new = with_reentrancy_guard(None, || resolve_at(newdirfd, new));
+    let new = resolved_new
+        .as_ref()
+        .map_or(new, |resolved| resolved.as_ptr());
+    let ret = rename_merged(old, new, 0, |old, new| {
+        C_RENAMEAT.call(olddirfd, old, newdirfd, new)
+    });
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_RENAMEAT2, b"renameat2\0", (olddirfd: c_int, old: *const c_char, newdirfd: c_int, new: *const c_char, flags: c_uint) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn renameat2(
+    olddirfd: c_int,
+    old: *const c_char,
+    newdirfd: c_int,
+    new: *const c_char,
+    flags: c_uint,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "renameat2",
+            &[
+                ("olddirfd", Arg::Dec(&olddirfd)),
+                ("old", Arg::Str(CStr::from_ptr(old).to_string_lossy())),
+                ("newdirfd", Arg::Dec(&newdirfd)),
+                ("new", Arg::Str(CStr::from_ptr(new).to_string_lossy())),
+                ("flags", Arg::Bin(&flags)),
+            ],
+        )
+    });
+    // Relative paths are resolved against the corresponding dirfd, which will be ignored afterwards.
+    let resolved_old = with_reentrancy_guard(None, || resolve_at(olddirfd, old));
+    let old = resolved_old
+        .as_ref()
+        .map_or(old, |resolved| resolved.as_ptr());
+    let resolved_new = with_reentrancy_guard(None, || resolve_at(newdirfd, new));
+    let new = resolved_new
+        .as_ref()
+        .map_or(new, |resolved| resolved.as_ptr());
+    let ret = rename_merged(old, new, flags, |old, new| {
+        C_RENAMEAT2.call(olddirfd, old, newdirfd, new, flags)
+    });
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+/// Renames an entry of the merged view. Lower entries are copied up first, so that `rename` only
+/// ever operates on the upper dir, and the old name is whited out afterwards.
+unsafe fn rename_merged<F: FnOnce(*const c_char, *const c_char) -> c_int>(
+    old: *const c_char,
+    new: *const c_char,
+    flags: c_uint,
+    rename: F,
+) -> c_int {
+    let old_layers = with_reentrancy_guard(None, || redir::layers(c_char_ptr_to_path(old)));
+    let new_layers = with_reentrancy_guard(None, || redir::layers(c_char_ptr_to_path(new)));
+    if old_layers.is_none() && new_layers.is_none() {
+        return rename(old, new);
+    }
+
+    // Renaming an entry to itself does nothing, and must not white it out
+    if let (Some(old_layers), Some(new_layers)) = (&old_layers, &new_layers) {
+        if old_layers.path == new_layers.path {
+            return match (old_layers.upper, old_layers.lower) {
+                (None, None) => fail(ENOENT),
+                _ => 0,
+            };
+        }
+    }
+
+    let exchange = flags & RENAME_EXCHANGE != 0;
+    let prepared = with_reentrancy_guard(Err(EIO), || {
+        // The kernel refuses to move entries between file systems, which is reported before copying
+        // up anything that could not be moved anyway
+        let old_device = rename_device(c_char_ptr_to_path(old), &old_layers);
+        let new_device = rename_device(c_char_ptr_to_path(new), &new_layers);
+        if let (Some(old_device), Some(new_device)) = (old_device, new_device) {
+            if old_device != new_device {
+                return Err(EXDEV);
+            }
+        }
+        if let (false, Some(new_layers)) = (exchange, &new_layers) {
+            let old_type = match &old_layers {
+                Some(layers) => layers.upper.or(layers.lower),
+                None => c_char_ptr_to_path(old)
+                    .symlink_metadata()
+                    .ok()
+                    .map(|old| old.file_type()),
+            };
+            check_rename_target(old_type, new_layers)?;
+            if old_type.map_or(false, |old| old.is_dir()) {
+                clear_replaced_dir(new_layers)?;
+            }
+        }
+        let old_upper = match &old_layers {
+            Some(layers) => copy_up_existing(layers)?,
+            None => c_char_ptr_to_path(old).to_owned(),
+        };
+        let new_upper = match &new_layers {
+            Some(layers) if exchange => copy_up_existing(layers)?,
+            Some(layers) if flags & RENAME_NOREPLACE != 0 && layers.lower.is_some() => {
+                return Err(EEXIST)
+            }
+            Some(layers) => upper_for_new_entry(layers)?,
+            None => c_char_ptr_to_path(new).to_owned(),
+        };
+        match (path_to_cstring(&old_upper), path_to_cstring(&new_upper)) {
+            (Some(old_upper), Some(new_upper)) => Ok((old_upper, new_upper)),
+            _ => Err(EINVAL),
+        }
+    });
+    let (old_upper, new_upper) = match prepared {
+        Ok(prepared) => prepared,
+        Err(errno) => return fail(errno),
+    };
+
+    let ret = rename(old_upper.as_ptr(), new_upper.as_ptr());
+    // After an exchange, both names still exist
+    if ret != 0 || exchange {
+        return ret;
+    }
+    // A directory replacing a lower one must not show the lower entries, which were all whited out
+    if let Some(layers) = &new_layers {
+        let replaced_dir = layers.lower.map_or(false, |lower| lower.is_dir());
+        if replaced_dir && !whiteout::exists(&layers.upper_path) {
+            let ret = create_whiteout(&layers.upper_path);
+            if ret != 0 {
+                return ret;
+            }
+        }
+    }
+    let old_lower = old_layers.as_ref().and_then(|layers| layers.lower);
+    if let Some(layers) = &old_layers {
+        if layers.lower.is_some() {
+            let ret = create_whiteout(&layers.upper_path);
+            if ret != 0 {
+                return ret;
+            }
+        }
+    }
+    // A copied up tree has no lower entries left to hide at its new place, or is opaque there
+    if old_lower.map_or(false, |lower| lower.is_dir()) {
+        let cleared = with_reentrancy_guard(None, || {
+            Some(whiteout::clear_tree(c_char_ptr_to_path(new_upper.as_ptr())))
+        });
+        return match cleared {
+            Some(Ok(())) => 0,
+            Some(Err(err)) => fail(err.raw_os_error().unwrap_or(EIO)),
+            None => fail(EIO),
+        };
+    }
+    ret
+}
+
+/// Returns the file system that a rename of `path` operates on, which is the one of its upper dir
+/// for paths of the merged view.
+fn rename_device(path: &Path, layers: &Option<redir::Layers>) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    match layers {
+        Some(layers) => layers.mapping.upper_dev,
+        None => {
+            let parent = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            parent.metadata().ok().map(|parent| parent.dev())
+        }
+    }
+}
+
+/// Checks whether an entry of type `old` may replace the existing entry `new` of the merged view.
+/// The kernel only sees the upper entries, so replacing lower ones is checked like it would.
+fn check_rename_target(old: Option<std::fs::FileType>, new: &redir::Layers) -> Result<(), c_int> {
+    let (old, replaced) = match (old, new.upper.or(new.lower)) {
+        (Some(old), Some(replaced)) => (old, replaced),
+        _ => return Ok(()),
+    };
+    match (old.is_dir(), replaced.is_dir()) {
+        (true, false) => Err(ENOTDIR),
+        (false, true) => Err(EISDIR),
+        (true, true) => match redir::is_empty_dir(new) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ENOTEMPTY),
+            Err(err) => Err(err.raw_os_error().unwrap_or(EIO)),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Prepares the empty directory `new` of the merged view for being replaced by another directory.
+/// Its upper directory may still hold whiteouts, which the kernel does not consider empty. They are
+/// removed, after making the directory opaque to keep the lower entries hidden.
+fn clear_replaced_dir(new: &redir::Layers) -> Result<(), c_int> {
+    if !new.upper.map_or(false, |upper| upper.is_dir()) {
+        return Ok(());
+    }
+    let errno = |err: std::io::Error| err.raw_os_error().unwrap_or(EIO);
+    if new.lower.is_some() {
+        whiteout::create(&new.upper_path).map_err(errno)?;
+    }
+    whiteout::clear(&new.upper_path).map_err(errno)
+}
+
+/// Makes sure that an existing entry of the merged view exists in the upper dir.
+fn copy_up_existing(layers: &redir::Layers) -> Result<PathBuf, c_int> {
+    if layers.mapping.read_only {
+        return Err(EROFS);
+    }
+    // Only copying up is refused, entries that are in the upper dir already may be moved
+    let refuses_copy_up = layers.mapping.copy_up == CopyUp::Never && layers.lower.is_some();
+    if let Some(upper) = layers.upper {
+        // The marker of a metadata-only copy does not move along with it
+        if metacopy::is_stub(&layers.upper_path) {
+            metacopy::copy_contents(&layers.path, &layers.upper_path).ok_or(EIO)?;
+        }
+        // The entries of a lower directory merged into an upper one would be left behind
+        if upper.is_dir() && layers.lower.map_or(false, |lower| lower.is_dir()) {
+            let lower_only = redir::Layers {
+                mapping: layers.mapping,
+                path: layers.path.clone(),
+                upper_path: layers.upper_path.clone(),
+                upper: None,
+                lower: layers.lower,
+            };
+            if !redir::is_empty_dir(&lower_only).map_err(|err| err.raw_os_error().unwrap_or(EIO))? {
+                if refuses_copy_up {
+                    return Err(EROFS);
+                }
+                copy_up_dir_tree(layers)?;
+            }
+        }
+        return Ok(layers.upper_path.clone());
+    }
+    if refuses_copy_up {
+        return Err(EROFS);
+    }
+    match layers.lower {
+        None => Err(ENOENT),
+        Some(lower) if lower.is_dir() => {
+            copy_up_dir_tree(layers)?;
+            Ok(layers.upper_path.clone())
+        }
+        Some(_) => {
+            redir::create_upper_parent(&layers.upper_path).ok_or(EIO)?;
+            // Another process may have copied it up in the meantime
+            let _lock = lock::copy_up(&layers.upper_path);
+            if layers.upper_path.symlink_metadata().is_err() {
+                let size = layers
+                    .path
+                    .symlink_metadata()
+                    .map_or(0, |lower| lower.len());
+                if !quota::reserve(size, 1) {
+                    return Err(ENOSPC);
+                }
+                redir::copy_up(&layers.path, &layers.upper_path).ok_or(EIO)?;
+            }
+            Ok(layers.upper_path.clone())
+        }
+    }
+}
+
+/// Copies the lower directory of `layers` up with all of its entries. Directories nested deeper
+/// than configured are not copied, like overlayfs without redirect_dir the caller has to fall back
+/// to copying them.
+fn copy_up_dir_tree(layers: &redir::Layers) -> Result<(), c_int> {
+    let depth = config::get_config().and_then(|cfg| cfg.dir_copy_up_depth);
+    if let Some(depth) = depth {
+        if !redir::tree_within_depth(&layers.path, depth).map_err(|_| EIO)? {
+            return Err(EXDEV);
+        }
+    }
+    match redir::copy_up_tree(&layers.path) {
+        Some(true) => Ok(()),
+        Some(false) => Err(ENOSPC),
+        None => Err(EIO),
+    }
+}
+
+/// Returns where a new entry of the merged view has to be created in the upper dir.
+fn upper_for_new_entry(layers: &redir::Layers) -> Result<PathBuf, c_int> {
+    if layers.mapping.read_only {
+        return Err(EROFS);
+    }
+    if layers.path.parent().map_or(false, Path::exists) {
+        redir::create_upper_parent(&layers.upper_path).ok_or(EIO)?;
+    }
+    Ok(layers.upper_path.clone())
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_LINK, b"link\0", (old: *const c_char, new: *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn link(old: *const c_char, new: *const c_char) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "link",
+            &[
+                ("old", Arg::Str(CStr::from_ptr(old).to_string_lossy())),
+                ("new", Arg::Str(CStr::from_ptr(new).to_string_lossy())),
+            ],
+        )
+    });
+    let ret = link_merged(old, new, |old, new| C_LINK.call(old, new));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_LINKAT, b"linkat\0", (olddirfd: c_int, old: *const c_char, newdirfd: c_int, new: *const c_char, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn linkat(
+    olddirfd: c_int,
+    old: *const c_char,
+    newdirfd: c_int,
+    new: *const c_char,
+    flags: c_int,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "linkat",
+            &[
+                ("olddirfd", Arg::Dec(&olddirfd)),
+                ("old", Arg::Str(CStr::from_ptr(old).to_string_lossy())),
+                ("newdirfd", Arg::Dec(&newdirfd)),
+                ("new", Arg::Str(CStr::from_ptr(new).to_string_lossy())),
+                ("flags", Arg::Hex(&flags)),
+            ],
+        )
+    });
+    // With AT_EMPTY_PATH, the file olddirfd refers to is linked, e.g. one opened with O_TMPFILE.
+    // It is named by its path instead, so that it can be copied where linking is not possible.
+    let (resolved_old, flags) = if flags & AT_EMPTY_PATH != 0 && *old == 0 {
+        let named = with_reentrancy_guard(None, || fd_link_source(olddirfd));
+        (named, flags & !AT_EMPTY_PATH | AT_SYMLINK_FOLLOW)
+    } else {
+        // Relative paths are resolved against the corresponding dirfd, which will be ignored
+        // afterwards.
+        (
+            with_reentrancy_guard(None, || resolve_at(olddirfd, old)),
+            flags,
+        )
+    };
+    let old = resolved_old
+        .as_ref()
+        .map_or(old, |resolved| resolved.as_ptr());
+    let resolved_new = with_reentrancy_guard(None, || resolve_at(newdirfd, new));
+    let new = resolved_new
+        .as_ref()
+        .map_or(new, |resolved| resolved.as_ptr());
+    let ret = link_merged(old, new, |old, new| {
+        C_LINKAT.call(olddirfd, old, newdirfd, new, flags)
+    });
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+/// Names the file `fd` refers to for linking. Files of the merged view are named by their path, so
+/// that the usual copy-up applies, others like anonymous files by their entry in `/proc`.
+fn fd_link_source(fd: c_int) -> Option<CString> {
+    if let Some(path) = redir::fd_in_lower(fd) {
+        if redir::layers(&path).map_or(false, |layers| layers.lower.is_some()) {
+            return path_to_cstring(&path);
+        }
+    }
+    CString::new(format!("/proc/self/fd/{}", fd)).ok()
+}
+
+/// Creates a hard link in the merged view. The link is created between upper files, copying the
+/// source up first if necessary. Where a hard link would cross file systems, the new name gets an
+/// independent copy instead.
+unsafe fn link_merged<F: FnOnce(*const c_char, *const c_char) -> c_int>(
+    old: *const c_char,
+    new: *const c_char,
+    link: F,
+) -> c_int {
+    let old_layers = with_reentrancy_guard(None, || redir::layers(c_char_ptr_to_path(old)));
+    let new_layers = with_reentrancy_guard(None, || redir::layers(c_char_ptr_to_path(new)));
+    if old_layers.is_none() && new_layers.is_none() {
+        return link(old, new);
+    }
+
+    let prepared = with_reentrancy_guard(Err(EIO), || {
+        let old_upper = match &old_layers {
+            // Directories cannot be linked, there is no need to copy them up first
+            Some(layers) if layers.upper.or(layers.lower).map_or(false, |t| t.is_dir()) => {
+                return Err(EPERM)
+            }
+            Some(layers) => copy_up_existing(layers)?,
+            None => c_char_ptr_to_path(old).to_owned(),
+        };
+        let new_upper = match &new_layers {
+            Some(layers) if layers.upper.is_some() || layers.lower.is_some() => return Err(EEXIST),
+            Some(layers) => upper_for_new_entry(layers)?,
+            None => c_char_ptr_to_path(new).to_owned(),
+        };
+        Ok((old_upper, new_upper))
+    });
+    let (old_upper, new_upper) = match prepared {
+        Ok(prepared) => prepared,
+        Err(errno) => return fail(errno),
+    };
+    let (cold_upper, cnew_upper) = match (path_to_cstring(&old_upper), path_to_cstring(&new_upper))
+    {
+        (Some(cold_upper), Some(cnew_upper)) => (cold_upper, cnew_upper),
+        _ => return fail(EINVAL),
+    };
+
+    let ret = link(cold_upper.as_ptr(), cnew_upper.as_ptr());
+    if ret != 0 && errno() == EXDEV {
+        log::debug(Category::Copy, || {
+            log_println!("liboverlay: copying instead of linking across devices")
+        });
+        let copied = with_reentrancy_guard(None, || Some(std::fs::copy(&old_upper, &new_upper)));
+        return match copied {
+            Some(Ok(_)) => 0,
+            Some(Err(err)) => fail(err.raw_os_error().unwrap_or(EIO)),
+            None => fail(EIO),
+        };
+    }
+    ret
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+const W_OK: c_int = 2;
+
+import_real!(C_ACCESS, b"access\0", (path: *const c_char, mode: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn access(path: *const c_char, mode: c_int) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "access",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+            ],
+        )
+    });
+    let ret = access_merged(path, mode, |path, mode| C_ACCESS.call(path, mode));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_EUIDACCESS, b"euidaccess\0", (path: *const c_char, mode: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn euidaccess(path: *const c_char, mode: c_int) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "euidaccess",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+            ],
+        )
+    });
+    let ret = access_merged(path, mode, |path, mode| C_EUIDACCESS.call(path, mode));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_EACCESS, b"eaccess\0", (path: *const c_char, mode: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn eaccess(path: *const c_char, mode: c_int) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "eaccess",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+            ],
+        )
+    });
+    let ret = access_merged(path, mode, |path, mode| C_EACCESS.call(path, mode));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+// glibc has no separate wrapper for the faccessat2 syscall, `faccessat` uses it internally.
+import_real!(C_FACCESSAT, b"faccessat\0", (dirfd: c_int, path: *const c_char, mode: c_int, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn faccessat(
+    dirfd: c_int,
+    path: *const c_char,
+    mode: c_int,
+    flags: c_int,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "faccessat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+                ("flags", Arg::Hex(&flags)),
+            ],
+        )
+    });
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards. The
+    // flags (including AT_EACCESS) apply to the redirected path just as well.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let ret = access_merged(path, mode, |path, mode| {
+        C_FACCESSAT.call(dirfd, path, mode, flags)
+    });
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+/// Checks accessibility in the merged view. A file that only exists in the lower dir is writable
+/// if it can be read, since writing to it will make a writable copy first, unless the options of
+/// its mapping refuse that.
+unsafe fn access_merged<F: FnOnce(*const c_char, c_int) -> c_int>(
+    path: *const c_char,
+    mode: c_int,
+    access: F,
+) -> c_int {
+    if mode & W_OK != 0 && with_reentrancy_guard(false, || refuses_changes(path)) {
+        return fail(EROFS);
+    }
+    let redir_path = with_reentrancy_guard(None, || redirect_followed_raw(path, false));
+    match redir_path {
+        Some(redir) => access(redir.as_ptr(), mode),
+        None if mode & W_OK != 0 && with_reentrancy_guard(false, || is_in_lower(path)) => {
+            access(path, mode & !W_OK)
+        }
+        None => access(path, mode),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_CHMOD, b"chmod\0", (path: *const c_char, mode: mode_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn chmod(path: *const c_char, mode: mode_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "chmod",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+            ],
+        )
+    });
+    // Changing the mode requires an upper copy to change
+    let redir_path = with_reentrancy_guard(None, || redirect_metadata_raw(path, true));
+    let ret = match &redir_path {
+        Some(redir) => C_CHMOD.call(redir.as_ptr(), mode),
+        None => C_CHMOD.call(path, mode),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FCHMOD, b"fchmod\0", (fd: c_int, mode: mode_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn fchmod(fd: c_int, mode: mode_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "fchmod",
+            &[("fd", Arg::Dec(&fd)), ("mode", Arg::Oct(&mode))],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_metadata_fd(fd));
+    let ret = match &redir_path {
+        Some(redir) => C_CHMOD.call(redir.as_ptr(), mode),
+        None => C_FCHMOD.call(fd, mode),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FCHMODAT, b"fchmodat\0", (dirfd: c_int, path: *const c_char, mode: mode_t, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn fchmodat(
+    dirfd: c_int,
+    path: *const c_char,
+    mode: mode_t,
+    flags: c_int,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "fchmodat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+                ("flags", Arg::Hex(&flags)),
+            ],
+        )
+    });
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let redir_path = with_reentrancy_guard(None, || {
+        redirect_metadata_raw(path, flags & AT_SYMLINK_NOFOLLOW == 0)
+    });
+    let ret = match &redir_path {
+        Some(redir) => C_FCHMODAT.call(dirfd, redir.as_ptr(), mode, flags),
+        None => C_FCHMODAT.call(dirfd, path, mode, flags),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+#[allow(non_camel_case_types)]
+type uid_t = u32;
+#[allow(non_camel_case_types)]
+type gid_t = u32;
+
+import_real!(C_CHOWN, b"chown\0", (path: *const c_char, owner: uid_t, group: gid_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn chown(path: *const c_char, owner: uid_t, group: gid_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "chown",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("owner", Arg::Dec(&owner)),
+                ("group", Arg::Dec(&group)),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_metadata_raw(path, true));
+    let ret = match &redir_path {
+        Some(redir) => C_CHOWN.call(redir.as_ptr(), owner, group),
+        None => C_CHOWN.call(path, owner, group),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FCHOWN, b"fchown\0", (fd: c_int, owner: uid_t, group: gid_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn fchown(fd: c_int, owner: uid_t, group: gid_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "fchown",
+            &[
+                ("fd", Arg::Dec(&fd)),
+                ("owner", Arg::Dec(&owner)),
+                ("group", Arg::Dec(&group)),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_metadata_fd(fd));
+    let ret = match &redir_path {
+        Some(redir) => C_CHOWN.call(redir.as_ptr(), owner, group),
+        None => C_FCHOWN.call(fd, owner, group),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_LCHOWN, b"lchown\0", (path: *const c_char, owner: uid_t, group: gid_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn lchown(path: *const c_char, owner: uid_t, group: gid_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "lchown",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("owner", Arg::Dec(&owner)),
+                ("group", Arg::Dec(&group)),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_metadata_raw(path, false));
+    let ret = match &redir_path {
+        Some(redir) => C_LCHOWN.call(redir.as_ptr(), owner, group),
+        None => C_LCHOWN.call(path, owner, group),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FCHOWNAT, b"fchownat\0", (dirfd: c_int, path: *const c_char, owner: uid_t, group: gid_t, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn fchownat(
+    dirfd: c_int,
+    path: *const c_char,
+    owner: uid_t,
+    group: gid_t,
+    flags: c_int,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "fchownat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("owner", Arg::Dec(&owner)),
+                ("group", Arg::Dec(&group)),
+                ("flags", Arg::Hex(&flags)),
+            ],
+        )
+    });
+    // An empty path refers to dirfd itself with AT_EMPTY_PATH, just like fchown
+    let empty = flags & AT_EMPTY_PATH != 0 && *path == 0;
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let redir_path = with_reentrancy_guard(None, || {
+        if empty {
+            redirect_metadata_fd(dirfd)
+        } else {
+            redirect_metadata_raw(path, flags & AT_SYMLINK_NOFOLLOW == 0)
+        }
+    });
+    let ret = match &redir_path {
+        Some(redir) => C_FCHOWNAT.call(dirfd, redir.as_ptr(), owner, group, flags),
+        None => C_FCHOWNAT.call(dirfd, path, owner, group, flags),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_TRUNCATE, b"truncate\0", (path: *const c_char, length: off_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn truncate(path: *const c_char, length: off_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "truncate",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("length", Arg::Dec(&length)),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_followed_raw(path, true));
+    let ret = match &redir_path {
+        Some(redir) => C_TRUNCATE.call(redir.as_ptr(), length),
+        None => C_TRUNCATE.call(path, length),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_TRUNCATE64, b"truncate64\0", (path: *const c_char, length: off_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn truncate64(path: *const c_char, length: off_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "truncate64",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("length", Arg::Dec(&length)),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_followed_raw(path, true));
+    let ret = match &redir_path {
+        Some(redir) => C_TRUNCATE64.call(redir.as_ptr(), length),
+        None => C_TRUNCATE64.call(path, length),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_COPY_FILE_RANGE, b"copy_file_range\0", (fd_in: c_int, off_in: *mut off_t, fd_out: c_int, off_out: *mut off_t, len: usize, flags: c_uint) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn copy_file_range(
+    fd_in: c_int,
+    off_in: *mut off_t,
+    fd_out: c_int,
+    off_out: *mut off_t,
+    len: usize,
+    flags: c_uint,
+) -> isize {
+    log::trace(Category::Hook, || {
+        log::call(
+            "copy_file_range",
+            &[
+                ("fd_in", Arg::Dec(&fd_in)),
+                ("fd_out", Arg::Dec(&fd_out)),
+                ("len", Arg::Dec(&len)),
+            ],
+        )
+    });
+    let ret = if with_reentrancy_guard(true, || writable_fd(fd_out)) {
+        C_COPY_FILE_RANGE.call(fd_in, off_in, fd_out, off_out, len, flags)
+    } else {
+        fail(EROFS) as isize
+    };
+    log::trace(Category::Hook, || {
+        log::returned(ret as i64, errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_SENDFILE, b"sendfile\0", (out_fd: c_int, in_fd: c_int, offset: *mut off_t, count: usize) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn sendfile(
+    out_fd: c_int,
+    in_fd: c_int,
+    offset: *mut off_t,
+    count: usize,
+) -> isize {
+    log::trace(Category::Hook, || {
+        log::call(
+            "sendfile",
+            &[
+                ("out_fd", Arg::Dec(&out_fd)),
+                ("in_fd", Arg::Dec(&in_fd)),
+                ("count", Arg::Dec(&count)),
+            ],
+        )
+    });
+    let ret = if with_reentrancy_guard(true, || writable_fd(out_fd)) {
+        C_SENDFILE.call(out_fd, in_fd, offset, count)
+    } else {
+        fail(EROFS) as isize
+    };
+    log::trace(Category::Hook, || {
+        log::returned(ret as i64, errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_SENDFILE64, b"sendfile64\0", (out_fd: c_int, in_fd: c_int, offset: *mut off_t, count: usize) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn sendfile64(
+    out_fd: c_int,
+    in_fd: c_int,
+    offset: *mut off_t,
+    count: usize,
+) -> isize {
+    log::trace(Category::Hook, || {
+        log::call(
+            "sendfile64",
+            &[
+                ("out_fd", Arg::Dec(&out_fd)),
+                ("in_fd", Arg::Dec(&in_fd)),
+                ("count", Arg::Dec(&count)),
+            ],
+        )
+    });
+    let ret = if with_reentrancy_guard(true, || writable_fd(out_fd)) {
+        C_SENDFILE64.call(out_fd, in_fd, offset, count)
+    } else {
+        fail(EROFS) as isize
+    };
+    log::trace(Category::Hook, || {
+        log::returned(ret as i64, errno_if(ret == -1))
+    });
+    ret
+}
+
+/// Checks that writing to `fd` does not modify the lower dir. Descriptors opened through the
+/// overlay always refer to the upper dir, but ones that were inherited or opened before liboverlay
+/// was set up may not. Those writes are refused, loudly, rather than corrupting the lower dir.
+/// The standard streams are exempt, where they point is a deliberate choice of whoever started
+/// the process, e.g. a shell redirecting output when the whole root is overlaid.
+fn writable_fd(fd: c_int) -> bool {
+    if fd <= 2 {
+        return true;
+    }
+    match redir::fd_in_lower(fd) {
+        Some(path) => {
+            log::error(Category::Redir, || {
+                log_println!(
+                    "liboverlay: refusing to write to {} in the lower dir through fd {}",
+                    path.display(),
+                    fd
+                )
+            });
+            false
+        }
+        None => true,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_SYMLINK, b"symlink\0", (target: *const c_char, path: *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn symlink(target: *const c_char, path: *const c_char) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "symlink",
+            &[
+                ("target", Arg::Str(CStr::from_ptr(target).to_string_lossy())),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+            ],
+        )
+    });
+    // The target is stored as is, only the location of the link is redirected
+    let ret = create_merged(path, |path| C_SYMLINK.call(target, path));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_SYMLINKAT, b"symlinkat\0", (target: *const c_char, dirfd: c_int, path: *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn symlinkat(target: *const c_char, dirfd: c_int, path: *const c_char) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "symlinkat",
+            &[
+                ("target", Arg::Str(CStr::from_ptr(target).to_string_lossy())),
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+            ],
+        )
+    });
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let ret = create_merged(path, |path| C_SYMLINKAT.call(target, dirfd, path));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+/// Creates a new entry of the merged view in the upper dir, failing if there already is one.
+unsafe fn create_merged<F: FnOnce(*const c_char) -> c_int>(
+    path: *const c_char,
+    create: F,
+) -> c_int {
+    let layers = match with_reentrancy_guard(None, || redir::layers(c_char_ptr_to_path(path))) {
+        Some(layers) => layers,
+        None => return create(path),
+    };
+    if layers.upper.is_none() && layers.lower.is_some() {
+        return fail(EEXIST);
+    }
+    let upper = with_reentrancy_guard(Err(EIO), || upper_for_new_entry(&layers));
+    match upper.map(|upper| path_to_cstring(&upper)) {
+        Ok(Some(cupper)) => create(cupper.as_ptr()),
+        Ok(None) => fail(EINVAL),
+        Err(errno) => fail(errno),
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+#[allow(non_camel_case_types)]
+type dev_t = u64;
+
+import_real!(C_MKNOD, b"mknod\0", (path: *const c_char, mode: mode_t, dev: dev_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn mknod(path: *const c_char, mode: mode_t, dev: dev_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mknod",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+                ("dev", Arg::Hex(&dev)),
+            ],
+        )
+    });
+    let ret = create_merged(path, |path| C_MKNOD.call(path, mode, dev));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_MKNODAT, b"mknodat\0", (dirfd: c_int, path: *const c_char, mode: mode_t, dev: dev_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn mknodat(dirfd: c_int, path: *const c_char, mode: mode_t, dev: dev_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mknodat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+                ("dev", Arg::Hex(&dev)),
+            ],
+        )
+    });
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let ret = create_merged(path, |path| C_MKNODAT.call(dirfd, path, mode, dev));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+// glibc before 2.33 implements mknod and mknodat as inline wrappers around these
+
+import_real!(C_XMKNOD, b"__xmknod\0", (version: c_int, path: *const c_char, mode: mode_t, dev: *mut dev_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn __xmknod(
+    version: c_int,
+    path: *const c_char,
+    mode: mode_t,
+    dev: *mut dev_t,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "__xmknod",
+            &[
+                ("version", Arg::Dec(&version)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+            ],
+        )
+    });
+    let ret = create_merged(path, |path| C_XMKNOD.call(version, path, mode, dev));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_XMKNODAT, b"__xmknodat\0", (version: c_int, dirfd: c_int, path: *const c_char, mode: mode_t, dev: *mut dev_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn __xmknodat(
+    version: c_int,
+    dirfd: c_int,
+    path: *const c_char,
+    mode: mode_t,
+    dev: *mut dev_t,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "__xmknodat",
+            &[
+                ("version", Arg::Dec(&version)),
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+            ],
+        )
+    });
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let ret = create_merged(path, |path| {
+        C_XMKNODAT.call(version, dirfd, path, mode, dev)
+    });
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+// mkfifo calls mknod internally, which bypasses the hooks above
+
+import_real!(C_MKFIFO, b"mkfifo\0", (path: *const c_char, mode: mode_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn mkfifo(path: *const c_char, mode: mode_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mkfifo",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+            ],
+        )
+    });
+    let ret = create_merged(path, |path| C_MKFIFO.call(path, mode));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_MKFIFOAT, b"mkfifoat\0", (dirfd: c_int, path: *const c_char, mode: mode_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn mkfifoat(dirfd: c_int, path: *const c_char, mode: mode_t) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mkfifoat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mode", Arg::Oct(&mode)),
+            ],
+        )
+    });
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let ret = create_merged(path, |path| C_MKFIFOAT.call(dirfd, path, mode));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_READLINK, b"readlink\0", (path: *const c_char, buf: *mut c_char, bufsiz: usize) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn readlink(path: *const c_char, buf: *mut c_char, bufsiz: usize) -> isize {
+    log::trace(Category::Hook, || {
+        log::call(
+            "readlink",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    // The link itself is read, relative targets are therefore reported relative to the merged view
+    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+    let ret = match redir_path {
+        Some(redir) => C_READLINK.call(redir.as_ptr(), buf, bufsiz),
+        None => C_READLINK.call(path, buf, bufsiz),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(ret as i64, errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_READLINKAT, b"readlinkat\0", (dirfd: c_int, path: *const c_char, buf: *mut c_char, bufsiz: usize) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn readlinkat(
+    dirfd: c_int,
+    path: *const c_char,
+    buf: *mut c_char,
+    bufsiz: usize,
+) -> isize {
+    log::trace(Category::Hook, || {
+        log::call(
+            "readlinkat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+            ],
+        )
+    });
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+    let ret = match redir_path {
+        Some(redir) => C_READLINKAT.call(dirfd, redir.as_ptr(), buf, bufsiz),
+        None => C_READLINKAT.call(dirfd, path, buf, bufsiz),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(ret as i64, errno_if(ret == -1))
+    });
+    ret
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_GETXATTR, b"getxattr\0", (path: *const c_char, name: *const c_char, value: *mut c_void, size: usize) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn getxattr(
+    path: *const c_char,
+    name: *const c_char,
+    value: *mut c_void,
+    size: usize,
+) -> isize {
+    log::trace(Category::Hook, || {
+        log::call(
+            "getxattr",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("name", Arg::Str(CStr::from_ptr(name).to_string_lossy())),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_followed_raw(path, false));
+    let ret = match redir_path {
+        Some(redir) => C_GETXATTR.call(redir.as_ptr(), name, value, size),
+        None => C_GETXATTR.call(path, name, value, size),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(ret as i64, errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_LGETXATTR, b"lgetxattr\0", (path: *const c_char, name: *const c_char, value: *mut c_void, size: usize) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn lgetxattr(
+    path: *const c_char,
+    name: *const c_char,
+    value: *mut c_void,
+    size: usize,
+) -> isize {
+    log::trace(Category::Hook, || {
+        log::call(
+            "lgetxattr",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("name", Arg::Str(CStr::from_ptr(name).to_string_lossy())),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+    let ret = match redir_path {
+        Some(redir) => C_LGETXATTR.call(redir.as_ptr(), name, value, size),
+        None => C_LGETXATTR.call(path, name, value, size),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(ret as i64, errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_LISTXATTR, b"listxattr\0", (path: *const c_char, list: *mut c_char, size: usize) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn listxattr(path: *const c_char, list: *mut c_char, size: usize) -> isize {
+    log::trace(Category::Hook, || {
+        log::call(
+            "listxattr",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_followed_raw(path, false));
+    let ret = match redir_path {
+        Some(redir) => C_LISTXATTR.call(redir.as_ptr(), list, size),
+        None => C_LISTXATTR.call(path, list, size),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(ret as i64, errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_LLISTXATTR, b"llistxattr\0", (path: *const c_char, list: *mut c_char, size: usize) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn llistxattr(path: *const c_char, list: *mut c_char, size: usize) -> isize {
+    log::trace(Category::Hook, || {
+        log::call(
+            "llistxattr",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, false));
+    let ret = match redir_path {
+        Some(redir) => C_LLISTXATTR.call(redir.as_ptr(), list, size),
+        None => C_LLISTXATTR.call(path, list, size),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(ret as i64, errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_SETXATTR, b"setxattr\0", (path: *const c_char, name: *const c_char, value: *const c_void, size: usize, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn setxattr(
+    path: *const c_char,
+    name: *const c_char,
+    value: *const c_void,
+    size: usize,
+    flags: c_int,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "setxattr",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("name", Arg::Str(CStr::from_ptr(name).to_string_lossy())),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_followed_raw(path, true));
+    let ret = match &redir_path {
+        Some(redir) => C_SETXATTR.call(redir.as_ptr(), name, value, size, flags),
+        None => C_SETXATTR.call(path, name, value, size, flags),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_LSETXATTR, b"lsetxattr\0", (path: *const c_char, name: *const c_char, value: *const c_void, size: usize, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn lsetxattr(
+    path: *const c_char,
+    name: *const c_char,
+    value: *const c_void,
+    size: usize,
+    flags: c_int,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "lsetxattr",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("name", Arg::Str(CStr::from_ptr(name).to_string_lossy())),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, true));
+    let ret = match &redir_path {
+        Some(redir) => C_LSETXATTR.call(redir.as_ptr(), name, value, size, flags),
+        None => C_LSETXATTR.call(path, name, value, size, flags),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FSETXATTR, b"fsetxattr\0", (fd: c_int, name: *const c_char, value: *const c_void, size: usize, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn fsetxattr(
+    fd: c_int,
+    name: *const c_char,
+    value: *const c_void,
+    size: usize,
+    flags: c_int,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "fsetxattr",
+            &[
+                ("fd", Arg::Dec(&fd)),
+                ("name", Arg::Str(CStr::from_ptr(name).to_string_lossy())),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_fd(fd, true));
+    let ret = match &redir_path {
+        Some(redir) => C_SETXATTR.call(redir.as_ptr(), name, value, size, flags),
+        None => C_FSETXATTR.call(fd, name, value, size, flags),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_REMOVEXATTR, b"removexattr\0", (path: *const c_char, name: *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn removexattr(path: *const c_char, name: *const c_char) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "removexattr",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("name", Arg::Str(CStr::from_ptr(name).to_string_lossy())),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_followed_raw(path, true));
+    let ret = match &redir_path {
+        Some(redir) => C_REMOVEXATTR.call(redir.as_ptr(), name),
+        None => C_REMOVEXATTR.call(path, name),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_LREMOVEXATTR, b"lremovexattr\0", (path: *const c_char, name: *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn lremovexattr(path: *const c_char, name: *const c_char) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "lremovexattr",
+            &[
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("name", Arg::Str(CStr::from_ptr(name).to_string_lossy())),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(path, true));
+    let ret = match &redir_path {
+        Some(redir) => C_LREMOVEXATTR.call(redir.as_ptr(), name),
+        None => C_LREMOVEXATTR.call(path, name),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FREMOVEXATTR, b"fremovexattr\0", (fd: c_int, name: *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn fremovexattr(fd: c_int, name: *const c_char) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "fremovexattr",
+            &[
+                ("fd", Arg::Dec(&fd)),
+                ("name", Arg::Str(CStr::from_ptr(name).to_string_lossy())),
+            ],
+        )
+    });
+    // Like fsetxattr
+    let redir_path = with_reentrancy_guard(None, || redirect_fd(fd, true));
+    let ret = match &redir_path {
+        Some(redir) => C_REMOVEXATTR.call(redir.as_ptr(), name),
+        None => C_FREMOVEXATTR.call(fd, name),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_UTIME, b"utime\0", (path: *const c_char, times: *const c_void) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn utime(path: *const c_char, times: *const c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "utime",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_metadata_raw(path, true));
+    let ret = match &redir_path {
+        Some(redir) => C_UTIME.call(redir.as_ptr(), times),
+        None => C_UTIME.call(path, times),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_UTIMES, b"utimes\0", (path: *const c_char, times: *const c_void) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn utimes(path: *const c_char, times: *const c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "utimes",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_metadata_raw(path, true));
+    let ret = match &redir_path {
+        Some(redir) => C_UTIMES.call(redir.as_ptr(), times),
+        None => C_UTIMES.call(path, times),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_LUTIMES, b"lutimes\0", (path: *const c_char, times: *const c_void) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn lutimes(path: *const c_char, times: *const c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "lutimes",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_metadata_raw(path, false));
+    let ret = match &redir_path {
+        Some(redir) => C_LUTIMES.call(redir.as_ptr(), times),
+        None => C_LUTIMES.call(path, times),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_UTIMENSAT, b"utimensat\0", (dirfd: c_int, path: *const c_char, times: *const c_void, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn utimensat(
+    dirfd: c_int,
+    path: *const c_char,
+    times: *const c_void,
+    flags: c_int,
+) -> c_int {
+    // A null path refers to dirfd itself, just like futimens
+    if path.is_null() {
+        return futimens(dirfd, times);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "utimensat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("flags", Arg::Hex(&flags)),
+            ],
+        )
+    });
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let redir_path = with_reentrancy_guard(None, || {
+        if flags & AT_SYMLINK_NOFOLLOW != 0 {
+            redirect_metadata_raw(path, false)
+        } else {
+            redirect_metadata_raw(path, true)
+        }
+    });
+    let ret = match &redir_path {
+        Some(redir) => C_UTIMENSAT.call(dirfd, redir.as_ptr(), times, flags),
+        None => C_UTIMENSAT.call(dirfd, path, times, flags),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FUTIMENS, b"futimens\0", (fd: c_int, times: *const c_void) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn futimens(fd: c_int, times: *const c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call("futimens", &[("fd", Arg::Dec(&fd))])
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_metadata_fd(fd));
+    let ret = match &redir_path {
+        Some(redir) => C_UTIMENSAT.call(AT_FDCWD, redir.as_ptr(), times, 0),
+        None => C_FUTIMENS.call(fd, times),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FUTIMES, b"futimes\0", (fd: c_int, times: *const c_void) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn futimes(fd: c_int, times: *const c_void) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call("futimes", &[("fd", Arg::Dec(&fd))])
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_metadata_fd(fd));
+    let ret = match &redir_path {
+        Some(redir) => C_UTIMES.call(redir.as_ptr(), times),
+        None => C_FUTIMES.call(fd, times),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FUTIMESAT, b"futimesat\0", (dirfd: c_int, path: *const c_char, times: *const c_void) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn futimesat(dirfd: c_int, path: *const c_char, times: *const c_void) -> c_int {
+    // A null path refers to dirfd itself, just like futimes
+    if path.is_null() {
+        return futimes(dirfd, times);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "futimesat",
+            &[
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+            ],
+        )
+    });
+    // Relative paths are resolved against dirfd, so that dirfd will be ignored afterwards.
+    let resolved = with_reentrancy_guard(None, || resolve_at(dirfd, path));
+    let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+    let redir_path = with_reentrancy_guard(None, || redirect_metadata_raw(path, true));
+    let ret = match &redir_path {
+        Some(redir) => C_FUTIMESAT.call(dirfd, redir.as_ptr(), times),
+        None => C_FUTIMESAT.call(dirfd, path, times),
+    };
+    if ret < 0 {
+        refused_errno(redir_path.as_ref());
+    }
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_MKSTEMP, b"mkstemp\0", (template: *mut c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn mkstemp(template: *mut c_char) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mkstemp",
+            &[(
+                "template",
+                Arg::Str(CStr::from_ptr(template).to_string_lossy()),
+            )],
+        )
+    });
+    let ret = mktemp_merged(template, |template| C_MKSTEMP.call(template));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_MKSTEMP64, b"mkstemp64\0", (template: *mut c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn mkstemp64(template: *mut c_char) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mkstemp64",
+            &[(
+                "template",
+                Arg::Str(CStr::from_ptr(template).to_string_lossy()),
+            )],
+        )
+    });
+    let ret = mktemp_merged(template, |template| C_MKSTEMP64.call(template));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_MKOSTEMP, b"mkostemp\0", (template: *mut c_char, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn mkostemp(template: *mut c_char, flags: c_int) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mkostemp",
+            &[(
+                "template",
+                Arg::Str(CStr::from_ptr(template).to_string_lossy()),
+            )],
+        )
+    });
+    let ret = mktemp_merged(template, |template| C_MKOSTEMP.call(template, flags));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_MKOSTEMP64, b"mkostemp64\0", (template: *mut c_char, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn mkostemp64(template: *mut c_char, flags: c_int) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mkostemp64",
+            &[(
+                "template",
+                Arg::Str(CStr::from_ptr(template).to_string_lossy()),
+            )],
+        )
+    });
+    let ret = mktemp_merged(template, |template| C_MKOSTEMP64.call(template, flags));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_MKSTEMPS, b"mkstemps\0", (template: *mut c_char, suffixlen: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn mkstemps(template: *mut c_char, suffixlen: c_int) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mkstemps",
+            &[(
+                "template",
+                Arg::Str(CStr::from_ptr(template).to_string_lossy()),
+            )],
+        )
+    });
+    let ret = mktemp_merged(template, |template| C_MKSTEMPS.call(template, suffixlen));
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_MKOSTEMPS, b"mkostemps\0", (template: *mut c_char, suffixlen: c_int, flags: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn mkostemps(template: *mut c_char, suffixlen: c_int, flags: c_int) -> c_int {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mkostemps",
+            &[(
+                "template",
+                Arg::Str(CStr::from_ptr(template).to_string_lossy()),
+            )],
+        )
+    });
+    let ret = mktemp_merged(template, |template| {
+        C_MKOSTEMPS.call(template, suffixlen, flags)
+    });
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_MKDTEMP, b"mkdtemp\0", (template: *mut c_char) -> *mut c_char);
+
+#[no_mangle]
+unsafe extern "C" fn mkdtemp(template: *mut c_char) -> *mut c_char {
+    log::trace(Category::Hook, || {
+        log::call(
+            "mkdtemp",
+            &[(
+                "template",
+                Arg::Str(CStr::from_ptr(template).to_string_lossy()),
+            )],
+        )
+    });
+    let ret = mktemp_merged(template, |template| C_MKDTEMP.call(template));
+    // The real function returns its argument, which may have been the upper template
+    let ret = if ret.is_null() { ret } else { template };
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null()))
+    });
+    ret
+}
+
+/// Creates a temporary file or directory from a template in the lower dir. `make` is called with
+/// a copy of the template that points into the upper dir, and the unique name it generated is then
+/// copied back into the caller's template, which keeps referring to the merged view.
+unsafe fn mktemp_merged<R, F: FnOnce(*mut c_char) -> R>(template: *mut c_char, make: F) -> R {
+    let redir_path = with_reentrancy_guard(None, || redirect_path_raw(template, true));
+    let mut upper_template = match redir_path {
+        Some(redir) => redir.into_bytes_with_nul(),
+        None => return make(template),
+    };
+    let ret = make(upper_template.as_mut_ptr() as *mut c_char);
+
+    // Only the file name, which contains the `XXXXXX`, is changed
+    let template_bytes = CStr::from_ptr(template).to_bytes();
+    let name_len = template_bytes
+        .iter()
+        .rev()
+        .position(|c| *c == b'/')
+        .unwrap_or(template_bytes.len());
+    let generated = &upper_template[upper_template.len() - 1 - name_len..];
+    std::ptr::copy_nonoverlapping(
+        generated.as_ptr() as *const c_char,
+        template.add(template_bytes.len() - name_len),
+        name_len,
+    );
+    ret
+}
+
+/// The directory `tmpfile` creates its files in, `P_tmpdir` of glibc.
+const P_TMPDIR: &[u8] = b"/tmp\0";
+
+import_real!(C_TMPFILE, b"tmpfile\0", () -> *mut c_void);
+
+#[no_mangle]
+unsafe extern "C" fn tmpfile() -> *mut c_void {
+    log::trace(Category::Hook, || log::call("tmpfile", &[]));
+    let ret = tmpfile_merged(|| C_TMPFILE.call());
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null()))
+    });
+    ret
+}
+
+import_real!(C_TMPFILE64, b"tmpfile64\0", () -> *mut c_void);
+
+#[no_mangle]
+unsafe extern "C" fn tmpfile64() -> *mut c_void {
+    log::trace(Category::Hook, || log::call("tmpfile64", &[]));
+    let ret = tmpfile_merged(|| C_TMPFILE64.call());
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null()))
+    });
+    ret
+}
+
+/// Creates an anonymous temporary file like `tmpfile`, whose file glibc creates without going
+/// through the hooks. If `P_tmpdir` is overlaid, the file is created in the upper dir instead, with
+/// `O_TMPFILE` or else as a file that is removed right away.
+unsafe fn tmpfile_merged<F: FnOnce() -> *mut c_void>(tmpfile: F) -> *mut c_void {
+    let tmpdir = P_TMPDIR.as_ptr() as *const c_char;
+    let upper = match with_reentrancy_guard(None, || redirect_open(tmpdir, O_TMPFILE | O_RDWR)) {
+        Some(upper) => upper,
+        None => return tmpfile(),
+    };
+    let mut fd = C_OPEN.call(upper.as_ptr(), O_TMPFILE | O_RDWR, 0o600);
+    if fd < 0 {
+        let mut template = upper.into_bytes();
+        template.extend_from_slice(b"/tmpfXXXXXX\0");
+        let template = template.as_mut_ptr() as *mut c_char;
+        fd = C_MKSTEMP.call(template);
+        if fd < 0 {
+            return std::ptr::null_mut();
+        }
+        C_UNLINK.call(template);
+    }
+    let file = fdopen(fd, b"w+\0".as_ptr() as *const c_char);
+    if file.is_null() {
+        let e = errno();
+        C_CLOSE.call(fd);
+        set_errno(e);
+    }
+    file
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_EXECVE, b"execve\0", (path: *const c_char, argv: *const *const c_char, envp: *const *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn execve(
+    path: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    if !hooks_enabled(HookGroup::Exec) {
+        return C_EXECVE.call(path, argv, envp);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "execve",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    // Executables that have been replaced in the upper dir are run from there
+    let redir_path = with_reentrancy_guard(None, || redirect_executable_raw(path));
+    let path = redir_path.as_ref().map_or(path, |redir| redir.as_ptr());
+    let child_env = with_reentrancy_guard(None, || child_env(envp));
+    let child_envp = child_env.as_ref().map(|env| nul_terminated(env));
+    let envp = child_envp.as_ref().map_or(envp, |envp| envp.as_ptr());
+    let ret = C_EXECVE.call(path, argv, envp);
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+import_real!(C_FEXECVE, b"fexecve\0", (fd: c_int, argv: *const *const c_char, envp: *const *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn fexecve(
+    fd: c_int,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    if !hooks_enabled(HookGroup::Exec) {
+        return C_FEXECVE.call(fd, argv, envp);
+    }
+    log::trace(Category::Hook, || {
+        log::call("fexecve", &[("fd", Arg::Dec(&fd))])
+    });
+    let child_env = with_reentrancy_guard(None, || child_env(envp));
+    let child_envp = child_env.as_ref().map(|env| nul_terminated(env));
+    let envp = child_envp.as_ref().map_or(envp, |envp| envp.as_ptr());
+    let ret = C_FEXECVE.call(fd, argv, envp);
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+// glibc implements the remaining exec functions with internal calls to execve, so they are
+// reimplemented on top of the hook above. The variadic execl functions cannot be defined here.
+
+#[no_mangle]
+unsafe extern "C" fn execv(path: *const c_char, argv: *const *const c_char) -> c_int {
+    execve(path, argv, environ)
+}
+
+#[no_mangle]
+unsafe extern "C" fn execvp(file: *const c_char, argv: *const *const c_char) -> c_int {
+    execvpe(file, argv, environ)
+}
+
+#[no_mangle]
+unsafe extern "C" fn execvpe(
+    file: *const c_char,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    log::trace(Category::Hook, || {
+        log::void_call(
+            "execvpe",
+            &[("file", Arg::Str(CStr::from_ptr(file).to_string_lossy()))],
+        )
+    });
+    let name = CStr::from_ptr(file).to_bytes();
+    if name.is_empty() {
+        return fail(ENOENT);
+    }
+    if name.contains(&b'/') {
+        return execve(file, argv, envp);
+    }
+
+    use std::os::unix::ffi::OsStrExt;
+    let search_path = std::env::var_os("PATH").unwrap_or_else(|| "/bin:/usr/bin".into());
+    let mut denied = false;
+    for dir in search_path.as_bytes().split(|c| *c == b':') {
+        // An empty entry refers to the current directory
+        let mut candidate = if dir.is_empty() {
+            b".".to_vec()
+        } else {
+            dir.to_vec()
+        };
+        candidate.push(b'/');
+        candidate.extend_from_slice(name);
+        let candidate = match CString::new(candidate) {
+            Ok(candidate) => candidate,
+            Err(_) => continue,
+        };
+        execve(candidate.as_ptr(), argv, envp);
+        match errno() {
+            // Like the shell, run files that are not executables as scripts
+            ENOEXEC => return exec_script(&candidate, argv, envp),
+            EACCES => denied = true,
+            ENOENT | ENOTDIR => {}
+            _ => return -1,
+        }
+    }
+    fail(if denied { EACCES } else { ENOENT })
+}
+
+#[allow(non_camel_case_types)]
+type pid_t = c_int;
+
+import_real!(C_POSIX_SPAWN, b"posix_spawn\0", (pid: *mut pid_t, path: *const c_char, file_actions: *const c_void, attrp: *const c_void, argv: *const *const c_char, envp: *const *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn posix_spawn(
+    pid: *mut pid_t,
+    path: *const c_char,
+    file_actions: *const c_void,
+    attrp: *const c_void,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    if !hooks_enabled(HookGroup::Exec) {
+        return C_POSIX_SPAWN.call(pid, path, file_actions, attrp, argv, envp);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "posix_spawn",
+            &[("path", Arg::Str(CStr::from_ptr(path).to_string_lossy()))],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || redirect_executable_raw(path));
+    let path = redir_path.as_ref().map_or(path, |redir| redir.as_ptr());
+    let child_env = with_reentrancy_guard(None, || child_env(envp));
+    let child_envp = child_env.as_ref().map(|env| nul_terminated(env));
+    let envp = child_envp.as_ref().map_or(envp, |envp| envp.as_ptr());
+    let ret = C_POSIX_SPAWN.call(pid, path, file_actions, attrp, argv, envp);
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), Some(ret).filter(|&ret| ret != 0))
+    });
+    ret
+}
+
+import_real!(C_POSIX_SPAWNP, b"posix_spawnp\0", (pid: *mut pid_t, file: *const c_char, file_actions: *const c_void, attrp: *const c_void, argv: *const *const c_char, envp: *const *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn posix_spawnp(
+    pid: *mut pid_t,
+    file: *const c_char,
+    file_actions: *const c_void,
+    attrp: *const c_void,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    if !hooks_enabled(HookGroup::Exec) {
+        return C_POSIX_SPAWNP.call(pid, file, file_actions, attrp, argv, envp);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "posix_spawnp",
+            &[("file", Arg::Str(CStr::from_ptr(file).to_string_lossy()))],
+        )
+    });
+    // The search happens in the child, where it would not see executables in the upper dir. It is
+    // therefore done up front whenever it ends up in the lower dir.
+    let found = with_reentrancy_guard(None, || search_merged_path(file));
+    let child_env = with_reentrancy_guard(None, || child_env(envp));
+    let child_envp = child_env.as_ref().map(|env| nul_terminated(env));
+    let envp = child_envp.as_ref().map_or(envp, |envp| envp.as_ptr());
+    let ret = match found {
+        Some(path) => C_POSIX_SPAWN.call(pid, path.as_ptr(), file_actions, attrp, argv, envp),
+        None => C_POSIX_SPAWNP.call(pid, file, file_actions, attrp, argv, envp),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), Some(ret).filter(|&ret| ret != 0))
+    });
+    ret
+}
+
+/// Searches `PATH` for the executable `file` like execvp. Returns the path to run if the search
+/// ends in the lower dir, and `None` if it can be left to libc.
+fn search_merged_path(file: *const c_char) -> Option<CString> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    let name = c_char_ptr_to_path(file);
+    if name.as_os_str().is_empty() || name.as_os_str().as_bytes().contains(&b'/') {
+        return None;
+    }
+    let search_path = std::env::var_os("PATH")?;
+    for dir in std::env::split_paths(&search_path) {
+        let candidate = dir.join(name);
+        let in_lower = redir::layers(&candidate).is_some();
+        let resolved = redirect_followed(&candidate, false).unwrap_or_else(|| candidate.clone());
+        let executable = std::fs::metadata(&resolved).map_or(false, |meta| {
+            meta.is_file() && meta.permissions().mode() & 0o111 != 0
+        });
+        if executable {
+            return if in_lower {
+                path_to_cstring(&resolved)
+            } else {
+                None
+            };
+        }
+    }
+    None
+}
+
+/// Runs `path` with `/bin/sh`, passing along the arguments in `argv`.
+unsafe fn exec_script(
+    path: &CStr,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+) -> c_int {
+    let shell = b"/bin/sh\0".as_ptr() as *const c_char;
+    let mut script_argv = vec![shell, path.as_ptr()];
+    if !argv.is_null() && !(*argv).is_null() {
+        let mut i = 1;
+        while !(*argv.add(i)).is_null() {
+            script_argv.push(*argv.add(i));
+            i += 1;
+        }
+    }
+    script_argv.push(std::ptr::null());
+    execve(shell, script_argv.as_ptr(), envp)
+}
+
+// Whether the processes started by this thread are overlaid, see `liboverlay_overlay_children`
+thread_local! {
+    static CHILDREN_OVERLAID: Cell<bool> = Cell::new(true);
+}
+
+/// Sets whether the processes that the calling thread starts from now on are overlaid as well, e.g.
+/// to run a helper on the real file system, and returns whether they were before. Programs look it
+/// up with `dlsym`, so that they also run without liboverlay.
+#[no_mangle]
+pub extern "C" fn liboverlay_overlay_children(overlaid: c_int) -> c_int {
+    CHILDREN_OVERLAID.with(|children_overlaid| children_overlaid.replace(overlaid != 0)) as c_int
+}
+
+/// Returns the environment for a child process if the overlay configuration needs to be added to
+/// `envp`, so that the child is overlaid as well even if the environment has been scrubbed. Children
+/// that are not overlaid get `envp` without liboverlay in `LD_PRELOAD` instead.
+fn child_env(envp: *const *const c_char) -> Option<Vec<CString>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let cfg = config::get_config()?;
+    let mut env = Vec::new();
+    if !envp.is_null() {
+        let mut i = 0;
+        unsafe {
+            while !(*envp.add(i)).is_null() {
+                env.push(CStr::from_ptr(*envp.add(i)).to_owned());
+                i += 1;
+            }
+        }
+    }
+    if cfg.disable_for_children || !CHILDREN_OVERLAID.with(Cell::get) {
+        return env_without_preload(cfg, env);
+    }
+    let mut changed = false;
+    for (name, value) in &cfg.inherited_env {
+        let prefix = format!("{}=", name);
+        let existing = env
+            .iter()
+            .position(|var| var.as_bytes().starts_with(prefix.as_bytes()));
+        let mut var = prefix.into_bytes();
+        match existing {
+            Some(index) => {
+                // Other preloaded libraries are kept, liboverlay is added to them
+                let current = &env[index].as_bytes()[var.len()..];
+                if *name != "LD_PRELOAD" || contains_bytes(current, value.as_bytes()) {
+                    continue;
+                }
+                var.extend_from_slice(current);
+                var.push(b':');
+                var.extend_from_slice(value.as_bytes());
+                env[index] = CString::new(var).ok()?;
+            }
+            None => {
+                var.extend_from_slice(value.as_bytes());
+                env.push(CString::new(var).ok()?);
+            }
+        }
+        changed = true;
+    }
+    if changed {
+        Some(env)
+    } else {
+        None
+    }
+}
+
+/// Removes liboverlay from `LD_PRELOAD` in `env`, dropping the variable if nothing else is left to
+/// preload. Returns `None` if it is not preloaded.
+fn env_without_preload(cfg: &config::Config, mut env: Vec<CString>) -> Option<Vec<CString>> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let library = std::fs::metadata(cfg.library.as_ref()?).ok()?;
+    let index = env
+        .iter()
+        .position(|var| var.as_bytes().starts_with(b"LD_PRELOAD="))?;
+    // The dynamic loader separates entries by colons as well as spaces
+    let entries = env[index].as_bytes()[b"LD_PRELOAD=".len()..]
+        .split(|c| *c == b':' || *c == b' ')
+        .filter(|entry| !entry.is_empty())
+        .collect::<Vec<_>>();
+    let kept = entries
+        .iter()
+        .filter(|entry| {
+            std::fs::metadata(OsStr::from_bytes(entry)).map_or(true, |meta| {
+                meta.dev() != library.dev() || meta.ino() != library.ino()
+            })
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if kept.len() == entries.len() {
+        return None;
+    }
+    if kept.is_empty() {
+        env.remove(index);
+    } else {
+        let mut var = b"LD_PRELOAD=".to_vec();
+        var.extend_from_slice(&kept.join(&b':'));
+        env[index] = CString::new(var).ok()?;
+    }
+    Some(env)
+}
+
+fn contains_bytes(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len().max(1))
+        .any(|window| window == needle)
+}
+
+/// Builds a null-terminated array of pointers to `strings`, as used for `argv` and `envp`.
+fn nul_terminated(strings: &[CString]) -> Vec<*const c_char> {
+    strings
+        .iter()
+        .map(|string| string.as_ptr())
+        .chain(std::iter::once(std::ptr::null()))
+        .collect()
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+// An entry that only exists in the lower dir never changes there, all modifications happen to its
+// upper copy instead. Watched entries are therefore copied up, so that there is something to watch.
+
+const IN_DONT_FOLLOW: u32 = 0x0200_0000;
+
+import_real!(C_INOTIFY_ADD_WATCH, b"inotify_add_watch\0", (fd: c_int, path: *const c_char, mask: u32) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn inotify_add_watch(fd: c_int, path: *const c_char, mask: u32) -> c_int {
+    if !hooks_enabled(HookGroup::Watch) {
+        return C_INOTIFY_ADD_WATCH.call(fd, path, mask);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "inotify_add_watch",
+            &[
+                ("fd", Arg::Dec(&fd)),
+                ("path", Arg::Str(CStr::from_ptr(path).to_string_lossy())),
+                ("mask", Arg::Hex(&mask)),
+            ],
+        )
+    });
+    let redir_path = with_reentrancy_guard(None, || {
+        redirect_watched_raw(path, mask & IN_DONT_FOLLOW == 0, true)
+    });
+    let ret = match redir_path {
+        Some(redir) => C_INOTIFY_ADD_WATCH.call(fd, redir.as_ptr(), mask),
+        None => C_INOTIFY_ADD_WATCH.call(fd, path, mask),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+const FAN_MARK_REMOVE: c_uint = 0x02;
+const FAN_MARK_DONT_FOLLOW: c_uint = 0x04;
+const FAN_MARK_FLUSH: c_uint = 0x80;
+
+import_real!(C_FANOTIFY_MARK, b"fanotify_mark\0", (fd: c_int, flags: c_uint, mask: u64, dirfd: c_int, path: *const c_char) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn fanotify_mark(
+    fd: c_int,
+    flags: c_uint,
+    mask: u64,
+    dirfd: c_int,
+    path: *const c_char,
+) -> c_int {
+    // Flushing ignores the path, and a null path refers to dirfd itself
+    if flags & FAN_MARK_FLUSH != 0 || !hooks_enabled(HookGroup::Watch) {
+        return C_FANOTIFY_MARK.call(fd, flags, mask, dirfd, path);
+    }
+    log::trace(Category::Hook, || {
+        let path = if path.is_null() {
+            "".into()
+        } else {
+            CStr::from_ptr(path).to_string_lossy()
+        };
+        log::call(
+            "fanotify_mark",
+            &[
+                ("fd", Arg::Dec(&fd)),
+                ("flags", Arg::Hex(&flags)),
+                ("mask", Arg::Hex(&mask)),
+                ("dirfd", Arg::Dec(&dirfd)),
+                ("path", Arg::Str(path)),
+            ],
+        )
+    });
+    // Removing a mark must not copy anything up
+    let write = flags & FAN_MARK_REMOVE == 0;
+    let redir_path = with_reentrancy_guard(None, || {
+        if path.is_null() {
+            return redirect_fd(dirfd, write);
+        }
+        let resolved = resolve_at(dirfd, path);
+        let path = resolved.as_ref().map_or(path, |resolved| resolved.as_ptr());
+        redirect_watched_raw(path, flags & FAN_MARK_DONT_FOLLOW == 0, write)
+    });
+    let ret = match redir_path {
+        Some(redir) => C_FANOTIFY_MARK.call(fd, flags, mask, AT_FDCWD, redir.as_ptr()),
+        None => C_FANOTIFY_MARK.call(fd, flags, mask, dirfd, path),
+    };
+    log::trace(Category::Hook, || {
+        log::returned(i64::from(ret), errno_if(ret == -1))
+    });
+    ret
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_SIGACTION, b"sigaction\0", (signum: c_int, act: *const reload::SigAction, oldact: *mut reload::SigAction) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn sigaction(
+    signum: c_int,
+    act: *const reload::SigAction,
+    oldact: *mut reload::SigAction,
+) -> c_int {
+    // SIGHUP reloads the configuration, the action of the program runs after that
+    if signum != reload::SIGHUP || !reload::installed() {
+        return C_SIGACTION.call(signum, act, oldact);
+    }
+    let previous = reload::replace_action(act.as_ref());
+    if let Some(oldact) = oldact.as_mut() {
+        *oldact = previous;
+    }
+    0
+}
+
+import_real!(C__EXIT, b"_exit\0", (status: c_int) -> !);
+
+#[no_mangle]
+unsafe extern "C" fn _exit(status: c_int) -> ! {
+    // Shells like dash leave with `_exit`, the upper dir they created is removed all the same
+    config::remove_created_upper_dir();
+    C__EXIT.call(status)
+}
+
+import_real!(C_SIGNAL, b"signal\0", (signum: c_int, handler: usize) -> usize);
+
+#[no_mangle]
+unsafe extern "C" fn signal(signum: c_int, handler: usize) -> usize {
+    if signum != reload::SIGHUP || !reload::installed() {
+        return C_SIGNAL.call(signum, handler);
+    }
+    reload::replace_action(Some(&reload::signal_action(handler))).handler
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_DLOPEN, b"dlopen\0", (filename: *const c_char, flags: c_int) -> *mut c_void);
+
+#[no_mangle]
+unsafe extern "C" fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void {
+    // A null filename refers to the main program
+    if filename.is_null() {
+        return C_DLOPEN.call(filename, flags);
+    }
+    log::trace(Category::Hook, || {
+        log::call(
+            "dlopen",
+            &[
+                (
+                    "filename",
+                    Arg::Str(CStr::from_ptr(filename).to_string_lossy()),
+                ),
+                ("flags", Arg::Hex(&flags)),
+            ],
+        )
+    });
+    // Names without a slash are searched for by the dynamic linker rather than resolved against the
+    // working directory, so only paths are redirected.
+    let has_slash = CStr::from_ptr(filename).to_bytes().contains(&b'/');
+    let redir_path = if has_slash {
+        with_reentrancy_guard(None, || redirect_contents_raw(filename))
+    } else {
+        None
+    };
+    let ret = match redir_path {
+        Some(redir) => C_DLOPEN.call(redir.as_ptr(), flags),
+        None => C_DLOPEN.call(filename, flags),
+    };
+    log::trace(Category::Hook, || {
+        log::returned_pointer(ret as usize, errno_if(ret.is_null()))
+    });
+    ret
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+import_real!(C_SYSCALL, b"syscall\0", (number: c_long, a1: c_long, a2: c_long, a3: c_long, a4: c_long, a5: c_long, a6: c_long) -> c_long);
+
+// HACK: syscall is actually a varargs function, of which we always read six arguments. Unused ones
+// just contain garbage, which is fine on ABIs that pass varargs in the same registers as fixed
+// arguments, like x86_64 and aarch64 on Linux.
+#[no_mangle]
+unsafe extern "C" fn syscall(
+    number: c_long,
+    a1: c_long,
+    a2: c_long,
+    a3: c_long,
+    a4: c_long,
+    a5: c_long,
+    a6: c_long,
+) -> c_long {
+    let args = [a1, a2, a3, a4, a5, a6];
+    // Calls that are routed through our hooks end up there with the same errno convention
+    let hook = SYSCALL_HOOKS
+        .iter()
+        .chain(LEGACY_SYSCALL_HOOKS)
+        .find(|(hooked, _)| *hooked == number);
+    match hook {
+        Some((_, hook)) => hook(&args),
+        None => C_SYSCALL.call(number, a1, a2, a3, a4, a5, a6),
+    }
+}
+
+type SyscallHook = unsafe fn(&[c_long; 6]) -> c_long;
+
+/// The system calls that `syscall` routes through the hooks, with the hook that handles each.
+static SYSCALL_HOOKS: &[(c_long, SyscallHook)] = &[
+    (sysno::OPENAT, |a| unsafe {
+        openat(
+            a[0] as c_int,
+            a[1] as *const c_char,
+            a[2] as c_int,
+            a[3] as mode_t,
+        ) as c_long
+    }),
+    (sysno::OPENAT2, |a| unsafe {
+        openat2(
+            a[0] as c_int,
+            a[1] as *const c_char,
+            a[2] as *mut open_how,
+            a[3] as usize,
+        ) as c_long
+    }),
+    (sysno::NEWFSTATAT, |a| unsafe {
+        let follow = a[3] as c_int & AT_SYMLINK_NOFOLLOW == 0;
+        syscall_redirected(sysno::NEWFSTATAT, a, 1, |path| {
+            redirect_at_raw(a[0] as c_int, path, false, follow)
+        })
+    }),
+    (sysno::STATX, |a| unsafe {
+        statx(
+            a[0] as c_int,
+            a[1] as *const c_char,
+            a[2] as c_int,
+            a[3] as c_uint,
+            a[4] as *mut c_void,
+        ) as c_long
+    }),
+    // The system call has no flags, unlike the libc function
+    (sysno::FACCESSAT, |a| unsafe {
+        faccessat(a[0] as c_int, a[1] as *const c_char, a[2] as c_int, 0) as c_long
+    }),
+    (sysno::FACCESSAT2, |a| unsafe {
+        faccessat(
+            a[0] as c_int,
+            a[1] as *const c_char,
+            a[2] as c_int,
+            a[3] as c_int,
+        ) as c_long
+    }),
+    (sysno::MKDIRAT, |a| unsafe {
+        mkdirat(a[0] as c_int, a[1] as *const c_char, a[2] as mode_t) as c_long
+    }),
+    (sysno::UNLINKAT, |a| unsafe {
+        unlinkat(a[0] as c_int, a[1] as *const c_char, a[2] as c_int) as c_long
+    }),
+    (sysno::RENAMEAT, |a| unsafe {
+        renameat(
+            a[0] as c_int,
+            a[1] as *const c_char,
+            a[2] as c_int,
+            a[3] as *const c_char,
+        ) as c_long
+    }),
+    (sysno::RENAMEAT2, |a| unsafe {
+        renameat2(
+            a[0] as c_int,
+            a[1] as *const c_char,
+            a[2] as c_int,
+            a[3] as *const c_char,
+            a[4] as c_uint,
+        ) as c_long
+    }),
+    (sysno::GETDENTS64, |a| unsafe {
+        getdents64(a[0] as c_int, a[1] as *mut c_void, a[2] as usize) as c_long
+    }),
+    (sysno::LSEEK, |a| unsafe {
+        lseek(a[0] as c_int, a[1] as off_t, a[2] as c_int) as c_long
+    }),
+    (sysno::CLOSE, |a| unsafe { close(a[0] as c_int) as c_long }),
+];
+
+/// The system calls that only exist on some architectures.
+#[cfg(target_arch = "x86_64")]
+static LEGACY_SYSCALL_HOOKS: &[(c_long, SyscallHook)] = &[
+    (sysno::OPEN, |a| unsafe {
+        open(a[0] as *const c_char, a[1] as c_int, a[2] as mode_t) as c_long
+    }),
+    (sysno::STAT, |a| unsafe {
+        syscall_redirected(sysno::STAT, a, 0, |path| redirect_followed_raw(path, false))
+    }),
+    (sysno::LSTAT, |a| unsafe {
+        syscall_redirected(sysno::LSTAT, a, 0, |path| redirect_path_raw(path, false))
+    }),
+    (sysno::ACCESS, |a| unsafe {
+        access(a[0] as *const c_char, a[1] as c_int) as c_long
+    }),
+    (sysno::MKDIR, |a| unsafe {
+        mkdir(a[0] as *const c_char, a[1] as mode_t) as c_long
+    }),
+    (sysno::RMDIR, |a| unsafe {
+        rmdir(a[0] as *const c_char) as c_long
+    }),
+    (sysno::UNLINK, |a| unsafe {
+        unlink(a[0] as *const c_char) as c_long
+    }),
+    (sysno::RENAME, |a| unsafe {
+        rename(a[0] as *const c_char, a[1] as *const c_char) as c_long
+    }),
+];
+
+#[cfg(not(target_arch = "x86_64"))]
+static LEGACY_SYSCALL_HOOKS: &[(c_long, SyscallHook)] = &[];
+
+/// Issues a system call that only reads from the path at `args[index]`, which is redirected first.
+/// The kernel structures it fills are passed along untouched, so that no libc wrapper has to agree
+/// with them.
+unsafe fn syscall_redirected<F: FnOnce(*const c_char) -> Option<CString>>(
+    number: c_long,
+    args: &[c_long; 6],
+    index: usize,
+    redirect: F,
+) -> c_long {
+    let path = args[index] as *const c_char;
+    // The kernel reports invalid pointers, rather than us crashing on them
+    let redir_path = if path.is_null() {
+        None
+    } else {
+        with_reentrancy_guard(None, || redirect(path))
+    };
+    let mut args = *args;
+    if let Some(redir) = &redir_path {
+        args[index] = redir.as_ptr() as c_long;
+    }
+    C_SYSCALL.call(number, args[0], args[1], args[2], args[3], args[4], args[5])
+}
+
+// glibc only has a wrapper since 2.30, so the real function is always the system call.
+#[no_mangle]
+unsafe extern "C" fn getdents64(fd: c_int, buf: *mut c_void, count: usize) -> isize {
+    log::trace(Category::Hook, || {
+        log::call(
+            "getdents64",
+            &[("fd", Arg::Dec(&fd)), ("count", Arg::Dec(&count))],
+        )
+    });
+    let merged = if hooks_enabled(HookGroup::Readdir) {
+        with_reentrancy_guard(None, || merged_dir_fd(fd))
+    } else {
+        None
+    };
+    let ret = match merged {
+        Some((path, id)) => getdents_merged(fd, &path, id, buf as *mut u8, count),
+        None => C_SYSCALL.call(
+            sysno::GETDENTS64,
+            fd as c_long,
+            buf as c_long,
+            count as c_long,
+            0,
+            0,
+            0,
+        ) as isize,
+    };
+    log::trace(Category::Hook, || {
+        log::returned(ret as i64, errno_if(ret == -1))
+    });
+    ret
+}
+
+/// The merged contents of a directory that is being read with `getdents64`. Entries are handed
+/// out from this list rather than from the directory descriptor, the `d_off` of an entry is its
+/// index in the list plus one.
+struct Listing {
+    /// The device and inode number of the directory, which detect reuse of the descriptor.
+    id: (u64, u64),
+    entries: Vec<(u64, c_uchar, CString)>,
+    /// The index of the next entry to return.
+    next: usize,
+}
+
+static LISTINGS: AtomicPtr<Mutex<HashMap<c_int, Listing>>> = AtomicPtr::new(std::ptr::null_mut());
+
+/// The number of merged listings, which spares seeks and closes from taking the lock in the common
+/// case.
+static LISTING_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[used]
+#[cfg_attr(target_os = "linux", link_section = ".init_array")]
+pub static INIT_LISTINGS: extern "C" fn() = {
+    extern "C" fn init() {
+        let listings = Box::new(Mutex::new(HashMap::new()));
+        LISTINGS.store(Box::into_raw(listings), Ordering::SeqCst);
+    }
+    init
+};
+
+// Unlike the other hooks, close is likely to be called before our constructors ran
+fn listings() -> Option<&'static Mutex<HashMap<c_int, Listing>>> {
+    if LISTING_COUNT.load(Ordering::SeqCst) == 0 {
+        return None;
+    }
+    unsafe { LISTINGS.load(Ordering::SeqCst).as_ref() }
+}
+
+fn forget_listing(fd: c_int) {
+    let removed = listings().and_then(|listings| listings.lock().unwrap().remove(&fd));
+    if removed.is_some() {
+        LISTING_COUNT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Returns the merged path and the identity of the directory `fd` refers to, if it is part of the
+/// merged view.
+fn merged_dir_fd(fd: c_int) -> Option<(CString, (u64, u64))> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = redir::fd_path(fd)?;
+    redir::layers(&path)?;
+    let meta = std::fs::metadata(format!("/proc/self/fd/{}", fd)).ok()?;
+    if !meta.is_dir() {
+        return None;
+    }
+    Some((path_to_cstring(&path)?, (meta.dev(), meta.ino())))
+}
+
+unsafe fn getdents_merged(
+    fd: c_int,
+    path: &CStr,
+    id: (u64, u64),
+    buf: *mut u8,
+    count: usize,
+) -> isize {
+    let listings = match LISTINGS.load(Ordering::SeqCst).as_ref() {
+        Some(listings) => listings,
+        None => return fail(EIO) as isize,
+    };
+    let current = listings
+        .lock()
+        .unwrap()
+        .get(&fd)
+        .map_or(false, |listing| listing.id == id);
+    if !current {
+        // Listed before taking the lock, since closing the stream ends up in our close hook
+        let entries = match list_merged(path) {
+            Some(entries) => entries,
+            None => return -1,
+        };
+        let listing = Listing {
+            id,
+            entries,
+            next: 0,
+        };
+        if listings.lock().unwrap().insert(fd, listing).is_none() {
+            LISTING_COUNT.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut listings = listings.lock().unwrap();
+    let listing = match listings.get_mut(&fd) {
+        Some(listing) => listing,
+        None => return fail(EIO) as isize,
+    };
+    let name_offset = std::mem::size_of::<dirent64>();
+    let mut written = 0;
+    while let Some((ino, kind, name)) = listing.entries.get(listing.next) {
+        let name = name.as_bytes_with_nul();
+        // Entries are aligned like the kernel does
+        let reclen = (name_offset + name.len() + 7) & !7;
+        if written + reclen > count {
+            if written == 0 {
+                return fail(EINVAL) as isize;
+            }
+            break;
+        }
+        let entry = buf.add(written);
+        std::ptr::write_bytes(entry, 0, reclen);
+        let entry = entry as *mut dirent64;
+        (*entry).d_ino = *ino;
+        (*entry).d_off = listing.next as i64 + 1;
+        (*entry).d_reclen = reclen as c_ushort;
+        (*entry).d_type = *kind;
+        std::ptr::copy_nonoverlapping(
+            name.as_ptr() as *const c_char,
+            (*entry).d_name.as_mut_ptr(),
+            name.len(),
+        );
+        listing.next += 1;
+        written += reclen;
+    }
+    written as isize
+}
+
+/// Reads all entries of the merged directory `path` through our `opendir` and `readdir64` hooks.
+unsafe fn list_merged(path: &CStr) -> Option<Vec<(u64, c_uchar, CString)>> {
+    let dir = opendir(path.as_ptr(), 0);
+    if dir.is_null() {
+        return None;
+    }
+    let mut entries = Vec::new();
+    loop {
+        let entry = readdir64(dir);
+        if entry.is_null() {
+            break;
+        }
+        let name = dirent64::name(entry).to_owned();
+        entries.push(((*entry).d_ino, (*entry).d_type, name));
+    }
+    closedir(dir);
+    Some(entries)
+}
+
+const SEEK_SET: c_int = 0;
+const SEEK_CUR: c_int = 1;
+
+import_real!(C_LSEEK, b"lseek\0", (fd: c_int, offset: off_t, whence: c_int) -> off_t);
+
+#[no_mangle]
+unsafe extern "C" fn lseek(fd: c_int, offset: off_t, whence: c_int) -> off_t {
+    seek_listing(fd, offset, whence, |offset, whence| {
+        C_LSEEK.call(fd, offset, whence)
+    })
+}
+
+import_real!(C_LSEEK64, b"lseek64\0", (fd: c_int, offset: off_t, whence: c_int) -> off_t);
+
+#[no_mangle]
+unsafe extern "C" fn lseek64(fd: c_int, offset: off_t, whence: c_int) -> off_t {
+    seek_listing(fd, offset, whence, |offset, whence| {
+        C_LSEEK64.call(fd, offset, whence)
+    })
+}
+
+/// Seeks `fd` with `seek`, moving to the entry at the new offset when it is a merged directory,
+/// e.g. when it is rewound. `SEEK_CUR` is relative to the next entry of the listing, other seeks
+/// than `SEEK_SET` and `SEEK_CUR` end the listing.
+fn seek_listing<F: Fn(off_t, c_int) -> off_t>(
+    fd: c_int,
+    offset: off_t,
+    whence: c_int,
+    seek: F,
+) -> off_t {
+    let listings = match listings() {
+        Some(listings) => listings,
+        None => return seek(offset, whence),
+    };
+    let next = listings
+        .lock()
+        .unwrap()
+        .get(&fd)
+        .map(|listing| listing.next);
+    let (offset, whence) = match next {
+        Some(next) if whence == SEEK_CUR => ((next as off_t).saturating_add(offset), SEEK_SET),
+        Some(_) => (offset, whence),
+        None => return seek(offset, whence),
+    };
+    let ret = seek(offset, whence);
+    if ret >= 0 {
+        let mut listings = listings.lock().unwrap();
+        if whence != SEEK_SET {
+            if listings.remove(&fd).is_some() {
+                LISTING_COUNT.fetch_sub(1, Ordering::SeqCst);
+            }
+        } else if let Some(listing) = listings.get_mut(&fd) {
+            listing.next = ret as usize;
+        }
+    }
+    ret
+}
+
+import_real!(C_CLOSE, b"close\0", (fd: c_int) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn close(fd: c_int) -> c_int {
+    // The descriptor may be reused for another directory
+    forget_listing(fd);
+    C_CLOSE.call(fd)
+}
+
+////////////////////////////////////////////////////////////////////////////
+
+/// Checks whether writing `bytes` more bytes to `fd` would exceed the quota of the upper dirs. Only
+/// files in the upper dirs count, the size of the write is only computed for those.
+fn exceeds_quota<F: FnOnce() -> u64>(fd: c_int, bytes: F) -> bool {
+    if !quota::enabled() {
+        return false;
+    }
+    with_reentrancy_guard(false, || {
+        redir::fd_in_upper(fd).is_some() && !quota::reserve(bytes(), 0)
+    })
+}
+
+#[repr(C)]
+struct iovec {
+    base: *mut c_void,
+    len: usize,
+}
+
+/// Returns the number of bytes that a vectored write of `iov` writes.
+fn iov_len(iov: *const c_void, iovcnt: c_int) -> u64 {
+    if iov.is_null() || iovcnt <= 0 {
+        return 0;
+    }
+    let iov = unsafe { std::slice::from_raw_parts(iov as *const iovec, iovcnt as usize) };
+    iov.iter().map(|vec| vec.len as u64).sum()
+}
+
+/// Returns by how much the file behind `fd` grows if it is extended to `length` bytes.
+fn growth(fd: c_int, length: off_t) -> u64 {
+    let size = std::fs::metadata(format!("/proc/self/fd/{}", fd)).map_or(0, |file| file.len());
+    (length.max(0) as u64).saturating_sub(size)
+}
+
+// Writes are far too frequent for debug output, which is also written through these hooks.
+
+import_real!(C_WRITE, b"write\0", (fd: c_int, buf: *const c_void, count: usize) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn write(fd: c_int, buf: *const c_void, count: usize) -> isize {
+    if exceeds_quota(fd, || count as u64) {
+        set_errno(ENOSPC);
+        return -1;
+    }
+    C_WRITE.call(fd, buf, count)
+}
+
+import_real!(C_PWRITE, b"pwrite\0", (fd: c_int, buf: *const c_void, count: usize, offset: off_t) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn pwrite(fd: c_int, buf: *const c_void, count: usize, offset: off_t) -> isize {
+    if exceeds_quota(fd, || count as u64) {
+        set_errno(ENOSPC);
+        return -1;
+    }
+    C_PWRITE.call(fd, buf, count, offset)
+}
+
+import_real!(C_PWRITE64, b"pwrite64\0", (fd: c_int, buf: *const c_void, count: usize, offset: off_t) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn pwrite64(fd: c_int, buf: *const c_void, count: usize, offset: off_t) -> isize {
+    if exceeds_quota(fd, || count as u64) {
+        set_errno(ENOSPC);
+        return -1;
+    }
+    C_PWRITE64.call(fd, buf, count, offset)
+}
+
+import_real!(C_WRITEV, b"writev\0", (fd: c_int, iov: *const c_void, iovcnt: c_int) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn writev(fd: c_int, iov: *const c_void, iovcnt: c_int) -> isize {
+    if exceeds_quota(fd, || iov_len(iov, iovcnt)) {
+        set_errno(ENOSPC);
+        return -1;
+    }
+    C_WRITEV.call(fd, iov, iovcnt)
+}
+
+import_real!(C_PWRITEV, b"pwritev\0", (fd: c_int, iov: *const c_void, iovcnt: c_int, offset: off_t) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn pwritev(fd: c_int, iov: *const c_void, iovcnt: c_int, offset: off_t) -> isize {
+    if exceeds_quota(fd, || iov_len(iov, iovcnt)) {
+        set_errno(ENOSPC);
+        return -1;
+    }
+    C_PWRITEV.call(fd, iov, iovcnt, offset)
+}
+
+import_real!(C_PWRITEV2, b"pwritev2\0", (fd: c_int, iov: *const c_void, iovcnt: c_int, offset: off_t, flags: c_int) -> isize);
+
+#[no_mangle]
+unsafe extern "C" fn pwritev2(
+    fd: c_int,
+    iov: *const c_void,
+    iovcnt: c_int,
+    offset: off_t,
+    flags: c_int,
+) -> isize {
+    if exceeds_quota(fd, || iov_len(iov, iovcnt)) {
+        set_errno(ENOSPC);
+        return -1;
+    }
+    C_PWRITEV2.call(fd, iov, iovcnt, offset, flags)
+}
+
+import_real!(C_FTRUNCATE, b"ftruncate\0", (fd: c_int, length: off_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn ftruncate(fd: c_int, length: off_t) -> c_int {
+    if exceeds_quota(fd, || growth(fd, length)) {
+        return fail(ENOSPC);
+    }
+    C_FTRUNCATE.call(fd, length)
+}
+
+import_real!(C_FTRUNCATE64, b"ftruncate64\0", (fd: c_int, length: off_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn ftruncate64(fd: c_int, length: off_t) -> c_int {
+    if exceeds_quota(fd, || growth(fd, length)) {
+        return fail(ENOSPC);
+    }
+    C_FTRUNCATE64.call(fd, length)
+}
+
+const FALLOC_FL_KEEP_SIZE: c_int = 0x01;
+const FALLOC_FL_COLLAPSE_RANGE: c_int = 0x08;
+const FALLOC_FL_INSERT_RANGE: c_int = 0x20;
+
+import_real!(C_FALLOCATE, b"fallocate\0", (fd: c_int, mode: c_int, offset: off_t, len: off_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn fallocate(fd: c_int, mode: c_int, offset: off_t, len: off_t) -> c_int {
+    if exceeds_quota(fd, || allocated(fd, mode, offset, len)) {
+        return fail(ENOSPC);
+    }
+    C_FALLOCATE.call(fd, mode, offset, len)
+}
+
+import_real!(C_FALLOCATE64, b"fallocate64\0", (fd: c_int, mode: c_int, offset: off_t, len: off_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn fallocate64(fd: c_int, mode: c_int, offset: off_t, len: off_t) -> c_int {
+    if exceeds_quota(fd, || allocated(fd, mode, offset, len)) {
+        return fail(ENOSPC);
+    }
+    C_FALLOCATE64.call(fd, mode, offset, len)
+}
+
+/// Returns by how much `fallocate` grows the file behind `fd`. Punching holes requires keeping the
+/// size, and inserting a range moves the rest of the file by its length. Ranges that overflow are
+/// refused by the call itself.
+fn allocated(fd: c_int, mode: c_int, offset: off_t, len: off_t) -> u64 {
+    if mode & (FALLOC_FL_KEEP_SIZE | FALLOC_FL_COLLAPSE_RANGE) != 0 {
+        0
+    } else if mode & FALLOC_FL_INSERT_RANGE != 0 {
+        len.max(0) as u64
+    } else {
+        offset.checked_add(len).map_or(0, |end| growth(fd, end))
+    }
+}
+
+import_real!(C_POSIX_FALLOCATE, b"posix_fallocate\0", (fd: c_int, offset: off_t, len: off_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn posix_fallocate(fd: c_int, offset: off_t, len: off_t) -> c_int {
+    if exceeds_quota(fd, || allocated(fd, 0, offset, len)) {
+        return ENOSPC;
+    }
+    C_POSIX_FALLOCATE.call(fd, offset, len)
+}
+
+import_real!(C_POSIX_FALLOCATE64, b"posix_fallocate64\0", (fd: c_int, offset: off_t, len: off_t) -> c_int);
+
+#[no_mangle]
+unsafe extern "C" fn posix_fallocate64(fd: c_int, offset: off_t, len: off_t) -> c_int {
+    if exceeds_quota(fd, || allocated(fd, 0, offset, len)) {
+        return ENOSPC;
+    }
+    C_POSIX_FALLOCATE64.call(fd, offset, len)
+}
+
+// HACK: fcntl is a varargs function as well, its optional argument is read like the mode of open.
+import_real!(C_FCNTL, b"fcntl\0", (fd: c_int, cmd: c_int, arg: c_long) -> c_int);
@@ -0,0 +1,144 @@
+//! Serializes copy-ups between processes sharing an upper dir.
+//!
+//! A copy-up holds an exclusive `flock` on one of the lock files `.wh..wh.locks/0` to `63` in the
+//! upper dir, picked by a hash of the path it creates. Other processes copying up the same path
+//! wait for the lock, and then find the upper entry in place. Unrelated paths rarely share a lock
+//! file, and the number of lock files stays fixed no matter how many entries are copied up.
+//!
+//! Threads of one process first wait for each other by path alone, so that they neither contend
+//! for the lock files nor block on copy-ups of unrelated paths that happen to share one.
+
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::File;
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Condvar, Mutex};
+
+use crate::config;
+use crate::log::{self, Category};
+
+extern "C" {
+    fn flock(fd: c_int, operation: c_int) -> c_int;
+}
+
+const LOCK_EX: c_int = 2;
+
+const O_RDWR: c_int = 0o2;
+const O_CREAT: c_int = 0o100;
+const O_CLOEXEC: c_int = 0o2000000;
+
+/// The lock files use the reserved names of whiteouts, so they are never part of the merged view.
+const LOCK_DIR: &str = ".wh..wh.locks";
+
+const LOCK_COUNT: u64 = 64;
+
+/// The paths that threads of this process are copying up.
+struct Copying {
+    paths: Mutex<HashSet<PathBuf>>,
+    done: Condvar,
+}
+
+static COPYING: AtomicPtr<Copying> = AtomicPtr::new(std::ptr::null_mut());
+
+#[used]
+#[cfg_attr(target_os = "linux", link_section = ".init_array")]
+pub static INIT_COPYING: extern "C" fn() = {
+    extern "C" fn init() {
+        let copying = Box::new(Copying {
+            paths: Mutex::new(HashSet::new()),
+            done: Condvar::new(),
+        });
+        COPYING.store(Box::into_raw(copying), Ordering::SeqCst);
+    }
+    init
+};
+
+/// An exclusive lock on copying up a path, released when dropped.
+pub struct CopyUpLock {
+    /// The path claimed among the threads of this process.
+    path: Option<PathBuf>,
+    file: Option<File>,
+}
+
+impl Drop for CopyUpLock {
+    fn drop(&mut self) {
+        self.file.take();
+        let copying = unsafe { COPYING.load(Ordering::SeqCst).as_ref() };
+        if let (Some(path), Some(copying)) = (self.path.take(), copying) {
+            copying.paths.lock().unwrap().remove(&path);
+            copying.done.notify_all();
+        }
+    }
+}
+
+/// Waits until no other thread or process is copying up `path_to_upper`, and keeps others from
+/// doing so until the returned lock is dropped. Where the lock file cannot be locked, e.g. because
+/// the file system does not support locks, only the threads of this process are kept out.
+pub fn copy_up(path_to_upper: &Path) -> CopyUpLock {
+    let path = claim(path_to_upper);
+    CopyUpLock {
+        path,
+        file: lock_file(path_to_upper),
+    }
+}
+
+/// Waits until no other thread of this process is copying up `path_to_upper`, then claims it.
+fn claim(path_to_upper: &Path) -> Option<PathBuf> {
+    let copying = unsafe { COPYING.load(Ordering::SeqCst).as_ref() }?;
+    let mut paths = copying.paths.lock().unwrap();
+    while paths.contains(path_to_upper) {
+        paths = copying.done.wait(paths).unwrap();
+    }
+    paths.insert(path_to_upper.to_path_buf());
+    Some(path_to_upper.to_path_buf())
+}
+
+/// Takes the lock file for `path_to_upper`, shared with other processes.
+fn lock_file(path_to_upper: &Path) -> Option<File> {
+    let (mapping, path_in_upper) = config::get_config()?.upper_mapping(path_to_upper)?;
+    let dir = mapping.upper_dir.join(LOCK_DIR);
+    let path = dir.join((hash(path_in_upper) % LOCK_COUNT).to_string());
+    let cpath = CString::new(path.as_os_str().as_bytes()).ok()?;
+
+    let mut fd = unsafe { crate::C_OPEN.call(cpath.as_ptr(), O_RDWR | O_CREAT | O_CLOEXEC, 0o600) };
+    if fd < 0 && std::fs::create_dir_all(&dir).is_ok() {
+        fd = unsafe { crate::C_OPEN.call(cpath.as_ptr(), O_RDWR | O_CREAT | O_CLOEXEC, 0o600) };
+    }
+    if fd < 0 {
+        log::warn(Category::Lock, || {
+            log_println!(
+                "liboverlay: could not open lock {}: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            )
+        });
+        return None;
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    if unsafe { flock(file.as_raw_fd(), LOCK_EX) } != 0 {
+        log::warn(Category::Lock, || {
+            log_println!(
+                "liboverlay: could not lock {}: {}",
+                path.display(),
+                std::io::Error::last_os_error()
+            )
+        });
+        return None;
+    }
+    Some(file)
+}
+
+/// Hashes a path with FNV-1a, which unlike the hasher of the standard library is guaranteed to be
+/// the same in all processes.
+fn hash(path: &Path) -> u64 {
+    path.as_os_str()
+        .as_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
@@ -0,0 +1,505 @@
+//! Log messages are filtered by their level and category, as configured by `LIBOVERLAY_LOG`, e.g.
+//! `info,redir=trace,dir=debug`. A level on its own applies to all categories that are not named.
+//! The levels are `off`, `error`, `warn`, `info`, `debug` and `trace`, the default is `error`. The
+//! categories are:
+//!
+//! - `hook` traces every intercepted call along with its result,
+//! - `redir` covers how paths are rewritten, redirected and followed,
+//! - `copy` covers copying up, linking and evicting copies,
+//! - `dir` covers merging directory listings and whiting out lower entries,
+//! - `remote` covers fetching files of remote trees,
+//! - `lock` covers the locks taken during copy-ups,
+//! - `config` covers loading the configuration and the control socket.
+//!
+//! Errors in the configuration itself are reported regardless, since it holds the filter.
+//!
+//! Messages go to stderr, unless `LIBOVERLAY_LOG_FILE` names a file they are appended to, or
+//! `LIBOVERLAY_LOG_FD` an inherited file descriptor they are written to, so that they do not mix
+//! with the output of the program.
+//!
+//! With `LIBOVERLAY_LOG_FORMAT=json`, messages are written as JSON objects, one per line. Each hook
+//! invocation that is traced makes one object, with the call, its arguments by name, the messages
+//! logged while it ran as its decision, its result as a number and `errno` if it failed. Hooks tell
+//! whether they failed themselves, see `returned`. Other messages make an object of their own. All
+//! objects carry a timestamp in seconds, the PID and the thread ID.
+
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::{c_int, c_long, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::sysno;
+
+const STDERR: c_int = 2;
+
+const O_WRONLY: c_int = 0o1;
+const O_CREAT: c_int = 0o100;
+const O_APPEND: c_int = 0o2000;
+const O_CLOEXEC: c_int = 0o2000000;
+
+const EINTR: i32 = 4;
+
+/// The file descriptor that messages are written to.
+static DESTINATION: AtomicI32 = AtomicI32::new(STDERR);
+
+/// Whether messages are written as JSON objects rather than as text.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// A traced hook invocation, whose result is not written yet.
+struct Call {
+    call: String,
+    /// The arguments as the members of a JSON object.
+    args: String,
+    /// The other messages that are logged while the call runs, e.g. where it is redirected.
+    decision: Vec<String>,
+}
+
+thread_local! {
+    /// The category and level of the message that is being logged, if any.
+    static CURRENT: Cell<Option<(Category, Level)>> = Cell::new(None);
+    /// The traced hook invocations of the thread that are under way, the innermost comes last.
+    static CALLS: RefCell<Vec<Call>> = RefCell::new(Vec::new());
+    /// The ID of the thread, once it is known.
+    static TID: Cell<c_long> = Cell::new(0);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    Hook,
+    Redir,
+    Copy,
+    Dir,
+    Remote,
+    Lock,
+    Config,
+}
+
+const CATEGORIES: &[(Category, &str)] = &[
+    (Category::Hook, "hook"),
+    (Category::Redir, "redir"),
+    (Category::Copy, "copy"),
+    (Category::Dir, "dir"),
+    (Category::Remote, "remote"),
+    (Category::Lock, "lock"),
+    (Category::Config, "config"),
+];
+
+const LEVELS: &[(Level, &str)] = &[
+    (Level::Off, "off"),
+    (Level::Error, "error"),
+    (Level::Warn, "warn"),
+    (Level::Info, "info"),
+    (Level::Debug, "debug"),
+    (Level::Trace, "trace"),
+];
+
+/// The most detailed level that is logged for each category.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    levels: [Level; CATEGORIES.len()],
+    /// The filter as it was given.
+    spec: String,
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter {
+            levels: [Level::Error; CATEGORIES.len()],
+            spec: String::from("error"),
+        }
+    }
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+impl Filter {
+    pub fn enabled(&self, category: Category, level: Level) -> bool {
+        level <= self.levels[category as usize]
+    }
+}
+
+/// Parses a filter like `info,redir=trace`, the categories are given with their levels, separated by
+/// `,`, and a level on its own applies to the other categories.
+pub fn parse_filter(spec: &str) -> Result<Filter, String> {
+    let mut default = Level::Error;
+    let mut named = Vec::new();
+    for item in spec.split(',').filter(|item| !item.is_empty()) {
+        let split = item.find('=');
+        let level = split.map_or(item, |split| &item[split + 1..]);
+        let level = LEVELS
+            .iter()
+            .find(|(_, name)| *name == level)
+            .map(|(level, _)| *level)
+            .ok_or_else(|| format!("unknown level `{}`", level))?;
+        match split {
+            None => default = level,
+            Some(split) => {
+                let category = &item[..split];
+                let category = CATEGORIES
+                    .iter()
+                    .find(|(_, name)| *name == category)
+                    .map(|(category, _)| *category)
+                    .ok_or_else(|| format!("unknown category `{}`", category))?;
+                named.push((category, level));
+            }
+        }
+    }
+    let mut levels = [default; CATEGORIES.len()];
+    for (category, level) in named {
+        levels[category as usize] = level;
+    }
+    Ok(Filter {
+        levels,
+        spec: spec.to_string(),
+    })
+}
+
+/// How messages are written, as `LIBOVERLAY_LOG_FORMAT` chooses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+const FORMATS: &[(Format, &str)] = &[(Format::Text, "text"), (Format::Json, "json")];
+
+/// Parses the name of a format, `text` or `json`.
+pub fn parse_format(name: &str) -> Result<Format, String> {
+    FORMATS
+        .iter()
+        .find(|(_, format)| *format == name)
+        .map(|(format, _)| *format)
+        .ok_or_else(|| format!("unknown format `{}`", name))
+}
+
+/// Writes messages in `format` from now on.
+pub fn write_as(format: Format) {
+    JSON.store(format == Format::Json, Ordering::SeqCst);
+}
+
+/// Writes messages to the file descriptor `fd` from now on.
+pub fn write_to_fd(fd: c_int) {
+    DESTINATION.store(fd, Ordering::SeqCst);
+}
+
+/// Appends messages to the file at `path` from now on, which is created if it does not exist.
+pub fn write_to_file(path: &Path) -> std::io::Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    // The log is written where it is, even within a lower dir
+    let flags = O_WRONLY | O_CREAT | O_APPEND | O_CLOEXEC;
+    let fd = unsafe { crate::C_OPEN.call(cpath.as_ptr(), flags, 0o644) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    write_to_fd(fd);
+    Ok(())
+}
+
+/// A number that is written in any base as text, and in decimal as JSON.
+pub trait Number: fmt::Display + fmt::Binary + fmt::Octal + fmt::LowerHex {}
+
+impl<T: fmt::Display + fmt::Binary + fmt::Octal + fmt::LowerHex> Number for T {}
+
+/// An argument of a traced hook invocation. Numbers are written in the base of their variant as
+/// text, pointers in hex.
+pub enum Arg<'a> {
+    Str(Cow<'a, str>),
+    Dec(&'a dyn Number),
+    Bin(&'a dyn Number),
+    Oct(&'a dyn Number),
+    Hex(&'a dyn Number),
+}
+
+impl fmt::Display for Arg<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arg::Str(s) => write!(f, "{}", s),
+            Arg::Dec(n) => write!(f, "{}", n),
+            Arg::Bin(n) => write!(f, "{:b}", n),
+            Arg::Oct(n) => write!(f, "{:o}", n),
+            Arg::Hex(n) => write!(f, "{:x}", n),
+        }
+    }
+}
+
+impl Arg<'_> {
+    fn to_json(&self) -> String {
+        match self {
+            Arg::Str(s) => json_string(s),
+            Arg::Dec(n) | Arg::Bin(n) | Arg::Oct(n) | Arg::Hex(n) => n.to_string(),
+        }
+    }
+}
+
+/// Traces the invocation of the hook `name` with `args`, whose result follows with `returned`.
+pub fn call(name: &str, args: &[(&str, Arg)]) {
+    if !JSON.load(Ordering::Relaxed) {
+        return write_all(&format!("{}({}) = ", name, text_args(args)));
+    }
+    let call = Call {
+        call: name.to_string(),
+        args: json_args(args),
+        decision: Vec::new(),
+    };
+    if CALLS
+        .try_with(|calls| calls.borrow_mut().push(call))
+        .is_err()
+    {
+        // The thread is exiting
+        write_all(&format!("{}({}) = ", name, text_args(args)));
+    }
+}
+
+/// Traces the invocation of the hook `name` with `args`, whose result is not traced.
+pub fn void_call(name: &str, args: &[(&str, Arg)]) {
+    if !JSON.load(Ordering::Relaxed) {
+        return write_all(&format!("{}({})\n", name, text_args(args)));
+    }
+    write_all(&json_call(name, &json_args(args), &[], "null", None));
+}
+
+/// Traces the result of the hook invocation that is under way, along with the `errno` it failed
+/// with, if it did. Whether a result is a failure differs between hooks, so each one tells.
+pub fn returned(result: i64, errno: Option<c_int>) {
+    write_result(&result.to_string(), &result.to_string(), errno)
+}
+
+/// Like `returned`, but for a pointer, which is written in hex as text.
+pub fn returned_pointer(result: usize, errno: Option<c_int>) {
+    write_result(&format!("{:x}", result), &result.to_string(), errno)
+}
+
+fn write_result(text: &str, json: &str, errno: Option<c_int>) {
+    if !JSON.load(Ordering::Relaxed) {
+        return write_all(&format!("{}\n", text));
+    }
+    let call = CALLS.try_with(|calls| calls.borrow_mut().pop());
+    match call {
+        Ok(Some(call)) => write_all(&json_call(
+            &call.call,
+            &call.args,
+            &call.decision,
+            json,
+            errno,
+        )),
+        // The invocation started before tracing did
+        Ok(None) => {}
+        Err(_) => write_all(&format!("{}\n", text)),
+    }
+}
+
+fn text_args(args: &[(&str, Arg)]) -> String {
+    args.iter()
+        .map(|(_, arg)| arg.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn json_args(args: &[(&str, Arg)]) -> String {
+    args.iter()
+        .map(|(name, arg)| format!("{}:{}", json_string(name), arg.to_json()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Returns the JSON object of a hook invocation.
+fn json_call(
+    call: &str,
+    args: &str,
+    decision: &[String],
+    result: &str,
+    errno: Option<c_int>,
+) -> String {
+    let decision = decision
+        .iter()
+        .map(|message| json_string(message))
+        .collect::<Vec<_>>();
+    format!(
+        "{{{},\"call\":{},\"args\":{{{}}},\"decision\":[{}],\"result\":{},\"errno\":{}}}\n",
+        json_origin(),
+        json_string(call),
+        args,
+        decision.join(","),
+        result,
+        errno.map_or_else(|| String::from("null"), |errno| errno.to_string())
+    )
+}
+
+/// Writes a message, or collects it into the JSON object of the hook invocation it is part of.
+pub fn write(message: std::fmt::Arguments) {
+    if !JSON.load(Ordering::Relaxed) {
+        return write_all(&message.to_string());
+    }
+    let message = message.to_string();
+    let current = CURRENT.try_with(Cell::get).ok().and_then(|current| current);
+    let object = CALLS.try_with(|calls| to_json(&mut calls.borrow_mut(), current, &message));
+    match object {
+        Ok(Some(object)) => write_all(&object),
+        Ok(None) => {}
+        // The thread is exiting
+        Err(_) => write_all(&message),
+    }
+}
+
+/// Turns a message into a JSON object, unless it is part of a hook invocation that is under way,
+/// whose decision it becomes.
+fn to_json(
+    calls: &mut [Call],
+    current: Option<(Category, Level)>,
+    message: &str,
+) -> Option<String> {
+    let text = message.trim_end_matches('\n');
+    let text = text.trim_start_matches("liboverlay:").trim_start();
+    if let Some(call) = calls.last_mut() {
+        call.decision.push(text.to_string());
+        return None;
+    }
+    // Messages outside of `log`, about the configuration itself, are errors
+    let (category, level) = match current {
+        Some((category, level)) => (json_string(name(CATEGORIES, category)), level),
+        None => (String::from("null"), Level::Error),
+    };
+    Some(format!(
+        "{{{},\"level\":{},\"category\":{},\"message\":{}}}\n",
+        json_origin(),
+        json_string(name(LEVELS, level)),
+        category,
+        json_string(text)
+    ))
+}
+
+/// Returns the name of `value` in `names`.
+fn name<T: PartialEq>(names: &'static [(T, &'static str)], value: T) -> &'static str {
+    names
+        .iter()
+        .find(|(named, _)| *named == value)
+        .map_or("", |(_, name)| name)
+}
+
+/// Returns the members telling when and where a message was logged.
+fn json_origin() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let tid = TID
+        .try_with(|tid| {
+            if tid.get() == 0 {
+                tid.set(unsafe { crate::C_SYSCALL.call(sysno::GETTID, 0, 0, 0, 0, 0, 0) });
+            }
+            tid.get()
+        })
+        .unwrap_or(0);
+    format!(
+        "\"ts\":{}.{:06},\"pid\":{},\"tid\":{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        tid
+    )
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Writes a message as a whole, so that it does not mix with those of other threads and processes
+/// writing to the same file. The write is not hooked, it is never refused nor logged itself.
+fn write_all(message: &str) {
+    let fd = DESTINATION.load(Ordering::Relaxed);
+    let mut bytes = message.as_bytes();
+    while !bytes.is_empty() {
+        let written =
+            unsafe { crate::C_WRITE.call(fd, bytes.as_ptr() as *const c_void, bytes.len()) };
+        if written > 0 {
+            bytes = &bytes[written as usize..];
+        } else if written == 0 || std::io::Error::last_os_error().raw_os_error() != Some(EINTR) {
+            return;
+        }
+    }
+}
+
+/// Calls `callback` to log a message of `category` at `level`, if the filter lets it through.
+#[inline(always)]
+pub fn log<F: FnOnce()>(category: Category, level: Level, callback: F) {
+    if let Some(cfg) = config::get_config() {
+        log_with(&cfg.log, category, level, callback)
+    }
+}
+
+/// Like `log`, but with `filter` rather than the filter of the configuration, e.g. for a
+/// configuration that is not installed yet.
+#[inline(always)]
+pub fn log_with<F: FnOnce()>(filter: &Filter, category: Category, level: Level, callback: F) {
+    if !filter.enabled(category, level) {
+        return;
+    }
+    if !JSON.load(Ordering::Relaxed) {
+        return callback();
+    }
+    // The JSON objects tell the category and level of the messages
+    let outer = CURRENT
+        .try_with(|current| current.replace(Some((category, level))))
+        .ok()
+        .and_then(|outer| outer);
+    callback();
+    let _ = CURRENT.try_with(|current| current.set(outer));
+}
+
+#[inline(always)]
+pub fn error<F: FnOnce()>(category: Category, callback: F) {
+    log(category, Level::Error, callback)
+}
+
+#[inline(always)]
+pub fn warn<F: FnOnce()>(category: Category, callback: F) {
+    log(category, Level::Warn, callback)
+}
+
+#[inline(always)]
+pub fn info<F: FnOnce()>(category: Category, callback: F) {
+    log(category, Level::Info, callback)
+}
+
+#[inline(always)]
+pub fn debug<F: FnOnce()>(category: Category, callback: F) {
+    log(category, Level::Debug, callback)
+}
+
+#[inline(always)]
+pub fn trace<F: FnOnce()>(category: Category, callback: F) {
+    log(category, Level::Trace, callback)
+}
@@ -0,0 +1,127 @@
+//! Metadata-only copy-up: changing the metadata of a lower file, e.g. with `chmod` or `utimes`,
+//! creates a stub in the upper dir that carries the new metadata, while the contents stay in the
+//! lower file. They are only copied up once the file is opened for writing.
+//!
+//! A stub is a sparse file with the size of the lower file, so that it can be stat'ed in place of a
+//! full copy. It is marked by a hard link `dir/.wh..wh.meta.name` in the upper dir. Being a hard
+//! link, the marker keeps referring to the stub alone, even after the stub is replaced or removed.
+
+use std::ffi::{CString, OsString};
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::copy;
+use crate::lock;
+use crate::log::{self, Category};
+
+/// Markers use the reserved names of whiteouts, so they are never part of the merged view.
+const PREFIX: &str = ".wh..wh.meta.";
+
+/// Returns the path of the marker of the stub `path_to_upper`.
+fn marker_path(path_to_upper: &Path) -> Option<PathBuf> {
+    let mut marker = OsString::from(PREFIX);
+    marker.push(path_to_upper.file_name()?);
+    Some(path_to_upper.with_file_name(marker))
+}
+
+/// Checks whether `path_to_upper` is a stub, whose contents still live in the lower file.
+pub fn is_stub(path_to_upper: &Path) -> bool {
+    let stub = match path_to_upper.symlink_metadata() {
+        Ok(stub) if stub.file_type().is_file() && stub.nlink() > 1 => stub,
+        _ => return false,
+    };
+    let marker = marker_path(path_to_upper).and_then(|marker| marker.symlink_metadata().ok());
+    marker.map_or(false, |marker| {
+        marker.dev() == stub.dev() && marker.ino() == stub.ino()
+    })
+}
+
+/// Creates a stub for the lower file `path` at `path_to_upper`. Its parent dir has to exist.
+pub fn create(path: &Path, path_to_upper: &Path) -> Option<()> {
+    log::debug(Category::Copy, || {
+        log_println!("liboverlay: making metadata-only copy")
+    });
+    let lower = std::fs::metadata(path).ok()?;
+    let created = std::fs::File::create(path_to_upper).and_then(|stub| stub.set_len(lower.len()));
+    if let Err(e) = created {
+        log::warn(Category::Copy, || {
+            log_println!(
+                "liboverlay: failed to create stub {}: {}",
+                path_to_upper.display(),
+                e
+            )
+        });
+        return None;
+    }
+    copy::copy_metadata(path, path_to_upper).ok()?;
+    std::fs::hard_link(path_to_upper, marker_path(path_to_upper)?).ok()
+}
+
+/// Copies the contents of the lower file `path` into the stub `path_to_upper`, turning it into a
+/// regular writable copy. The metadata of the stub is kept.
+pub fn copy_contents(path: &Path, path_to_upper: &Path) -> Option<()> {
+    // Another process may be completing the same stub
+    let _lock = lock::copy_up(path_to_upper);
+    if !is_stub(path_to_upper) {
+        return Some(());
+    }
+    log::debug(Category::Copy, || {
+        log_println!("liboverlay: copying contents of metadata-only copy")
+    });
+    let stub = path_to_upper.symlink_metadata().ok()?;
+    let mode = stub.permissions().mode() | 0o200;
+    // The copy writes to the stub in place
+    std::fs::set_permissions(path_to_upper, std::fs::Permissions::from_mode(mode)).ok()?;
+    copy::copy_contents(path, path_to_upper)
+        .map_err(|e| {
+            log::warn(Category::Copy, || {
+                log_println!(
+                    "liboverlay: failed to copy from lower {} to stub {}: {}",
+                    path.display(),
+                    path_to_upper.display(),
+                    e
+                )
+            })
+        })
+        .ok()?;
+    set_times(path_to_upper, &stub)?;
+    std::fs::remove_file(marker_path(path_to_upper)?).ok()
+}
+
+/// Turns the stub `path_to_upper` into an empty writable file, for opens that truncate it anyway.
+pub fn discard_contents(path_to_upper: &Path) -> Option<()> {
+    log::debug(Category::Copy, || {
+        log_println!("liboverlay: truncating metadata-only copy")
+    });
+    let mode = path_to_upper.symlink_metadata().ok()?.permissions().mode() | 0o200;
+    std::fs::set_permissions(path_to_upper, std::fs::Permissions::from_mode(mode)).ok()?;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path_to_upper)
+        .ok()?;
+    std::fs::remove_file(marker_path(path_to_upper)?).ok()
+}
+
+extern "C" {
+    fn utimensat(dirfd: c_int, path: *const c_char, times: *const [i64; 2], flags: c_int) -> c_int;
+}
+
+const AT_FDCWD: c_int = -100;
+
+/// Sets the access and modification times of `path` to the ones in `metadata`.
+fn set_times(path: &Path, metadata: &std::fs::Metadata) -> Option<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let times = [
+        [metadata.atime(), metadata.atime_nsec()],
+        [metadata.mtime(), metadata.mtime_nsec()],
+    ];
+    let ret = unsafe { utimensat(AT_FDCWD, cpath.as_ptr(), times.as_ptr(), 0) };
+    if ret == 0 {
+        Some(())
+    } else {
+        None
+    }
+}
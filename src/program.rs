@@ -0,0 +1,72 @@
+//! Program filters limit the overlay to some of the processes that load liboverlay, e.g. to only
+//! overlay an application but not the shells and tools that start it or that it starts. All other
+//! processes pass every call through to libc.
+//!
+//! A pattern is a shell glob as understood by `fnmatch`. Patterns containing a `/` are matched
+//! against the path of the executable, with `FNM_PATHNAME`, others against its file name, e.g.
+//! `python3*` or `/opt/app/bin/*`. Scripts are matched by their interpreter.
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+const FNM_PATHNAME: c_int = 1;
+
+extern "C" {
+    fn fnmatch(pattern: *const c_char, string: *const c_char, flags: c_int) -> c_int;
+}
+
+/// A pattern selecting the programs it matches.
+pub struct Pattern {
+    pattern: CString,
+}
+
+impl std::fmt::Debug for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.pattern.to_string_lossy())
+    }
+}
+
+impl Pattern {
+    /// Checks whether the pattern matches the executable at `exe`.
+    pub fn matches(&self, exe: &Path) -> bool {
+        let (subject, flags) = if self.pattern.as_bytes().contains(&b'/') {
+            (exe.as_os_str(), FNM_PATHNAME)
+        } else {
+            match exe.file_name() {
+                Some(name) => (name, 0),
+                None => return false,
+            }
+        };
+        let subject = match CString::new(subject.as_bytes()) {
+            Ok(subject) => subject,
+            Err(_) => return false,
+        };
+        unsafe { fnmatch(self.pattern.as_ptr(), subject.as_ptr(), flags) == 0 }
+    }
+}
+
+/// Parses a list of patterns separated by `;`.
+pub fn parse_patterns(list: &str) -> Result<Vec<Pattern>, String> {
+    list.split(';')
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| {
+            CString::new(pattern)
+                .map(|pattern| Pattern { pattern })
+                .map_err(|_| format!("invalid pattern `{}`", pattern))
+        })
+        .collect()
+}
+
+/// Checks whether the current process runs one of the programs `patterns` select, all programs are
+/// selected if there are no patterns.
+pub fn selected(patterns: &[Pattern]) -> bool {
+    if patterns.is_empty() {
+        return true;
+    }
+    match std::env::current_exe() {
+        Ok(exe) => patterns.iter().any(|pattern| pattern.matches(&exe)),
+        Err(_) => false,
+    }
+}
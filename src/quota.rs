@@ -0,0 +1,152 @@
+//! Limits the size and number of entries of the upper dirs, e.g. to keep a runaway build from
+//! filling up a small tmpfs. Copy-ups and writes that would exceed the limits fail with `ENOSPC`.
+//!
+//! Walking the upper dirs to measure them is far too slow to do for every write. A process measures
+//! them at most once per second, and adds what it copies up and writes in the meantime. The limits
+//! are therefore approximate, all the more with several processes writing at once.
+
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::evict;
+use crate::log::{self, Category};
+use crate::whiteout;
+
+/// The limits of `LIBOVERLAY_QUOTA_BYTES` and `LIBOVERLAY_QUOTA_FILES`.
+#[derive(Debug)]
+pub struct Quota {
+    pub bytes: Option<u64>,
+    pub files: Option<u64>,
+    /// Whether unmodified copies are evicted to make room, see `LIBOVERLAY_QUOTA_EVICT`.
+    pub evict: bool,
+}
+
+/// How long a measurement of the upper dirs is used, in milliseconds.
+const MEASUREMENT_TTL: u64 = 1000;
+
+/// When the upper dirs were measured last, in milliseconds since the epoch.
+static MEASURED_AT: AtomicU64 = AtomicU64::new(0);
+static USED_BYTES: AtomicU64 = AtomicU64::new(0);
+static USED_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Copy-ups refused for lack of quota are redirected to this path in the upper dir, which does not
+/// exist and cannot be created, so that nothing is written to the lower dir in their place.
+const REFUSED: &str = ".wh..wh.quota/refused";
+
+/// Checks whether limits are configured, which is all that writes check for in the common case.
+pub fn enabled() -> bool {
+    config::get_config().map_or(false, |cfg| cfg.quota.is_some())
+}
+
+/// Makes room for `bytes` more bytes and `files` more entries in the upper dirs, evicting unmodified
+/// copies if configured. Returns false if that would exceed the quota.
+pub fn reserve(bytes: u64, files: u64) -> bool {
+    let quota = match config::get_config().and_then(|cfg| cfg.quota.as_ref()) {
+        Some(quota) => quota,
+        None => return true,
+    };
+    refresh();
+    if try_reserve(quota, bytes, files) {
+        return true;
+    }
+    if quota.evict {
+        let used_bytes = USED_BYTES.load(Ordering::SeqCst);
+        let used_files = USED_FILES.load(Ordering::SeqCst);
+        let excess = |used: u64, needed: u64, max: Option<u64>| {
+            max.map_or(0, |max| (used + needed).saturating_sub(max))
+        };
+        let (freed_bytes, freed_files) = evict::evict(
+            excess(used_bytes, bytes, quota.bytes),
+            excess(used_files, files, quota.files),
+        );
+        USED_BYTES.fetch_sub(freed_bytes.min(used_bytes), Ordering::SeqCst);
+        USED_FILES.fetch_sub(freed_files.min(used_files), Ordering::SeqCst);
+        if try_reserve(quota, bytes, files) {
+            return true;
+        }
+    }
+    log::error(Category::Copy, || {
+        log_println!(
+            "liboverlay: upper dir quota exceeded, {} bytes and {} entries are in use",
+            USED_BYTES.load(Ordering::SeqCst),
+            USED_FILES.load(Ordering::SeqCst)
+        )
+    });
+    false
+}
+
+/// Adds `bytes` and `files` to what is in use, unless that would exceed `quota`.
+fn try_reserve(quota: &Quota, bytes: u64, files: u64) -> bool {
+    let used_bytes = USED_BYTES.fetch_add(bytes, Ordering::SeqCst);
+    let used_files = USED_FILES.fetch_add(files, Ordering::SeqCst);
+    let exceeded = quota.bytes.map_or(false, |max| used_bytes + bytes > max)
+        || quota.files.map_or(false, |max| used_files + files > max);
+    if exceeded {
+        USED_BYTES.fetch_sub(bytes, Ordering::SeqCst);
+        USED_FILES.fetch_sub(files, Ordering::SeqCst);
+    }
+    !exceeded
+}
+
+/// Returns the path that a copy-up into `upper_dir` refused for lack of quota is redirected to.
+pub fn refused_path(upper_dir: &Path) -> PathBuf {
+    upper_dir.join(REFUSED)
+}
+
+/// Checks whether `path` is the result of a refused copy-up, see `refused_path`.
+pub fn is_refused(path: &Path) -> bool {
+    path.ends_with(REFUSED)
+}
+
+/// Makes the next reservation measure the upper dirs again, e.g. after they have been cleaned up.
+pub fn remeasure() {
+    MEASURED_AT.store(0, Ordering::SeqCst);
+}
+
+/// Measures the upper dirs again if the last measurement is outdated.
+fn refresh() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64);
+    let measured_at = MEASURED_AT.load(Ordering::SeqCst);
+    if now < measured_at + MEASUREMENT_TTL {
+        return;
+    }
+    MEASURED_AT.store(now, Ordering::SeqCst);
+    let (mut bytes, mut files) = (0, 0);
+    if let Some(cfg) = config::get_config() {
+        for mapping in &cfg.mappings {
+            measure(&mapping.upper_dir, false, &mut bytes, &mut files);
+        }
+    }
+    USED_BYTES.store(bytes, Ordering::SeqCst);
+    USED_FILES.store(files, Ordering::SeqCst);
+}
+
+/// Adds up the space taken by the entries below `dir` and their number. Whiteouts and other
+/// bookkeeping entries with reserved names take up space, but do not count as entries, and neither
+/// does anything in a `hidden` dir.
+fn measure(dir: &Path, hidden: bool, bytes: &mut u64, files: &mut u64) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        *bytes += metadata.blocks() * 512;
+        let hidden = hidden || whiteout::hidden_name(entry.file_name().as_bytes()).is_some();
+        if !hidden {
+            *files += 1;
+        }
+        if metadata.is_dir() {
+            measure(&entry.path(), hidden, bytes, files);
+        }
+    }
+}
@@ -1,69 +1,219 @@
-use std::path::{Path, PathBuf};
+use std::borrow::Cow;
+use std::fs::FileType;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 
-use crate::config;
+use crate::config::{self, CopyUp};
+use crate::copy;
+use crate::hardlink;
+use crate::lock;
+use crate::log::{self, Category};
+use crate::metacopy;
+use crate::quota;
+use crate::synthetic;
+use crate::transform;
+use crate::whiteout;
+
+/// How a redirected path is accessed.
+#[derive(Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    /// Only the metadata is changed, the contents of lower files need not be copied up.
+    Metadata,
+    Write,
+    /// Opened for reading, lower files are copied up as a cache if configured.
+    Cache,
+}
 
 pub fn redirect_path(path: &Path, write: bool) -> Option<PathBuf> {
-    if path.is_relative() {
-        config::if_debug(|| {
-            eprintln!(
-                "liboverlay: relative paths not supported {}",
-                path.display()
-            )
-        });
+    let access = if write { Access::Write } else { Access::Read };
+    redirect(path, access)
+}
+
+/// Redirects a path that is opened, `write` tells whether it is opened for writing.
+pub fn redirect_opened(path: &Path, write: bool) -> Option<PathBuf> {
+    let access = if write { Access::Write } else { Access::Cache };
+    redirect(path, access)
+}
+
+/// Redirects a path whose metadata is changed, copying up lower files without their contents.
+pub fn redirect_metadata(path: &Path) -> Option<PathBuf> {
+    redirect(path, Access::Metadata)
+}
+
+/// Returns where the contents of a redirected path are read from, which is the lower file for
+/// metadata-only copies.
+pub fn contents_path(redirected: PathBuf) -> PathBuf {
+    if metacopy::is_stub(&redirected) {
+        to_merged(redirected)
+    } else {
+        redirected
+    }
+}
+
+fn redirect(path: &Path, access: Access) -> Option<PathBuf> {
+    // A trailing slash demands a directory, it is kept so that the kernel checks for one in either
+    // layer. Files are not copied up for such paths, since they would fail to open anyway.
+    let slash = path.as_os_str().as_bytes().ends_with(b"/");
+    let path = &*absolute(path)?;
+    if whiteout::is_marker(path) {
+        return None;
+    }
+    let access = if slash && path.exists() && !path.is_dir() {
+        Access::Read
+    } else {
+        access
+    };
+    let redirected = redirect_resolved(path, access)?;
+    if slash && !redirected.as_os_str().as_bytes().ends_with(b"/") {
+        let mut redirected = redirected.into_os_string();
+        redirected.push("/");
+        Some(PathBuf::from(redirected))
+    } else {
+        Some(redirected)
+    }
+}
+
+/// Redirects an absolute path that has been resolved, applying the rewrite rules.
+fn redirect_resolved(path: &Path, access: Access) -> Option<PathBuf> {
+    // Rewritten paths are subject to the mappings as well, but not to further rewrites
+    let cfg = config::get_config()?;
+    match cfg.rewrite(path) {
+        Some(rewritten) => {
+            let rewritten = absolute(&rewritten)?.into_owned();
+            log::debug(Category::Redir, || {
+                log_println!(
+                    "liboverlay: rewriting {} to {}",
+                    path.display(),
+                    rewritten.display()
+                )
+            });
+            Some(redirect_mapped(&rewritten, access).unwrap_or(rewritten))
+        }
+        None => redirect_mapped(path, access),
+    }
+}
+
+/// Changes refused by the options of a mapping are redirected to this path in the upper dir, which
+/// does not exist and cannot be created, so that nothing is written to the lower dir in their place.
+const READ_ONLY: &str = ".wh..wh.readonly/refused";
+
+/// Returns the path that a change refused by the options of the mapping with the upper dir
+/// `upper_dir` is redirected to.
+fn read_only_path(upper_dir: &Path) -> PathBuf {
+    upper_dir.join(READ_ONLY)
+}
+
+/// Checks whether `path` is the result of a change refused by the options of a mapping, see
+/// `read_only_path`.
+pub fn is_read_only(path: &Path) -> bool {
+    path.ends_with(READ_ONLY)
+}
+
+/// Checks whether the options of its mapping refuse changes to the existing entry `path` of the
+/// merged view, like `redirect_mapped` does.
+pub fn refuses_changes(path: &Path) -> bool {
+    layers(path).map_or(false, |layers| match (layers.upper, layers.lower) {
+        (Some(_), _) => layers.mapping.read_only,
+        (None, Some(_)) => layers.mapping.read_only || layers.mapping.copy_up == CopyUp::Never,
+        (None, None) => false,
+    })
+}
+
+/// Redirects an absolute path according to the mappings.
+fn redirect_mapped(path: &Path, access: Access) -> Option<PathBuf> {
+    if whiteout::is_marker(path) {
         return None;
     }
-    // TODO: do things break when path contains `..` in the middle?
 
     let cfg = config::get_config()?;
-    // Only redirect accesses to the lower directory, ignore any other accesses
-    let path_in_lower = path.strip_prefix(&cfg.lower_dir).ok()?;
+    // Only redirect accesses to the lower directories, ignore any other accesses
+    let (mapping, path_in_lower) = cfg.lower_mapping(path)?;
+    if let Some(folded) = fold_case(mapping, path_in_lower) {
+        return Some(redirect_mapped(&folded, access).unwrap_or(folded));
+    }
 
-    let path_to_upper = cfg.upper_dir.join(path_in_lower);
+    let path_to_upper = mapping.upper_dir.join(path_in_lower);
+    let change = access == Access::Write || access == Access::Metadata;
+    if mapping.read_only && change {
+        return Some(read_only_path(&mapping.upper_dir));
+    }
 
-    // If the path alrady exists in the upper directory, redirect to that one
-    let redirect = if path_to_upper.exists() {
+    // If the path alrady exists in the upper directory, redirect to that one.
+    // Whited out paths are redirected as well, where they don't exist (yet).
+    let in_upper = path_to_upper.symlink_metadata().is_ok();
+    let shadowed = in_upper || hidden(mapping, &path_to_upper);
+    let never = mapping.copy_up == CopyUp::Never;
+    if never && change && !shadowed && path.symlink_metadata().is_ok() {
+        return Some(read_only_path(&mapping.upper_dir));
+    }
+    if let (false, Some(file)) = (shadowed, cfg.synthetic_file(path)) {
+        return redirect_synthetic(file, &mapping.upper_dir, &path_to_upper, access);
+    }
+    // Files missing from a lower dir that mirrors a remote tree are fetched into it first
+    if let (false, Some(remote)) = (shadowed, cfg.remote(mapping)) {
+        if !path_in_lower.as_os_str().is_empty() && path.symlink_metadata().is_err() {
+            // Another process may be fetching the same file, its copy is used once it is done
+            let _lock = lock::copy_up(&path_to_upper);
+            if path.symlink_metadata().is_err() {
+                remote.fetch(path_in_lower);
+            }
+        }
+    }
+    let redirect = if shadowed {
+        // A hidden entry is created in the upper dir, where its parent may not exist yet
+        if !in_upper && access == Access::Write && parent_visible(mapping, path) {
+            create_upper_parent(&path_to_upper)?;
+        }
+        // Writing to a metadata-only copy requires its contents
+        if access == Access::Write && metacopy::is_stub(&path_to_upper) {
+            if !quota::reserve(file_size(path), 0) {
+                return Some(quota::refused_path(&mapping.upper_dir));
+            }
+            metacopy::copy_contents(path, &path_to_upper)?;
+        }
+        true
+    // Another link of the lower file has been copied up already, this one shares the copy
+    } else if hardlink::link_copy(path, &path_to_upper) {
         true
+    // Lower files opened for reading are served from a copy, if that is configured
+    } else if access == Access::Cache {
+        !never && cache(mapping, path, &path_to_upper)
     // If the flags imply write access, make a copy and redirect to that one
-    } else if write {
+    } else if access != Access::Read {
         let parent_in_lower = path.parent()?;
 
         if parent_in_lower.exists() {
             // Make sure the directory exists
-            let parent_in_upper = path_to_upper.parent()?;
-            std::fs::create_dir_all(parent_in_upper)
-                .map_err(|e| {
-                    config::if_debug(|| {
-                        eprintln!(
-                            "liboverlay: could not create {}: {}",
-                            parent_in_upper.display(),
-                            e
-                        )
-                    })
-                })
-                .ok()?;
-
-            // Copy source file if it exists
-            if path.is_file() {
-                config::if_debug(|| eprintln!("liboverlay: making writable copy"));
-                // HACK: This relies crucially on the fact that fs::copy first opens the source path,
-                //  otherwise, our own redirection logic would apply and send the read request to the
-                //  newly created upper file.
-                // HACK: This is not thread safe!
-                std::fs::copy(path, &path_to_upper)
-                    .map_err(|e| {
-                        config::if_debug(|| {
-                            eprintln!(
-                                "liboverlay: failed to copy from lower {} to upper {}: {}",
-                                path.display(),
-                                path_to_upper.display(),
-                                e
-                            )
-                        })
-                    })
-                    .ok()?;
-                let mut perms = std::fs::metadata(&path_to_upper).ok()?.permissions();
-                perms.set_readonly(false);
-                std::fs::set_permissions(&path_to_upper, perms).ok()?;
+            create_upper_parent(&path_to_upper)?;
+
+            // Another process may be copying up the same path, its copy is used once it is done
+            let _lock = lock::copy_up(&path_to_upper);
+            let copied = path_to_upper.symlink_metadata().is_ok();
+            // Stubs cannot be shared between links, since they are completed independently
+            // Nor can files be transformed without copying their contents
+            let stub = path.is_file()
+                && access == Access::Metadata
+                && mapping.copy_up != CopyUp::Lazy
+                && !hardlink::has_links(path)
+                && transforms(path).is_none();
+            let bytes = if stub { 0 } else { file_size(path) };
+            if !copied && !quota::reserve(bytes, 1) {
+                return Some(quota::refused_path(&mapping.upper_dir));
+            }
+
+            // Copy source file if it exists, symlinks are copied as they are unless configured
+            // otherwise
+            if copied {
+            } else if preserves_symlink(path) {
+                copy_up(path, &path_to_upper)?;
+            } else if stub {
+                metacopy::create(path, &path_to_upper)?;
+            } else if path.is_file() {
+                copy_up(path, &path_to_upper)?;
+            } else if path.is_dir() {
+                copy_up_dir(path, &path_to_upper)?;
             }
         }
         true
@@ -72,8 +222,8 @@ pub fn redirect_path(path: &Path, write: bool) -> Option<PathBuf> {
     };
 
     if redirect {
-        config::if_debug(|| {
-            eprintln!(
+        log::debug(Category::Redir, || {
+            log_println!(
                 "liboverlay: redirecting {} to {}",
                 path.display(),
                 path_to_upper.display()
@@ -84,3 +234,665 @@ pub fn redirect_path(path: &Path, write: bool) -> Option<PathBuf> {
         None
     }
 }
+
+/// Copies the lower file `path` to `path_to_upper` when copy-on-read is enabled, the mapping copies
+/// up eagerly, or the file is transformed, so that it is read from the upper dir from then on.
+/// Mappings preferring reflinks only keep clones. Returns whether the copy exists. Nothing is
+/// copied if the quota does not allow it, the lower file is read in that case.
+fn cache(mapping: &config::Mapping, path: &Path, path_to_upper: &Path) -> bool {
+    let copies = config::get_config().map_or(false, |cfg| cfg.copy_on_read)
+        || mapping.copy_up == CopyUp::Eager
+        || transforms(path).is_some();
+    let clones = !copies && mapping.copy_up == CopyUp::Reflink;
+    if !(copies || clones)
+        || !path
+            .symlink_metadata()
+            .map_or(false, |lower| lower.is_file())
+    {
+        return false;
+    }
+    if create_upper_parent(path_to_upper).is_none() {
+        return false;
+    }
+    // Another process may be caching the same file, its copy is used once it is done
+    let _lock = lock::copy_up(path_to_upper);
+    if path_to_upper.symlink_metadata().is_ok() {
+        return true;
+    }
+    // Clones share the data of the lower file
+    let bytes = if clones { 0 } else { file_size(path) };
+    if !quota::reserve(bytes, 1) {
+        return false;
+    }
+    if clones {
+        return clone_up(path, path_to_upper);
+    }
+    copy_up(path, path_to_upper).is_some()
+}
+
+/// Copies the lower file `path` to `path_to_upper` if it can be cloned. Returns whether it was.
+fn clone_up(path: &Path, path_to_upper: &Path) -> bool {
+    match copy::clone_file(path, path_to_upper) {
+        Ok(()) => {
+            hardlink::add_copy(path, path_to_upper);
+            true
+        }
+        Err(e) => {
+            log::debug(Category::Copy, || {
+                log_println!("liboverlay: not cloning {}: {}", path.display(), e)
+            });
+            false
+        }
+    }
+}
+
+/// Looks up the entry `path_in_lower` of a case-insensitive mapping by names that differ from those
+/// of existing entries in case only. Returns the path in the merged view with the names of the
+/// existing entries, or `None` if `path_in_lower` exists as it is or no names differ.
+fn fold_case(mapping: &config::Mapping, path_in_lower: &Path) -> Option<PathBuf> {
+    let exists = |dir: &Path, path: &Path| dir.join(path).symlink_metadata().is_ok();
+    if !mapping.case_insensitive
+        || exists(&mapping.upper_dir, path_in_lower)
+        || exists(&mapping.lower_dir, path_in_lower)
+    {
+        return None;
+    }
+    let mut folded = PathBuf::new();
+    for name in path_in_lower.iter() {
+        let path = folded.join(name);
+        if exists(&mapping.upper_dir, &path) || exists(&mapping.lower_dir, &path) {
+            folded = path;
+            continue;
+        }
+        // Upper entries take precedence, like they do in the merged view
+        let existing = [&mapping.upper_dir, &mapping.lower_dir]
+            .iter()
+            .find_map(|dir| {
+                std::fs::read_dir(dir.join(&folded))
+                    .ok()?
+                    .filter_map(Result::ok)
+                    .map(|entry| entry.file_name())
+                    .find(|entry| {
+                        whiteout::hidden_name(entry.as_bytes()).is_none()
+                            && same_name_ignoring_case(entry, name)
+                    })
+            });
+        folded.push(
+            existing
+                .as_ref()
+                .map_or(name, |existing| existing.as_os_str()),
+        );
+    }
+    if folded == path_in_lower {
+        None
+    } else {
+        Some(mapping.lower_dir.join(folded))
+    }
+}
+
+/// Compares two names ignoring case, names that are not valid UTF-8 only ignore the case of ASCII
+/// letters.
+fn same_name_ignoring_case(a: &std::ffi::OsStr, b: &std::ffi::OsStr) -> bool {
+    match (a.to_str(), b.to_str()) {
+        (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+        _ => a.as_bytes().eq_ignore_ascii_case(b.as_bytes()),
+    }
+}
+
+/// Checks whether the lower entry corresponding to `path_to_upper` is hidden from the merged view,
+/// by a whiteout or a hide rule for it or for one of its parent directories.
+fn hidden(mapping: &config::Mapping, path_to_upper: &Path) -> bool {
+    if whiteout::hides(&mapping.upper_dir, path_to_upper) {
+        return true;
+    }
+    let cfg = match config::get_config() {
+        Some(cfg) if !cfg.hidden.is_empty() => cfg,
+        _ => return false,
+    };
+    path_to_upper
+        .strip_prefix(&mapping.upper_dir)
+        .map_or(false, |path_in_lower| {
+            path_in_lower
+                .ancestors()
+                .take_while(|ancestor| *ancestor != Path::new(""))
+                .any(|ancestor| cfg.hides(mapping, ancestor))
+        })
+}
+
+/// Checks whether the parent directory of the lower path `path` is part of the merged view.
+fn parent_visible(mapping: &config::Mapping, path: &Path) -> bool {
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => return false,
+    };
+    match parent.strip_prefix(&mapping.lower_dir) {
+        Ok(parent_in_lower) => {
+            let parent_to_upper = mapping.upper_dir.join(parent_in_lower);
+            parent_to_upper.is_dir() || (parent.is_dir() && !hidden(mapping, &parent_to_upper))
+        }
+        Err(_) => false,
+    }
+}
+
+/// Checks whether a hide rule hides the entry `name` of the directory `dir` in a lower dir.
+pub fn hidden_entry(dir: &Path, name: &std::ffi::OsStr) -> bool {
+    let cfg = match config::get_config() {
+        Some(cfg) if !cfg.hidden.is_empty() => cfg,
+        _ => return false,
+    };
+    let path = dir.join(name);
+    cfg.lower_mapping(&path)
+        .map_or(false, |(mapping, path_in_lower)| {
+            cfg.hides(mapping, path_in_lower)
+        })
+}
+
+/// Redirects the synthetic file `file` to the file holding its contents, or to its copy
+/// `path_to_upper` if it is changed.
+fn redirect_synthetic(
+    file: &synthetic::File,
+    upper_dir: &Path,
+    path_to_upper: &Path,
+    access: Access,
+) -> Option<PathBuf> {
+    let backing = file.backing(upper_dir)?;
+    if access == Access::Read || access == Access::Cache {
+        log::debug(Category::Redir, || {
+            log_println!(
+                "liboverlay: redirecting synthetic {} to {}",
+                file.path.display(),
+                backing.display()
+            )
+        });
+        return Some(backing);
+    }
+    create_upper_parent(path_to_upper)?;
+    let _lock = lock::copy_up(path_to_upper);
+    if path_to_upper.symlink_metadata().is_err() {
+        if !quota::reserve(file_size(&backing), 1) {
+            return Some(quota::refused_path(upper_dir));
+        }
+        log::debug(Category::Copy, || {
+            log_println!("liboverlay: making writable copy of synthetic file")
+        });
+        copy::copy_file(&backing, path_to_upper).ok()?;
+        let mut perms = std::fs::metadata(path_to_upper).ok()?.permissions();
+        perms.set_mode(perms.mode() | 0o200);
+        std::fs::set_permissions(path_to_upper, perms).ok()?;
+    }
+    Some(path_to_upper.to_path_buf())
+}
+
+/// Returns the names and inode numbers of the synthetic files in the lower directory `dir` that are
+/// not shadowed by an upper entry of the same name, or hidden.
+pub fn synthetic_entries(dir: &Path) -> Vec<(std::ffi::OsString, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let cfg = match config::get_config() {
+        Some(cfg) if !cfg.synthetic.is_empty() => cfg,
+        _ => return Vec::new(),
+    };
+    cfg.synthetic
+        .iter()
+        .filter(|file| file.path.parent() == Some(dir))
+        .filter_map(|file| {
+            let (mapping, path_in_lower) = cfg.lower_mapping(&file.path)?;
+            let path_to_upper = mapping.upper_dir.join(path_in_lower);
+            if path_to_upper.symlink_metadata().is_ok() || hidden(mapping, &path_to_upper) {
+                return None;
+            }
+            let backing = file.backing(&mapping.upper_dir)?;
+            let ino = std::fs::metadata(backing).ok()?.ino();
+            Some((file.path.file_name()?.to_os_string(), ino))
+        })
+        .collect()
+}
+
+/// Returns the size of the contents of the lower file `path`, which are copied up for writing.
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map_or(0, |lower| if lower.is_file() { lower.len() } else { 0 })
+}
+
+/// Checks whether a directory has no entries in the merged view.
+pub fn is_empty_dir(layers: &Layers) -> std::io::Result<bool> {
+    if layers.upper.map_or(false, |upper| upper.is_dir()) {
+        for entry in std::fs::read_dir(&layers.upper_path)? {
+            if whiteout::hidden_name(entry?.file_name().as_bytes()).is_none() {
+                return Ok(false);
+            }
+        }
+    }
+    if layers.lower.map_or(false, |lower| lower.is_dir()) {
+        for entry in std::fs::read_dir(&layers.path)? {
+            let name = entry?.file_name();
+            if !whiteout::exists(&layers.upper_path.join(&name))
+                && !hidden_entry(&layers.path, &name)
+            {
+                return Ok(false);
+            }
+        }
+        if !synthetic_entries(&layers.path).is_empty() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Copies the directory `path` of the merged view up along with all of its entries, recursively, so
+/// that its upper directory holds the whole directory and can be moved on its own. Returns
+/// `Some(false)` if the quota does not allow the copies.
+pub fn copy_up_tree(path: &Path) -> Option<bool> {
+    // Redirecting for writing copies up whatever is missing of a single entry
+    if quota::is_refused(&redirect_path(path, true)?) {
+        return Some(false);
+    }
+    let dir = layers(path)?;
+    let is_dir = |entry: Option<FileType>| entry.map_or(false, |entry| entry.is_dir());
+    if !is_dir(dir.upper) || !is_dir(dir.lower) {
+        return Some(true);
+    }
+    let lower_names = std::fs::read_dir(&dir.path)
+        .ok()?
+        .map(|entry| entry.map(|entry| (entry.file_name(), false)))
+        .collect::<std::io::Result<Vec<_>>>()
+        .ok()?;
+    let synthetic_names = synthetic_entries(&dir.path)
+        .into_iter()
+        .map(|(name, _)| (name, true));
+    for (name, synthetic) in lower_names.into_iter().chain(synthetic_names) {
+        let child = layers(&dir.path.join(&name))?;
+        let complete = match child.upper {
+            // Upper directories may still lack lower entries, and stubs their contents
+            Some(upper) => !upper.is_dir() && !metacopy::is_stub(&child.upper_path),
+            // Whited out and hidden entries are not copied
+            None => child.lower.is_none() && !synthetic,
+        };
+        if !complete && !copy_up_tree(&child.path)? {
+            return Some(false);
+        }
+    }
+    Some(true)
+}
+
+/// Checks whether the lower directories below the lower directory `path` are nested at most `depth`
+/// levels deep, counting `path` itself.
+pub fn tree_within_depth(path: &Path, depth: usize) -> std::io::Result<bool> {
+    if depth == 0 {
+        return Ok(false);
+    }
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() && !tree_within_depth(&entry.path(), depth - 1)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Creates the parent directories of `path_to_upper` in the upper dir.
+pub fn create_upper_parent(path_to_upper: &Path) -> Option<()> {
+    let parent_in_upper = path_to_upper.parent()?;
+    std::fs::create_dir_all(parent_in_upper)
+        .map_err(|e| {
+            log::warn(Category::Copy, || {
+                log_println!(
+                    "liboverlay: could not create {}: {}",
+                    parent_in_upper.display(),
+                    e
+                )
+            })
+        })
+        .ok()
+}
+
+/// Makes a writable copy of the lower file `path` at `path_to_upper`. Callers hold the copy-up lock
+/// of `path_to_upper`, see `lock::copy_up`.
+pub fn copy_up(path: &Path, path_to_upper: &Path) -> Option<()> {
+    if preserves_symlink(path) {
+        return copy_up_symlink(path, path_to_upper);
+    }
+    log::debug(Category::Copy, || {
+        log_println!("liboverlay: making writable copy")
+    });
+    // A followed symlink is copied with the contents its target has in the merged view
+    let source = match follow_symlinks(path) {
+        Some(target) => redirect_path(&target, false).map_or(target, contents_path),
+        None => path.to_path_buf(),
+    };
+    let copied = match transforms(path) {
+        Some(transforms) => copy::copy_file_transformed(&source, path_to_upper, |contents| {
+            transform::apply(transforms, contents)
+        }),
+        None => copy::copy_file(&source, path_to_upper),
+    };
+    copied
+        .map_err(|e| {
+            log::warn(Category::Copy, || {
+                log_println!(
+                    "liboverlay: failed to copy from lower {} to upper {}: {}",
+                    source.display(),
+                    path_to_upper.display(),
+                    e
+                )
+            })
+        })
+        .ok()?;
+    hardlink::add_copy(path, path_to_upper);
+    let mut perms = std::fs::metadata(path_to_upper).ok()?.permissions();
+    perms.set_mode(perms.mode() | 0o200);
+    std::fs::set_permissions(path_to_upper, perms).ok()
+}
+
+/// Returns the transformations applied to the lower file `path` when it is copied up, if any.
+fn transforms(path: &Path) -> Option<&'static [transform::Transform]> {
+    let cfg = config::get_config()?;
+    let (mapping, path_in_lower) = cfg.lower_mapping(path)?;
+    cfg.transforms(mapping, path_in_lower)
+}
+
+/// Checks whether `path` is a symlink that is copied up as a symlink, rather than as the file it
+/// points to.
+fn preserves_symlink(path: &Path) -> bool {
+    let follow = config::get_config().map_or(false, |cfg| cfg.follow_symlinks);
+    !follow
+        && path
+            .symlink_metadata()
+            .map_or(false, |lower| lower.file_type().is_symlink())
+}
+
+/// Recreates the lower symlink `path` at `path_to_upper`, pointing to the same target.
+fn copy_up_symlink(path: &Path, path_to_upper: &Path) -> Option<()> {
+    log::debug(Category::Copy, || {
+        log_println!("liboverlay: copying symlink")
+    });
+    std::fs::read_link(path)
+        .and_then(|target| std::os::unix::fs::symlink(target, path_to_upper))
+        .and_then(|_| copy::copy_symlink_metadata(path, path_to_upper))
+        .map_err(|e| {
+            log::warn(Category::Copy, || {
+                log_println!(
+                    "liboverlay: failed to copy symlink {} to {}: {}",
+                    path.display(),
+                    path_to_upper.display(),
+                    e
+                )
+            })
+        })
+        .ok()
+}
+
+/// Creates an empty upper file in place of the lower file `path`, for opens that truncate it
+/// anyway. Copying its contents up first would be wasted effort, which adds up for large files.
+pub fn create_truncated(path: &Path) -> Option<()> {
+    let path = &*absolute(path)?;
+    let (mapping, path_in_lower) = config::get_config()?.lower_mapping(path)?;
+    // The open itself is refused for the mapping
+    if mapping.read_only || mapping.copy_up == CopyUp::Never {
+        return None;
+    }
+    let path_to_upper = mapping.upper_dir.join(path_in_lower);
+    let _lock = lock::copy_up(&path_to_upper);
+    if metacopy::is_stub(&path_to_upper) {
+        return metacopy::discard_contents(&path_to_upper);
+    }
+    let in_upper = path_to_upper.symlink_metadata().is_ok();
+    if in_upper || hidden(mapping, &path_to_upper) || !path.is_file() {
+        return None;
+    }
+    if hardlink::link_copy(path, &path_to_upper) {
+        return Some(());
+    }
+    if !quota::reserve(0, 1) {
+        return None;
+    }
+    create_upper_parent(&path_to_upper)?;
+
+    log::debug(Category::Copy, || {
+        log_println!("liboverlay: making empty writable copy")
+    });
+    let mode = std::fs::metadata(path).ok()?.permissions().mode();
+    std::fs::File::create(&path_to_upper)
+        .and_then(|_| copy::copy_metadata(path, &path_to_upper))
+        .map_err(|e| {
+            log::warn(Category::Copy, || {
+                log_println!(
+                    "liboverlay: failed to create upper {}: {}",
+                    path_to_upper.display(),
+                    e
+                )
+            })
+        })
+        .ok()?;
+    hardlink::add_copy(path, &path_to_upper);
+    std::fs::set_permissions(
+        &path_to_upper,
+        std::fs::Permissions::from_mode(mode | 0o200),
+    )
+    .ok()
+}
+
+/// Creates an empty counterpart of the lower directory `path` at `path_to_upper`, its contents are
+/// provided by merging both directories.
+pub fn copy_up_dir(path: &Path, path_to_upper: &Path) -> Option<()> {
+    log::debug(Category::Copy, || {
+        log_println!("liboverlay: making writable directory")
+    });
+    let mode = std::fs::metadata(path).ok()?.permissions().mode();
+    std::fs::DirBuilder::new()
+        .mode(mode | 0o700)
+        .create(path_to_upper)
+        .and_then(|_| copy::copy_metadata(path, path_to_upper))
+        .map_err(|e| {
+            log::warn(Category::Copy, || {
+                log_println!(
+                    "liboverlay: failed to create upper dir {}: {}",
+                    path_to_upper.display(),
+                    e
+                )
+            })
+        })
+        .ok()?;
+    std::fs::set_permissions(path_to_upper, std::fs::Permissions::from_mode(mode | 0o700)).ok()
+}
+
+/// Returns the path that the open file descriptor `fd` refers to. Descriptors of files in the upper
+/// dir are reported with their path in the merged view.
+pub fn fd_path(fd: i32) -> Option<PathBuf> {
+    let path = std::fs::read_link(format!("/proc/self/fd/{}", fd)).ok()?;
+    Some(to_merged(path))
+}
+
+/// Returns the path of the open file descriptor `fd` if it refers to an entry of a lower dir
+/// itself, rather than to its counterpart in the upper dir.
+pub fn fd_in_lower(fd: i32) -> Option<PathBuf> {
+    let cfg = config::get_config()?;
+    let path = std::fs::read_link(format!("/proc/self/fd/{}", fd)).ok()?;
+    cfg.lower_mapping(&path)?;
+    Some(path)
+}
+
+/// Returns the path of the open file descriptor `fd` if it refers to an entry of an upper dir.
+pub fn fd_in_upper(fd: i32) -> Option<PathBuf> {
+    let cfg = config::get_config()?;
+    let path = std::fs::read_link(format!("/proc/self/fd/{}", fd)).ok()?;
+    cfg.upper_mapping(&path)?;
+    Some(path)
+}
+
+/// Returns the current directory with its path in the merged view.
+pub fn current_dir() -> Option<PathBuf> {
+    std::env::current_dir().ok().map(to_merged)
+}
+
+/// Maps a path in the upper dir to the corresponding path in the merged view, other paths are
+/// returned unchanged.
+pub fn to_merged(path: PathBuf) -> PathBuf {
+    let merged = config::get_config()
+        .and_then(|cfg| cfg.upper_mapping(&path))
+        .map(|(mapping, path_in_upper)| mapping.lower_dir.join(path_in_upper));
+    merged.unwrap_or(path)
+}
+
+/// Makes `path` absolute by resolving it against the current directory in the merged view, and
+/// resolves `..` components as well as symlinks leading to the last component within the lower
+/// dirs. Returns `None` for the empty path, which does not refer to anything.
+fn absolute(path: &Path) -> Option<Cow<'_, Path>> {
+    let absolute = if path.is_absolute() {
+        Cow::Borrowed(path)
+    } else if path.as_os_str().is_empty() {
+        return None;
+    } else {
+        Cow::Owned(current_dir()?.join(path))
+    };
+    // `.` components are skipped by `components` already, which the mappings are matched against
+    let parent_dirs = absolute.components().any(|c| c == Component::ParentDir);
+    let nested = config::get_config()?
+        .lower_mapping(&absolute)
+        .map_or(false, |(_, path_in_lower)| path_in_lower.parent().is_some());
+    if parent_dirs || nested {
+        Some(Cow::Owned(resolve(&absolute, &mut 0)))
+    } else {
+        Some(absolute)
+    }
+}
+
+/// Resolves the absolute `path` component by component, like the kernel would if the merged view
+/// was a real overlay: symlinks leading to the last component are followed where they lie in a
+/// lower dir, looking at the upper entries and whiteouts at each step, and `..` leads to the parent
+/// of what precedes it, which is the parent of the target for a symlink. The result neither
+/// escapes a lower dir in the upper dir nor dodges the mapping of a lower dir it leads into. The
+/// last component is left as it is, for callers to follow or not. `links` counts the symlinks
+/// followed so far.
+fn resolve(path: &Path, links: &mut usize) -> PathBuf {
+    let cfg = config::get_config();
+    let mut resolved = PathBuf::new();
+    let mut components = path.components().peekable();
+    while let Some(component) = components.next() {
+        if component == Component::ParentDir {
+            // Outside the lower dirs, symlinks are left to the kernel except for those before `..`
+            follow_links(&mut resolved, links);
+            resolved.pop();
+            continue;
+        }
+        resolved.push(component);
+        let mapped = cfg.map_or(false, |cfg| cfg.lower_mapping(&resolved).is_some());
+        if mapped && components.peek().is_some() {
+            follow_links(&mut resolved, links);
+        }
+    }
+    resolved
+}
+
+/// Replaces `resolved` by the resolved target while it is a symlink.
+fn follow_links(resolved: &mut PathBuf, links: &mut usize) {
+    while *links < MAX_SYMLINKS {
+        match read_link(resolved) {
+            Some(target) => {
+                *links += 1;
+                *resolved = resolve(&target, links);
+            }
+            None => break,
+        }
+    }
+}
+
+/// Reads the symlink `path` as part of the merged view, and returns its target resolved against
+/// the directory containing the link. Returns `None` if `path` is not a symlink.
+fn read_link(path: &Path) -> Option<PathBuf> {
+    let target = match config::get_config()?.lower_mapping(path) {
+        Some((mapping, path_in_lower)) => {
+            let upper = mapping.upper_dir.join(path_in_lower);
+            if upper.symlink_metadata().is_ok() {
+                std::fs::read_link(&upper).ok()?
+            } else if hidden(mapping, &upper) {
+                return None;
+            } else {
+                std::fs::read_link(path).ok()?
+            }
+        }
+        None => std::fs::read_link(path).ok()?,
+    };
+    // Absolute targets replace the path entirely
+    Some(match path.parent() {
+        Some(parent) => parent.join(target),
+        None => target,
+    })
+}
+
+/// The maximum number of symlinks followed while resolving a path, like Linux' `MAXSYMLINKS`.
+const MAX_SYMLINKS: usize = 40;
+
+/// Follows symlinks as if they were part of the merged view, i.e. symlinks in the upper dir shadow
+/// the lower entries, whited out symlinks are gone, and relative targets are resolved against the
+/// parent directory in the lower dir rather than the upper dir a link lives in. Returns `None` if
+/// `path` does not refer to a symlink in a lower dir.
+pub fn follow_symlinks(path: &Path) -> Option<PathBuf> {
+    let cfg = config::get_config()?;
+    let mut current = absolute(path)?.into_owned();
+    let mut followed = false;
+    for _ in 0..MAX_SYMLINKS {
+        if cfg.lower_mapping(&current).is_none() {
+            break;
+        }
+        current = match read_link(&current) {
+            Some(target) => absolute(&target)?.into_owned(),
+            None => break,
+        };
+        followed = true;
+    }
+    if followed {
+        log::debug(Category::Redir, || {
+            log_println!(
+                "liboverlay: followed {} to {}",
+                path.display(),
+                current.display()
+            )
+        });
+        Some(current)
+    } else {
+        None
+    }
+}
+
+/// Where an entry of the merged view lives.
+pub struct Layers {
+    /// The mapping whose lower dir contains the entry.
+    pub mapping: &'static config::Mapping,
+    /// The absolute path of the entry in the merged view.
+    pub path: PathBuf,
+    /// The path the entry has (or would have) in the upper dir.
+    pub upper_path: PathBuf,
+    /// The type of the upper entry, if there is one.
+    pub upper: Option<FileType>,
+    /// The type of the lower entry, if there is one that has not been whited out.
+    pub lower: Option<FileType>,
+}
+
+/// Looks up `path` in both layers, returns `None` for paths outside the lower dirs.
+pub fn layers(path: &Path) -> Option<Layers> {
+    let path = absolute(path)?.into_owned();
+    let (mapping, path_in_lower) = config::get_config()?.lower_mapping(&path)?;
+    if let Some(folded) = fold_case(mapping, path_in_lower) {
+        return layers(&folded);
+    }
+    let upper_path = mapping.upper_dir.join(path_in_lower);
+
+    let upper = if whiteout::is_marker(&upper_path) {
+        None
+    } else {
+        upper_path.symlink_metadata().ok().map(|m| m.file_type())
+    };
+    let lower = if hidden(mapping, &upper_path) {
+        None
+    } else {
+        path.symlink_metadata().ok().map(|m| m.file_type())
+    };
+
+    Some(Layers {
+        mapping,
+        path,
+        upper_path,
+        upper,
+        lower,
+    })
+}
@@ -0,0 +1,146 @@
+//! Reloads the configuration on `SIGHUP`, so that long-running processes pick up changes of their
+//! config file without a restart. The signal only requests the reload, which happens on the next
+//! access of the configuration. Operations that are under way keep the configuration they started
+//! with.
+//!
+//! The handler is installed once the configuration is loaded. Programs handle `SIGHUP` as before:
+//! the actions they install are kept aside and run by the handler after requesting the reload, with
+//! their mask and flags. Without an action of their own, the signal still terminates them, so only
+//! programs that handle or ignore `SIGHUP` are reloaded by it.
+
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::log::{self, Category};
+
+pub const SIGHUP: c_int = 1;
+
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+const SA_SIGINFO: c_int = 4;
+const SA_RESTART: c_int = 0x1000_0000;
+const SA_RESETHAND: c_int = 0x8000_0000_u32 as c_int;
+
+extern "C" {
+    fn pthread_sigmask(how: c_int, set: *const SigSet, oldset: *mut SigSet) -> c_int;
+    fn raise(signum: c_int) -> c_int;
+}
+
+/// `sigset_t` of glibc.
+type SigSet = [u64; 16];
+
+const SIG_BLOCK: c_int = 0;
+const SIG_SETMASK: c_int = 2;
+
+/// `struct sigaction` of glibc, the default is the default action.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SigAction {
+    pub handler: usize,
+    pub mask: SigSet,
+    pub flags: c_int,
+    pub restorer: usize,
+}
+
+/// Whether a reload has been requested and not yet done.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the handler is installed, from then on the hooks keep the actions of the program aside.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// The action the program installed for `SIGHUP`, the default action if null. Replaced actions are
+/// never freed, since the handler may still be running them.
+static PROGRAM_ACTION: AtomicPtr<SigAction> = AtomicPtr::new(std::ptr::null_mut());
+
+extern "C" fn handle(signum: c_int, info: *mut c_void, context: *mut c_void) {
+    REQUESTED.store(true, Ordering::SeqCst);
+    let action = match unsafe { PROGRAM_ACTION.load(Ordering::SeqCst).as_ref() } {
+        Some(action) => *action,
+        None => return terminate(signum),
+    };
+    if action.flags & SA_RESETHAND != 0 {
+        PROGRAM_ACTION.store(std::ptr::null_mut(), Ordering::SeqCst);
+    }
+    let handler = match action.handler {
+        SIG_DFL => return terminate(signum),
+        SIG_IGN => return,
+        handler => handler,
+    };
+    // Only the signal itself is blocked while the handler runs, the program's mask is added here
+    let mut previous_mask = SigSet::default();
+    unsafe { pthread_sigmask(SIG_BLOCK, &action.mask, &mut previous_mask) };
+    if action.flags & SA_SIGINFO != 0 {
+        let handler: extern "C" fn(c_int, *mut c_void, *mut c_void) =
+            unsafe { std::mem::transmute(handler) };
+        handler(signum, info, context)
+    } else {
+        let handler: extern "C" fn(c_int) = unsafe { std::mem::transmute(handler) };
+        handler(signum)
+    }
+    unsafe { pthread_sigmask(SIG_SETMASK, &previous_mask, std::ptr::null_mut()) };
+}
+
+/// Takes the default action for `signum`, which terminates the process. The handler gives way to
+/// the default action and raises the signal again, which arrives once the handler returns.
+fn terminate(signum: c_int) {
+    let action = SigAction::default();
+    unsafe {
+        crate::C_SIGACTION.call(signum, &action, std::ptr::null_mut());
+        raise(signum);
+    }
+}
+
+/// Installs the handler, keeping the action that is installed already as the one of the program.
+pub fn install() {
+    let action = SigAction {
+        handler: handle as extern "C" fn(c_int, *mut c_void, *mut c_void) as usize,
+        flags: SA_SIGINFO | SA_RESTART,
+        ..SigAction::default()
+    };
+    let mut previous = SigAction::default();
+    // The hook passes this on to libc, since the handler is not installed yet
+    if unsafe { crate::sigaction(SIGHUP, &action, &mut previous) } != 0 {
+        log::error(Category::Config, || {
+            log_println!("liboverlay: cannot handle SIGHUP, the configuration is not reloaded")
+        });
+        return;
+    }
+    if previous.handler != SIG_DFL {
+        PROGRAM_ACTION.store(Box::into_raw(Box::new(previous)), Ordering::SeqCst);
+    }
+    INSTALLED.store(true, Ordering::SeqCst);
+}
+
+/// Checks whether the handler is installed, so that the program's actions for `SIGHUP` are kept
+/// aside by `replace_action`.
+pub fn installed() -> bool {
+    INSTALLED.load(Ordering::SeqCst)
+}
+
+/// Replaces the action of the program for `SIGHUP` with `action`, and returns the one it replaces.
+/// The action is only queried if `action` is `None`.
+pub fn replace_action(action: Option<&SigAction>) -> SigAction {
+    let previous = unsafe { PROGRAM_ACTION.load(Ordering::SeqCst).as_ref() }
+        .cloned()
+        .unwrap_or_default();
+    if let Some(action) = action {
+        PROGRAM_ACTION.store(Box::into_raw(Box::new(*action)), Ordering::SeqCst);
+    }
+    previous
+}
+
+/// Returns the action that `signal` installs for `handler`, which restarts interrupted calls.
+pub fn signal_action(handler: usize) -> SigAction {
+    SigAction {
+        handler,
+        flags: SA_RESTART,
+        ..SigAction::default()
+    }
+}
+
+/// Checks whether a reload has been requested, only the first caller afterwards is told so.
+#[inline(always)]
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed) && REQUESTED.swap(false, Ordering::SeqCst)
+}
@@ -0,0 +1,185 @@
+//! Remote lower dirs are mirrored lazily: a lower dir declared as a mirror of a remote tree, e.g.
+//! `http://artifacts.example/tree/`, serves as a cache of it. When a path within the lower dir is
+//! looked up but exists in neither layer, the file is fetched into the lower dir first, and is
+//! then served like any other lower file.
+//!
+//! `http://`, `https://` and `ftp://` locations are fetched with `curl`, anything else, like
+//! `rsync://` locations and `host:path` over SSH, with `rsync`. Only files are fetched, the
+//! directories leading to them are created in the cache. Paths that could not be fetched are not
+//! tried again by the same process.
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::log::{self, Category};
+
+/// Fetches in progress use the reserved names of whiteouts, so they are never part of the merged
+/// view, even when left behind.
+const TEMP_PREFIX: &str = ".wh..wh.fetch.";
+
+/// Distinguishes the fetches made by the threads of one process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The paths that this process failed to fetch.
+static MISSED: AtomicPtr<Mutex<HashSet<PathBuf>>> = AtomicPtr::new(std::ptr::null_mut());
+
+#[used]
+#[cfg_attr(target_os = "linux", link_section = ".init_array")]
+pub static INIT_MISSED: extern "C" fn() = {
+    extern "C" fn init() {
+        let missed = Box::new(Mutex::new(HashSet::new()));
+        MISSED.store(Box::into_raw(missed), Ordering::SeqCst);
+    }
+    init
+};
+
+/// A lower dir mirroring a remote tree.
+#[derive(Debug)]
+pub struct Remote {
+    pub lower_dir: PathBuf,
+    /// The location of the tree, to which the paths within the lower dir are appended.
+    location: String,
+}
+
+impl Remote {
+    /// Fetches the file `path_in_lower` of the remote tree into the lower dir. Returns whether it
+    /// exists there now.
+    pub fn fetch(&self, path_in_lower: &Path) -> bool {
+        let path = self.lower_dir.join(path_in_lower);
+        let missed = unsafe { MISSED.load(Ordering::SeqCst).as_ref() };
+        if missed.map_or(false, |missed| missed.lock().unwrap().contains(&path)) {
+            return false;
+        }
+        let fetched = self.fetch_to(path_in_lower, &path);
+        if !fetched {
+            if let Some(missed) = missed {
+                missed.lock().unwrap().insert(path);
+            }
+        }
+        fetched
+    }
+
+    fn fetch_to(&self, path_in_lower: &Path, path: &Path) -> bool {
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return false,
+        };
+        let mut temp = OsString::from(format!(
+            "{}{}.{}.",
+            TEMP_PREFIX,
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        temp.push(name);
+        let temp = parent.join(temp);
+
+        let path_in_lower = path_in_lower.as_os_str().as_bytes();
+        let source = format!(
+            "{}/{}",
+            self.location.trim_end_matches('/'),
+            if self.is_url() {
+                encode(path_in_lower)
+            } else {
+                String::from_utf8_lossy(path_in_lower).into_owned()
+            }
+        );
+        log::info(Category::Remote, || {
+            log_println!("liboverlay: fetching {}", source)
+        });
+        let mut command = if self.is_url() {
+            let mut command = Command::new("curl");
+            command
+                .arg("--fail")
+                .arg("--silent")
+                .arg("--location")
+                .arg("--output")
+                .arg(&temp)
+                .arg(&source);
+            command
+        } else {
+            let mut command = Command::new("rsync");
+            command
+                .arg("--copy-links")
+                .arg("--protect-args")
+                .arg("--times")
+                .arg(&source)
+                .arg(&temp);
+            command
+        };
+        // The fetching tools are not overlaid themselves
+        let status = std::fs::create_dir_all(parent).and_then(|_| {
+            command
+                .env_remove("LD_PRELOAD")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+        });
+        // Directories are not fetched, e.g. rsync skips them and succeeds
+        let fetched = match status {
+            Ok(status) => status.success() && temp.is_file(),
+            Err(e) => {
+                log::warn(Category::Remote, || {
+                    log_println!("liboverlay: failed to fetch {}: {}", source, e)
+                });
+                false
+            }
+        };
+        if fetched && std::fs::rename(&temp, path).is_ok() {
+            return true;
+        }
+        let _ = std::fs::remove_file(&temp);
+        false
+    }
+
+    fn is_url(&self) -> bool {
+        ["http://", "https://", "ftp://"]
+            .iter()
+            .any(|scheme| self.location.starts_with(scheme))
+    }
+}
+
+/// Forgets the paths that could not be fetched, so that they are tried again.
+pub fn forget_missed() {
+    if let Some(missed) = unsafe { MISSED.load(Ordering::SeqCst).as_ref() } {
+        missed.lock().unwrap().clear();
+    }
+}
+
+/// Percent-encodes a path for a URL.
+fn encode(path: &[u8]) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for &b in path {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(b as char),
+            b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Parses a list of `LOWER_DIR=LOCATION` pairs separated by `;`.
+pub fn parse_remotes(list: &str) -> Result<Vec<Remote>, String> {
+    list.split(';')
+        .filter(|remote| !remote.is_empty())
+        .map(|remote| {
+            let split = remote
+                .find('=')
+                .ok_or_else(|| format!("missing `=` in `{}`", remote))?;
+            let (lower_dir, location) = (&remote[..split], &remote[split + 1..]);
+            if lower_dir.is_empty() || location.is_empty() {
+                return Err(format!("invalid remote `{}`", remote));
+            }
+            Ok(Remote {
+                lower_dir: PathBuf::from(lower_dir),
+                location: location.to_string(),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,528 @@
+//! Rewrite rules relocate paths before they are mapped to the layers, in order to support
+//! programs with hard-coded paths.
+//!
+//! A rule has the form of sed's `s` command, e.g. `s#^/opt/app/v([0-9]+)/#/upper/v$1/#`. The
+//! first character after the `s` delimits the pattern and the replacement, and can be escaped with
+//! a backslash within them.
+//!
+//! Patterns are regular expressions matched against the bytes of absolute paths. They support
+//! literals, `.`, classes like `[a-z]` and `[^/]`, the shorthands `\d`, `\w` and `\s`, the anchors
+//! `^` and `$`, groups, alternatives and the greedy quantifiers `*`, `+`, `?` and `{m,n}`. In the
+//! replacement, `$0` to `$9` refer to the whole match and its groups, and `$$` is a literal `$`.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// A rule replacing the first match of a pattern in a path.
+pub struct Rule {
+    source: String,
+    pattern: Regex,
+    replacement: Vec<u8>,
+}
+
+impl std::fmt::Debug for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Rule {
+    /// Returns the rewritten path, or `None` if the pattern does not match `path`.
+    pub fn apply(&self, path: &Path) -> Option<PathBuf> {
+        let input = path.as_os_str().as_bytes();
+        let groups = self.pattern.captures(input)?;
+        let (start, end) = groups[0]?;
+
+        let mut rewritten = input[..start].to_vec();
+        let mut replacement = self.replacement.iter();
+        while let Some(&byte) = replacement.next() {
+            if byte != b'$' {
+                rewritten.push(byte);
+                continue;
+            }
+            match replacement.next() {
+                Some(&digit @ b'0'..=b'9') => {
+                    let group = groups.get(usize::from(digit - b'0')).cloned().flatten();
+                    if let Some((start, end)) = group {
+                        rewritten.extend_from_slice(&input[start..end]);
+                    }
+                }
+                Some(&b'$') | None => rewritten.push(b'$'),
+                Some(&other) => rewritten.extend_from_slice(&[b'$', other]),
+            }
+        }
+        rewritten.extend_from_slice(&input[end..]);
+        Some(PathBuf::from(std::ffi::OsString::from_vec(rewritten)))
+    }
+}
+
+/// Parses a list of rules separated by `;`.
+pub fn parse_rules(list: &str) -> Result<Vec<Rule>, String> {
+    let mut rules = Vec::new();
+    let mut rest = list.as_bytes();
+    while !rest.is_empty() {
+        if rest[0] == b';' {
+            rest = &rest[1..];
+            continue;
+        }
+        let (rule, remainder) = parse_rule(rest)?;
+        rules.push(rule);
+        rest = remainder;
+    }
+    Ok(rules)
+}
+
+/// Parses the rule at the start of `input`, returns it together with the remaining input.
+fn parse_rule(input: &[u8]) -> Result<(Rule, &[u8]), String> {
+    if input.len() < 2 || input[0] != b's' || input[1] == b'\\' {
+        return Err(format!("`{}` is not an s command", lossy(input)));
+    }
+    let delimiter = input[1];
+    let (pattern, rest) = split_delimited(&input[2..], delimiter)
+        .ok_or_else(|| format!("unterminated pattern in `{}`", lossy(input)))?;
+    let (replacement, rest) = split_delimited(rest, delimiter)
+        .ok_or_else(|| format!("unterminated replacement in `{}`", lossy(input)))?;
+    if !rest.is_empty() && rest[0] != b';' {
+        return Err(format!("trailing characters after `{}`", lossy(input)));
+    }
+
+    let source = &input[..input.len() - rest.len()];
+    let rule = Rule {
+        source: lossy(source),
+        pattern: Regex::new(&pattern).map_err(|e| format!("{} in `{}`", e, lossy(source)))?,
+        replacement,
+    };
+    Ok((rule, rest))
+}
+
+/// Splits `input` at the first unescaped `delimiter`, removing the backslashes that escape it.
+fn split_delimited(input: &[u8], delimiter: u8) -> Option<(Vec<u8>, &[u8])> {
+    let mut part = Vec::new();
+    let mut index = 0;
+    while index < input.len() {
+        match input[index] {
+            byte if byte == delimiter => return Some((part, &input[index + 1..])),
+            b'\\' if input.get(index + 1) == Some(&delimiter) => {
+                part.push(delimiter);
+                index += 1;
+            }
+            b'\\' if index + 1 < input.len() => {
+                part.extend_from_slice(&input[index..index + 2]);
+                index += 1;
+            }
+            byte => part.push(byte),
+        }
+        index += 1;
+    }
+    None
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    OsStr::from_bytes(bytes).to_string_lossy().into_owned()
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////
+// Regular expressions
+////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// The instructions of a compiled pattern, see <https://swtch.com/~rsc/regexp/regexp2.html>.
+enum Inst {
+    Byte(u8),
+    Any,
+    Class(Vec<(u8, u8)>, bool),
+    /// Continues at both targets, preferring the first.
+    Split(usize, usize),
+    Jump(usize),
+    /// Records the current position in a capture slot.
+    Save(usize),
+    Start,
+    End,
+    Match,
+}
+
+/// A parsed pattern, before it is compiled.
+enum Node {
+    Byte(u8),
+    Any,
+    Class(Vec<(u8, u8)>, bool),
+    Start,
+    End,
+    Group(Box<Node>, usize),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+/// Upper bound of counted repetitions, which are compiled by duplicating their operand.
+const MAX_REPEAT: usize = 1000;
+
+struct Regex {
+    program: Vec<Inst>,
+    groups: usize,
+}
+
+impl Regex {
+    fn new(pattern: &[u8]) -> Result<Regex, String> {
+        let mut parser = Parser {
+            pattern,
+            position: 0,
+            groups: 1,
+        };
+        let node = parser.alternate()?;
+        if parser.position < pattern.len() {
+            return Err("unmatched `)`".to_string());
+        }
+
+        let mut program = Vec::new();
+        compile(&Node::Group(Box::new(node), 0), &mut program);
+        program.push(Inst::Match);
+        Ok(Regex {
+            program,
+            groups: parser.groups,
+        })
+    }
+
+    /// Finds the leftmost match in `input` and returns the bounds of all groups, where group 0 is
+    /// the whole match.
+    fn captures(&self, input: &[u8]) -> Option<Vec<Option<(usize, usize)>>> {
+        // Whether a thread failed at an instruction and position does not depend on how it got
+        // there, so each combination needs to be tried only once.
+        let mut visited = vec![false; self.program.len() * (input.len() + 1)];
+        for start in 0..=input.len() {
+            let mut slots = vec![None; self.groups * 2];
+            if self.run(input, start, &mut slots, &mut visited) {
+                let groups = slots
+                    .chunks(2)
+                    .map(|slot| match (slot[0], slot[1]) {
+                        (Some(start), Some(end)) => Some((start, end)),
+                        _ => None,
+                    })
+                    .collect();
+                return Some(groups);
+            }
+        }
+        None
+    }
+
+    /// Backtracks through the program starting at `position`.
+    fn run(
+        &self,
+        input: &[u8],
+        position: usize,
+        slots: &mut [Option<usize>],
+        visited: &mut [bool],
+    ) -> bool {
+        enum Job {
+            Thread(usize, usize),
+            Restore(usize, Option<usize>),
+        }
+
+        let mut jobs = vec![Job::Thread(0, position)];
+        while let Some(job) = jobs.pop() {
+            let (mut pc, mut position) = match job {
+                Job::Thread(pc, position) => (pc, position),
+                Job::Restore(slot, value) => {
+                    slots[slot] = value;
+                    continue;
+                }
+            };
+            loop {
+                let state = pc * (input.len() + 1) + position;
+                if visited[state] {
+                    break;
+                }
+                visited[state] = true;
+
+                let byte = input.get(position).cloned();
+                match &self.program[pc] {
+                    Inst::Byte(expected) => {
+                        if byte != Some(*expected) {
+                            break;
+                        }
+                        pc += 1;
+                        position += 1;
+                    }
+                    Inst::Any => {
+                        if byte.is_none() {
+                            break;
+                        }
+                        pc += 1;
+                        position += 1;
+                    }
+                    Inst::Class(ranges, negated) => {
+                        let matches = byte.map(|byte| {
+                            ranges
+                                .iter()
+                                .any(|&(low, high)| low <= byte && byte <= high)
+                                != *negated
+                        });
+                        if matches != Some(true) {
+                            break;
+                        }
+                        pc += 1;
+                        position += 1;
+                    }
+                    Inst::Split(first, second) => {
+                        jobs.push(Job::Thread(*second, position));
+                        pc = *first;
+                    }
+                    Inst::Jump(target) => pc = *target,
+                    Inst::Save(slot) => {
+                        jobs.push(Job::Restore(*slot, slots[*slot]));
+                        slots[*slot] = Some(position);
+                        pc += 1;
+                    }
+                    Inst::Start => {
+                        if position != 0 {
+                            break;
+                        }
+                        pc += 1;
+                    }
+                    Inst::End => {
+                        if position != input.len() {
+                            break;
+                        }
+                        pc += 1;
+                    }
+                    Inst::Match => return true,
+                }
+            }
+        }
+        false
+    }
+}
+
+fn compile(node: &Node, program: &mut Vec<Inst>) {
+    match node {
+        Node::Byte(byte) => program.push(Inst::Byte(*byte)),
+        Node::Any => program.push(Inst::Any),
+        Node::Class(ranges, negated) => program.push(Inst::Class(ranges.clone(), *negated)),
+        Node::Start => program.push(Inst::Start),
+        Node::End => program.push(Inst::End),
+        Node::Group(node, index) => {
+            program.push(Inst::Save(index * 2));
+            compile(node, program);
+            program.push(Inst::Save(index * 2 + 1));
+        }
+        Node::Concat(nodes) => {
+            for node in nodes {
+                compile(node, program);
+            }
+        }
+        Node::Alternate(nodes) => {
+            let mut jumps = Vec::new();
+            for (index, node) in nodes.iter().enumerate() {
+                if index + 1 < nodes.len() {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(node, program);
+                    jumps.push(program.len());
+                    program.push(Inst::Jump(0));
+                    program[split] = Inst::Split(split + 1, program.len());
+                } else {
+                    compile(node, program);
+                }
+            }
+            let end = program.len();
+            for jump in jumps {
+                program[jump] = Inst::Jump(end);
+            }
+        }
+        Node::Repeat(node, min, max) => {
+            for _ in 0..*min {
+                compile(node, program);
+            }
+            match max {
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(node, program);
+                    program.push(Inst::Jump(split));
+                    program[split] = Inst::Split(split + 1, program.len());
+                }
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Split(program.len() + 1, 0));
+                        compile(node, program);
+                    }
+                    let end = program.len();
+                    for split in splits {
+                        program[split] = Inst::Split(split + 1, end);
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    pattern: &'a [u8],
+    position: usize,
+    /// The number of groups seen so far, including the implicit group 0.
+    groups: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.pattern.get(self.position).cloned()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    fn alternate(&mut self) -> Result<Node, String> {
+        let mut alternatives = vec![self.concat()?];
+        while self.peek() == Some(b'|') {
+            self.position += 1;
+            alternatives.push(self.concat()?);
+        }
+        if alternatives.len() == 1 {
+            Ok(alternatives.pop().unwrap())
+        } else {
+            Ok(Node::Alternate(alternatives))
+        }
+    }
+
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(b'|') | Some(b')') => break,
+                Some(_) => {
+                    let atom = self.atom()?;
+                    nodes.push(self.quantified(atom)?);
+                }
+            }
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(b'.') => Ok(Node::Any),
+            Some(b'^') => Ok(Node::Start),
+            Some(b'$') => Ok(Node::End),
+            Some(b'(') => {
+                let index = self.groups;
+                self.groups += 1;
+                let node = self.alternate()?;
+                if self.next() != Some(b')') {
+                    return Err("unmatched `(`".to_string());
+                }
+                Ok(Node::Group(Box::new(node), index))
+            }
+            Some(b'[') => self.class(),
+            Some(b'\\') => self.escape(),
+            Some(byte @ b'*') | Some(byte @ b'+') | Some(byte @ b'?') => {
+                Err(format!("`{}` without operand", byte as char))
+            }
+            Some(byte) => Ok(Node::Byte(byte)),
+            None => Err("unexpected end of pattern".to_string()),
+        }
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        match self.next() {
+            Some(b'd') => Ok(Node::Class(DIGIT.to_vec(), false)),
+            Some(b'w') => Ok(Node::Class(WORD.to_vec(), false)),
+            Some(b's') => Ok(Node::Class(SPACE.to_vec(), false)),
+            Some(byte) => Ok(Node::Byte(byte)),
+            None => Err("trailing backslash".to_string()),
+        }
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.peek() == Some(b'^');
+        if negated {
+            self.position += 1;
+        }
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let low = match self.next() {
+                Some(b']') if !first => break,
+                Some(b'\\') => self.next(),
+                byte => byte,
+            }
+            .ok_or_else(|| "unmatched `[`".to_string())?;
+            first = false;
+
+            let is_range = self.peek() == Some(b'-')
+                && self
+                    .pattern
+                    .get(self.position + 1)
+                    .map_or(false, |&b| b != b']');
+            if is_range {
+                self.position += 1;
+                let high = match self.next() {
+                    Some(b'\\') => self.next(),
+                    byte => byte,
+                }
+                .ok_or_else(|| "unmatched `[`".to_string())?;
+                if high < low {
+                    return Err("invalid class range".to_string());
+                }
+                ranges.push((low, high));
+            } else {
+                ranges.push((low, low));
+            }
+        }
+        Ok(Node::Class(ranges, negated))
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some(b'*') => (0, None),
+            Some(b'+') => (1, None),
+            Some(b'?') => (0, Some(1)),
+            Some(b'{') => return self.counted(atom),
+            _ => return Ok(atom),
+        };
+        self.position += 1;
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    /// Parses the counted repetitions `{n}`, `{n,}` and `{m,n}`.
+    fn counted(&mut self, atom: Node) -> Result<Node, String> {
+        self.position += 1;
+        let min = self.number()?;
+        let max = if self.peek() == Some(b',') {
+            self.position += 1;
+            if self.peek() == Some(b'}') {
+                None
+            } else {
+                Some(self.number()?)
+            }
+        } else {
+            Some(min)
+        };
+        if self.next() != Some(b'}') {
+            return Err("unmatched `{`".to_string());
+        }
+        if max.map_or(false, |max| max < min) || max.unwrap_or(min) > MAX_REPEAT {
+            return Err("invalid repetition count".to_string());
+        }
+        Ok(Node::Repeat(Box::new(atom), min, max))
+    }
+
+    fn number(&mut self) -> Result<usize, String> {
+        let start = self.position;
+        while self.peek().map_or(false, |byte| byte.is_ascii_digit()) {
+            self.position += 1;
+        }
+        std::str::from_utf8(&self.pattern[start..self.position])
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(|| "invalid repetition count".to_string())
+    }
+}
+
+const DIGIT: &[(u8, u8)] = &[(b'0', b'9')];
+const WORD: &[(u8, u8)] = &[(b'0', b'9'), (b'A', b'Z'), (b'_', b'_'), (b'a', b'z')];
+const SPACE: &[(u8, u8)] = &[(b'\t', b'\r'), (b' ', b' ')];
@@ -0,0 +1,125 @@
+//! Synthetic files appear in the merged view of a lower dir without existing in either layer, e.g.
+//! to inject generated configuration files into a read-only application tree.
+//!
+//! A synthetic file is declared as `PATH=CONTENTS`, or as `PATH=@SOURCE` to show the file `SOURCE`
+//! in its place. Within the contents, `\n` stands for a newline, `\;` for a `;`, `\@` for a `@`
+//! and `\\` for a backslash. Literal contents are stored in `.wh..wh.files` in the upper dir, named
+//! after a hash of them, which is never part of the merged view.
+//!
+//! Synthetic files shadow lower entries of the same name, and are shadowed by upper entries in
+//! turn. Like lower files, they are copied up when they are changed.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use crate::log::{self, Category};
+
+/// Synthetic files use the reserved names of whiteouts, so they are never part of the merged view.
+const FILES_DIR: &str = ".wh..wh.files";
+
+#[derive(Debug)]
+pub struct File {
+    /// The absolute path of the file in the merged view.
+    pub path: PathBuf,
+    source: Source,
+}
+
+#[derive(Debug)]
+enum Source {
+    Contents(Vec<u8>),
+    File(PathBuf),
+}
+
+impl File {
+    /// Returns the file holding the contents of the synthetic file, which is created in `upper_dir`
+    /// for literal contents.
+    pub fn backing(&self, upper_dir: &Path) -> Option<PathBuf> {
+        let contents = match &self.source {
+            Source::File(source) => return Some(source.clone()),
+            Source::Contents(contents) => contents,
+        };
+        let mut hasher = DefaultHasher::new();
+        contents.hash(&mut hasher);
+        let dir = upper_dir.join(FILES_DIR);
+        let backing = dir.join(format!("{:016x}", hasher.finish()));
+        if backing.symlink_metadata().is_ok() {
+            return Some(backing);
+        }
+        // Other processes may read the file as soon as it exists, so it is written elsewhere first
+        let partial = dir.join(format!(".{:016x}.{}", hasher.finish(), std::process::id()));
+        std::fs::create_dir_all(&dir)
+            .and_then(|_| std::fs::write(&partial, contents))
+            .and_then(|_| std::fs::rename(&partial, &backing))
+            .map_err(|e| {
+                log::warn(Category::Redir, || {
+                    log_println!("liboverlay: failed to create {}: {}", backing.display(), e)
+                });
+                let _ = std::fs::remove_file(&partial);
+            })
+            .ok()?;
+        Some(backing)
+    }
+}
+
+/// Parses a list of synthetic files separated by `;`.
+pub fn parse_files(list: &str) -> Result<Vec<File>, String> {
+    let mut files = Vec::new();
+    let mut chars = list.chars();
+    loop {
+        // The escapes are kept, they are resolved along with the others in the contents
+        let mut entry = String::new();
+        let mut escaped = false;
+        let mut end = true;
+        for c in &mut chars {
+            if c == ';' && !escaped {
+                end = false;
+                break;
+            }
+            escaped = c == '\\' && !escaped;
+            entry.push(c);
+        }
+        if !entry.is_empty() {
+            files.push(parse_file(&entry)?);
+        }
+        if end {
+            return Ok(files);
+        }
+    }
+}
+
+fn parse_file(entry: &str) -> Result<File, String> {
+    let split = entry
+        .find('=')
+        .ok_or_else(|| format!("missing `=` in `{}`", entry))?;
+    let (path, value) = (Path::new(&entry[..split]), &entry[split + 1..]);
+    if !path.is_absolute() {
+        return Err(format!("path `{}` is not absolute", path.display()));
+    }
+    let source = match value.find('@') {
+        Some(0) => Source::File(PathBuf::from(&value[1..])),
+        _ => Source::Contents(unescape(value)?),
+    };
+    Ok(File {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn unescape(value: &str) -> Result<Vec<u8>, String> {
+    let mut contents = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            contents.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => contents.push('\n'),
+            Some(c @ ';') | Some(c @ '@') | Some(c @ '\\') => contents.push(c),
+            Some(c) => return Err(format!("invalid escape `\\{}`", c)),
+            None => return Err(String::from("trailing `\\`")),
+        }
+    }
+    Ok(contents.into_bytes())
+}
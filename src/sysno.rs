@@ -0,0 +1,59 @@
+//! Numbers of the system calls that the `syscall` hook intercepts, and of those liboverlay makes
+//! through it.
+//!
+//! Newer architectures like aarch64 only have the `*at` variants of the path based calls, their
+//! legacy counterparts are x86_64 specific.
+
+use std::os::raw::c_long;
+
+#[cfg(target_arch = "x86_64")]
+mod arch {
+    use std::os::raw::c_long;
+
+    pub const OPEN: c_long = 2;
+    pub const CLOSE: c_long = 3;
+    pub const STAT: c_long = 4;
+    pub const LSTAT: c_long = 6;
+    pub const LSEEK: c_long = 8;
+    pub const ACCESS: c_long = 21;
+    pub const RENAME: c_long = 82;
+    pub const MKDIR: c_long = 83;
+    pub const RMDIR: c_long = 84;
+    pub const UNLINK: c_long = 87;
+    pub const GETDENTS64: c_long = 217;
+    pub const OPENAT: c_long = 257;
+    pub const MKDIRAT: c_long = 258;
+    pub const NEWFSTATAT: c_long = 262;
+    pub const UNLINKAT: c_long = 263;
+    pub const RENAMEAT: c_long = 264;
+    pub const FACCESSAT: c_long = 269;
+    pub const RENAMEAT2: c_long = 316;
+    pub const STATX: c_long = 332;
+    pub const COPY_FILE_RANGE: c_long = 326;
+    pub const GETTID: c_long = 186;
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arch {
+    use std::os::raw::c_long;
+
+    pub const MKDIRAT: c_long = 34;
+    pub const UNLINKAT: c_long = 35;
+    pub const RENAMEAT: c_long = 38;
+    pub const FACCESSAT: c_long = 48;
+    pub const OPENAT: c_long = 56;
+    pub const CLOSE: c_long = 57;
+    pub const GETDENTS64: c_long = 61;
+    pub const LSEEK: c_long = 62;
+    pub const NEWFSTATAT: c_long = 79;
+    pub const GETTID: c_long = 178;
+    pub const RENAMEAT2: c_long = 276;
+    pub const COPY_FILE_RANGE: c_long = 285;
+    pub const STATX: c_long = 291;
+}
+
+pub use self::arch::*;
+
+// Newer system calls share their numbers across architectures
+pub const OPENAT2: c_long = 437;
+pub const FACCESSAT2: c_long = 439;
@@ -0,0 +1,359 @@
+//! Parses the subset of TOML that configuration files use: tables, arrays of tables, and keys with
+//! strings, integers, booleans, arrays and inline tables as values. Floats, dates and multi-line
+//! strings are not supported.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+/// The entries of a table, in the order they appear in the file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table(pub Vec<(String, Value)>);
+
+impl Table {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.0
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Removes the entry `key`, and returns its value.
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        let index = self.0.iter().position(|(name, _)| name == key)?;
+        Some(self.0.remove(index).1)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.0
+            .iter_mut()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Parses a TOML document, errors name the line they occur on.
+pub fn parse(text: &str) -> Result<Table, String> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+    parser
+        .document()
+        .map_err(|e| format!("line {}: {}", parser.line, e))
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn document(&mut self) -> Result<Table, String> {
+        let mut root = Table::default();
+        let mut current = Vec::new();
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') => {
+                    self.pos += 1;
+                    let array = self.eat('[');
+                    self.skip_spaces();
+                    let path = self.key()?;
+                    self.skip_spaces();
+                    if !self.eat(']') || (array && !self.eat(']')) {
+                        return Err(String::from("expected `]`"));
+                    }
+                    let (last, parents) = path.split_last().expect("keys are never empty");
+                    let parent = table_at(&mut root, parents)?;
+                    match (array, parent.get_mut(last)) {
+                        (false, None) => parent
+                            .0
+                            .push((last.clone(), Value::Table(Table::default()))),
+                        (false, Some(Value::Table(_))) => {}
+                        (true, None) => parent.0.push((
+                            last.clone(),
+                            Value::Array(vec![Value::Table(Table::default())]),
+                        )),
+                        (true, Some(Value::Array(items))) => {
+                            items.push(Value::Table(Table::default()))
+                        }
+                        _ => return Err(format!("`{}` is defined already", last)),
+                    }
+                    current = path;
+                }
+                Some(_) => {
+                    let path = self.key()?;
+                    self.skip_spaces();
+                    if !self.eat('=') {
+                        return Err(String::from("expected `=`"));
+                    }
+                    self.skip_spaces();
+                    let value = self.value()?;
+                    insert(table_at(&mut root, &current)?, &path, value)?;
+                }
+            }
+            self.end_of_line()?;
+        }
+    }
+
+    /// Parses a dotted key.
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut path = Vec::new();
+        loop {
+            let part = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let start = self.pos;
+                    while self
+                        .peek()
+                        .map_or(false, |c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                    {
+                        self.pos += 1;
+                    }
+                    if start == self.pos {
+                        return Err(String::from("expected a key"));
+                    }
+                    self.chars[start..self.pos].iter().collect()
+                }
+            };
+            path.push(part);
+            self.skip_spaces();
+            if !self.eat('.') {
+                return Ok(path);
+            }
+            self.skip_spaces();
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') => self.basic_string().map(Value::String),
+            Some('\'') => self.literal_string().map(Value::String),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some('t') | Some('f') => {
+                for (word, value) in &[("true", true), ("false", false)] {
+                    if self.chars[self.pos..].starts_with(&word.chars().collect::<Vec<_>>()) {
+                        self.pos += word.len();
+                        return Ok(Value::Boolean(*value));
+                    }
+                }
+                Err(String::from("expected a value"))
+            }
+            Some(c) if c.is_ascii_digit() || c == '+' || c == '-' => self.integer(),
+            _ => Err(String::from("expected a value")),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return Err(String::from("unterminated string")),
+                Some('"') => return Ok(string),
+                Some('\\') => string.push(match self.next() {
+                    Some('b') => '\u{8}',
+                    Some('t') => '\t',
+                    Some('n') => '\n',
+                    Some('f') => '\u{c}',
+                    Some('r') => '\r',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('u') => self.unicode(4)?,
+                    Some('U') => self.unicode(8)?,
+                    _ => return Err(String::from("invalid escape")),
+                }),
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    fn unicode(&mut self, digits: usize) -> Result<char, String> {
+        let hex: String = self.chars.iter().skip(self.pos).take(digits).collect();
+        self.pos += digits;
+        u32::from_str_radix(&hex, 16)
+            .ok()
+            .and_then(std::char::from_u32)
+            .ok_or_else(|| String::from("invalid escape"))
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                None | Some('\n') => return Err(String::from("unterminated string")),
+                Some('\'') => return Ok(string),
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    fn integer(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        self.pos += 1;
+        while self
+            .peek()
+            .map_or(false, |c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos]
+            .iter()
+            .filter(|&&c| c != '_')
+            .collect();
+        digits
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("invalid integer `{}`", digits))
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.eat(']') {
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            if !self.eat(',') && self.peek() != Some(']') {
+                return Err(String::from("expected `,` or `]`"));
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, String> {
+        self.pos += 1;
+        let mut table = Table::default();
+        self.skip_spaces();
+        if self.eat('}') {
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_spaces();
+            let path = self.key()?;
+            self.skip_spaces();
+            if !self.eat('=') {
+                return Err(String::from("expected `=`"));
+            }
+            self.skip_spaces();
+            let value = self.value()?;
+            insert(&mut table, &path, value)?;
+            self.skip_spaces();
+            if self.eat('}') {
+                return Ok(Value::Table(table));
+            }
+            if !self.eat(',') {
+                return Err(String::from("expected `,` or `}`"));
+            }
+        }
+    }
+
+    /// Skips spaces and a comment, which must end the line.
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_spaces();
+        if self.peek() == Some('#') {
+            while self.peek().map_or(false, |c| c != '\n') {
+                self.pos += 1;
+            }
+        }
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some('\r') if self.chars.get(self.pos + 1) == Some(&'\n') => Ok(()),
+            Some(_) => Err(String::from("expected the end of the line")),
+        }
+    }
+
+    /// Skips whitespace, newlines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_spaces();
+            match self.peek() {
+                Some('#') => {
+                    while self.peek().map_or(false, |c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                Some('\n') | Some('\r') => {
+                    self.next();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek() == Some(' ') || self.peek() == Some('\t') {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+}
+
+/// Returns the table at `path` below `table`, creating missing tables. Arrays of tables stand for
+/// their last table.
+fn table_at<'a>(table: &'a mut Table, path: &[String]) -> Result<&'a mut Table, String> {
+    let (first, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return Ok(table),
+    };
+    if table.get(first).is_none() {
+        table
+            .0
+            .push((first.clone(), Value::Table(Table::default())));
+    }
+    let next = match table.get_mut(first) {
+        Some(Value::Table(next)) => next,
+        Some(Value::Array(items)) => match items.last_mut() {
+            Some(Value::Table(next)) => next,
+            _ => return Err(format!("`{}` is not a table", first)),
+        },
+        _ => return Err(format!("`{}` is not a table", first)),
+    };
+    table_at(next, rest)
+}
+
+/// Sets the dotted key `path` in `table`.
+fn insert(table: &mut Table, path: &[String], value: Value) -> Result<(), String> {
+    let (last, parents) = path.split_last().expect("keys are never empty");
+    let table = table_at(table, parents)?;
+    if table.get(last).is_some() {
+        return Err(format!("`{}` is defined already", last));
+    }
+    table.0.push((last.clone(), value));
+    Ok(())
+}
@@ -0,0 +1,139 @@
+//! Transformations rewrite the contents of lower files while they are copied up, e.g. to
+//! materialize templated configuration files for the current environment without changing the
+//! lower dir.
+//!
+//! A rule is declared as `PATTERN=TRANSFORM,...`, where the pattern is matched like a hide rule
+//! and the transformations are applied in order. The first matching rule applies. Files with
+//! transformations are copied up as soon as they are opened, so that they are never read in their
+//! original form. The builtin transformations are:
+//!
+//! - `crlf` converts line endings to `\r\n`,
+//! - `lf` converts line endings to `\n`,
+//! - `env` replaces `${NAME}` with the value of the environment variable `NAME`, unset variables
+//!   are left as they are.
+
+use std::path::Path;
+
+use crate::hide;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    Crlf,
+    Lf,
+    Env,
+}
+
+/// A pattern selecting the lower files that are transformed.
+#[derive(Debug)]
+pub struct Rule {
+    pattern: hide::Rule,
+    transforms: Vec<Transform>,
+}
+
+impl Rule {
+    /// Returns the transformations for the entry `path_in_lower` of the lower dir `lower_dir`, if
+    /// the rule matches it.
+    pub fn transforms(&self, lower_dir: &Path, path_in_lower: &Path) -> Option<&[Transform]> {
+        if self.pattern.matches(lower_dir, path_in_lower) {
+            Some(&self.transforms)
+        } else {
+            None
+        }
+    }
+}
+
+/// Applies `transforms` to the contents of a file.
+pub fn apply(transforms: &[Transform], mut contents: Vec<u8>) -> Vec<u8> {
+    for transform in transforms {
+        contents = match transform {
+            Transform::Crlf => to_crlf(&contents),
+            Transform::Lf => to_lf(&contents),
+            Transform::Env => substitute_env(&contents),
+        };
+    }
+    contents
+}
+
+fn to_crlf(contents: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(contents.len());
+    for (i, &b) in contents.iter().enumerate() {
+        if b == b'\n' && (i == 0 || contents[i - 1] != b'\r') {
+            converted.push(b'\r');
+        }
+        converted.push(b);
+    }
+    converted
+}
+
+fn to_lf(contents: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(contents.len());
+    for (i, &b) in contents.iter().enumerate() {
+        if b != b'\r' || contents.get(i + 1) != Some(&b'\n') {
+            converted.push(b);
+        }
+    }
+    converted
+}
+
+fn substitute_env(contents: &[u8]) -> Vec<u8> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    let mut substituted = Vec::with_capacity(contents.len());
+    let mut rest = contents;
+    while let Some(start) = find(rest, b"${") {
+        substituted.extend_from_slice(&rest[..start]);
+        rest = &rest[start + 2..];
+        let end = rest
+            .iter()
+            .position(|&b| !(b.is_ascii_alphanumeric() || b == b'_'))
+            .filter(|&end| end > 0 && rest[end] == b'}');
+        let value = end.and_then(|end| std::env::var_os(OsStr::from_bytes(&rest[..end])));
+        match (end, value) {
+            (Some(end), Some(value)) => {
+                substituted.extend(value.into_vec());
+                rest = &rest[end + 1..];
+            }
+            // Anything but a set variable is kept as it is
+            _ => substituted.extend_from_slice(b"${"),
+        }
+    }
+    substituted.extend_from_slice(rest);
+    substituted
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Parses a list of rules separated by `;`.
+pub fn parse_rules(list: &str) -> Result<Vec<Rule>, String> {
+    list.split(';')
+        .filter(|rule| !rule.is_empty())
+        .map(parse_rule)
+        .collect()
+}
+
+fn parse_rule(rule: &str) -> Result<Rule, String> {
+    let split = rule
+        .find('=')
+        .ok_or_else(|| format!("missing `=` in `{}`", rule))?;
+    let (pattern, names) = (&rule[..split], &rule[split + 1..]);
+    let pattern =
+        hide::Rule::new(pattern).ok_or_else(|| format!("invalid pattern `{}`", pattern))?;
+    let transforms = names
+        .split(',')
+        .map(|name| match name {
+            "crlf" => Ok(Transform::Crlf),
+            "lf" => Ok(Transform::Lf),
+            "env" => Ok(Transform::Env),
+            _ => Err(format!("unknown transformation `{}`", name)),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Rule {
+        pattern,
+        transforms,
+    })
+}
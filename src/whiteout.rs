@@ -0,0 +1,93 @@
+//! Whiteouts hide entries of the lower dir from the merged view.
+//!
+//! A whiteout for `dir/name` is an empty marker file `dir/.wh.name` in the upper dir (the format
+//! used by aufs). Unlike the character device whiteouts of overlayfs, these markers can be
+//! created without any special privileges.
+//!
+//! A whiteout stays in place when the entry is recreated in the upper dir. The upper entry shadows
+//! the whiteout, and a directory recreated this way is opaque: the contents of the lower directory
+//! it replaces remain hidden.
+//!
+//! Since the markers live in the upper dir, deletions persist across runs and are shared by all
+//! processes using the same upper dir. Names in the marker format are reserved for this purpose.
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+const PREFIX: &[u8] = b".wh.";
+
+/// Returns the path of the marker that hides `path_to_upper`.
+pub fn marker_path(path_to_upper: &Path) -> Option<PathBuf> {
+    let mut marker = OsString::from(".wh.");
+    marker.push(path_to_upper.file_name()?);
+    Some(path_to_upper.with_file_name(marker))
+}
+
+/// Checks whether the lower entry corresponding to `path_to_upper` has been whited out.
+pub fn exists(path_to_upper: &Path) -> bool {
+    marker_path(path_to_upper).map_or(false, |marker| marker.symlink_metadata().is_ok())
+}
+
+/// Checks whether the lower entry corresponding to `path_to_upper` is hidden, either by its own
+/// whiteout or by a whiteout of one of its parent directories below `upper_dir`.
+pub fn hides(upper_dir: &Path, path_to_upper: &Path) -> bool {
+    path_to_upper
+        .ancestors()
+        .take_while(|ancestor| *ancestor != upper_dir && ancestor.starts_with(upper_dir))
+        .any(exists)
+}
+
+/// Hides the lower entry corresponding to `path_to_upper`, creating the parent directories in
+/// the upper dir as necessary.
+pub fn create(path_to_upper: &Path) -> std::io::Result<()> {
+    let marker = marker_path(path_to_upper)
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    if let Some(parent) = marker.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::File::create(&marker)?;
+    Ok(())
+}
+
+/// Removes all whiteout markers from a directory in the upper dir.
+pub fn clear(dir_in_upper: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    for entry in std::fs::read_dir(dir_in_upper)? {
+        let entry = entry?;
+        if hidden_name(entry.file_name().as_bytes()).is_some() {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes all whiteout markers from a directory in the upper dir and from its subdirectories.
+pub fn clear_tree(dir_in_upper: &Path) -> std::io::Result<()> {
+    clear(dir_in_upper)?;
+    for entry in std::fs::read_dir(dir_in_upper)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            clear_tree(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks whether `path` names a whiteout marker. Markers are not part of the merged view, so
+/// they can neither be looked up nor created through it.
+pub fn is_marker(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.file_name()
+        .map_or(false, |name| hidden_name(name.as_bytes()).is_some())
+}
+
+/// If `name` is a whiteout marker, returns the name of the entry it hides.
+pub fn hidden_name(name: &[u8]) -> Option<&[u8]> {
+    if name.starts_with(PREFIX) {
+        Some(&name[PREFIX.len()..])
+    } else {
+        None
+    }
+}
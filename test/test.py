@@ -1,9 +1,18 @@
 #!/usr/bin/env python3.7
 
+import errno
+import functools
+import http.server
+import json
 import os
+import signal
+import socket
 import sys
 import subprocess
+import sysconfig
+import tarfile
 import tempfile
+import threading
 import traceback
 from pathlib import Path
 from typing import Callable, Mapping, NamedTuple, Union
@@ -11,6 +20,7 @@ from typing import Callable, Mapping, NamedTuple, Union
 import tap
 
 SCRIPT_DIR = os.path.dirname(os.path.realpath(__file__))
+OVERLAY_TOOL = os.path.realpath(f"{SCRIPT_DIR}/../target/debug/overlay")
 
 
 class TestEnv(NamedTuple):
@@ -35,6 +45,20 @@ def read_all(path: Union[str, Path]) -> bytes:
         return file.read()
 
 
+def run_in(env: Mapping[str, str], *args: Union[str, Path]) -> subprocess.CompletedProcess:
+    """Runs a program with the variables `env`, capturing its output."""
+    return subprocess.run(args, env=env, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+
+
+def build_preload(source: str, directory: Union[str, Path]) -> Path:
+    """Builds a library from C `source`, which is preloaded after liboverlay to fake the results of
+    the functions it calls."""
+    Path(directory, "preload.c").write_text(source)
+    library = Path(directory, "preload.so")
+    subprocess.run(["cc", "-shared", "-fPIC", "-o", library, Path(directory, "preload.c"), "-ldl"], check=True)
+    return library
+
+
 def can_read_lower(env: TestEnv) -> None:
     ret = env.overlay_read("foo.txt")
     assert ret.returncode == 0
@@ -73,6 +97,154 @@ def redirect_lower_writes_new(env: TestEnv) -> None:
     assert ret.stdout == b"It is new"
 
 
+def redirect_creat(env: TestEnv) -> None:
+    script = (
+        "import ctypes, os, sys\n"
+        "fd = ctypes.CDLL(None).creat(sys.argv[1].encode(), 0o644)\n"
+        "sys.exit(fd < 0 or os.write(fd, b'Created') != 7)\n"
+    )
+    for name in ["foo.txt", "created.txt"]:
+        ret = subprocess.run(
+            [sys.executable, "-c", script, env.lower / name],
+            env=env.env,
+            stdout=subprocess.PIPE,
+            stderr=None,
+        )
+        assert ret.returncode == 0
+
+        ret = env.overlay_read(name)
+        assert ret.returncode == 0
+        assert ret.stdout == b"Created"
+
+    assert not (env.lower / "created.txt").exists()
+
+
+def redirect_fopen(env: TestEnv) -> None:
+    # Appends to a file through the given fopen variant
+    script = (
+        "import ctypes, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "libc.fopen64.restype = libc.freopen.restype = ctypes.c_void_p\n"
+        "path = sys.argv[2].encode()\n"
+        "if sys.argv[1] == 'fopen64':\n"
+        "    f = libc.fopen64(path, b'a')\n"
+        "else:\n"
+        "    f = libc.freopen(path, b'a+', ctypes.c_void_p(libc.fopen64(b'/dev/null', b'r')))\n"
+        "assert f\n"
+        "libc.fputs(b'!', ctypes.c_void_p(f))\n"
+        "libc.fclose(ctypes.c_void_p(f))\n"
+    )
+    for variant in ["fopen64", "freopen"]:
+        ret = subprocess.run(
+            [sys.executable, "-c", script, variant, env.lower / "foo.txt"],
+            env=env.env,
+            stdout=subprocess.PIPE,
+            stderr=None,
+        )
+        assert ret.returncode == 0
+
+    ret = env.overlay_read("foo.txt")
+    assert ret.returncode == 0
+    assert ret.stdout == read_all(env.lower / "foo.txt") + b"!!"
+
+
+def redirect_truncate_open(env: TestEnv) -> None:
+    lower_mode = (env.lower / "foo.txt").stat().st_mode & 0o777
+
+    # Files that are truncated when opened are not copied up first
+    ret = subprocess.run(
+        [sys.executable, "-c", "import sys; open(sys.argv[1], 'w').write('It is new')", env.lower / "foo.txt"],
+        env={**env.env, "LIBOVERLAY_DEBUG": "1"},
+        stdout=subprocess.PIPE,
+        stderr=subprocess.PIPE,
+    )
+    assert ret.returncode == 0
+    assert b"making empty writable copy" in ret.stderr
+    assert b"making writable copy" not in ret.stderr
+    assert read_all(env.upper / "foo.txt") == b"It is new"
+    assert (env.upper / "foo.txt").stat().st_mode & 0o777 == lower_mode | 0o200
+
+
+def redirect_open_fortified(env: TestEnv) -> None:
+    # Programs built with _FORTIFY_SOURCE call these instead of open when no mode is passed
+    script = (
+        "import ctypes, os, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "fd = libc.__open_2(sys.argv[1].encode(), os.O_WRONLY | os.O_TRUNC)\n"
+        "os.write(fd, b'It is new')\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "foo.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert read_all(env.upper / "foo.txt") == b"It is new"
+    assert read_all(env.lower / "foo.txt") != b"It is new"
+
+
+def redirect_openat2(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
+
+    # Opens relative to a directory of the merged view, with and without escaping it
+    script = (
+        "import ctypes, os, sys\n"
+        "class OpenHow(ctypes.Structure):\n"
+        "    _fields_ = [('flags', ctypes.c_uint64), ('mode', ctypes.c_uint64), ('resolve', ctypes.c_uint64)]\n"
+        "libc = ctypes.CDLL(None, use_errno=True)\n"
+        "def openat2(dirfd, path, flags, resolve):\n"
+        "    how = OpenHow(flags, 0o644 if flags & os.O_CREAT else 0, resolve)\n"
+        "    fd = libc.openat2(dirfd, path.encode(), ctypes.byref(how), ctypes.sizeof(how))\n"
+        "    return fd if fd >= 0 else -ctypes.get_errno()\n"
+        "dirfd = os.open(sys.argv[1], os.O_RDONLY | os.O_DIRECTORY)\n"
+        "RESOLVE_BENEATH = 0x08\n"
+        "print(os.read(openat2(dirfd, 'baz.txt', os.O_RDONLY, RESOLVE_BENEATH), 100).decode())\n"
+        "print(os.read(openat2(dirfd, 'bar.txt', os.O_RDONLY, RESOLVE_BENEATH), 100).decode())\n"
+        "print(openat2(dirfd, '../foo.txt', os.O_RDONLY, RESOLVE_BENEATH))\n"
+        "fd = openat2(dirfd, 'new.txt', os.O_WRONLY | os.O_CREAT, 0)\n"
+        "os.write(fd, b'It is new')\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    lines = ret.stdout.splitlines()
+    assert lines[0] == b"It is new"
+    assert lines[2] == b"-18"
+    assert not (env.lower / "bar" / "new.txt").exists()
+    assert read_all(env.upper / "bar" / "new.txt") == b"It is new"
+
+
+def redirect_mkstemp(env: TestEnv) -> None:
+    script = (
+        "import ctypes, os, sys\n"
+        "template = ctypes.create_string_buffer(sys.argv[1].encode())\n"
+        "fd = ctypes.CDLL(None).mkstemp(template)\n"
+        "assert fd >= 0 and os.write(fd, b'Temporary') == 9\n"
+        "print(template.value.decode())\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar" / "tmp.XXXXXX"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    temp_path = Path(ret.stdout.decode().strip())
+    assert temp_path.parent == env.lower / "bar"
+    assert temp_path.name != "tmp.XXXXXX"
+    assert not temp_path.exists()
+
+    ret = env.overlay_read(f"bar/{temp_path.name}")
+    assert ret.returncode == 0
+    assert ret.stdout == b"Temporary"
+
+
 def redirect_mkdir(env: TestEnv) -> None:
     ret = subprocess.run(
         ["mkdir", env.lower / "new_dir"],
@@ -90,6 +262,31 @@ def redirect_mkdir(env: TestEnv) -> None:
     assert ret.stdout == b"It is new"
 
 
+def redirect_mkdirat(env: TestEnv) -> None:
+    # Creates a directory relative to a directory fd
+    script = (
+        "import os, sys\n"
+        "fd = os.open(sys.argv[1], os.O_RDONLY | os.O_DIRECTORY)\n"
+        "os.mkdir(sys.argv[2], dir_fd=fd)\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar", "new_dir"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert not (env.lower / "bar" / "new_dir").exists()
+    assert (env.upper / "bar" / "new_dir").is_dir()
+
+    ret = env.overlay_write("bar/new_dir/new_file.txt", b"It is new")
+    assert ret.returncode == 0
+
+    ret = env.overlay_read("bar/new_dir/new_file.txt")
+    assert ret.returncode == 0
+    assert ret.stdout == b"It is new"
+
+
 def redirect_readdir(env: TestEnv) -> None:
     ret = subprocess.run(
         ["ls", env.lower / "bar"], env=env.env, stdout=subprocess.PIPE, stderr=None,
@@ -117,91 +314,3331 @@ def redirect_readdir(env: TestEnv) -> None:
     assert sorted(ret.stdout.splitlines()) == [b"bar.txt", b"baz.txt"]
 
 
-def redirect_stat(env: TestEnv) -> None:
-    ret = env.overlay_write("new_file.txt", b"It is new")
+def redirect_readdir64(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
     assert ret.returncode == 0
 
+    # Lists a directory with readdir64, the name starts at offset 19 of struct dirent64
+    script = (
+        "import ctypes, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "libc.opendir.restype = ctypes.c_void_p\n"
+        "libc.readdir64.argtypes = [ctypes.c_void_p]\n"
+        "libc.readdir64.restype = ctypes.c_void_p\n"
+        "libc.closedir.argtypes = [ctypes.c_void_p]\n"
+        "dir = libc.opendir(sys.argv[1].encode())\n"
+        "while True:\n"
+        "    entry = libc.readdir64(dir)\n"
+        "    if not entry:\n"
+        "        break\n"
+        "    print(ctypes.string_at(entry + 19).decode())\n"
+        "libc.closedir(dir)\n"
+    )
     ret = subprocess.run(
-        ["stat", env.lower / "foo.txt"],
+        [sys.executable, "-c", script, env.lower / "bar"],
         env=env.env,
         stdout=subprocess.PIPE,
         stderr=None,
     )
     assert ret.returncode == 0
+    assert sorted(ret.stdout.splitlines()) == [b".", b"..", b"bar.txt", b"baz.txt"]
+
+
+def redirect_readdir_r(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
 
+    # Lists a directory with readdir_r into a buffer the size of struct dirent
+    script = (
+        "import ctypes, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "libc.opendir.restype = ctypes.c_void_p\n"
+        "libc.readdir_r.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_void_p]\n"
+        "libc.closedir.argtypes = [ctypes.c_void_p]\n"
+        "dir = libc.opendir(sys.argv[1].encode())\n"
+        "entry = ctypes.create_string_buffer(280)\n"
+        "result = ctypes.c_void_p()\n"
+        "while True:\n"
+        "    assert libc.readdir_r(dir, entry, ctypes.byref(result)) == 0\n"
+        "    if not result:\n"
+        "        break\n"
+        "    print(ctypes.string_at(ctypes.addressof(entry) + 19).decode())\n"
+        "libc.closedir(dir)\n"
+    )
     ret = subprocess.run(
-        ["stat", env.lower / "new_file.txt"],
+        [sys.executable, "-c", script, env.lower / "bar"],
         env=env.env,
         stdout=subprocess.PIPE,
         stderr=None,
     )
     assert ret.returncode == 0
+    assert sorted(ret.stdout.splitlines()) == [b".", b"..", b"bar.txt", b"baz.txt"]
 
 
-def redirect_unlink(env: TestEnv) -> None:
-    ret = env.overlay_write("new_file.txt", b"It is new")
+def redirect_scandir(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
+    ret = env.overlay_write("bar/bar.txt", b"Overwrite")
     assert ret.returncode == 0
 
-    ret = env.overlay_write("foo.txt", b"It is new")
+    # Lists a directory with scandir, sorted by alphasort
+    script = (
+        "import ctypes, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "namelist = ctypes.POINTER(ctypes.c_void_p)()\n"
+        "alphasort = ctypes.cast(libc.alphasort, ctypes.c_void_p)\n"
+        "count = libc.scandir(sys.argv[1].encode(), ctypes.byref(namelist), None, alphasort)\n"
+        "assert count >= 0\n"
+        "for i in range(count):\n"
+        "    print(ctypes.string_at(namelist[i] + 19).decode())\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b".", b"..", b"bar.txt", b"baz.txt"]
+
+
+def redirect_fdopendir(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
     assert ret.returncode == 0
 
+    # Listing a directory fd goes through fdopendir
+    script = (
+        "import os, sys\n"
+        "fd = os.open(sys.argv[1], os.O_RDONLY | os.O_DIRECTORY)\n"
+        "print('\\n'.join(sorted(os.listdir(fd))))\n"
+    )
     ret = subprocess.run(
-        ["unlink", env.lower / "foo.txt"],
-        input=b"n\n",
+        [sys.executable, "-c", script, env.lower / "bar"],
         env=env.env,
         stdout=subprocess.PIPE,
         stderr=None,
     )
     assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b"bar.txt", b"baz.txt"]
+
+
+def redirect_getdents64(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
 
+    # Lists a directory twice with the raw system call, rewinding the descriptor in between, then
+    # steps back by one entry relative to the end
+    script = (
+        "import ctypes, os, platform, struct, sys\n"
+        "SYS_getdents64 = {'x86_64': 217, 'aarch64': 61}[platform.machine()]\n"
+        "libc = ctypes.CDLL(None)\n"
+        "fd = os.open(sys.argv[1], os.O_RDONLY | os.O_DIRECTORY)\n"
+        "buf = ctypes.create_string_buffer(4096)\n"
+        "def list_rest():\n"
+        "    names = []\n"
+        "    while True:\n"
+        "        n = libc.syscall(SYS_getdents64, fd, buf, len(buf))\n"
+        "        if n <= 0:\n"
+        "            break\n"
+        "        pos = 0\n"
+        "        while pos < n:\n"
+        "            reclen = struct.unpack_from('H', buf.raw, pos + 16)[0]\n"
+        "            names.append(buf.raw[pos + 19:pos + reclen].split(b'\\0')[0].decode())\n"
+        "            pos += reclen\n"
+        "    return names\n"
+        "for _ in range(2):\n"
+        "    print(' '.join(sorted(list_rest())))\n"
+        "    print(os.lseek(fd, 0, os.SEEK_CUR))\n"
+        "    os.lseek(fd, 0, os.SEEK_SET)\n"
+        "list_rest()\n"
+        "print(os.lseek(fd, -1, os.SEEK_CUR))\n"
+        "print(len(list_rest()))\n"
+    )
     ret = subprocess.run(
-        ["unlink", env.lower / "new_file.txt"],
-        input=b"n\n",
+        [sys.executable, "-c", script, env.lower / "bar"],
         env=env.env,
         stdout=subprocess.PIPE,
         stderr=None,
     )
     assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b". .. bar.txt baz.txt", b"4"] * 2 + [b"3", b"1"]
 
-    # Deletion should fail if it hits the underlying directory
+
+def redirect_syscall(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
+
+    # Manipulates the merged view with raw system calls only
+    script = (
+        "import ctypes, os, platform, sys\n"
+        "SYS = {\n"
+        "    'x86_64': dict(mkdirat=258, newfstatat=262, unlinkat=263, renameat=264, faccessat=269),\n"
+        "    'aarch64': dict(mkdirat=34, newfstatat=79, unlinkat=35, renameat=38, faccessat=48),\n"
+        "}[platform.machine()]\n"
+        "SYS['faccessat2'] = 439\n"
+        "libc = ctypes.CDLL(None)\n"
+        "AT_FDCWD = -100\n"
+        "lower = sys.argv[1]\n"
+        "buf = ctypes.create_string_buffer(256)\n"
+        "print(libc.syscall(SYS['newfstatat'], AT_FDCWD, f'{lower}/bar/baz.txt'.encode(), buf, 0))\n"
+        "print(libc.syscall(SYS['faccessat'], AT_FDCWD, f'{lower}/bar/baz.txt'.encode(), os.R_OK))\n"
+        "print(libc.syscall(SYS['faccessat2'], AT_FDCWD, f'{lower}/bar/baz.txt'.encode(), os.R_OK, 0))\n"
+        "print(libc.syscall(SYS['mkdirat'], AT_FDCWD, f'{lower}/new_dir'.encode(), 0o755))\n"
+        "print(libc.syscall(SYS['unlinkat'], AT_FDCWD, f'{lower}/foo.txt'.encode(), 0))\n"
+        "print(libc.syscall(SYS['renameat'], AT_FDCWD, f'{lower}/bar/baz.txt'.encode(), AT_FDCWD, f'{lower}/new_dir/baz.txt'.encode()))\n"
+        "print(libc.syscall(SYS['newfstatat'], AT_FDCWD, f'{lower}/foo.txt'.encode(), buf, 0))\n"
+    )
     ret = subprocess.run(
-        ["unlink", env.lower / "foo.txt"],
-        input=b"n\n",
+        [sys.executable, "-c", script, env.lower],
         env=env.env,
         stdout=subprocess.PIPE,
         stderr=None,
     )
-    assert ret.returncode != 0
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b"0", b"0", b"0", b"0", b"0", b"0", b"-1"]
+    assert (env.lower / "foo.txt").exists()
+    assert not (env.lower / "new_dir").exists()
+    assert read_all(env.upper / "new_dir" / "baz.txt") == b"It is new"
 
 
-def redirect_rmdir(env: TestEnv) -> None:
+def redirect_rewinddir(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
+
+    # Lists a directory twice, rewinding the stream in between
+    script = (
+        "import ctypes, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "libc.opendir.restype = ctypes.c_void_p\n"
+        "libc.readdir.argtypes = [ctypes.c_void_p]\n"
+        "libc.readdir.restype = ctypes.c_void_p\n"
+        "libc.rewinddir.argtypes = [ctypes.c_void_p]\n"
+        "dir = libc.opendir(sys.argv[1].encode())\n"
+        "for _ in range(2):\n"
+        "    names = []\n"
+        "    while True:\n"
+        "        entry = libc.readdir(dir)\n"
+        "        if not entry:\n"
+        "            break\n"
+        "        names.append(ctypes.string_at(entry + 19).decode())\n"
+        "    print(' '.join(sorted(names)))\n"
+        "    libc.rewinddir(dir)\n"
+    )
     ret = subprocess.run(
-        ["rmdir", env.lower / "new_dir"],
-        input=b"n\n",
+        [sys.executable, "-c", script, env.lower / "bar"],
         env=env.env,
         stdout=subprocess.PIPE,
         stderr=None,
     )
-    assert ret.returncode != 0
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b". .. bar.txt baz.txt"] * 2
+
+
+def redirect_seekdir(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
 
+    # Remembers the position after each entry, then seeks back to each of them in reverse
+    script = (
+        "import ctypes, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "libc.opendir.restype = ctypes.c_void_p\n"
+        "libc.readdir.argtypes = [ctypes.c_void_p]\n"
+        "libc.readdir.restype = ctypes.c_void_p\n"
+        "libc.telldir.argtypes = [ctypes.c_void_p]\n"
+        "libc.telldir.restype = ctypes.c_long\n"
+        "libc.seekdir.argtypes = [ctypes.c_void_p, ctypes.c_long]\n"
+        "dir = libc.opendir(sys.argv[1].encode())\n"
+        "def next_name():\n"
+        "    entry = libc.readdir(dir)\n"
+        "    return ctypes.string_at(entry + 19).decode() if entry else None\n"
+        "positions = []\n"
+        "names = []\n"
+        "while True:\n"
+        "    positions.append(libc.telldir(dir))\n"
+        "    name = next_name()\n"
+        "    if name is None:\n"
+        "        break\n"
+        "    names.append(name)\n"
+        "for position, name in reversed(list(zip(positions, names))):\n"
+        "    libc.seekdir(dir, position)\n"
+        "    assert next_name() == name\n"
+        "print(' '.join(sorted(names)))\n"
+    )
     ret = subprocess.run(
-        ["mkdir", env.lower / "new_dir"],
-        input=b"n\n",
+        [sys.executable, "-c", script, env.lower / "bar"],
         env=env.env,
         stdout=subprocess.PIPE,
         stderr=None,
     )
     assert ret.returncode == 0
+    assert ret.stdout == b". .. bar.txt baz.txt\n"
+
+
+def redirect_openat(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
 
+    # Opens files of both layers relative to a descriptor of the lower directory
+    script = (
+        "import os, sys\n"
+        "fd = os.open(sys.argv[1], os.O_RDONLY | os.O_DIRECTORY)\n"
+        "print(os.read(os.open('baz.txt', os.O_RDONLY, dir_fd=fd), 100).decode())\n"
+        "os.write(os.open('bar.txt', os.O_WRONLY | os.O_TRUNC, dir_fd=fd), b'Bar is new')\n"
+    )
     ret = subprocess.run(
-        ["rmdir", env.lower / "new_dir"],
-        input=b"n\n",
+        [sys.executable, "-c", script, env.lower / "bar"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b"It is new"]
+    assert read_all(env.upper / "bar" / "bar.txt") == b"Bar is new"
+    assert read_all(env.lower / "bar" / "bar.txt") != b"Bar is new"
+
+
+def redirect_dirfd(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
+
+    # Accesses entries of both layers relative to the fd of a merged directory stream
+    script = (
+        "import ctypes, os, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "libc.opendir.restype = ctypes.c_void_p\n"
+        "libc.dirfd.argtypes = [ctypes.c_void_p]\n"
+        "fd = libc.dirfd(libc.opendir(sys.argv[1].encode()))\n"
+        "assert os.access('bar.txt', os.R_OK, dir_fd=fd)\n"
+        "print(os.stat('baz.txt', dir_fd=fd).st_size)\n"
+        "os.chmod('bar.txt', 0o600, dir_fd=fd)\n"
+        "os.symlink('bar.txt', 'link.txt', dir_fd=fd)\n"
+        "print(os.readlink('link.txt', dir_fd=fd))\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b"9", b"bar.txt"]
+    assert (env.upper / "bar" / "bar.txt").stat().st_mode & 0o777 == 0o600
+    assert os.readlink(env.upper / "bar" / "link.txt") == "bar.txt"
+
+
+def redirect_dirfd_symlinks(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "file.txt").write_bytes(b"File")
+        Path(other_lower, "file.txt").chmod(0o644)
+        Path(other_lower, "absolute").symlink_to(Path(other_lower, "file.txt"))
+        Path(other_lower, "relative").symlink_to("file.txt")
+        Path(other_lower, "owned.txt").write_bytes(b"Owned")
+        Path(other_lower, "owned_absolute").symlink_to(Path(other_lower, "owned.txt"))
+        Path(other_lower, "owned_relative").symlink_to("owned.txt")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+
+        # Symlinks relative to a dirfd are followed in the merged view, whether they are absolute or not
+        script = (
+            "import os, sys\n"
+            "fd = os.open(sys.argv[1], os.O_RDONLY)\n"
+            "os.chmod('absolute', 0o600, dir_fd=fd)\n"
+            "assert os.stat('file.txt', dir_fd=fd).st_mode & 0o777 == 0o600\n"
+            "os.chmod('relative', 0o640, dir_fd=fd)\n"
+            "assert os.stat('file.txt', dir_fd=fd).st_mode & 0o777 == 0o640\n"
+            "os.chown('owned_absolute', os.getuid(), os.getgid(), dir_fd=fd)\n"
+            "os.chown('owned_relative', os.getuid(), os.getgid(), dir_fd=fd)\n"
+        )
+        ret = subprocess.run([sys.executable, "-c", script, other_lower], env=mapped_env, stdout=subprocess.PIPE)
+        assert ret.returncode == 0
+        assert Path(other_lower, "file.txt").stat().st_mode & 0o777 == 0o644
+        assert Path(other_upper, "file.txt").stat().st_mode & 0o777 == 0o640
+        assert not os.path.lexists(Path(other_upper, "absolute"))
+        assert not os.path.lexists(Path(other_upper, "relative"))
+        assert Path(other_upper, "owned.txt").stat().st_uid == os.getuid()
+        assert not os.path.lexists(Path(other_upper, "owned_absolute"))
+        assert not os.path.lexists(Path(other_upper, "owned_relative"))
+
+
+def redirect_chdir(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
+
+    # Relative paths resolve against the merged view of the current directory
+    script = (
+        "import os, sys\n"
+        "os.chdir(sys.argv[1])\n"
+        "print(open('baz.txt').read())\n"
+        "print(open('bar.txt').read())\n"
+        "print(os.getcwd())\n"
+        "open('new.txt', 'w').write('It is new')\n"
+        "os.mkdir('new_dir')\n"
+        "os.chdir('new_dir')\n"
+        "print(os.getcwd())\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    lines = ret.stdout.splitlines()
+    assert lines[0] == b"It is new"
+    assert lines[2] == str(env.lower / "bar").encode()
+    assert lines[3] == str(env.lower / "bar" / "new_dir").encode()
+    assert not (env.lower / "bar" / "new.txt").exists()
+    assert read_all(env.upper / "bar" / "new.txt") == b"It is new"
+    assert (env.upper / "bar" / "new_dir").is_dir()
+
+
+def redirect_nftw(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
+    ret = subprocess.run(["rm", env.lower / "foo.txt"], env=env.env, stdout=subprocess.PIPE, stderr=None)
+    assert ret.returncode == 0
+
+    # Prints the path relative to the root, the type flag and the level of each visited entry
+    script = (
+        "import ctypes, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "class FTW(ctypes.Structure):\n"
+        "    _fields_ = [('base', ctypes.c_int), ('level', ctypes.c_int)]\n"
+        "VISIT = ctypes.CFUNCTYPE(ctypes.c_int, ctypes.c_char_p, ctypes.c_void_p, ctypes.c_int, ctypes.POINTER(FTW))\n"
+        "def visit(path, buf, typeflag, ftw):\n"
+        "    print(path.decode()[len(sys.argv[1]):], typeflag, ftw.contents.level)\n"
+        "    return 0\n"
+        "assert libc.nftw(sys.argv[1].encode(), VISIT(visit), 16, 1) == 0\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, str(env.lower)],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert sorted(ret.stdout.splitlines()) == [b" 1 0", b"/bar 1 1", b"/bar/bar.txt 0 2", b"/bar/baz.txt 0 2"]
+
+
+def redirect_fts(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
+    ret = subprocess.run(["rm", env.lower / "foo.txt"], env=env.env, stdout=subprocess.PIPE, stderr=None)
+    assert ret.returncode == 0
+
+    # Prints the path relative to the root, fts_info and fts_level of each entry, then removes the
+    # files through fts_accpath
+    script = (
+        "import ctypes, os, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "class FTSENT(ctypes.Structure):\n"
+        "    _fields_ = [\n"
+        "        ('cycle', ctypes.c_void_p), ('parent', ctypes.c_void_p), ('link', ctypes.c_void_p),\n"
+        "        ('number', ctypes.c_long), ('pointer', ctypes.c_void_p), ('accpath', ctypes.c_char_p),\n"
+        "        ('path', ctypes.c_char_p), ('errno', ctypes.c_int), ('symfd', ctypes.c_int),\n"
+        "        ('pathlen', ctypes.c_ushort), ('namelen', ctypes.c_ushort), ('ino', ctypes.c_uint64),\n"
+        "        ('dev', ctypes.c_uint64), ('nlink', ctypes.c_uint64), ('level', ctypes.c_short),\n"
+        "        ('info', ctypes.c_ushort), ('flags', ctypes.c_ushort), ('instr', ctypes.c_ushort),\n"
+        "        ('statp', ctypes.c_void_p), ('name', ctypes.c_char * 256),\n"
+        "    ]\n"
+        "libc.fts_open.restype = ctypes.c_void_p\n"
+        "libc.fts_read.argtypes = [ctypes.c_void_p]\n"
+        "libc.fts_read.restype = ctypes.POINTER(FTSENT)\n"
+        "libc.fts_close.argtypes = [ctypes.c_void_p]\n"
+        "paths = (ctypes.c_char_p * 2)(sys.argv[1].encode(), None)\n"
+        "fts = libc.fts_open(paths, 0x10, None)\n"
+        "while True:\n"
+        "    entry = libc.fts_read(fts)\n"
+        "    if not entry:\n"
+        "        break\n"
+        "    entry = entry.contents\n"
+        "    print(entry.path.decode()[len(sys.argv[1]):], entry.info, entry.level)\n"
+        "    if entry.info == 8:\n"
+        "        os.unlink(entry.accpath)\n"
+        "assert libc.fts_close(fts) == 0\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, str(env.lower)],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    lines = ret.stdout.splitlines()
+    assert lines[0] == b" 1 0"
+    assert lines[-2:] == [b"/bar 6 1", b" 6 0"]
+    assert sorted(lines[1:-2]) == [b"/bar 1 1", b"/bar/bar.txt 8 2", b"/bar/baz.txt 8 2"]
+
+    # Both files are gone from the merged view
+    ret = subprocess.run(
+        ["ls", env.lower / "bar"], env=env.env, stdout=subprocess.PIPE, stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout == b""
+    assert (env.lower / "bar" / "bar.txt").exists()
+
+
+def redirect_exec(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
+    ret = env.overlay_write("bar/run.sh", f"#!/bin/sh\ncat {env.lower}/bar/baz.txt\n".encode())
+    assert ret.returncode == 0
+    ret = subprocess.run(["chmod", "+x", env.lower / "bar" / "run.sh"], env=env.env, stderr=None)
+    assert ret.returncode == 0
+
+    # The script only exists in the upper dir, and its child is started with an empty environment
+    script = (
+        "import subprocess, sys\n"
+        "ret = subprocess.run([sys.argv[1]], env={}, stdout=subprocess.PIPE)\n"
+        "sys.stdout.buffer.write(ret.stdout)\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar" / "run.sh"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout == b"It is new"
+
+
+def redirect_posix_spawn(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
+    ret = env.overlay_write("bar/run.sh", f"#!/bin/sh\ncat {env.lower}/bar/baz.txt\n".encode())
+    assert ret.returncode == 0
+    ret = subprocess.run(["chmod", "+x", env.lower / "bar" / "run.sh"], env=env.env, stderr=None)
+    assert ret.returncode == 0
+
+    # Spawns the script once by path and once through a PATH search, with scrubbed environments
+    script = (
+        "import os, sys\n"
+        "pid = os.posix_spawn(sys.argv[1], [sys.argv[1]], {})\n"
+        "assert os.waitpid(pid, 0)[1] == 0\n"
+        "os.environ['PATH'] = os.path.dirname(sys.argv[1])\n"
+        "pid = os.posix_spawnp('run.sh', ['run.sh'], {})\n"
+        "assert os.waitpid(pid, 0)[1] == 0\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar" / "run.sh"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout == b"It is newIt is new"
+
+
+def redirect_dlopen(env: TestEnv) -> None:
+    # Any shared object that is not loaded yet will do, python extension modules are at hand
+    dynload = sysconfig.get_config_var("DESTSHARED")
+    library = sorted(Path(dynload).glob("*.so"))[0]
+    ret = subprocess.run(["cp", library, env.lower / "bar" / "plugin.so"], env=env.env, stderr=None)
+    assert ret.returncode == 0
+    assert not (env.lower / "bar" / "plugin.so").exists()
+
+    ret = subprocess.run(
+        [sys.executable, "-c", "import ctypes, sys; ctypes.CDLL(sys.argv[1])", env.lower / "bar" / "plugin.so"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+
+    # Names without a slash are left to the search of the dynamic linker, even if the merged view has
+    # a file of that name
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_upper, "libc.so.6").write_bytes(b"Not a library")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        ret = subprocess.run(
+            [sys.executable, "-c", "import ctypes; ctypes.CDLL('libc.so.6')"],
+            cwd=other_lower,
+            env=mapped_env,
+            stdout=subprocess.PIPE,
+            stderr=None,
+        )
+        assert ret.returncode == 0
+
+
+def redirect_stat(env: TestEnv) -> None:
+    ret = env.overlay_write("new_file.txt", b"It is new")
+    assert ret.returncode == 0
+
+    ret = subprocess.run(
+        ["stat", env.lower / "foo.txt"],
         env=env.env,
         stdout=subprocess.PIPE,
         stderr=None,
     )
     assert ret.returncode == 0
 
+    ret = subprocess.run(
+        ["stat", env.lower / "new_file.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+
+
+def redirect_stat64(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
+
+    # Calls the LFS entry points directly, as programs built for 32-bit targets do
+    script = (
+        "import ctypes, os, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "buf = ctypes.create_string_buffer(256)\n"
+        "path = sys.argv[1].encode()\n"
+        "print(libc.__xstat64(1, path, buf))\n"
+        "print(libc.__lxstat64(1, path, buf))\n"
+        "print(libc.__fxstatat64(1, -100, path, buf, 0))\n"
+        "print(os.read(libc.openat64(-100, path, os.O_RDONLY, 0), 100).decode())\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar" / "baz.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b"0", b"0", b"0", b"It is new"]
+
+
+def redirect_statx(env: TestEnv) -> None:
+    ret = env.overlay_write("foo.txt", b"Bigger than before")
+    assert ret.returncode == 0
+
+    ret = subprocess.run(
+        ["stat", "-c", "%s", env.lower / "foo.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout == b"18\n"
+
+    ret = subprocess.run(
+        ["unlink", env.lower / "foo.txt"], env=env.env, stdout=subprocess.PIPE, stderr=None,
+    )
+    assert ret.returncode == 0
+
+    ret = subprocess.run(
+        ["stat", env.lower / "foo.txt"], env=env.env, stdout=subprocess.PIPE, stderr=None,
+    )
+    assert ret.returncode != 0
+
+
+def redirect_fxstatat(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/new.txt", b"It is new")
+    assert ret.returncode == 0
+
+    # Stats files relative to a directory fd and prints their sizes
+    script = (
+        "import ctypes, os, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "fd = os.open(sys.argv[1], os.O_RDONLY | os.O_DIRECTORY)\n"
+        "for name in sys.argv[2:]:\n"
+        "    buf = ctypes.create_string_buffer(256)\n"
+        "    assert libc.__fxstatat(1, fd, name.encode(), buf, 0) == 0\n"
+        "    print(int.from_bytes(buf[48:56], 'little'))\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar", "bar.txt", "new.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [
+        str(len(read_all(env.lower / "bar" / "bar.txt"))).encode(),
+        b"9",
+    ]
+
+
+def redirect_plain_stat(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/new.txt", b"It is new")
+    assert ret.returncode == 0
+
+    script = (
+        "import os, sys\n"
+        "fd = os.open(os.path.dirname(sys.argv[1]), os.O_RDONLY | os.O_DIRECTORY)\n"
+        "print(os.stat(sys.argv[1]).st_size)\n"
+        "print(os.lstat(sys.argv[1]).st_size)\n"
+        "print(os.stat(os.path.basename(sys.argv[1]), dir_fd=fd).st_size)\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar" / "new.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b"9", b"9", b"9"]
+
+
+def redirect_name_to_handle_at(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/baz.txt", b"It is new")
+    assert ret.returncode == 0
+
+    # Prints the file handle of each argument
+    script = (
+        "import ctypes, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "for path in sys.argv[1:]:\n"
+        "    handle = (ctypes.c_uint * 34)(128)\n"
+        "    mount_id = ctypes.c_int()\n"
+        "    assert libc.name_to_handle_at(-100, path.encode(), handle, ctypes.byref(mount_id), 0) == 0\n"
+        "    print(bytes(handle).hex())\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar" / "baz.txt", env.upper / "bar" / "baz.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    lines = ret.stdout.splitlines()
+    assert lines[0] == lines[1]
+
+
+def redirect_copy_file_range(env: TestEnv) -> None:
+    # Copies between descriptors, the last one was opened without liboverlay
+    script = (
+        "import os, sys\n"
+        "src = os.open(sys.argv[1], os.O_RDONLY)\n"
+        "dst = os.open(sys.argv[2], os.O_WRONLY | os.O_CREAT, 0o644)\n"
+        "os.copy_file_range(src, dst, 100)\n"
+        "for copy in [lambda: os.copy_file_range(src, int(sys.argv[3]), 100, 0),\n"
+        "             lambda: os.sendfile(int(sys.argv[3]), src, 0, 100)]:\n"
+        "    try:\n"
+        "        copy()\n"
+        "    except OSError as e:\n"
+        "        print(e.errno)\n"
+    )
+    foo = read_all(env.lower / "foo.txt")
+    lower_fd = os.open(env.lower / "foo.txt", os.O_WRONLY | os.O_APPEND)
+    try:
+        ret = subprocess.run(
+            [sys.executable, "-c", script, env.lower / "foo.txt", env.lower / "new.txt", str(lower_fd)],
+            env=env.env,
+            stdout=subprocess.PIPE,
+            stderr=None,
+            pass_fds=[lower_fd],
+        )
+    finally:
+        os.close(lower_fd)
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b"30", b"30"]
+    assert read_all(env.lower / "foo.txt") == foo
+    assert read_all(env.upper / "new.txt") == foo
+
+
+def multiple_mappings(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "other.txt").write_bytes(b"Other tree")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+
+        # Both trees are overlaid, each one into its own upper dir
+        for path in [Path(other_lower, "other.txt"), env.lower / "foo.txt"]:
+            ret = subprocess.run(
+                ["tee", "-a", path], input=b" is new", env=mapped_env, stdout=subprocess.PIPE, stderr=None
+            )
+            assert ret.returncode == 0
+        assert read_all(Path(other_lower, "other.txt")) == b"Other tree"
+        assert read_all(Path(other_upper, "other.txt")) == b"Other tree is new"
+        assert read_all(env.upper / "foo.txt") == read_all(env.lower / "foo.txt") + b" is new"
+
+
+def sparse_copy_up(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        with open(Path(other_lower, "disk.img"), "wb") as image:
+            image.write(b"Header")
+            image.seek(64 << 20)
+            image.write(b"Footer")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+
+        ret = subprocess.run(
+            ["tee", "-a", Path(other_lower, "disk.img")],
+            input=b" is new",
+            env=mapped_env,
+            stdout=subprocess.PIPE,
+            stderr=None,
+        )
+        assert ret.returncode == 0
+
+        # The hole between the data stays a hole in the copy
+        copy = Path(other_upper, "disk.img")
+        assert copy.stat().st_size == (64 << 20) + len(b"Footer is new")
+        assert copy.stat().st_blocks * 512 < (1 << 20)
+        with open(copy, "rb") as image:
+            assert image.read(6) == b"Header"
+            assert image.read(10) == bytes(10)
+            image.seek(64 << 20)
+            assert image.read() == b"Footer is new"
+
+
+# Clones in place of the file system, or fails with the errno in CLONE_RESULT
+CLONE_PRELOAD = """
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <errno.h>
+#include <linux/fs.h>
+#include <stdarg.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+int ioctl(int fd, unsigned long request, ...) {
+    va_list args;
+    va_start(args, request);
+    void *arg = va_arg(args, void *);
+    va_end(args);
+    const char *result = getenv("CLONE_RESULT");
+    if (request != FICLONE || result == NULL) {
+        int (*real)(int, unsigned long, ...) = dlsym(RTLD_NEXT, "ioctl");
+        return real(fd, request, arg);
+    }
+    if (strcmp(result, "ok") != 0) {
+        errno = atoi(result);
+        return -1;
+    }
+    char buf[4096];
+    ssize_t n;
+    for (off_t offset = 0; (n = pread((int)(long)arg, buf, sizeof(buf), offset)) > 0; offset += n) {
+        if (pwrite(fd, buf, n, offset) != n) {
+            return -1;
+        }
+    }
+    return n < 0 ? -1 : 0;
+}
+"""
+
+
+def clone_copy_up(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as build_dir:
+        preload = build_preload(CLONE_PRELOAD, build_dir)
+        for result in ["ok", str(errno.EOPNOTSUPP), str(errno.EXDEV)]:
+            with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+                Path(other_lower, "read.txt").write_bytes(b"Lower")
+                Path(other_lower, "written.txt").write_bytes(b"Lower")
+                mapped_env = dict(env.env)
+                mapped_env["LD_PRELOAD"] = f"{env.env['LD_PRELOAD']} {preload}"
+                mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+                mapped_env["LIBOVERLAY_MAPPING_OPTIONS"] = f"{other_lower}=copy_up=reflink"
+                mapped_env["LIBOVERLAY_LOG"] = "error,copy=debug"
+                mapped_env["CLONE_RESULT"] = result
+
+                # Reading caches a clone, unless the file system cannot clone
+                ret = subprocess.run(["cat", Path(other_lower, "read.txt")], env=mapped_env, capture_output=True)
+                assert ret.returncode == 0
+                assert ret.stdout == b"Lower"
+                if result == "ok":
+                    assert read_all(Path(other_upper, "read.txt")) == b"Lower"
+                else:
+                    assert b"liboverlay: not cloning" in ret.stderr
+                    assert not Path(other_upper, "read.txt").exists()
+
+                # Writing copies up by cloning, or else by copying the data
+                ret = subprocess.run(
+                    ["tee", "-a", Path(other_lower, "written.txt")],
+                    input=b" is new",
+                    env=mapped_env,
+                    capture_output=True,
+                )
+                assert ret.returncode == 0
+                assert (b"liboverlay: cloned" in ret.stderr) == (result == "ok")
+                assert read_all(Path(other_upper, "written.txt")) == b"Lower is new"
+                assert not any(name.startswith(".wh..wh.copy.") for name in os.listdir(other_upper))
+
+
+# Copies at most three bytes per copy_file_range, or fails with the errno in COPY_RESULT
+COPY_PRELOAD = """
+#define _GNU_SOURCE
+#include <dlfcn.h>
+#include <errno.h>
+#include <stdarg.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <sys/syscall.h>
+
+long syscall(long number, ...) {
+    va_list args;
+    va_start(args, number);
+    long arg[6];
+    for (int i = 0; i < 6; i++) {
+        arg[i] = va_arg(args, long);
+    }
+    va_end(args);
+    const char *result = getenv("COPY_RESULT");
+    if (number == SYS_copy_file_range && result != NULL) {
+        if (strcmp(result, "short") != 0) {
+            dprintf(2, "failed copy\\n");
+            errno = atoi(result);
+            return -1;
+        }
+        dprintf(2, "short copy\\n");
+        if (arg[4] > 3) {
+            arg[4] = 3;
+        }
+    }
+    long (*real)(long, ...) = dlsym(RTLD_NEXT, "syscall");
+    return real(number, arg[0], arg[1], arg[2], arg[3], arg[4], arg[5]);
+}
+"""
+
+
+def copy_up_ranges(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as build_dir:
+        preload = build_preload(COPY_PRELOAD, build_dir)
+        for result in ["short", str(errno.ENOSYS), str(errno.EXDEV)]:
+            with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+                Path(other_lower, "file.txt").write_bytes(b"0123456789")
+                mapped_env = dict(env.env)
+                mapped_env["LD_PRELOAD"] = f"{env.env['LD_PRELOAD']} {preload}"
+                mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+                mapped_env["COPY_RESULT"] = result
+
+                # Short copies are continued until all data is copied, failed ones by copying by hand
+                ret = subprocess.run(
+                    ["tee", "-a", Path(other_lower, "file.txt")],
+                    input=b" is new",
+                    env=mapped_env,
+                    capture_output=True,
+                )
+                assert ret.returncode == 0
+                assert read_all(Path(other_upper, "file.txt")) == b"0123456789 is new"
+                if result == "short":
+                    assert ret.stderr.splitlines() == [b"short copy"] * 4
+                else:
+                    assert ret.stderr.splitlines() == [b"failed copy"]
+
+
+def copy_up_metadata(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        lower = Path(other_lower, "script.sh")
+        lower.write_bytes(b"#!/bin/sh\n")
+        lower.chmod(0o755)
+        os.setxattr(lower, "user.origin", b"lower")
+        os.utime(lower, ns=(1_000_000_000_123, 2_000_000_000_456))
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+
+        # Opening for writing copies the file up, but does not change it yet
+        script = "import os, sys\nos.close(os.open(sys.argv[1], os.O_WRONLY))\n"
+        ret = subprocess.run(
+            [sys.executable, "-c", script, lower], env=mapped_env, stdout=subprocess.PIPE, stderr=None
+        )
+        assert ret.returncode == 0
+        upper = Path(other_upper, "script.sh")
+        assert read_all(upper) == b"#!/bin/sh\n"
+        assert upper.stat().st_mode & 0o7777 == 0o755
+        assert upper.stat().st_mtime_ns == 2_000_000_000_456
+        assert upper.stat().st_uid == lower.stat().st_uid
+        assert os.getxattr(upper, "user.origin") == b"lower"
+
+
+def copy_up_hard_links(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "dir").mkdir()
+        Path(other_lower, "first.txt").write_bytes(b"Shared")
+        os.link(Path(other_lower, "first.txt"), Path(other_lower, "dir", "second.txt"))
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+
+        ret = subprocess.run(
+            ["tee", "-a", Path(other_lower, "first.txt")],
+            input=b" is new",
+            env=mapped_env,
+            stdout=subprocess.PIPE,
+            stderr=None,
+        )
+        assert ret.returncode == 0
+
+        # The other link shows the change as well, and both share the upper copy
+        ret = subprocess.run(
+            ["cat", Path(other_lower, "dir", "second.txt")], env=mapped_env, stdout=subprocess.PIPE, stderr=None
+        )
+        assert ret.returncode == 0
+        assert ret.stdout == b"Shared is new"
+        first = Path(other_upper, "first.txt").stat()
+        second = Path(other_upper, "dir", "second.txt").stat()
+        assert (first.st_dev, first.st_ino) == (second.st_dev, second.st_ino)
+        assert read_all(Path(other_lower, "first.txt")) == b"Shared"
+
+        ret = subprocess.run(["ls", "-a", other_lower], env=mapped_env, stdout=subprocess.PIPE, stderr=None)
+        assert ret.returncode == 0
+        assert ret.stdout.splitlines() == [b".", b"..", b"dir", b"first.txt"]
+
+
+def copy_up_symlinks(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "target.txt").write_bytes(b"Target")
+        Path(other_lower, "relative").symlink_to("target.txt")
+        Path(other_lower, "absolute").symlink_to(Path(other_lower, "target.txt"))
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+
+        # Writing through a lower symlink copies up its target, not the link
+        ret = subprocess.run(
+            ["tee", "-a", Path(other_lower, "absolute")],
+            input=b" is new",
+            env=mapped_env,
+            stdout=subprocess.PIPE,
+            stderr=None,
+        )
+        assert ret.returncode == 0
+        assert read_all(Path(other_upper, "target.txt")) == b"Target is new"
+        assert not os.path.lexists(Path(other_upper, "absolute"))
+
+        # Moving a symlink copies it up as a symlink
+        ret = subprocess.run(
+            ["mv", Path(other_lower, "relative"), Path(other_lower, "moved")],
+            env=mapped_env,
+            stdout=subprocess.PIPE,
+            stderr=None,
+        )
+        assert ret.returncode == 0
+        assert os.readlink(Path(other_upper, "moved")) == "target.txt"
+        ret = subprocess.run(["cat", Path(other_lower, "moved")], env=mapped_env, stdout=subprocess.PIPE, stderr=None)
+        assert ret.returncode == 0
+        assert ret.stdout == b"Target is new"
+
+        # Unless the links are configured to be followed
+        mapped_env["LIBOVERLAY_FOLLOW_SYMLINKS"] = "1"
+        ret = subprocess.run(
+            ["mv", Path(other_lower, "absolute"), Path(other_lower, "copied")],
+            env=mapped_env,
+            stdout=subprocess.PIPE,
+            stderr=None,
+        )
+        assert ret.returncode == 0
+        assert not Path(other_upper, "copied").is_symlink()
+        assert read_all(Path(other_upper, "copied")) == b"Target is new"
+
+
+def copy_up_atomic(env: TestEnv) -> None:
+    # A temporary copy left behind by a crash is not part of the merged view
+    (env.upper / ".wh..wh.copy.1.0.foo.txt").write_bytes(b"Half")
+
+    ret = subprocess.run(
+        ["tee", "-a", env.lower / "foo.txt"], input=b" is new", env=env.env, stdout=subprocess.PIPE, stderr=None
+    )
+    assert ret.returncode == 0
+    assert read_all(env.upper / "foo.txt") == read_all(env.lower / "foo.txt") + b" is new"
+    assert sorted(os.listdir(env.upper)) == [".wh..wh.copy.1.0.foo.txt", ".wh..wh.locks", "foo.txt"]
+
+    ret = subprocess.run(["ls", "-a", env.lower], env=env.env, stdout=subprocess.PIPE, stderr=None)
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b".", b"..", b"bar", b"foo.txt"]
+
+
+def copy_up_locking(env: TestEnv) -> None:
+    # Many processes copying up the same file at once all end up with one complete copy
+    script = (
+        "import sys\n"
+        "with open(sys.argv[1], 'a') as file:\n"
+        "    file.write('!')\n"
+    )
+    processes = [
+        subprocess.Popen([sys.executable, "-c", script, env.lower / "foo.txt"], env=env.env) for _ in range(8)
+    ]
+    for process in processes:
+        assert process.wait() == 0
+    assert read_all(env.upper / "foo.txt") == read_all(env.lower / "foo.txt") + b"!" * 8
+
+
+def copy_up_threads(env: TestEnv) -> None:
+    # Threads copying up the same file at once share one copy as well
+    script = (
+        "import sys, threading\n"
+        "def append():\n"
+        "    with open(sys.argv[1], 'a') as file:\n"
+        "        file.write('!')\n"
+        "threads = [threading.Thread(target=append) for _ in range(8)]\n"
+        "for thread in threads:\n"
+        "    thread.start()\n"
+        "for thread in threads:\n"
+        "    thread.join()\n"
+    )
+    ret = subprocess.run([sys.executable, "-c", script, env.lower / "foo.txt"], env=env.env)
+    assert ret.returncode == 0
+    assert read_all(env.upper / "foo.txt") == read_all(env.lower / "foo.txt") + b"!" * 8
+
+
+def normalize_parent_dirs(env: TestEnv) -> None:
+    # `..` cannot lead out of the mapping, nor past it into the upper dir
+    ret = subprocess.run(
+        ["tee", "-a", f"{env.lower}/bar/../foo.txt"], input=b" is new", env=env.env, stdout=subprocess.PIPE, stderr=None
+    )
+    assert ret.returncode == 0
+    assert read_all(env.upper / "foo.txt") == read_all(env.lower / "foo.txt") + b" is new"
+    ret = subprocess.run(
+        ["cat", f"{env.lower}/./bar/../../lower/foo.txt"], env=env.env, stdout=subprocess.PIPE, stderr=None
+    )
+    assert ret.returncode == 0
+    assert ret.stdout == read_all(env.lower / "foo.txt") + b" is new"
+
+    # `..` after a symlink leads to the parent of its target
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "sub", "deep").mkdir(parents=True)
+        Path(other_lower, "link").symlink_to("sub/deep")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        ret = subprocess.run(
+            ["tee", f"{other_lower}/link/../new.txt"], input=b"It is new", env=mapped_env, stdout=subprocess.PIPE, stderr=None
+        )
+        assert ret.returncode == 0
+        assert read_all(Path(other_upper, "sub", "new.txt")) == b"It is new"
+        assert not Path(other_upper, "new.txt").exists()
+
+
+def resolve_symlinked_dirs(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "sub").mkdir()
+        Path(other_lower, "sub", "file.txt").write_bytes(b"In sub")
+        Path(other_lower, "linkdir").symlink_to("sub")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+
+        # Writing below a symlinked lower dir copies up the entry in the dir it points to
+        ret = subprocess.run(
+            ["tee", "-a", f"{other_lower}/linkdir/file.txt"],
+            input=b" is new",
+            env=mapped_env,
+            stdout=subprocess.PIPE,
+            stderr=None,
+        )
+        assert ret.returncode == 0
+        assert read_all(Path(other_upper, "sub", "file.txt")) == b"In sub is new"
+        assert not os.path.lexists(Path(other_upper, "linkdir"))
+
+        # Symlinks that only exist in the upper dir lead into the merged view as well
+        ret = subprocess.run(
+            ["ln", "-s", "sub", f"{other_lower}/newlink"], env=mapped_env, stdout=subprocess.PIPE, stderr=None
+        )
+        assert ret.returncode == 0
+        ret = subprocess.run(
+            ["cat", f"{other_lower}/newlink/file.txt"], env=mapped_env, stdout=subprocess.PIPE, stderr=None
+        )
+        assert ret.returncode == 0
+        assert ret.stdout == b"In sub is new"
+
+
+def directory_semantics(env: TestEnv) -> None:
+    script = (
+        "import errno, os, sys\n"
+        "lower = sys.argv[1]\n"
+        "def error(path, flags):\n"
+        "    try:\n"
+        "        os.close(os.open(path, flags))\n"
+        "    except OSError as e:\n"
+        "        return errno.errorcode[e.errno]\n"
+        "    return 'ok'\n"
+        "open(lower + '/foo.txt', 'a').close()\n"
+        "os.mkdir(lower + '/newdir/')\n"
+        "print(error(lower + '/foo.txt/', os.O_RDONLY))\n"
+        "print(error(lower + '/bar/bar.txt/', os.O_WRONLY))\n"
+        "print(error(lower + '/bar', os.O_RDWR | os.O_DIRECTORY))\n"
+        "print(error(lower + '/newdir/', os.O_RDONLY | os.O_DIRECTORY))\n"
+        "print(error(lower + '/bar/', os.O_RDONLY))\n"
+    )
+    ret = subprocess.run([sys.executable, "-c", script, env.lower], env=env.env, stdout=subprocess.PIPE, stderr=None)
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b"ENOTDIR", b"ENOTDIR", b"EISDIR", b"ok", b"ok"]
+    # Opens that fail for lack of a directory do not copy anything up
+    assert sorted(os.listdir(env.upper)) == [".wh..wh.locks", "foo.txt", "newdir"]
+
+
+def upper_quota(env: TestEnv) -> None:
+    script = (
+        "import errno, os, sys\n"
+        "lower = sys.argv[1]\n"
+        "def error(path, contents):\n"
+        "    try:\n"
+        "        with open(path, 'ab') as file:\n"
+        "            file.write(contents)\n"
+        "    except OSError as e:\n"
+        "        return errno.errorcode[e.errno]\n"
+        "    return 'ok'\n"
+        "def error_of(call):\n"
+        "    try:\n"
+        "        call()\n"
+        "    except OSError as e:\n"
+        "        return errno.errorcode[e.errno]\n"
+        "    return 'ok'\n"
+        "print(error(lower + '/old.txt', b'!'))\n"
+        "print(error(lower + '/new.txt', b'New'))\n"
+        "print(error(lower + '/newer.txt', b'Newer'))\n"
+        "print(error(lower + '/new.txt', bytes(2 << 20)))\n"
+        "import ctypes\n"
+        "libc = ctypes.CDLL(None, use_errno=True)\n"
+        "libc.fallocate.argtypes = [ctypes.c_int, ctypes.c_int, ctypes.c_long, ctypes.c_long]\n"
+        "def fallocate(fd, mode, offset, len):\n"
+        "    if libc.fallocate(fd, mode, offset, len) != 0:\n"
+        "        raise OSError(ctypes.get_errno(), 'fallocate')\n"
+        "fd = os.open(lower + '/new.txt', os.O_WRONLY)\n"
+        "print(error_of(lambda: fallocate(fd, 0, 0, 2 << 20)))\n"
+        "print(error_of(lambda: fallocate(fd, 1, 0, 2 << 20)))\n"
+        "print(error_of(lambda: os.posix_fallocate(fd, 0, 2 << 20)))\n"
+        "print(error_of(lambda: os.posix_fallocate(fd, 2 ** 62, 2 ** 62)))\n"
+    )
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "old.txt").write_bytes(b"Old")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        mapped_env["LIBOVERLAY_QUOTA_FILES"] = "2"
+        mapped_env["LIBOVERLAY_QUOTA_BYTES"] = "1M"
+        ret = subprocess.run(
+            [sys.executable, "-c", script, other_lower], env=mapped_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE
+        )
+        assert ret.returncode == 0
+        lines = ret.stdout.splitlines()
+        assert lines[:7] == [b"ok", b"ok", b"ENOSPC", b"ENOSPC", b"ENOSPC", b"ok", b"ENOSPC"]
+        # Ranges beyond the largest offset are refused by the call rather than by the quota
+        assert lines[7] not in (b"ok", b"ENOSPC")
+        assert b"quota exceeded" in ret.stderr
+        # Nothing beyond the quota ends up in either dir
+        assert sorted(os.listdir(other_lower)) == ["old.txt"]
+        assert sorted(os.listdir(other_upper)) == [".wh..wh.locks", "new.txt", "old.txt"]
+        assert read_all(Path(other_upper, "new.txt")) == b"New"
+
+        # Errors are logged like everything else
+        mapped_env["LIBOVERLAY_LOG"] = "off"
+        ret = subprocess.run(
+            [sys.executable, "-c", script, other_lower], env=mapped_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE
+        )
+        assert ret.returncode == 0
+        assert b"ENOSPC" in ret.stdout
+        assert ret.stderr == b""
+
+
+def quota_eviction(env: TestEnv) -> None:
+    script = (
+        "import errno, os, sys\n"
+        "lower = sys.argv[1]\n"
+        "def error(call):\n"
+        "    try:\n"
+        "        call()\n"
+        "    except OSError as e:\n"
+        "        return errno.errorcode[e.errno]\n"
+        "    return 'ok'\n"
+        "def append(name, contents):\n"
+        "    with open(lower + '/' + name, 'ab') as file:\n"
+        "        file.write(contents)\n"
+        "print(error(lambda: append('a.txt', b'')))\n"
+        "print(error(lambda: append('b.txt', b'!')))\n"
+        "kept = open(lower + '/c.txt', 'ab')\n"
+        "print(error(lambda: append('new.txt', b'New')))\n"
+        "kept.close()\n"
+        "print(error(lambda: append('new.txt', b'New')))\n"
+        "print(error(lambda: append('newer.txt', b'Newer')))\n"
+    )
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        for name in ["a.txt", "b.txt", "c.txt"]:
+            Path(other_lower, name).write_bytes(name.encode())
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        mapped_env["LIBOVERLAY_QUOTA_FILES"] = "2"
+        mapped_env["LIBOVERLAY_QUOTA_EVICT"] = "1"
+        ret = subprocess.run(
+            [sys.executable, "-c", script, other_lower], env=mapped_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE
+        )
+        assert ret.returncode == 0
+        # The unmodified copy of a.txt makes room for c.txt, which is kept while it is open
+        assert ret.stdout.splitlines() == [b"ok", b"ok", b"ENOSPC", b"ok", b"ENOSPC"]
+        assert sorted(os.listdir(other_upper)) == [".wh..wh.locks", "b.txt", "new.txt"]
+        assert read_all(Path(other_upper, "b.txt")) == b"b.txt!"
+        assert sorted(os.listdir(other_lower)) == ["a.txt", "b.txt", "c.txt"]
+
+    # Copies whose mode or owner changed count as modified as well
+    script = (
+        "import os, sys\n"
+        "lower = sys.argv[1]\n"
+        "open(lower + '/a.txt', 'ab').close()\n"
+        "os.chmod(lower + '/a.txt', 0o600)\n"
+        "open(lower + '/new.txt', 'ab').close()\n"
+    )
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "a.txt").write_bytes(b"a.txt")
+        Path(other_lower, "a.txt").chmod(0o644)
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        mapped_env["LIBOVERLAY_QUOTA_FILES"] = "1"
+        mapped_env["LIBOVERLAY_QUOTA_EVICT"] = "1"
+        ret = subprocess.run(
+            [sys.executable, "-c", script, other_lower], env=mapped_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE
+        )
+        assert ret.returncode != 0
+        assert b"No space left on device" in ret.stderr
+        assert Path(other_upper, "a.txt").stat().st_mode & 0o777 == 0o600
+
+
+def gc_tool(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "sub").mkdir()
+        for name in ["same.txt", "changed.txt", "deleted.txt", "sub/same.txt"]:
+            Path(other_lower, name).write_bytes(b"Lower")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        script = (
+            "import os, sys\n"
+            "lower = sys.argv[1]\n"
+            "for name in ['same.txt', 'changed.txt', 'sub/same.txt']:\n"
+            "    open(lower + '/' + name, 'a').close()\n"
+            "with open(lower + '/changed.txt', 'w') as file:\n"
+            "    file.write('Upper')\n"
+            "os.remove(lower + '/deleted.txt')\n"
+            "with open(lower + '/deleted.txt', 'w') as file:\n"
+            "    file.write('Lower')\n"
+        )
+        ret = subprocess.run([sys.executable, "-c", script, other_lower], env=mapped_env)
+        assert ret.returncode == 0
+        Path(other_upper, ".wh..wh.copy.1.0.left.txt").write_bytes(b"Lower")
+        before = sorted(os.listdir(other_upper))
+
+        ret = subprocess.run(
+            [OVERLAY_TOOL, "gc", "--dry-run", other_lower, other_upper], stdout=subprocess.PIPE, stderr=subprocess.PIPE
+        )
+        assert ret.returncode == 0
+        assert sorted(ret.stdout.splitlines()) == [b".wh..wh.copy.1.0.left.txt", b"same.txt", b"sub", b"sub/same.txt"]
+        assert sorted(os.listdir(other_upper)) == before
+
+        ret = subprocess.run([OVERLAY_TOOL, "gc", other_lower, other_upper], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        assert ret.returncode == 0
+        # The recreated file is kept, it hides the whiteout of the deleted lower file
+        assert sorted(os.listdir(other_upper)) == [".wh..wh.locks", ".wh.deleted.txt", "changed.txt", "deleted.txt"]
+        ret = subprocess.run(["cat", f"{other_lower}/same.txt", f"{other_lower}/sub/same.txt"], env=mapped_env, stdout=subprocess.PIPE)
+        assert ret.stdout == b"LowerLower"
+
+
+def commit_tool(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "dir").mkdir()
+        Path(other_lower, "sub").mkdir()
+        for name in ["appended.txt", "deleted.txt", "chmodded.txt", "conflict.txt", "dir/old.txt", "sub/kept.txt"]:
+            Path(other_lower, name).write_bytes(b"Lower")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        script = (
+            "import os, shutil, sys\n"
+            "lower = sys.argv[1]\n"
+            "for name in ['appended.txt', 'conflict.txt']:\n"
+            "    with open(lower + '/' + name, 'a') as file:\n"
+            "        file.write(' and upper')\n"
+            "os.remove(lower + '/deleted.txt')\n"
+            "os.chmod(lower + '/chmodded.txt', 0o600)\n"
+            "shutil.rmtree(lower + '/dir')\n"
+            "os.mkdir(lower + '/dir')\n"
+            "with open(lower + '/dir/new.txt', 'w') as file:\n"
+            "    file.write('Upper')\n"
+            "with open(lower + '/sub/new.txt', 'w') as file:\n"
+            "    file.write('Upper')\n"
+        )
+        ret = subprocess.run([sys.executable, "-c", script, other_lower], env=mapped_env)
+        assert ret.returncode == 0
+        # Changed behind the back of the overlay
+        Path(other_lower, "conflict.txt").write_bytes(b"Changed")
+        before = sorted(os.listdir(other_upper))
+
+        ret = subprocess.run(
+            [OVERLAY_TOOL, "commit", "--dry-run", other_lower, other_upper], stdout=subprocess.PIPE, stderr=subprocess.PIPE
+        )
+        assert ret.returncode == 1
+        assert ret.stdout.splitlines() == [
+            b"D deleted.txt",
+            b"M appended.txt",
+            b"M chmodded.txt",
+            b"C conflict.txt",
+            b"A dir/new.txt",
+            b"D dir/old.txt",
+            b"A sub/new.txt",
+        ]
+        assert sorted(os.listdir(other_upper)) == before
+        assert read_all(Path(other_lower, "appended.txt")) == b"Lower"
+
+        ret = subprocess.run([OVERLAY_TOOL, "commit", other_lower, other_upper], stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        assert ret.returncode == 1
+        assert sorted(os.listdir(other_lower)) == ["appended.txt", "chmodded.txt", "conflict.txt", "dir", "sub"]
+        assert read_all(Path(other_lower, "appended.txt")) == b"Lower and upper"
+        assert Path(other_lower, "chmodded.txt").stat().st_mode & 0o777 == 0o600
+        assert read_all(Path(other_lower, "chmodded.txt")) == b"Lower"
+        assert read_all(Path(other_lower, "conflict.txt")) == b"Changed"
+        assert sorted(os.listdir(Path(other_lower, "dir"))) == ["new.txt"]
+        assert sorted(os.listdir(Path(other_lower, "sub"))) == ["kept.txt", "new.txt"]
+        # Only the conflict is left in the upper dir
+        assert sorted(os.listdir(other_upper)) == [".wh..wh.locks", "conflict.txt"]
+
+        ret = subprocess.run(
+            [OVERLAY_TOOL, "commit", "--force", other_lower, other_upper], stdout=subprocess.PIPE, stderr=subprocess.PIPE
+        )
+        assert ret.returncode == 0
+        assert ret.stdout.splitlines() == [b"M conflict.txt"]
+        assert read_all(Path(other_lower, "conflict.txt")) == b"Lower and upper"
+        assert sorted(os.listdir(other_upper)) == [".wh..wh.locks"]
+
+
+def export_tool(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "dir").mkdir()
+        for name in ["appended.txt", "deleted.txt", "chmodded.txt", "dir/old.txt"]:
+            Path(other_lower, name).write_bytes(b"Lower")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        long_name = "long" * 40
+        script = (
+            "import os, shutil, sys\n"
+            "lower = sys.argv[1]\n"
+            "with open(lower + '/appended.txt', 'a') as file:\n"
+            "    file.write(' and upper')\n"
+            "os.remove(lower + '/deleted.txt')\n"
+            "os.chmod(lower + '/chmodded.txt', 0o600)\n"
+            "shutil.rmtree(lower + '/dir')\n"
+            "os.mkdir(lower + '/dir')\n"
+            "os.symlink('../appended.txt', lower + '/dir/link')\n"
+            f"with open(lower + '/{long_name}', 'w') as file:\n"
+            "    file.write('Upper')\n"
+        )
+        ret = subprocess.run([sys.executable, "-c", script, other_lower], env=mapped_env)
+        assert ret.returncode == 0
+
+        ret = subprocess.run(
+            [OVERLAY_TOOL, "export", "--manifest", other_lower, other_upper, "-"], stdout=subprocess.PIPE, stderr=None
+        )
+        assert ret.returncode == 0
+        assert ret.stdout.splitlines() == [
+            b"D deleted.txt",
+            b"M appended.txt",
+            b"M chmodded.txt",
+            b"R dir/",
+            b"A dir/link",
+            f"A {long_name}".encode(),
+        ]
+
+        archive = Path(other_upper).parent / (Path(other_upper).name + ".tar")
+        try:
+            ret = subprocess.run([OVERLAY_TOOL, "export", other_lower, other_upper, archive], stderr=None)
+            assert ret.returncode == 0
+            with tarfile.open(archive) as tar:
+                assert tar.getnames() == [
+                    ".wh.deleted.txt",
+                    "appended.txt",
+                    "chmodded.txt",
+                    "dir",
+                    "dir/.wh..wh..opq",
+                    "dir/link",
+                    long_name,
+                ]
+                assert tar.extractfile("appended.txt").read() == b"Lower and upper"
+                assert tar.extractfile("chmodded.txt").read() == b"Lower"
+                assert tar.getmember("chmodded.txt").mode == 0o600
+                assert tar.getmember("dir/link").linkname == "../appended.txt"
+                assert tar.extractfile(long_name).read() == b"Upper"
+        finally:
+            archive.unlink()
+
+
+def snapshot_tool(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        for name in ["kept.txt", "chmodded.txt", "deleted.txt"]:
+            Path(other_lower, name).write_bytes(b"Lower")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+
+        def overlay(script: str) -> None:
+            ret = subprocess.run([sys.executable, "-c", script, other_lower], env=mapped_env)
+            assert ret.returncode == 0
+
+        def merged() -> bytes:
+            ret = subprocess.run(
+                ["sh", "-c", f"cd {other_lower} && for f in *; do echo $f; cat $f; echo; done"],
+                env=mapped_env,
+                stdout=subprocess.PIPE,
+            )
+            assert ret.returncode == 0
+            return ret.stdout
+
+        overlay(
+            "import os, sys\n"
+            "lower = sys.argv[1]\n"
+            "os.chmod(lower + '/chmodded.txt', 0o600)\n"
+            "with open(lower + '/kept.txt', 'a') as file:\n"
+            "    file.write(' and upper')\n"
+        )
+        ret = subprocess.run([OVERLAY_TOOL, "snapshot", other_upper, "before"])
+        assert ret.returncode == 0
+        before = merged()
+
+        overlay(
+            "import os, sys\n"
+            "lower = sys.argv[1]\n"
+            "os.remove(lower + '/deleted.txt')\n"
+            "with open(lower + '/kept.txt', 'w') as file:\n"
+            "    file.write('Overwritten')\n"
+            "with open(lower + '/chmodded.txt', 'a') as file:\n"
+            "    file.write(' and upper')\n"
+            "with open(lower + '/new.txt', 'w') as file:\n"
+            "    file.write('New')\n"
+        )
+        assert merged() != before
+        ret = subprocess.run([OVERLAY_TOOL, "snapshots", other_upper], stdout=subprocess.PIPE)
+        assert ret.returncode == 0
+        assert ret.stdout == b"before\n"
+        ret = subprocess.run([OVERLAY_TOOL, "snapshot", other_upper, "before"], stderr=subprocess.PIPE)
+        assert ret.returncode == 1
+
+        ret = subprocess.run([OVERLAY_TOOL, "rollback", other_upper, "before"])
+        assert ret.returncode == 0
+        assert merged() == before
+        # The metadata-only copy is still one
+        assert Path(other_upper, ".wh..wh.meta.chmodded.txt").exists()
+        assert Path(other_upper, "chmodded.txt").stat().st_mode & 0o777 == 0o600
+
+        ret = subprocess.run([OVERLAY_TOOL, "snapshot", "--delete", other_upper, "before"])
+        assert ret.returncode == 0
+        ret = subprocess.run([OVERLAY_TOOL, "snapshots", other_upper], stdout=subprocess.PIPE)
+        assert ret.stdout == b""
+        assert sorted(os.listdir(other_lower)) == ["chmodded.txt", "deleted.txt", "kept.txt"]
+
+
+def session_upper_dirs(env: TestEnv) -> None:
+    # Named sessions do not see each other's writes
+    for session in ["first", "second"]:
+        session_env = dict(env.env)
+        session_env["LIBOVERLAY_SESSION"] = session
+        ret = subprocess.run(
+            ["tee", "-a", env.lower / "foo.txt"], input=session.encode(), env=session_env, stdout=subprocess.PIPE
+        )
+        assert ret.returncode == 0
+        ret = subprocess.run(["cat", env.lower / "foo.txt"], env=session_env, stdout=subprocess.PIPE)
+        assert ret.stdout == read_all(env.lower / "foo.txt") + session.encode()
+    assert os.listdir(env.upper) == [".wh..wh.sessions"]
+    assert sorted(os.listdir(env.upper / ".wh..wh.sessions")) == ["first", "second"]
+    # Neither do the shared upper dirs
+    assert env.overlay_read("foo.txt").stdout == read_all(env.lower / "foo.txt")
+
+    # A process tree shares the session named after its first process
+    session_env = dict(env.env)
+    session_env["LIBOVERLAY_SESSION"] = "pid"
+    script = f"echo $$; echo new >{env.lower}/new.txt; cat {env.lower}/new.txt"
+    ret = subprocess.run(["sh", "-c", script], env=session_env, stdout=subprocess.PIPE)
+    assert ret.returncode == 0
+    pid, contents = ret.stdout.splitlines()
+    assert contents == b"new"
+    assert read_all(env.upper / ".wh..wh.sessions" / pid.decode() / "new.txt") == b"new\n"
+    # Another one does not see its writes
+    ret = subprocess.run(["cat", env.lower / "new.txt"], env=session_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+    assert ret.returncode != 0
+
+
+def copy_on_read(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "sub").mkdir()
+        for name in ["a.txt", "b.txt", "sub/c.txt"]:
+            Path(other_lower, name).write_bytes(name.encode())
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        mapped_env["LIBOVERLAY_COPY_ON_READ"] = "1"
+
+        # Reading copies the file up, listing directories and stat'ing files does not
+        ret = subprocess.run(
+            ["cat", f"{other_lower}/a.txt", f"{other_lower}/sub/c.txt"],
+            env=mapped_env,
+            stdout=subprocess.PIPE,
+            stderr=subprocess.PIPE,
+        )
+        assert ret.returncode == 0
+        assert ret.stdout == b"a.txtsub/c.txt"
+        ret = subprocess.run(["ls", other_lower], env=mapped_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        assert ret.returncode == 0
+        ret = subprocess.run(["stat", f"{other_lower}/b.txt"], env=mapped_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        assert ret.returncode == 0
+        assert sorted(os.listdir(other_upper)) == [".wh..wh.locks", "a.txt", "sub"]
+        assert os.listdir(Path(other_upper, "sub")) == ["c.txt"]
+        assert read_all(Path(other_upper, "a.txt")) == b"a.txt"
+        assert os.stat(Path(other_upper, "a.txt")).st_mtime_ns == os.stat(Path(other_lower, "a.txt")).st_mtime_ns
+
+        # Later reads are served from the copy
+        Path(other_upper, "a.txt").write_bytes(b"Cached")
+        ret = subprocess.run(["cat", f"{other_lower}/a.txt"], env=mapped_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        assert ret.stdout == b"Cached"
+
+        # Without room for a copy, the lower file is read
+        mapped_env["LIBOVERLAY_QUOTA_FILES"] = "0"
+        ret = subprocess.run(["cat", f"{other_lower}/b.txt"], env=mapped_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        assert ret.returncode == 0
+        assert ret.stdout == b"b.txt"
+        assert not Path(other_upper, "b.txt").exists()
+        assert read_all(Path(other_lower, "a.txt")) == b"a.txt"
+
+
+def hide_rules(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        for name in ["plugins/old", "plugins/sub", "cache"]:
+            Path(other_lower, name).mkdir(parents=True)
+        for name in ["keep.txt", "stale.txt", "plugins/a.so", "plugins/b.so", "plugins/old/x.so", "plugins/sub/b.so", "cache/a.o"]:
+            Path(other_lower, name).write_bytes(name.encode())
+        hide_env = dict(env.env)
+        hide_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        hide_env["LIBOVERLAY_HIDE"] = f"plugins/b.so;plugins/old/;{other_lower}/stale.*;cache/*.o"
+
+        # Hidden entries are neither listed nor found, patterns do not match across directories
+        ret = run_in(hide_env, "ls", "-A", other_lower, f"{other_lower}/plugins")
+        assert ret.returncode == 0
+        assert ret.stdout.split() == [f"{other_lower}:".encode(), b"cache", b"keep.txt", b"plugins", f"{other_lower}/plugins:".encode(), b"a.so", b"sub"]
+        ret = run_in(hide_env, "find", other_lower)
+        assert ret.returncode == 0
+        assert sorted(ret.stdout.split()) == sorted(
+            os.fsencode(Path(other_lower, name))
+            for name in ["", "cache", "keep.txt", "plugins", "plugins/a.so", "plugins/sub", "plugins/sub/b.so"]
+        )
+        for name in ["stale.txt", "plugins/b.so", "plugins/old", "plugins/old/x.so"]:
+            ret = run_in(hide_env, "stat", f"{other_lower}/{name}")
+            assert ret.returncode != 0
+        ret = run_in(hide_env, "cat", f"{other_lower}/plugins/old/x.so")
+        assert ret.returncode != 0
+
+        # Hidden entries can be created anew, but not within hidden directories
+        ret = run_in(hide_env, "sh", "-c", f"echo New > {other_lower}/plugins/b.so && cat {other_lower}/plugins/b.so")
+        assert ret.returncode == 0
+        assert ret.stdout == b"New\n"
+        ret = run_in(hide_env, "sh", "-c", f"echo New > {other_lower}/plugins/old/y.so")
+        assert ret.returncode != 0
+        ret = run_in(hide_env, "ls", f"{other_lower}/plugins")
+        assert ret.stdout.split() == [b"a.so", b"b.so", b"sub"]
+
+        # Directories with only hidden entries are empty
+        ret = run_in(hide_env, "rmdir", f"{other_lower}/plugins/sub")
+        assert ret.returncode != 0
+        ret = run_in(hide_env, "rmdir", f"{other_lower}/cache")
+        assert ret.returncode == 0
+        ret = run_in(hide_env, "ls", other_lower)
+        assert ret.stdout.split() == [b"keep.txt", b"plugins"]
+
+        assert sorted(os.listdir(other_lower)) == ["cache", "keep.txt", "plugins", "stale.txt"]
+        assert sorted(os.listdir(Path(other_lower, "plugins"))) == ["a.so", "b.so", "old", "sub"]
+
+
+def synthetic_files(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper, tempfile.TemporaryDirectory() as elsewhere:
+        Path(other_lower, "etc").mkdir()
+        Path(other_lower, "etc/app.conf").write_bytes(b"Lower")
+        Path(elsewhere, "app.conf").write_bytes(b"Injected")
+        synthetic_env = dict(env.env)
+        synthetic_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        synthetic_env["LIBOVERLAY_FILES"] = (
+            f"{other_lower}/etc/generated.conf=key=value\\;1\\n;{other_lower}/etc/app.conf=@{elsewhere}/app.conf"
+        )
+
+        # Synthetic files are listed, found and read like lower files, replacing those of the same name
+        ret = run_in(synthetic_env, "cat", f"{other_lower}/etc/generated.conf", f"{other_lower}/etc/app.conf")
+        assert ret.returncode == 0
+        assert ret.stdout == b"key=value;1\nInjected"
+        ret = run_in(synthetic_env, "stat", "-c", "%s", f"{other_lower}/etc/generated.conf")
+        assert ret.stdout == b"12\n"
+        ret = run_in(synthetic_env, "ls", f"{other_lower}/etc")
+        assert ret.stdout.split() == [b"app.conf", b"generated.conf"]
+        ret = run_in(synthetic_env, "find", other_lower)
+        assert sorted(ret.stdout.split()) == sorted(
+            os.fsencode(Path(other_lower, name)) for name in ["", "etc", "etc/app.conf", "etc/generated.conf"]
+        )
+        ret = run_in(synthetic_env, "rmdir", f"{other_lower}/etc")
+        assert ret.returncode != 0
+
+        # Changes copy them up
+        ret = run_in(synthetic_env, "sh", "-c", f"echo Changed >> {other_lower}/etc/generated.conf")
+        assert ret.returncode == 0
+        ret = run_in(synthetic_env, "cat", f"{other_lower}/etc/generated.conf")
+        assert ret.stdout == b"key=value;1\nChanged\n"
+        ret = run_in(synthetic_env, "ls", f"{other_lower}/etc")
+        assert ret.stdout.split() == [b"app.conf", b"generated.conf"]
+        assert read_all(Path(other_upper, "etc/generated.conf")) == b"key=value;1\nChanged\n"
+        assert os.listdir(Path(other_lower, "etc")) == ["app.conf"]
+        assert read_all(Path(other_lower, "etc/app.conf")) == b"Lower"
+
+        # Synthetic files must lie within a lower dir
+        synthetic_env["LIBOVERLAY_FILES"] = f"{elsewhere}/other.conf=Other"
+        ret = run_in(synthetic_env, "true")
+        assert b"not within a lower dir" in ret.stderr
+
+
+def sorted_dirs(env: TestEnv) -> None:
+    script = (
+        "import os, sys\n"
+        "for dir in sys.argv[1:]:\n"
+        "    print(' '.join(os.listdir(dir)))\n"
+    )
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        for name in ["merged/c", "merged/a", "lower/z", "lower/x", "lower/y"]:
+            Path(other_lower, name).parent.mkdir(exist_ok=True)
+            Path(other_lower, name).write_bytes(b"")
+        for name in ["merged/d", "merged/b", "merged/.wh.a", "upper/2", "upper/3", "upper/1"]:
+            Path(other_upper, name).parent.mkdir(exist_ok=True)
+            Path(other_upper, name).write_bytes(b"")
+        sorted_env = dict(env.env)
+        sorted_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        sorted_env["LIBOVERLAY_SORT_DIRS"] = "1"
+        dirs = [f"{other_lower}/{name}" for name in ["", "merged", "lower", "upper"]]
+        ret = subprocess.run(
+            [sys.executable, "-c", script, *dirs], env=sorted_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE
+        )
+        assert ret.returncode == 0
+        assert ret.stdout.splitlines() == [b"lower merged upper", b"b c d", b"x y z", b"1 2 3"]
+        ret = subprocess.run(["ls", "-f", f"{other_lower}/merged"], env=sorted_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        assert ret.stdout.split() == [b".", b"..", b"b", b"c", b"d"]
+
+
+def rename_across_layers(env: TestEnv) -> None:
+    script = (
+        "import errno, os, sys\n"
+        "os.chdir(sys.argv[1])\n"
+        "def rename(old, new):\n"
+        "    try:\n"
+        "        os.rename(old, new)\n"
+        "    except OSError as e:\n"
+        "        return errno.errorcode[e.errno]\n"
+        "    return 'ok'\n"
+        "print(rename('a.txt', 'b.txt'))\n"
+        "print(rename('b.txt', 'b.txt'))\n"
+        "print(rename('b.txt', 'full'))\n"
+        "print(rename('empty', 'b.txt'))\n"
+        "os.mkdir('new')\n"
+        "open('new/n', 'w').close()\n"
+        "print(rename('new', 'full'))\n"
+        "os.unlink('emptied/x')\n"
+        "print(rename('new', 'emptied'))\n"
+        "open('merged/m2', 'w').close()\n"
+        "print(rename('merged', 'moved'))\n"
+        "print(' '.join(sorted(os.listdir('.'))))\n"
+        "print(' '.join(os.listdir('emptied')))\n"
+        "print(' '.join(sorted(os.listdir('moved'))))\n"
+        "print(open('b.txt').read())\n"
+    )
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        for name in ["full", "empty", "emptied", "merged"]:
+            Path(other_lower, name).mkdir()
+        for name in ["a.txt", "b.txt", "full/f", "emptied/x", "merged/m1"]:
+            Path(other_lower, name).write_bytes(name.encode())
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        ret = subprocess.run(
+            [sys.executable, "-c", script, other_lower], env=mapped_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE
+        )
+        assert ret.returncode == 0
+        assert ret.stdout.splitlines() == [
+            b"ok",
+            b"ok",
+            b"EISDIR",
+            b"ENOTDIR",
+            b"ENOTEMPTY",
+            b"ok",
+            b"ok",
+            b"b.txt emptied empty full moved",
+            b"n",
+            b"m1 m2",
+            b"a.txt",
+        ]
+        assert sorted(os.listdir(other_lower)) == ["a.txt", "b.txt", "emptied", "empty", "full", "merged"]
+        assert read_all(Path(other_lower, "b.txt")) == b"b.txt"
+
+
+def dir_copy_up(env: TestEnv) -> None:
+    script = (
+        "import errno, os, sys\n"
+        "os.chdir(sys.argv[1])\n"
+        "def rename(old, new):\n"
+        "    try:\n"
+        "        os.rename(old, new)\n"
+        "    except OSError as e:\n"
+        "        return errno.errorcode[e.errno]\n"
+        "    return 'ok'\n"
+        "if 'LIBOVERLAY_DIR_COPY_UP_DEPTH' in os.environ:\n"
+        "    os.unlink('tree/gone')\n"
+        "    os.chmod('tree/sub/deep/file', 0o600)\n"
+        "    print(rename('shallow', 'shallow2'))\n"
+        "print(rename('tree', 'moved'))\n"
+        "for root, dirs, files in sorted(os.walk('.')):\n"
+        "    print(root, ' '.join(sorted(dirs + files)))\n"
+        "print(open('moved/sub/deep/file' if os.path.exists('moved') else 'tree/sub/deep/file').read())\n"
+    )
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        for name in ["tree", "tree/sub", "tree/sub/deep", "tree/empty", "shallow"]:
+            Path(other_lower, name).mkdir()
+        for name in ["tree/gone", "tree/kept", "tree/sub/deep/file", "shallow/s"]:
+            Path(other_lower, name).write_bytes(name.encode())
+        Path(other_lower, "tree/link").symlink_to("kept")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+
+        # Only the tree nested three levels deep exceeds the limit
+        mapped_env["LIBOVERLAY_DIR_COPY_UP_DEPTH"] = "2"
+        ret = subprocess.run(
+            [sys.executable, "-c", script, other_lower], env=mapped_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE
+        )
+        assert ret.returncode == 0
+        assert ret.stdout.splitlines() == [
+            b"ok",
+            b"EXDEV",
+            b". shallow2 tree",
+            b"./shallow2 s",
+            b"./tree empty kept link sub",
+            b"./tree/empty ",
+            b"./tree/sub deep",
+            b"./tree/sub/deep file",
+            b"tree/sub/deep/file",
+        ]
+
+        # Without a limit, whiteouts and metadata-only copies within the tree move along with it
+        del mapped_env["LIBOVERLAY_DIR_COPY_UP_DEPTH"]
+        ret = subprocess.run(
+            [sys.executable, "-c", script, other_lower], env=mapped_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE
+        )
+        assert ret.returncode == 0
+        assert ret.stdout.splitlines() == [
+            b"ok",
+            b". moved shallow2",
+            b"./moved empty kept link sub",
+            b"./moved/empty ",
+            b"./moved/sub deep",
+            b"./moved/sub/deep file",
+            b"./shallow2 s",
+            b"tree/sub/deep/file",
+        ]
+        assert os.readlink(Path(other_upper, "moved", "link")) == "kept"
+        assert Path(other_upper, "moved", "sub", "deep", "file").stat().st_mode & 0o777 == 0o600
+        assert not Path(other_upper, "moved", "sub", "deep", ".wh..wh.meta.file").exists()
+        assert sorted(os.listdir(other_lower)) == ["shallow", "tree"]
+        assert sorted(os.listdir(Path(other_lower, "tree"))) == ["empty", "gone", "kept", "link", "sub"]
+
+
+def cross_device(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory(dir="/dev/shm") as other_upper, tempfile.TemporaryDirectory() as outside:
+        # The test needs an upper dir on another file system, which tmpfs usually is
+        if os.stat(other_lower).st_dev == os.stat(other_upper).st_dev:
+            return
+        Path(other_lower, "tree").mkdir()
+        for name in ["a.txt", "b.txt", "tree/t"]:
+            Path(other_lower, name).write_bytes(name.encode())
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        script = (
+            "import errno, os, sys\n"
+            "try:\n"
+            "    os.rename(os.path.join(sys.argv[1], 'tree'), os.path.join(sys.argv[2], 'tree'))\n"
+            "except OSError as e:\n"
+            "    print(errno.errorcode[e.errno])\n"
+            "with open(os.path.join(sys.argv[1], 'a.txt'), 'a') as f:\n"
+            "    f.write(' appended')\n"
+        )
+        ret = subprocess.run(
+            [sys.executable, "-c", script, other_lower, outside],
+            env=mapped_env,
+            stdout=subprocess.PIPE,
+            stderr=subprocess.PIPE,
+        )
+        assert ret.returncode == 0
+        assert ret.stdout == b"EXDEV\n"
+        # Nothing has been copied up for the failed rename
+        assert sorted(os.listdir(other_upper)) == [".wh..wh.locks", "a.txt"]
+        assert read_all(Path(other_upper, "a.txt")) == b"a.txt appended"
+
+        # Tools fall back to copying
+        ret = subprocess.run(
+            ["mv", f"{other_lower}/b.txt", f"{other_lower}/tree", outside],
+            env=mapped_env,
+            stdout=subprocess.PIPE,
+            stderr=subprocess.PIPE,
+        )
+        assert ret.returncode == 0
+        assert sorted(os.listdir(outside)) == ["b.txt", "tree"]
+        assert read_all(Path(outside, "tree", "t")) == b"tree/t"
+        ret = subprocess.run(["ls", other_lower], env=mapped_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        assert ret.stdout.split() == [b"a.txt"]
+        assert sorted(os.listdir(other_lower)) == ["a.txt", "b.txt", "tree"]
+
+
+def transforms(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "etc").mkdir()
+        Path(other_lower, "etc/app.conf").write_bytes(b"home=${HOME}\nuser=${OVERLAY_USER}\nkept=${UNSET_VAR} ${ x}\n")
+        Path(other_lower, "etc/other.txt").write_bytes(b"${HOME}\n")
+        Path(other_lower, "run.bat").write_bytes(b"echo 1\necho 2\r\n")
+        transform_env = dict(env.env)
+        transform_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        transform_env["LIBOVERLAY_TRANSFORMS"] = "etc/*.conf=env;*.bat=crlf"
+        transform_env["HOME"] = "/home/me"
+        transform_env["OVERLAY_USER"] = "me"
+        transform_env.pop("UNSET_VAR", None)
+
+        # Matching files are transformed when they are first read
+        ret = run_in(transform_env, "cat", f"{other_lower}/etc/app.conf", f"{other_lower}/etc/other.txt", f"{other_lower}/run.bat")
+        assert ret.returncode == 0
+        assert ret.stdout == b"home=/home/me\nuser=me\nkept=${UNSET_VAR} ${ x}\n${HOME}\necho 1\r\necho 2\r\n"
+        assert sorted(os.listdir(other_upper)) == [".wh..wh.locks", "etc", "run.bat"]
+        assert os.listdir(Path(other_upper, "etc")) == ["app.conf"]
+
+        # Copies keep their contents, and the lower files are unchanged
+        transform_env["OVERLAY_USER"] = "other"
+        ret = run_in(transform_env, "cat", f"{other_lower}/etc/app.conf")
+        assert ret.stdout.splitlines()[1] == b"user=me"
+        assert read_all(Path(other_lower, "etc/app.conf")).startswith(b"home=${HOME}")
+
+        # Changing only the metadata copies the contents as well
+        Path(other_lower, "etc/new.conf").write_bytes(b"${OVERLAY_USER}")
+        ret = run_in(transform_env, "chmod", "600", f"{other_lower}/etc/new.conf")
+        assert ret.returncode == 0
+        assert read_all(Path(other_upper, "etc/new.conf")) == b"other"
+
+        transform_env["LIBOVERLAY_TRANSFORMS"] = "*.txt=upper"
+        ret = run_in(transform_env, "true")
+        assert b"invalid LIBOVERLAY_TRANSFORMS" in ret.stderr
+
+
+class QuietHandler(http.server.SimpleHTTPRequestHandler):
+    def log_message(self, format: str, *args: object) -> None:
+        pass
+
+
+def remote_lower(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as remote, tempfile.TemporaryDirectory() as cache, tempfile.TemporaryDirectory() as other_upper:
+        Path(remote, "assets", "big dir").mkdir(parents=True)
+        Path(remote, "assets", "big dir", "model.bin").write_bytes(b"Model")
+        Path(remote, "index.txt").write_bytes(b"Index")
+        server = http.server.ThreadingHTTPServer(
+            ("127.0.0.1", 0), functools.partial(QuietHandler, directory=remote)
+        )
+        thread = threading.Thread(target=server.serve_forever)
+        thread.start()
+        try:
+            remote_env = dict(env.env)
+            remote_env["LIBOVERLAY_MAPPINGS"] = f"{cache}:{other_upper}"
+            remote_env["LIBOVERLAY_REMOTES"] = f"{cache}=http://127.0.0.1:{server.server_port}/"
+
+            # Missing files are fetched into the cache, including their directories
+            ret = run_in(remote_env, "cat", f"{cache}/assets/big dir/model.bin", f"{cache}/index.txt")
+            assert ret.returncode == 0
+            assert ret.stdout == b"ModelIndex"
+            assert read_all(Path(cache, "assets", "big dir", "model.bin")) == b"Model"
+            assert sorted(os.listdir(cache)) == ["assets", "index.txt"]
+
+            # Cached files are served without fetching them again
+            Path(remote, "index.txt").write_bytes(b"Changed")
+            ret = run_in(remote_env, "cat", f"{cache}/index.txt")
+            assert ret.stdout == b"Index"
+
+            # Files missing remotely stay missing, and changes go to the upper dir as usual
+            ret = run_in(remote_env, "sh", "-c", f"test -e {cache}/missing.txt || echo Missing; echo New >> {cache}/index.txt")
+            assert ret.stdout == b"Missing\n"
+            assert sorted(os.listdir(cache)) == ["assets", "index.txt"]
+            assert read_all(Path(other_upper, "index.txt")) == b"IndexNew\n"
+        finally:
+            server.shutdown()
+            thread.join()
+            server.server_close()
+
+
+def config_file(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper, tempfile.TemporaryDirectory() as elsewhere:
+        for name in ["a.txt", "b.txt", "skip.txt"]:
+            Path(other_lower, name).write_bytes(name.encode())
+        Path(other_lower, "cache").mkdir()
+        config = Path(elsewhere, "overlay.toml")
+        config.write_text(
+            "# Overlay for the tests\n"
+            "[[mappings]]\n"
+            f"lower = '{other_lower}'\n"
+            f"upper = \"{other_upper}\"  # where changes go\n"
+            "\n"
+            "[logging]\n"
+            "debug = false\n"
+            "\n"
+            "[copy_up]\n"
+            "copy_on_read = true\n"
+            "\n"
+            "[exclusions]\n"
+            f"paths = ['{other_lower}/cache']\n"
+            "hide = [\n"
+            "    'skip.txt',\n"
+            "    'b.txt',\n"
+            "]\n"
+            "\n"
+            "[view]\n"
+            f"files = ['{other_lower}/c.txt=C\\n']\n"
+        )
+        config_env = {
+            name: value for name, value in env.env.items() if name not in ["LIBOVERLAY_LOWER_DIR", "LIBOVERLAY_UPPER_DIR"]
+        }
+        config_env["LIBOVERLAY_CONFIG"] = str(config)
+        # Variables override the settings of the file
+        config_env["LIBOVERLAY_HIDE"] = "skip.txt"
+
+        ret = run_in(config_env, "ls", other_lower)
+        assert ret.returncode == 0
+        assert ret.stdout.split() == [b"a.txt", b"b.txt", b"c.txt", b"cache"]
+        ret = run_in(config_env, "cat", f"{other_lower}/a.txt", f"{other_lower}/c.txt")
+        assert ret.stdout == b"a.txtC\n"
+        assert read_all(Path(other_upper, "a.txt")) == b"a.txt"
+        ret = run_in(config_env, "touch", f"{other_lower}/cache/new")
+        assert ret.returncode == 0
+        assert Path(other_lower, "cache", "new").exists()
+        ret = run_in(config_env, "bash", "-c", f"ls {other_lower}")
+        assert ret.stdout.split() == [b"a.txt", b"b.txt", b"c.txt", b"cache"]
+
+        config.write_text("[copy_up]\ncopy_on_read = yes\n")
+        ret = run_in(config_env, "true")
+        assert f"invalid LIBOVERLAY_CONFIG: {config}: line 2: expected a value".encode() in ret.stderr
+        config.write_text("[copy_up]\ncopy_on_write = true\n")
+        ret = run_in(config_env, "true")
+        assert b"unknown setting `copy_up.copy_on_write`" in ret.stderr
+        yaml_config = config.with_suffix(".yaml")
+        yaml_config.write_text("copy_up:\n  copy_on_read: true\n")
+        ret = run_in(dict(config_env, LIBOVERLAY_CONFIG=str(yaml_config)), "true")
+        assert f"invalid LIBOVERLAY_CONFIG: {yaml_config}: YAML is not supported".encode() in ret.stderr
+
+
+def mapping_options(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as ro_lower, tempfile.TemporaryDirectory() as ro_upper, tempfile.TemporaryDirectory() as docs_upper, tempfile.TemporaryDirectory() as fixed_lower, tempfile.TemporaryDirectory() as fixed_upper, tempfile.TemporaryDirectory() as elsewhere:
+        Path(ro_lower, "docs", "Guide").mkdir(parents=True)
+        for name in ["app.conf", "docs/Guide/Intro.txt", "docs/README"]:
+            Path(ro_lower, name).write_bytes(name.encode())
+        Path(fixed_lower, "cache").mkdir()
+        for name in ["base.txt", "other.txt", "cache/entry"]:
+            Path(fixed_lower, name).write_bytes(name.encode())
+        config = Path(elsewhere, "overlay.toml")
+        # The nested mapping takes precedence within the read-only one
+        config.write_text(
+            "[[mappings]]\n"
+            f"lower = '{ro_lower}'\n"
+            f"upper = '{ro_upper}'\n"
+            "read_only = true\n"
+            "\n"
+            "[[mappings]]\n"
+            f"lower = '{ro_lower}/docs'\n"
+            f"upper = '{docs_upper}'\n"
+            "case_insensitive = true\n"
+            "\n"
+            "[[mappings]]\n"
+            f"lower = '{fixed_lower}'\n"
+            f"upper = '{fixed_upper}'\n"
+            "no_copy_up = true\n"
+            "exclude = ['cache']\n"
+        )
+        options_env = {
+            name: value for name, value in env.env.items() if name not in ["LIBOVERLAY_LOWER_DIR", "LIBOVERLAY_UPPER_DIR"]
+        }
+        options_env["LIBOVERLAY_CONFIG"] = str(config)
+        script = (
+            "import ctypes, errno, os, sys\n"
+            "libc = ctypes.CDLL(None, use_errno=True)\n"
+            "def access(path, mode):\n"
+            "    if libc.access(path.encode(), mode) != 0:\n"
+            "        raise OSError(ctypes.get_errno(), path)\n"
+            "def attempt(f, *args):\n"
+            "    try:\n"
+            "        f(*args)\n"
+            "        print('ok')\n"
+            "    except OSError as e:\n"
+            "        print(errno.errorcode[e.errno])\n"
+            "def write(path, flags=os.O_WRONLY | os.O_APPEND):\n"
+            "    os.close(os.open(path, flags, 0o644))\n"
+            "ro, fixed = sys.argv[1], sys.argv[2]\n"
+            "attempt(write, os.path.join(ro, 'app.conf'))\n"
+            "attempt(write, os.path.join(ro, 'new.txt'), os.O_WRONLY | os.O_CREAT)\n"
+            "attempt(os.chmod, os.path.join(ro, 'app.conf'), 0o600)\n"
+            "attempt(os.mkdir, os.path.join(ro, 'dir'))\n"
+            "attempt(os.unlink, os.path.join(ro, 'app.conf'))\n"
+            "attempt(os.rename, os.path.join(ro, 'app.conf'), os.path.join(ro, 'moved.conf'))\n"
+            "attempt(write, os.path.join(ro, 'docs', 'readme'))\n"
+            "attempt(write, os.path.join(ro, 'docs', 'GUIDE', 'notes.txt'), os.O_WRONLY | os.O_CREAT)\n"
+            "attempt(write, os.path.join(fixed, 'base.txt'))\n"
+            "attempt(os.unlink, os.path.join(fixed, 'base.txt'))\n"
+            "attempt(write, os.path.join(fixed, 'new.txt'), os.O_WRONLY | os.O_CREAT)\n"
+            "attempt(write, os.path.join(fixed, 'new.txt'))\n"
+            "attempt(write, os.path.join(fixed, 'cache', 'entry'))\n"
+            "attempt(access, os.path.join(ro, 'app.conf'), os.W_OK)\n"
+            "attempt(access, os.path.join(ro, 'app.conf'), os.R_OK)\n"
+            "attempt(access, os.path.join(ro, 'docs', 'README'), os.W_OK)\n"
+            "attempt(access, os.path.join(fixed, 'other.txt'), os.W_OK)\n"
+            "attempt(access, os.path.join(fixed, 'new.txt'), os.W_OK)\n"
+            "attempt(access, os.path.join(fixed, 'cache', 'entry'), os.W_OK)\n"
+        )
+        ret = subprocess.run(
+            [sys.executable, "-c", script, ro_lower, fixed_lower],
+            env=options_env,
+            stdout=subprocess.PIPE,
+            stderr=subprocess.PIPE,
+        )
+        assert ret.returncode == 0
+        assert ret.stdout.split() == [
+            b"EROFS", b"EROFS", b"EROFS", b"EROFS", b"EROFS", b"EROFS",
+            b"ok", b"ok",
+            b"EROFS", b"ok", b"ok", b"ok", b"ok",
+            b"EROFS", b"ok", b"ok", b"EROFS", b"ok", b"ok",
+        ]
+        # Nothing has been written to the read-only mapping, names differing in case found the
+        # existing entries
+        assert os.listdir(ro_upper) == []
+        assert sorted(os.listdir(docs_upper)) == [".wh..wh.locks", "Guide", "README"]
+        assert os.listdir(Path(docs_upper, "Guide")) == ["notes.txt"]
+        ret = subprocess.run(["cat", f"{ro_lower}/docs/guide/INTRO.txt"], env=options_env, stdout=subprocess.PIPE)
+        assert ret.stdout == b"docs/Guide/Intro.txt"
+        # Lower files are not copied up, but can be removed, and excluded entries are changed in place
+        assert sorted(os.listdir(fixed_upper)) == [".wh..wh.locks", ".wh.base.txt", "new.txt"]
+        assert read_all(Path(fixed_lower, "base.txt")) == b"base.txt"
+        assert Path(fixed_lower, "cache", "entry").exists()
+
+        options_env["LIBOVERLAY_MAPPING_OPTIONS"] = f"{elsewhere}=read_only"
+        ret = subprocess.run(["true"], env=options_env, stderr=subprocess.PIPE)
+        assert f"invalid LIBOVERLAY_MAPPING_OPTIONS: {elsewhere} is not a lower dir".encode() in ret.stderr
+
+
+HUP_PROGRAM = """
+#include <signal.h>
+#include <unistd.h>
+
+static void hup(int signum) {
+    sigset_t mask;
+    sigprocmask(SIG_BLOCK, NULL, &mask);
+    write(1, sigismember(&mask, SIGUSR1) ? "hup 1\\n" : "hup 0\\n", 6);
+}
+
+int main(int argc, char **argv) {
+    if (argc > 1) {
+        struct sigaction action = {0};
+        action.sa_handler = hup;
+        sigemptyset(&action.sa_mask);
+        sigaddset(&action.sa_mask, SIGUSR1);
+        action.sa_flags = SA_RESETHAND;
+        sigaction(SIGHUP, &action, NULL);
+    }
+    write(1, "ready\\n", 6);
+    for (;;)
+        pause();
+}
+"""
+
+
+def reload_config(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as first_upper, tempfile.TemporaryDirectory() as second_upper, tempfile.TemporaryDirectory() as elsewhere:
+        Path(other_lower, "a.txt").write_bytes(b"lower")
+        Path(first_upper, "a.txt").write_bytes(b"first")
+        Path(second_upper, "a.txt").write_bytes(b"second")
+        config = Path(elsewhere, "overlay.toml")
+        config.write_text(f"reload = true\n[[mappings]]\nlower = '{other_lower}'\nupper = '{first_upper}'\n")
+        reload_env = {
+            name: value for name, value in env.env.items() if name not in ["LIBOVERLAY_LOWER_DIR", "LIBOVERLAY_UPPER_DIR"]
+        }
+        reload_env["LIBOVERLAY_CONFIG"] = "overlay.toml"
+        # The program handles SIGHUP itself as well
+        script = (
+            "import os, signal, sys\n"
+            "signal.signal(signal.SIGHUP, lambda signum, frame: print('hup', flush=True))\n"
+            "os.chdir('/')\n"
+            "for line in sys.stdin:\n"
+            "    with open(line.strip()) as f:\n"
+            "        print(f.read(), flush=True)\n"
+        )
+        proc = subprocess.Popen(
+            [sys.executable, "-c", script],
+            cwd=elsewhere,
+            env=reload_env,
+            stdin=subprocess.PIPE,
+            stdout=subprocess.PIPE,
+            stderr=subprocess.PIPE,
+        )
+        try:
+            proc.stdin.write(f"{other_lower}/a.txt\n".encode())
+            proc.stdin.flush()
+            assert proc.stdout.readline() == b"first\n"
+
+            # The new mapping applies once the signal arrives, from the file chosen at startup
+            config.write_text(f"reload = true\n[[mappings]]\nlower = '{other_lower}'\nupper = '{second_upper}'\n")
+            proc.send_signal(signal.SIGHUP)
+            proc.stdin.write(f"{other_lower}/a.txt\n".encode())
+            proc.stdin.flush()
+            assert sorted([proc.stdout.readline(), proc.stdout.readline()]) == [b"hup\n", b"second\n"]
+
+            # An invalid config is not swapped in
+            config.write_text("reload = yes\n")
+            proc.send_signal(signal.SIGHUP)
+            proc.stdin.write(f"{other_lower}/a.txt\n".encode())
+            proc.stdin.flush()
+            assert sorted([proc.stdout.readline(), proc.stdout.readline()]) == [b"hup\n", b"second\n"]
+
+            # Nor is one that fails the strict checks
+            config.write_text(
+                f"reload = true\nstrict = true\n[[mappings]]\nlower = '{other_lower}'\nupper = '{elsewhere}/missing'\n"
+            )
+            proc.send_signal(signal.SIGHUP)
+            proc.stdin.write(f"{other_lower}/a.txt\n".encode())
+            proc.stdin.flush()
+            assert sorted([proc.stdout.readline(), proc.stdout.readline()]) == [b"hup\n", b"second\n"]
+        finally:
+            proc.stdin.close()
+            proc.wait()
+        assert proc.returncode == 0
+        stderr = proc.stderr.read()
+        assert b"keeping the previous configuration" in stderr
+        assert b"invalid configuration" in stderr
+        reload_env["LIBOVERLAY_CONFIG"] = str(config)
+
+        # Without an action of its own, the program is still terminated by the signal. Its action
+        # runs with its mask and is reset after the first signal with SA_RESETHAND.
+        config.write_text(f"reload = true\n[[mappings]]\nlower = '{other_lower}'\nupper = '{second_upper}'\n")
+        Path(elsewhere, "hup.c").write_text(HUP_PROGRAM)
+        program = Path(elsewhere, "hup")
+        subprocess.run(["cc", "-o", program, Path(elsewhere, "hup.c")], check=True)
+        for args, outputs in [([], []), (["handle"], [b"hup 1\n"])]:
+            proc = subprocess.Popen([program, *args], env=reload_env, stdout=subprocess.PIPE)
+            assert proc.stdout.readline() == b"ready\n"
+            for output in outputs:
+                proc.send_signal(signal.SIGHUP)
+                assert proc.stdout.readline() == output
+            proc.send_signal(signal.SIGHUP)
+            assert proc.wait() == -signal.SIGHUP
+            proc.stdout.close()
+
+
+def control_socket(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper, tempfile.TemporaryDirectory() as elsewhere:
+        Path(other_lower, "a.txt").write_bytes(b"lower")
+        Path(other_upper, "a.txt").write_bytes(b"upper")
+        control_env = dict(env.env)
+        control_env["LIBOVERLAY_CONTROL"] = f"{elsewhere}/control.%p"
+        script = (
+            "import sys\n"
+            "for line in sys.stdin:\n"
+            "    with open(line.strip()) as f:\n"
+            "        print(f.read(), flush=True)\n"
+        )
+        proc = subprocess.Popen(
+            [sys.executable, "-c", script],
+            env=control_env,
+            stdin=subprocess.PIPE,
+            stdout=subprocess.PIPE,
+            stderr=subprocess.PIPE,
+        )
+        try:
+            def read(path: str) -> bytes:
+                proc.stdin.write(f"{path}\n".encode())
+                proc.stdin.flush()
+                return proc.stdout.readline()
+
+            # The socket is named after the process
+            path = Path(elsewhere, f"control.{proc.pid}")
+            assert read(f"{other_lower}/a.txt") == b"lower\n"
+            assert path.exists()
+            with socket.socket(socket.AF_UNIX) as control:
+                control.connect(str(path))
+                replies = control.makefile("rb")
+
+                def command(line: str) -> bytes:
+                    control.sendall(f"{line}\n".encode())
+                    return replies.readline()
+
+                assert command("status") == f"ok pid={proc.pid} log=error mappings={env.lower}:{env.upper}\n".encode()
+                assert command(f"map {other_lower}:{other_upper}") == b"ok\n"
+                assert read(f"{other_lower}/a.txt") == b"upper\n"
+                assert command("log info,redir=trace") == b"ok\n"
+                assert command("status").startswith(f"ok pid={proc.pid} log=info,redir=trace mappings=".encode())
+                assert command("log verbose") == b"error: unknown level `verbose`\n"
+                assert command("log error") == b"ok\n"
+                assert command(f"unmap {other_lower}") == b"ok\n"
+                assert read(f"{other_lower}/a.txt") == b"lower\n"
+                assert command("flush") == b"ok\n"
+                assert command("unmap") == b"error: unknown command `unmap`\n"
+        finally:
+            proc.stdin.close()
+            proc.wait()
+        assert proc.returncode == 0
+
+
+def log_categories(env: TestEnv) -> None:
+    log_env = dict(env.env)
+    log_env["LIBOVERLAY_LOG"] = "error,redir=debug"
+    touch = subprocess.run(["touch", f"{env.lower}/foo.txt"], env=log_env, capture_output=True)
+    assert touch.returncode == 0
+    assert b"liboverlay: redirecting" in touch.stderr
+    # Calls are only traced with the hook category
+    assert all(line.startswith(b"liboverlay: ") for line in touch.stderr.splitlines())
+
+    # The old flag logs everything, unless a filter is given
+    log_env["LIBOVERLAY_DEBUG"] = "1"
+    del log_env["LIBOVERLAY_LOG"]
+    cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=log_env, capture_output=True)
+    assert b"open" in cat.stderr
+    log_env["LIBOVERLAY_LOG"] = "off"
+    cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=log_env, capture_output=True)
+    assert cat.stderr == b""
+
+    log_env["LIBOVERLAY_LOG"] = "info,files=debug"
+    cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=log_env, capture_output=True)
+    assert b"invalid LIBOVERLAY_LOG: unknown category `files`" in cat.stderr
+
+
+def program_filter(env: TestEnv) -> None:
+    (env.upper / "foo.txt").write_bytes(b"upper")
+    program_env = dict(env.env)
+
+    # Only the selected programs are overlaid, by name or by path
+    bin_dir = Path("/bin/cat").resolve().parent
+    for programs in ["cat", "c?t;tail", f"{bin_dir}/c*"]:
+        program_env["LIBOVERLAY_PROGRAMS"] = programs
+        cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=program_env, stdout=subprocess.PIPE)
+        assert cat.stdout == b"upper"
+        head = subprocess.run(["head", f"{env.lower}/foo.txt"], env=program_env, stdout=subprocess.PIPE)
+        assert head.stdout == (env.lower / "foo.txt").read_bytes()
+
+    # Processes that are not overlaid still pass the configuration on
+    program_env["LIBOVERLAY_PROGRAMS"] = "cat"
+    sh = subprocess.run(["sh", "-c", f"cat {env.lower}/foo.txt; echo; cat < {env.lower}/foo.txt"], env=program_env, stdout=subprocess.PIPE)
+    assert sh.returncode == 0
+    assert sh.stdout == b"upper\n" + (env.lower / "foo.txt").read_bytes()
+
+
+def children_disabled(env: TestEnv) -> None:
+    (env.upper / "foo.txt").write_bytes(b"upper")
+    lower_contents = (env.lower / "foo.txt").read_bytes()
+    children_env = dict(env.env)
+    children_env["LIBOVERLAY_DISABLE_FOR_CHILDREN"] = "1"
+    sh = subprocess.run(["sh", "-c", f"cat < {env.lower}/foo.txt; echo; cat {env.lower}/foo.txt"], env=children_env, stdout=subprocess.PIPE)
+    assert sh.returncode == 0
+    assert sh.stdout == b"upper\n" + lower_contents
+
+    # Single children are run on the real file system through the API
+    script = (
+        "import ctypes, subprocess, sys\n"
+        "overlay_children = ctypes.CDLL(None).liboverlay_overlay_children\n"
+        "overlaid = overlay_children(0)\n"
+        "subprocess.run(['cat', sys.argv[1]])\n"
+        "print(flush=True)\n"
+        "assert overlay_children(overlaid) == 0\n"
+        "subprocess.run(['cat', sys.argv[1]])\n"
+    )
+    python = subprocess.run([sys.executable, "-c", script, f"{env.lower}/foo.txt"], env=env.env, stdout=subprocess.PIPE)
+    assert python.returncode == 0
+    assert python.stdout == lower_contents + b"\nupper"
+
+
+def strict_validation(env: TestEnv) -> None:
+    strict_env = dict(env.env)
+    strict_env["LIBOVERLAY_STRICT"] = "1"
+    cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=strict_env, capture_output=True)
+    assert cat.returncode == 0
+
+    with tempfile.TemporaryDirectory() as elsewhere:
+        for upper, reason in [
+            (f"{elsewhere}/missing", f"upper dir {elsewhere}/missing is not a directory"),
+            (f"{env.lower}/bar", f"lower dir {env.lower}/bar lies within upper dir {env.lower}/bar"),
+        ]:
+            strict_env["LIBOVERLAY_MAPPINGS"] = f"{env.lower}/bar:{upper}"
+            cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=strict_env, capture_output=True)
+            assert cat.returncode == -signal.SIGABRT
+            assert f"invalid configuration: {reason}".encode() in cat.stderr
+            assert b"aborting, since LIBOVERLAY_STRICT is set" in cat.stderr
+
+    strict_env["LIBOVERLAY_MAPPINGS"] = f"{env.lower}/bar:{env.upper}"
+    cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=strict_env, capture_output=True)
+    assert cat.returncode == -signal.SIGABRT
+    assert b"is shared by" in cat.stderr
+
+    # Invalid settings abort as well, without it the process runs without the overlay
+    strict_env["LIBOVERLAY_LOG"] = "verbose"
+    cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=strict_env, capture_output=True)
+    assert cat.returncode == -signal.SIGABRT
+    del strict_env["LIBOVERLAY_STRICT"]
+    cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=strict_env, capture_output=True)
+    assert cat.returncode == 0
+    assert cat.stdout == (env.lower / "foo.txt").read_bytes()
+
+
+def discovered_config(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as project:
+        Path(project, ".liboverlay.toml").write_text(
+            "[[mappings]]\n"
+            'lower = "app"\n'
+            'upper = "build/../upper"\n'
+        )
+        for dir in ["app", "upper", "src/nested"]:
+            Path(project, dir).mkdir(parents=True)
+        Path(project, "app", "a.txt").write_bytes(b"lower")
+        Path(project, "upper", "a.txt").write_bytes(b"upper")
+        discover_env = {
+            name: value for name, value in env.env.items() if not name.startswith("LIBOVERLAY_")
+        }
+
+        # The nearest file applies, and is passed on to children that run elsewhere
+        cat = subprocess.run(["cat", "../../app/a.txt"], env=discover_env, cwd=f"{project}/src/nested", stdout=subprocess.PIPE)
+        assert cat.stdout == b"upper"
+        sh = subprocess.run(["sh", "-c", f"cd / && cat {project}/app/a.txt"], env=discover_env, cwd=project, stdout=subprocess.PIPE)
+        assert sh.stdout == b"upper"
+
+        discover_env["LIBOVERLAY_CONFIG"] = ""
+        cat = subprocess.run(["cat", "app/a.txt"], env=discover_env, cwd=project, stdout=subprocess.PIPE, stderr=subprocess.DEVNULL)
+        assert cat.stdout == b"lower"
+
+
+def expanded_dirs(env: TestEnv) -> None:
+    (env.upper / "session").mkdir()
+    (env.upper / "session" / "foo.txt").write_bytes(b"upper")
+    expand_env = dict(env.env)
+    expand_env["LIBOVERLAY_UPPER_DIR"] = "${OVERLAY_ROOT}/${OVERLAY_SESSION}"
+    expand_env["OVERLAY_ROOT"] = str(env.upper)
+    expand_env["OVERLAY_SESSION"] = "session"
+    cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=expand_env, stdout=subprocess.PIPE)
+    assert cat.stdout == b"upper"
+
+    # The process and the user are known without variables
+    script = (
+        "import os, sys\n"
+        "root, user, lower = sys.argv[1:]\n"
+        "upper = f'{root}/{user}-{os.getpid()}'\n"
+        "os.mkdir(upper)\n"
+        "with open(f'{lower}/created.txt', 'w') as f:\n"
+        "    f.write('created')\n"
+        "print(os.path.exists(f'{upper}/created.txt'))\n"
+    )
+    user = subprocess.run(["id", "-un"], stdout=subprocess.PIPE).stdout.strip().decode()
+    expand_env["LIBOVERLAY_UPPER_DIR"] = f"{env.upper}/${{USER}}-${{PID}}"
+    expand_env.pop("USER", None)
+    python = subprocess.run([sys.executable, "-c", script, str(env.upper), user, str(env.lower)], env=expand_env, stdout=subprocess.PIPE)
+    assert python.returncode == 0
+    assert python.stdout == b"True\n"
+    assert not (env.lower / "created.txt").exists()
+
+    expand_env["LIBOVERLAY_UPPER_DIR"] = "${OVERLAY_UNSET}/upper"
+    cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=expand_env, capture_output=True)
+    assert b"cannot expand mapping of " + bytes(env.lower) + b": `${OVERLAY_UNSET}` is not set" in cat.stderr
+
+
+def user_config(env: TestEnv) -> None:
+    (env.upper / "foo.txt").write_bytes(b"upper")
+    with tempfile.TemporaryDirectory() as home, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_upper, "foo.txt").write_bytes(b"other")
+        Path(home, ".config", "liboverlay").mkdir(parents=True)
+        Path(home, ".config", "liboverlay", "config.toml").write_text(
+            "[[mappings]]\n"
+            f'lower = "{env.lower}"\n'
+            f'upper = "{env.upper}"\n'
+            "\n"
+            "[[profiles.other.mappings]]\n"
+            f'lower = "{env.lower}"\n'
+            f'upper = "{other_upper}"\n'
+        )
+        user_env = {
+            name: value for name, value in env.env.items() if not name.startswith("LIBOVERLAY_")
+        }
+        user_env["HOME"] = home
+        user_env.pop("XDG_CONFIG_HOME", None)
+
+        cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=user_env, stdout=subprocess.PIPE)
+        assert cat.stdout == b"upper"
+
+        # Profiles replace the settings of the same name
+        user_env["LIBOVERLAY_PROFILE"] = "other"
+        cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=user_env, stdout=subprocess.PIPE)
+        assert cat.stdout == b"other"
+
+        user_env["LIBOVERLAY_PROFILE"] = "missing"
+        cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=user_env, capture_output=True)
+        assert b"unknown profile `missing`" in cat.stderr
+        assert cat.stdout == (env.lower / "foo.txt").read_bytes()
+
+        user_env["LIBOVERLAY_CONFIG"] = ""
+        cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=user_env, capture_output=True)
+        assert b"profile `missing` not found, there is no config file" in cat.stderr
+
+
+def disabled_hooks(env: TestEnv) -> None:
+    (env.upper / "foo.txt").write_bytes(b"upper")
+    (env.upper / "new.txt").write_bytes(b"new")
+    hooks_env = dict(env.env)
+    ls = subprocess.run(["ls", env.lower], env=hooks_env, stdout=subprocess.PIPE)
+    assert b"new.txt" in ls.stdout.splitlines()
+    # Children with a scrubbed environment are overlaid through the exec hooks
+    scrubbed = subprocess.run(["env", "-i", "cat", f"{env.lower}/foo.txt"], env=hooks_env, stdout=subprocess.PIPE)
+    assert scrubbed.stdout == b"upper"
+
+    hooks_env["LIBOVERLAY_DISABLE_HOOKS"] = "readdir;exec"
+    ls = subprocess.run(["ls", env.lower], env=hooks_env, stdout=subprocess.PIPE)
+    assert ls.stdout.splitlines() == [b"bar", b"foo.txt"]
+    scrubbed = subprocess.run(["env", "-i", "/bin/cat", f"{env.lower}/foo.txt"], env=hooks_env, stdout=subprocess.PIPE)
+    assert scrubbed.stdout == (env.lower / "foo.txt").read_bytes()
+    cat = subprocess.run(["cat", f"{env.lower}/foo.txt"], env=hooks_env, stdout=subprocess.PIPE)
+    assert cat.stdout == b"upper"
+
+    with tempfile.NamedTemporaryFile("w", suffix=".toml") as config:
+        config.write("[hooks]\nreaddir = false\nexec = true\n")
+        config.flush()
+        del hooks_env["LIBOVERLAY_DISABLE_HOOKS"]
+        hooks_env["LIBOVERLAY_CONFIG"] = config.name
+        ls = subprocess.run(["ls", env.lower], env=hooks_env, stdout=subprocess.PIPE)
+        assert ls.stdout.splitlines() == [b"bar", b"foo.txt"]
+
+        config.write("open = false\n")
+        config.flush()
+        ls = subprocess.run(["ls", env.lower], env=hooks_env, capture_output=True)
+        assert b"unknown setting `hooks.open`" in ls.stderr
+
+
+def copy_up_strategies(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as lowers, tempfile.TemporaryDirectory() as uppers:
+        strategies = ["eager", "lazy", "metadata", "reflink", "never"]
+        for strategy in strategies:
+            Path(lowers, strategy).mkdir()
+            Path(lowers, strategy, "a.txt").write_bytes(b"a")
+            Path(uppers, strategy).mkdir()
+        strategy_env = {
+            name: value for name, value in env.env.items() if name not in ["LIBOVERLAY_LOWER_DIR", "LIBOVERLAY_UPPER_DIR"]
+        }
+        strategy_env["LIBOVERLAY_MAPPINGS"] = ";".join(f"{lowers}/{name}:{uppers}/{name}" for name in strategies)
+        # The metadata mapping keeps the default
+        strategy_env["LIBOVERLAY_MAPPING_OPTIONS"] = ";".join(
+            f"{lowers}/{name}=copy_up={name}" for name in strategies if name != "metadata"
+        )
+
+        # Reading copies up eagerly, and clones where the file system supports them
+        for strategy in strategies:
+            ret = run_in(strategy_env, "cat", f"{lowers}/{strategy}/a.txt")
+            assert ret.returncode == 0
+            assert ret.stdout == b"a"
+        assert read_all(Path(uppers, "eager", "a.txt")) == b"a"
+        for strategy in ["lazy", "metadata", "never"]:
+            assert not Path(uppers, strategy, "a.txt").exists()
+        if Path(uppers, "reflink", "a.txt").exists():
+            assert read_all(Path(uppers, "reflink", "a.txt")) == b"a"
+
+        # Changing the metadata copies the whole file up lazily, and only the metadata otherwise
+        for strategy in ["lazy", "metadata"]:
+            ret = run_in(strategy_env, "chmod", "600", f"{lowers}/{strategy}/a.txt")
+            assert ret.returncode == 0
+            assert Path(uppers, strategy, "a.txt").stat().st_mode & 0o777 == 0o600
+        assert read_all(Path(uppers, "lazy", "a.txt")) == b"a"
+        assert not Path(uppers, "lazy", ".wh..wh.meta.a.txt").exists()
+        assert Path(uppers, "metadata", ".wh..wh.meta.a.txt").exists()
+
+        # Lower files are never changed
+        ret = run_in(strategy_env, "chmod", "600", f"{lowers}/never/a.txt")
+        assert ret.returncode != 0
+        assert b"Read-only file system" in ret.stderr
+        assert not Path(uppers, "never", "a.txt").exists()
+        assert Path(lowers, "never", "a.txt").stat().st_mode & 0o777 != 0o600
+
+        strategy_env["LIBOVERLAY_MAPPING_OPTIONS"] = f"{lowers}/lazy=copy_up=sometimes"
+        ret = run_in(strategy_env, "true")
+        assert b"invalid LIBOVERLAY_MAPPING_OPTIONS: unknown copy-up strategy `sometimes`" in ret.stderr
+
+
+def auto_upper_dir(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as runtime_dir, tempfile.TemporaryDirectory() as lower:
+        Path(lower, "a.txt").write_bytes(b"a")
+        auto_env = dict(env.env)
+        auto_env["LIBOVERLAY_LOWER_DIR"] = lower
+        auto_env["LIBOVERLAY_UPPER_DIR"] = "auto"
+        auto_env["XDG_RUNTIME_DIR"] = runtime_dir
+        script = f"echo b >> {lower}/a.txt && cat {lower}/a.txt && printenv LIBOVERLAY_UPPER_DIR"
+
+        # Children share the upper dir of the process that created it
+        ret = subprocess.run(["sh", "-c", script], env=auto_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        assert ret.returncode == 0
+        lines = ret.stdout.splitlines()
+        assert lines[0] == b"ab"
+        upper = Path(os.fsdecode(lines[1]))
+        assert upper.parent == Path(runtime_dir)
+        assert upper.name.startswith("liboverlay.")
+        assert read_all(Path(upper, "a.txt")) == b"ab\n"
+        assert read_all(Path(lower, "a.txt")) == b"a"
+
+        # Each run gets a new one, which can be removed at exit
+        auto_env["LIBOVERLAY_REMOVE_UPPER_DIR"] = "1"
+        ret = subprocess.run(["sh", "-c", script], env=auto_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        assert ret.returncode == 0
+        lines = ret.stdout.splitlines()
+        assert lines[0] == b"ab"
+        assert os.fsdecode(lines[1]) != str(upper)
+        assert os.listdir(runtime_dir) == [upper.name]
+
+
+def log_destination(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as log_dir:
+        log_env = dict(env.env)
+        log_env["LIBOVERLAY_LOG"] = "hook=trace"
+        log_env["LIBOVERLAY_LOG_FILE"] = "overlay.log"
+        script = f"cat {env.lower}/foo.txt >&2; cd /; cat {env.lower}/foo.txt"
+
+        # Children append to the same file, wherever they run
+        ret = subprocess.run(["sh", "-c", script], cwd=log_dir, env=log_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        assert ret.returncode == 0
+        assert ret.stderr == ret.stdout == (env.lower / "foo.txt").read_bytes()
+        log = read_all(Path(log_dir, "overlay.log"))
+        assert log.count(f"open({env.lower}/foo.txt".encode()) == 2
+
+        # Or they are written to an inherited fd
+        del log_env["LIBOVERLAY_LOG_FILE"]
+        log_env["LIBOVERLAY_LOG_FD"] = "9"
+        with open(Path(log_dir, "fd.log"), "wb") as log_file:
+            os.dup2(log_file.fileno(), 9)
+            try:
+                ret = subprocess.run(
+                    ["cat", f"{env.lower}/foo.txt"], env=log_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE, pass_fds=[9]
+                )
+            finally:
+                os.close(9)
+        assert ret.returncode == 0
+        assert ret.stderr == b""
+        assert f"open({env.lower}/foo.txt".encode() in read_all(Path(log_dir, "fd.log"))
+
+
+def json_log(env: TestEnv) -> None:
+    with tempfile.TemporaryDirectory() as log_dir:
+        log_env = dict(env.env)
+        log_env["LIBOVERLAY_LOG"] = "hook=trace,redir=debug,config=debug"
+        log_env["LIBOVERLAY_LOG_FORMAT"] = "json"
+        log_env["LIBOVERLAY_LOG_FILE"] = str(Path(log_dir, "overlay.log"))
+        ret = subprocess.run(
+            ["cat", f"{env.lower}/foo.txt", f"{env.lower}/missing.txt"], env=log_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE
+        )
+        assert ret.returncode != 0
+        assert b"missing.txt" in ret.stderr
+
+        # Every line is an object, hook invocations are objects of their own
+        objects = [json.loads(line) for line in read_all(Path(log_dir, "overlay.log")).splitlines()]
+        assert all(isinstance(o["ts"], float) and o["pid"] == o["tid"] for o in objects)
+        assert any(o.get("category") == "config" and o["message"].startswith("initialized") for o in objects)
+        calls = {o["args"]["path"]: o for o in objects if o.get("call") in ["open", "open64", "openat"]}
+        opened = calls[f"{env.lower}/foo.txt"]
+        assert isinstance(opened["decision"], list) and isinstance(opened["args"]["flags"], int)
+        assert opened["result"] >= 0 and opened["errno"] is None
+        missing = calls[f"{env.lower}/missing.txt"]
+        assert missing["result"] == -1 and missing["errno"] == errno.ENOENT
+
+        # Hooks returning pointers fail with a null pointer
+        Path(log_dir, "overlay.log").unlink()
+        script = f"import os\nos.listdir('{env.lower}')\ntry:\n    os.listdir('{env.lower}/missing')\nexcept OSError:\n    pass\n"
+        subprocess.run([sys.executable, "-c", script], env=log_env, check=True)
+        objects = [json.loads(line) for line in read_all(Path(log_dir, "overlay.log")).splitlines()]
+        calls = {o["args"]["path"]: o for o in objects if o.get("call") == "opendir"}
+        listed = calls[str(env.lower)]
+        assert listed["result"] > 0 and listed["errno"] is None
+        missing = calls[f"{env.lower}/missing"]
+        assert missing["result"] == 0 and missing["errno"] == errno.ENOENT
+
+        log_env["LIBOVERLAY_LOG_FORMAT"] = "xml"
+        ret = subprocess.run(["true"], env=log_env, stdout=subprocess.PIPE, stderr=subprocess.PIPE)
+        assert b"invalid LIBOVERLAY_LOG_FORMAT: unknown format `xml`" in read_all(Path(log_dir, "overlay.log"))
+
+
+def rewrite_rules(env: TestEnv) -> None:
+    rewrite_env = dict(env.env)
+    rewrite_env["LIBOVERLAY_REWRITES"] = (
+        f"s#^/nonexistent/app/v([0-9]+)/#{env.upper}/v$1/#;s|^/legacy/|{env.lower}/|"
+    )
+    (env.upper / "v3").mkdir()
+
+    # Rewritten paths are relocated as a whole
+    ret = subprocess.run(
+        ["tee", "/nonexistent/app/v3/log.txt"], input=b"Logged", env=rewrite_env, stdout=subprocess.PIPE, stderr=None
+    )
+    assert ret.returncode == 0
+    assert read_all(env.upper / "v3" / "log.txt") == b"Logged"
+
+    # Rewritten paths are still subject to the mapping
+    ret = subprocess.run(["cat", "/legacy/bar/bar.txt"], env=rewrite_env, stdout=subprocess.PIPE, stderr=None)
+    assert ret.returncode == 0
+    assert ret.stdout == read_all(env.lower / "bar" / "bar.txt")
+    ret = subprocess.run(
+        ["tee", "-a", "/legacy/foo.txt"], input=b" is new", env=rewrite_env, stdout=subprocess.PIPE, stderr=None
+    )
+    assert ret.returncode == 0
+    assert read_all(env.upper / "foo.txt") == read_all(env.lower / "foo.txt") + b" is new"
+
+
+def whole_root(env: TestEnv) -> None:
+    root_env = dict(env.env)
+    root_env["LIBOVERLAY_LOWER_DIR"] = "/"
+
+    # Every path is overlaid, except for the upper dir and virtual file systems
+    script = (
+        "import sys\n"
+        "open(sys.argv[1], 'a').write(' is new')\n"
+        "open(sys.argv[2], 'w').write('In the upper dir')\n"
+        "open('/dev/null', 'w').write('Discarded')\n"
+        "print(open('/proc/self/comm').read().strip())\n"
+        "import ctypes, os\n"
+        "libc = ctypes.CDLL(None)\n"
+        "libc.tmpfile.restype = ctypes.c_void_p\n"
+        "libc.fileno.argtypes = [ctypes.c_void_p]\n"
+        "fd = libc.fileno(libc.tmpfile())\n"
+        "print(os.readlink(f'/proc/self/fd/{fd}'))\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "foo.txt", env.upper / "direct.txt"],
+        env=root_env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout.startswith(b"python")
+    # Anonymous temporary files are created in the upper dir as well
+    assert ret.stdout.splitlines()[1].startswith(f"{env.upper}/tmp/".encode())
+    upper_foo = env.upper / (env.lower / "foo.txt").relative_to("/")
+    assert read_all(upper_foo) == read_all(env.lower / "foo.txt") + b" is new"
+    assert read_all(env.upper / "direct.txt") == b"In the upper dir"
+
+
+def redirect_statfs(env: TestEnv) -> None:
+    # The upper dir has to live on a different file system than the lower dir for this test
+    with tempfile.TemporaryDirectory(dir="/dev/shm") as upper_dir:
+        shm_env = dict(env.env)
+        shm_env["LIBOVERLAY_UPPER_DIR"] = upper_dir
+        ret = subprocess.run(
+            [sys.executable, "-c", "import os, sys; print(os.statvfs(sys.argv[1]).f_blocks)", env.lower / "foo.txt"],
+            env=shm_env,
+            stdout=subprocess.PIPE,
+            stderr=None,
+        )
+        assert ret.returncode == 0
+        assert int(ret.stdout) == os.statvfs(upper_dir).f_blocks
+        assert int(ret.stdout) != os.statvfs(env.lower).f_blocks
+
+
+def redirect_inotify(env: TestEnv) -> None:
+    # Watches a lower file and a lower directory, then modifies them through the merged view
+    script = (
+        "import ctypes, os, select, struct, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "IN_MODIFY, IN_CREATE = 0x2, 0x100\n"
+        "fd = libc.inotify_init()\n"
+        "file_wd = libc.inotify_add_watch(fd, sys.argv[1].encode(), IN_MODIFY)\n"
+        "dir_wd = libc.inotify_add_watch(fd, sys.argv[2].encode(), IN_CREATE)\n"
+        "open(sys.argv[1], 'a').write('It is new')\n"
+        "open(os.path.join(sys.argv[2], 'new.txt'), 'w').close()\n"
+        "events = set()\n"
+        "while select.select([fd], [], [], 1)[0]:\n"
+        "    buf = os.read(fd, 4096)\n"
+        "    pos = 0\n"
+        "    while pos < len(buf):\n"
+        "        wd, mask, _, length = struct.unpack_from('iIII', buf, pos)\n"
+        "        events.add((wd == file_wd, wd == dir_wd, mask))\n"
+        "        pos += 16 + length\n"
+        "print((True, False, IN_MODIFY) in events, (False, True, IN_CREATE) in events)\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "foo.txt", env.lower / "bar"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout == b"True True\n"
+
+
+def redirect_pathconf(env: TestEnv) -> None:
+    ret = subprocess.run(["mkdir", env.lower / "new_dir"], env=env.env, stderr=None)
+    assert ret.returncode == 0
+
+    # Limits of a directory that only exists in the upper dir
+    script = (
+        "import os, sys\n"
+        "print(os.pathconf(sys.argv[1], 'PC_NAME_MAX'))\n"
+        "print(os.fpathconf(os.open(sys.argv[1], os.O_RDONLY), 'PC_NAME_MAX'))\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "new_dir"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    name_max = os.pathconf(env.upper, "PC_NAME_MAX")
+    assert ret.stdout.splitlines() == [str(name_max).encode()] * 2
+
+
+def redirect_access(env: TestEnv) -> None:
+    def overlay_access(relative: str) -> bool:
+        ret = subprocess.run(
+            [sys.executable, "-c", "import os, sys; sys.exit(not os.access(sys.argv[1], os.W_OK))",
+             env.lower / relative],
+            env=env.env,
+            stdout=subprocess.PIPE,
+            stderr=None,
+        )
+        return ret.returncode == 0
+
+    assert overlay_access("foo.txt")
+    assert not overlay_access("new_file.txt")
+
+    ret = env.overlay_write("new_file.txt", b"It is new")
+    assert ret.returncode == 0
+    assert overlay_access("new_file.txt")
+
+    ret = subprocess.run(
+        ["unlink", env.lower / "foo.txt"], env=env.env, stdout=subprocess.PIPE, stderr=None,
+    )
+    assert ret.returncode == 0
+    assert not overlay_access("foo.txt")
+
+
+def redirect_chmod(env: TestEnv) -> None:
+    lower_mode = (env.lower / "foo.txt").stat().st_mode
+
+    ret = subprocess.run(
+        ["chmod", "600", env.lower / "foo.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert (env.upper / "foo.txt").stat().st_mode & 0o777 == 0o600
+    assert (env.lower / "foo.txt").stat().st_mode == lower_mode
+
+    ret = env.overlay_read("foo.txt")
+    assert ret.returncode == 0
+    assert ret.stdout == read_all(env.lower / "foo.txt")
+
+
+def read_write_copy_up(env: TestEnv) -> None:
+    lower = read_all(env.lower / "foo.txt")
+
+    # Opening for reading and writing copies up right away, the descriptor keeps its access mode
+    script = (
+        "import fcntl, os, sys\n"
+        "fd = os.open(sys.argv[1], os.O_RDWR)\n"
+        "assert os.path.exists(sys.argv[2])\n"
+        "assert fcntl.fcntl(fd, fcntl.F_GETFL) & os.O_ACCMODE == os.O_RDWR\n"
+        "assert os.read(fd, 2) == open(sys.argv[1], 'rb').read(2)\n"
+        "os.write(fd, b'XY')\n"
+        "os.lseek(fd, 0, os.SEEK_SET)\n"
+        "sys.stdout.buffer.write(os.read(fd, 4))\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "foo.txt", env.upper / "foo.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout == lower[:2] + b"XY"
+    assert read_all(env.upper / "foo.txt") == lower[:2] + b"XY" + lower[4:]
+
+    # stdio writes through calls of its own, which reach the upper copy as well
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        Path(other_lower, "stream.txt").write_bytes(b"Lower")
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        script = (
+            "import ctypes, os, sys\n"
+            "libc = ctypes.CDLL(None, use_errno=True)\n"
+            "libc.fdopen.restype = ctypes.c_void_p\n"
+            "libc.fwrite.argtypes = [ctypes.c_char_p, ctypes.c_size_t, ctypes.c_size_t, ctypes.c_void_p]\n"
+            "libc.fclose.argtypes = [ctypes.c_void_p]\n"
+            "fd = os.open(sys.argv[1], os.O_RDWR)\n"
+            "stream = libc.fdopen(fd, b'r+')\n"
+            "assert stream, os.strerror(ctypes.get_errno())\n"
+            "assert libc.fwrite(b'U', 1, 1, stream) == 1\n"
+            "assert libc.fclose(stream) == 0\n"
+        )
+        ret = subprocess.run([sys.executable, "-c", script, Path(other_lower, "stream.txt")], env=mapped_env)
+        assert ret.returncode == 0
+        assert read_all(Path(other_upper, "stream.txt")) == b"Uower"
+        assert read_all(Path(other_lower, "stream.txt")) == b"Lower"
+
+    # Duplicates are copied up right away
+    script = "import os, sys; os.write(os.dup(os.open(sys.argv[1], os.O_RDWR | os.O_APPEND)), b' is new')"
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar" / "bar.txt"], env=env.env, stdout=subprocess.PIPE, stderr=None
+    )
+    assert ret.returncode == 0
+    assert read_all(env.upper / "bar" / "bar.txt") == read_all(env.lower / "bar" / "bar.txt") + b" is new"
+
+    # Changing the metadata through the descriptor copies up as well
+    with tempfile.TemporaryDirectory() as other_lower, tempfile.TemporaryDirectory() as other_upper:
+        names = ["fchmod", "fchown", "fchownat", "fsetxattr", "fremovexattr"]
+        for name in names:
+            Path(other_lower, name).write_bytes(b"Lower")
+            Path(other_lower, name).chmod(0o644)
+        os.setxattr(Path(other_lower, "fremovexattr"), "user.liboverlay", b"lower")
+        lower_ctimes = [Path(other_lower, name).stat().st_ctime_ns for name in names]
+        mapped_env = dict(env.env)
+        mapped_env["LIBOVERLAY_MAPPINGS"] = f"{other_lower}:{other_upper}"
+        script = (
+            "import ctypes, os, sys\n"
+            "AT_EMPTY_PATH = 0x1000\n"
+            "libc = ctypes.CDLL(None)\n"
+            "changes = {\n"
+            "    'fchmod': lambda fd: os.fchmod(fd, 0o600),\n"
+            "    'fchown': lambda fd: os.fchown(fd, os.getuid(), os.getgid()),\n"
+            "    'fchownat': lambda fd: libc.fchownat(fd, b'', os.getuid(), os.getgid(), AT_EMPTY_PATH),\n"
+            "    'fsetxattr': lambda fd: os.setxattr(fd, 'user.liboverlay', b'fd'),\n"
+            "    'fremovexattr': lambda fd: os.removexattr(fd, 'user.liboverlay'),\n"
+            "}\n"
+            "for name, change in changes.items():\n"
+            "    fd = os.open(os.path.join(sys.argv[1], name), os.O_RDWR)\n"
+            "    assert change(fd) in [None, 0]\n"
+            "    os.write(fd, b'U')\n"
+            "    os.close(fd)\n"
+        )
+        ret = subprocess.run([sys.executable, "-c", script, other_lower], env=mapped_env, stdout=subprocess.PIPE)
+        assert ret.returncode == 0
+        assert [Path(other_lower, name).stat().st_ctime_ns for name in names] == lower_ctimes
+        for name in names:
+            assert read_all(Path(other_upper, name)) == b"Uower"
+        assert Path(other_upper, "fchmod").stat().st_mode & 0o777 == 0o600
+        assert os.getxattr(Path(other_upper, "fsetxattr"), "user.liboverlay") == b"fd"
+        assert "user.liboverlay" not in os.listxattr(Path(other_upper, "fremovexattr"))
+
+
+def metadata_copy_up(env: TestEnv) -> None:
+    lower_size = (env.lower / "foo.txt").stat().st_size
+
+    # Changing the mode only copies up the metadata
+    ret = subprocess.run(["chmod", "640", env.lower / "foo.txt"], env=env.env, stdout=subprocess.PIPE, stderr=None)
+    assert ret.returncode == 0
+    upper_stat = (env.upper / "foo.txt").stat()
+    assert upper_stat.st_mode & 0o777 == 0o640
+    assert upper_stat.st_size == lower_size
+    assert upper_stat.st_blocks == 0
+
+    script = "import os, sys; st = os.stat(sys.argv[1]); print(oct(st.st_mode & 0o777), st.st_size)"
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "foo.txt"], env=env.env, stdout=subprocess.PIPE, stderr=None
+    )
+    assert ret.returncode == 0
+    assert ret.stdout == f"0o640 {lower_size}\n".encode()
+    ret = env.overlay_read("foo.txt")
+    assert ret.returncode == 0
+    assert ret.stdout == read_all(env.lower / "foo.txt")
+
+    # Writing copies up the contents as well, keeping the changed metadata
+    ret = subprocess.run(
+        ["tee", "-a", env.lower / "foo.txt"], input=b" is new", env=env.env, stdout=subprocess.PIPE, stderr=None
+    )
+    assert ret.returncode == 0
+    assert read_all(env.upper / "foo.txt") == read_all(env.lower / "foo.txt") + b" is new"
+    assert (env.upper / "foo.txt").stat().st_mode & 0o777 == 0o640
+    assert sorted(os.listdir(env.upper)) == [".wh..wh.locks", "foo.txt"]
+
+
+def redirect_chown(env: TestEnv) -> None:
+    lower_stat = (env.lower / "foo.txt").stat()
+
+    ret = subprocess.run(
+        ["chown", f"{os.getuid()}:{os.getgid()}", env.lower / "foo.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert (env.upper / "foo.txt").stat().st_uid == os.getuid()
+    assert (env.lower / "foo.txt").stat().st_ctime_ns == lower_stat.st_ctime_ns
+
+
+def redirect_truncate(env: TestEnv) -> None:
+    ret = subprocess.run(
+        [sys.executable, "-c", "import os, sys; os.truncate(sys.argv[1], 2)", env.lower / "foo.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+
+    ret = env.overlay_read("foo.txt")
+    assert ret.returncode == 0
+    assert ret.stdout == read_all(env.lower / "foo.txt")[:2]
+
+
+def redirect_utime(env: TestEnv) -> None:
+    lower_mtime = (env.lower / "foo.txt").stat().st_mtime_ns
+
+    ret = subprocess.run(
+        [sys.executable, "-c", "import os, sys; os.utime(sys.argv[1], (0, 946684800))",
+         env.lower / "foo.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert (env.upper / "foo.txt").stat().st_mtime == 946684800
+
+    # futimens on a descriptor that refers to the lower file
+    script = (
+        "import os, sys\n"
+        "fd = os.open(sys.argv[1], os.O_RDONLY)\n"
+        "os.utime(fd, (0, 978307200))\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar" / "bar.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert (env.upper / "bar" / "bar.txt").stat().st_mtime == 978307200
+
+    # futimesat relative to a directory descriptor
+    script = (
+        "import ctypes, os, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "times = (ctypes.c_long * 4)(0, 0, 1009843200, 0)\n"
+        "dirfd = os.open(sys.argv[1], os.O_RDONLY | os.O_DIRECTORY)\n"
+        "assert libc.futimesat(dirfd, b'foo.txt', times) == 0\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert (env.upper / "foo.txt").stat().st_mtime == 1009843200
+    assert (env.lower / "foo.txt").stat().st_mtime_ns == lower_mtime
+
+
+def redirect_unlink(env: TestEnv) -> None:
+    ret = env.overlay_write("new_file.txt", b"It is new")
+    assert ret.returncode == 0
+
+    ret = env.overlay_write("foo.txt", b"It is new")
+    assert ret.returncode == 0
+
+    ret = subprocess.run(
+        ["unlink", env.lower / "foo.txt"],
+        input=b"n\n",
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+
+    ret = subprocess.run(
+        ["unlink", env.lower / "new_file.txt"],
+        input=b"n\n",
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+
+    # Deletion should fail if it hits the underlying directory
+    ret = subprocess.run(
+        ["unlink", env.lower / "foo.txt"],
+        input=b"n\n",
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode != 0
+
+
+def whiteout_unlink(env: TestEnv) -> None:
+    ret = subprocess.run(
+        ["unlink", env.lower / "bar" / "bar.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert (env.lower / "bar" / "bar.txt").exists()
+
+    ret = env.overlay_read("bar/bar.txt")
+    assert ret.returncode != 0
+
+    ret = subprocess.run(
+        ["ls", env.lower / "bar"], env=env.env, stdout=subprocess.PIPE, stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == []
+
+    # The marker itself is not part of the merged view, so it cannot be removed through it either
+    ret = env.overlay_read("bar/.wh.bar.txt")
+    assert ret.returncode != 0
+
+    ret = subprocess.run(["rm", env.lower / "bar" / ".wh.bar.txt"], env=env.env, stderr=None)
+    assert ret.returncode != 0
+    assert (env.upper / "bar" / ".wh.bar.txt").exists()
+
+    ret = env.overlay_write("bar/bar.txt", b"Recreated")
+    assert ret.returncode == 0
+
+    ret = env.overlay_read("bar/bar.txt")
+    assert ret.returncode == 0
+    assert ret.stdout == b"Recreated"
+
+
+def redirect_rename(env: TestEnv) -> None:
+    ret = subprocess.run(
+        ["mv", env.lower / "foo.txt", env.lower / "bar" / "moved.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert (env.lower / "foo.txt").exists()
+
+    ret = env.overlay_read("foo.txt")
+    assert ret.returncode != 0
+
+    ret = env.overlay_read("bar/moved.txt")
+    assert ret.returncode == 0
+    assert ret.stdout == read_all(env.lower / "foo.txt")
+
+
+def redirect_link(env: TestEnv) -> None:
+    ret = subprocess.run(
+        ["ln", env.lower / "foo.txt", env.lower / "bar" / "linked.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert not (env.lower / "bar" / "linked.txt").exists()
+
+    ret = env.overlay_write("bar/linked.txt", b"Through the link")
+    assert ret.returncode == 0
+
+    ret = env.overlay_read("foo.txt")
+    assert ret.returncode == 0
+    assert ret.stdout == b"Through the link"
+
+
+def redirect_tmpfile(env: TestEnv) -> None:
+    # Creates an anonymous file in a lower directory and links it into place afterwards
+    script = (
+        "import ctypes, os, sys\n"
+        "libc = ctypes.CDLL(None)\n"
+        "fd = os.open(os.path.join(sys.argv[1], 'bar'), os.O_TMPFILE | os.O_WRONLY, 0o644)\n"
+        "os.write(fd, b'It is new')\n"
+        "AT_FDCWD, AT_EMPTY_PATH = -100, 0x1000\n"
+        "path = os.path.join(sys.argv[1], 'bar', 'new.txt')\n"
+        "assert libc.linkat(fd, b'', AT_FDCWD, path.encode(), AT_EMPTY_PATH) == 0\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert not (env.lower / "bar" / "new.txt").exists()
+    assert read_all(env.upper / "bar" / "new.txt") == b"It is new"
+
+    ret = env.overlay_read("bar/new.txt")
+    assert ret.returncode == 0
+    assert ret.stdout == b"It is new"
+
+
+def redirect_symlink(env: TestEnv) -> None:
+    ret = subprocess.run(
+        ["ln", "-s", env.lower / "foo.txt", env.lower / "bar" / "symlink.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert not (env.lower / "bar" / "symlink.txt").is_symlink()
+    assert (env.upper / "bar" / "symlink.txt").is_symlink()
+
+    ret = env.overlay_read("bar/symlink.txt")
+    assert ret.returncode == 0
+    assert ret.stdout == read_all(env.lower / "foo.txt")
+
+    ret = subprocess.run(
+        ["ln", "-s", env.lower / "foo.txt", env.lower / "bar" / "bar.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode != 0
+
+
+def redirect_mknod(env: TestEnv) -> None:
+    # Creates one FIFO with mkfifo and one with mknod
+    script = (
+        "import os, stat, sys\n"
+        "os.mkfifo(sys.argv[1])\n"
+        "os.mknod(sys.argv[2], stat.S_IFIFO | 0o600)\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar" / "fifo", env.lower / "node"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert not (env.lower / "bar" / "fifo").exists()
+    assert not (env.lower / "node").exists()
+    assert (env.upper / "bar" / "fifo").is_fifo()
+    assert (env.upper / "node").is_fifo()
+
+    # Existing entries of the lower dir cannot be replaced
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar" / "bar.txt", env.lower / "foo.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=subprocess.PIPE,
+    )
+    assert ret.returncode != 0
+    assert b"FileExistsError" in ret.stderr
+
+
+def redirect_readlink(env: TestEnv) -> None:
+    ret = subprocess.run(
+        ["ln", "-s", "../foo.txt", env.lower / "bar" / "relative.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+
+    ret = subprocess.run(
+        ["readlink", env.lower / "bar" / "relative.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout == b"../foo.txt\n"
+
+    # The target only exists in the lower dir
+    ret = env.overlay_read("bar/relative.txt")
+    assert ret.returncode == 0
+    assert ret.stdout == read_all(env.lower / "foo.txt")
+
+
+def redirect_getxattr(env: TestEnv) -> None:
+    ret = env.overlay_write("bar/bar.txt", b"Overwrite")
+    assert ret.returncode == 0
+    os.setxattr(env.upper / "bar" / "bar.txt", "user.liboverlay", b"upper")
+
+    # Prints the attribute names of each file, and the value of the test attribute if present
+    script = (
+        "import os, sys\n"
+        "for path in sys.argv[1:]:\n"
+        "    names = [name for name in os.listxattr(path) if name.startswith('user.')]\n"
+        "    print(names, os.getxattr(path, 'user.liboverlay') if names else None)\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "bar" / "bar.txt", env.lower / "foo.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b"['user.liboverlay'] b'upper'", b"[] None"]
+
+
+def redirect_setxattr(env: TestEnv) -> None:
+    # Sets an attribute on the first file by path, and on the second one through a read-only fd
+    script = (
+        "import os, sys\n"
+        "os.setxattr(sys.argv[1], 'user.liboverlay', b'path')\n"
+        "fd = os.open(sys.argv[2], os.O_RDONLY)\n"
+        "os.setxattr(fd, 'user.liboverlay', b'fd')\n"
+    )
+    ret = subprocess.run(
+        [sys.executable, "-c", script, env.lower / "foo.txt", env.lower / "bar" / "bar.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert "user.liboverlay" not in os.listxattr(env.lower / "foo.txt")
+    assert "user.liboverlay" not in os.listxattr(env.lower / "bar" / "bar.txt")
+    assert os.getxattr(env.upper / "foo.txt", "user.liboverlay") == b"path"
+    assert os.getxattr(env.upper / "bar" / "bar.txt", "user.liboverlay") == b"fd"
+    assert read_all(env.upper / "foo.txt") == read_all(env.lower / "foo.txt")
+
+    ret = subprocess.run(
+        [sys.executable, "-c", "import os, sys; os.removexattr(sys.argv[1], 'user.liboverlay')", env.lower / "foo.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+    assert "user.liboverlay" not in os.listxattr(env.upper / "foo.txt")
+
+
+def redirect_rmdir(env: TestEnv) -> None:
+    ret = subprocess.run(
+        ["rmdir", env.lower / "new_dir"],
+        input=b"n\n",
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode != 0
+
+    ret = subprocess.run(
+        ["mkdir", env.lower / "new_dir"],
+        input=b"n\n",
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+
+    ret = subprocess.run(
+        ["rmdir", env.lower / "new_dir"],
+        input=b"n\n",
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+
+
+def whiteout_rmdir(env: TestEnv) -> None:
+    ret = subprocess.run(
+        ["rmdir", env.lower / "bar"], env=env.env, stdout=subprocess.PIPE, stderr=None,
+    )
+    assert ret.returncode != 0
+
+    ret = subprocess.run(
+        ["unlink", env.lower / "bar" / "bar.txt"],
+        env=env.env,
+        stdout=subprocess.PIPE,
+        stderr=None,
+    )
+    assert ret.returncode == 0
+
+    ret = subprocess.run(
+        ["rmdir", env.lower / "bar"], env=env.env, stdout=subprocess.PIPE, stderr=None,
+    )
+    assert ret.returncode == 0
+    assert (env.lower / "bar" / "bar.txt").exists()
+
+    ret = subprocess.run(
+        ["ls", env.lower], env=env.env, stdout=subprocess.PIPE, stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == [b"foo.txt"]
+
+    ret = subprocess.run(
+        ["ls", env.lower / "bar"], env=env.env, stdout=subprocess.PIPE, stderr=None,
+    )
+    assert ret.returncode != 0
+
+    # A recreated directory must not show the old contents
+    ret = subprocess.run(
+        ["mkdir", env.lower / "bar"], env=env.env, stdout=subprocess.PIPE, stderr=None,
+    )
+    assert ret.returncode == 0
+
+    ret = subprocess.run(
+        ["ls", env.lower / "bar"], env=env.env, stdout=subprocess.PIPE, stderr=None,
+    )
+    assert ret.returncode == 0
+    assert ret.stdout.splitlines() == []
+
+    ret = env.overlay_read("bar/bar.txt")
+    assert ret.returncode != 0
+
 
 def run_test(test: Callable[[TestEnv], None]) -> None:
     with tempfile.TemporaryDirectory() as upper_dir:
@@ -228,11 +3665,108 @@ def run():
         can_read_lower,
         redirect_lower_writes_existing,
         redirect_lower_writes_new,
+        redirect_creat,
+        redirect_fopen,
+        redirect_truncate_open,
+        redirect_open_fortified,
+        redirect_openat2,
+        redirect_mkstemp,
         redirect_mkdir,
+        redirect_mkdirat,
         redirect_readdir,
+        redirect_readdir64,
+        redirect_readdir_r,
+        redirect_scandir,
+        redirect_fdopendir,
+        redirect_getdents64,
+        redirect_syscall,
+        redirect_rewinddir,
+        redirect_seekdir,
+        redirect_openat,
+        redirect_dirfd,
+        redirect_dirfd_symlinks,
+        redirect_chdir,
+        redirect_nftw,
+        redirect_fts,
+        redirect_exec,
+        redirect_posix_spawn,
+        redirect_dlopen,
         redirect_stat,
+        redirect_stat64,
+        redirect_statx,
+        redirect_fxstatat,
+        redirect_plain_stat,
+        redirect_name_to_handle_at,
+        redirect_copy_file_range,
+        multiple_mappings,
+        sparse_copy_up,
+        clone_copy_up,
+        copy_up_ranges,
+        copy_up_metadata,
+        copy_up_hard_links,
+        copy_up_symlinks,
+        copy_up_atomic,
+        copy_up_locking,
+        copy_up_threads,
+        normalize_parent_dirs,
+        resolve_symlinked_dirs,
+        directory_semantics,
+        upper_quota,
+        quota_eviction,
+        gc_tool,
+        commit_tool,
+        export_tool,
+        snapshot_tool,
+        session_upper_dirs,
+        copy_on_read,
+        hide_rules,
+        synthetic_files,
+        sorted_dirs,
+        rename_across_layers,
+        dir_copy_up,
+        cross_device,
+        transforms,
+        remote_lower,
+        config_file,
+        mapping_options,
+        reload_config,
+        control_socket,
+        log_categories,
+        program_filter,
+        children_disabled,
+        strict_validation,
+        discovered_config,
+        expanded_dirs,
+        user_config,
+        disabled_hooks,
+        copy_up_strategies,
+        auto_upper_dir,
+        log_destination,
+        json_log,
+        rewrite_rules,
+        whole_root,
+        redirect_statfs,
+        redirect_inotify,
+        redirect_pathconf,
+        redirect_access,
+        redirect_chmod,
+        read_write_copy_up,
+        metadata_copy_up,
+        redirect_chown,
+        redirect_truncate,
+        redirect_utime,
         redirect_unlink,
+        whiteout_unlink,
+        redirect_rename,
+        redirect_link,
+        redirect_tmpfile,
+        redirect_symlink,
+        redirect_readlink,
+        redirect_mknod,
+        redirect_getxattr,
+        redirect_setxattr,
         redirect_rmdir,
+        whiteout_rmdir,
     ]
 
     tap.plan(len(tests))
@@ -0,0 +1,55 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use overlay::{configure, with_overlay, Config, Mapping};
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("liboverlay-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn overlay(lower: &Path, upper: &Path) -> Config {
+    Config::new(vec![Mapping::new(lower, upper)])
+}
+
+// The config is global, so the cases run one after another in a single test.
+#[test]
+fn with_overlay_installs_config() {
+    let lower = temp_dir("lower");
+    let upper = temp_dir("upper");
+    let other_upper = temp_dir("other-upper");
+    fs::write(lower.join("a.txt"), "lower").unwrap();
+    fs::write(upper.join("a.txt"), "upper").unwrap();
+    fs::write(other_upper.join("a.txt"), "other").unwrap();
+
+    // Writes go to the upper dir while the closure runs, and nothing is overlaid afterwards
+    with_overlay(overlay(&lower, &upper), || {
+        assert_eq!(fs::read_to_string(lower.join("a.txt")).unwrap(), "upper");
+        fs::write(lower.join("b.txt"), "new").unwrap();
+        assert_eq!(fs::read_to_string(lower.join("b.txt")).unwrap(), "new");
+    });
+    assert_eq!(fs::read_to_string(lower.join("a.txt")).unwrap(), "lower");
+    assert!(!lower.join("b.txt").exists());
+    assert_eq!(fs::read_to_string(upper.join("b.txt")).unwrap(), "new");
+
+    // Nested calls are refused, and leave the outer config installed
+    with_overlay(overlay(&lower, &upper), || {
+        let nested =
+            std::panic::catch_unwind(|| with_overlay(overlay(&lower, &other_upper), || {}));
+        assert!(nested.is_err());
+        assert_eq!(fs::read_to_string(lower.join("a.txt")).unwrap(), "upper");
+    });
+
+    // A config installed by `configure` meanwhile is kept
+    with_overlay(overlay(&lower, &upper), || {
+        configure(overlay(&lower, &other_upper));
+    });
+    assert_eq!(fs::read_to_string(lower.join("a.txt")).unwrap(), "other");
+
+    configure(Config::new(Vec::new()));
+    for dir in &[lower, upper, other_upper] {
+        fs::remove_dir_all(dir).unwrap();
+    }
+}